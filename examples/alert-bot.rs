@@ -0,0 +1,129 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use eyre::{Result, eyre};
+use matrixbot_ezlogin::{
+    CatchUpPolicy, LoginOptions, RateLimiter, SyncOptions, Webhook, WebhookFormat,
+};
+use tracing::{info, warn};
+
+mod common;
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    #[clap(flatten)]
+    Common(common::Command),
+    #[clap(about = "Run the bot")]
+    Run {
+        #[clap(
+            long = "data",
+            value_name = "PATH",
+            help = "Path to an existing Matrix session"
+        )]
+        data_dir: PathBuf,
+        #[clap(
+            long,
+            value_name = "ROOM",
+            help = "Encrypted room ID or alias to post alerts to"
+        )]
+        room: String,
+        #[clap(
+            long,
+            value_name = "ADDR",
+            default_value = "127.0.0.1:9095",
+            help = "Address to receive Alertmanager webhooks at /alertmanager"
+        )]
+        listen: SocketAddr,
+        #[clap(
+            long,
+            value_name = "ADDR",
+            default_value = "127.0.0.1:9096",
+            help = "Address to serve health metrics at /metrics"
+        )]
+        metrics_listen: SocketAddr,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Parse before common::init puts the terminal in raw mode, because `--help` exits the process
+    let args: Args = clap::Parser::parse();
+    common::init()?;
+
+    match args.command {
+        Command::Common(command) => command.run().await?,
+        Command::Run {
+            data_dir,
+            room,
+            listen,
+            metrics_listen,
+        } => run(&data_dir, &room, listen, metrics_listen).await?,
+    };
+    matrixbot_ezlogin::DuplexLog::shutdown().await;
+    Ok(())
+}
+
+async fn run(
+    data_dir: &Path,
+    room: &str,
+    listen: SocketAddr,
+    metrics_listen: SocketAddr,
+) -> Result<()> {
+    // Configure the same secret in Alertmanager's `http_config.authorization.credentials`.
+    let secret = std::env::var("ALERT_BOT_SECRET")
+        .map_err(|_| eyre!("the environment variable ALERT_BOT_SECRET is not set"))?;
+
+    // The health endpoint. Alert on `time() - ezlogin_sync_last_response_timestamp_seconds`,
+    // so you learn when the bot itself stops delivering alerts.
+    let mut options = LoginOptions::default();
+    options.prometheus_listen_addr = Some(metrics_listen);
+    let (client, sync_helper) = matrixbot_ezlogin::login_with_options(data_dir, options).await?;
+
+    // SyncOptions enables room members lazy-loading, and keeps the bot appearing offline.
+    let sync_options = SyncOptions::default();
+
+    // The bot doesn't read messages, so there is nothing to catch up on.
+    let report = sync_helper
+        .catch_up(&client, sync_options.clone(), CatchUpPolicy::Skip)
+        .await?;
+    info!("Skipped {} messages.", report.skipped_events);
+
+    // Joining is a no-op if the bot is already a member.
+    let room = matrixbot_ezlogin::rooms::join(&client, room).await?;
+    if !room.latest_encryption_state().await?.is_encrypted() {
+        warn!(
+            "Room {} is not encrypted, alerts may leak internal details.",
+            room.room_id()
+        );
+    }
+
+    // Alerts are sent as notices, so other bots in the room don't react to them.
+    // A burst of alerts is paced by the default rate limiter.
+    let webhooks = matrixbot_ezlogin::serve_webhooks(
+        listen,
+        client.clone(),
+        vec![
+            Webhook::new("/alertmanager", room.room_id().to_owned(), &secret)?
+                .format(WebhookFormat::Alertmanager)
+                .rate_limiter(RateLimiter::default()),
+        ],
+    )
+    .await?;
+
+    // Returns on SIGINT (Ctrl-C) or SIGTERM, after the sync token is flushed.
+    info!("Starting sync.");
+    let result = matrixbot_ezlogin::run_until_shutdown(&client, &sync_helper, sync_options).await;
+
+    // Stop accepting alerts. Alertmanager retries undelivered alerts after the restart.
+    webhooks.shutdown().await;
+    result?;
+    info!("Stopped.");
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Command line and logging setup shared by the examples.
+
+use std::path::PathBuf;
+
+use eyre::Result;
+use tracing_subscriber::{EnvFilter, prelude::*};
+
+/// The subcommands every example has besides `run`. Flatten it into the example's own subcommands.
+#[derive(clap::Subcommand)]
+pub enum Command {
+    #[clap(about = "Perform initial setup of Matrix account")]
+    Setup {
+        #[clap(
+            long = "data",
+            value_name = "PATH",
+            help = "Path to store Matrix data between sessions"
+        )]
+        data_dir: PathBuf,
+        #[clap(
+            long,
+            value_name = "DEVICE_NAME",
+            default_value = concat!("matrixbot-ezlogin/", env!("CARGO_BIN_NAME")),
+            help = "Device name to use for this session"
+        )]
+        device_name: String,
+        #[clap(
+            long,
+            value_name = "HOMESERVER",
+            help = "Matrix homeserver, asked interactively if omitted"
+        )]
+        homeserver: Option<String>,
+        #[clap(
+            long,
+            value_name = "USERNAME",
+            help = "User name, asked interactively if omitted"
+        )]
+        username: Option<String>,
+    },
+    #[clap(about = "Log out of the Matrix session, and delete the state database")]
+    Logout {
+        #[clap(
+            long = "data",
+            value_name = "PATH",
+            help = "Path to an existing Matrix session"
+        )]
+        data_dir: PathBuf,
+    },
+}
+
+impl Command {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Command::Setup {
+                data_dir,
+                device_name,
+                homeserver,
+                username,
+            } => {
+                let partial = matrixbot_ezlogin::Partial {
+                    homeserver,
+                    username,
+                    ..Default::default()
+                };
+                drop(
+                    matrixbot_ezlogin::setup_interactive_with(&data_dir, &device_name, partial)
+                        .await?,
+                )
+            }
+            Command::Logout { data_dir } => matrixbot_ezlogin::logout(&data_dir).await?,
+        }
+        Ok(())
+    }
+}
+
+/// Installs the error reporter, and logs to the terminal above the input line.
+///
+/// The example logs at `debug`, the library at `info`, everything else at `warn`. `RUST_LOG` overrides it.
+pub fn init() -> Result<()> {
+    color_eyre::install()?;
+    matrixbot_ezlogin::DuplexLog::init();
+    tracing_subscriber::registry()
+        .with(tracing_error::ErrorLayer::default())
+        .with({
+            let mut filter = EnvFilter::new(concat!(
+                "warn,",
+                env!("CARGO_CRATE_NAME"),
+                "=debug,matrixbot_ezlogin=info"
+            ));
+            if let Some(env) = std::env::var_os(EnvFilter::DEFAULT_ENV) {
+                for segment in env.to_string_lossy().split(',') {
+                    if let Ok(directive) = segment.parse() {
+                        filter = filter.add_directive(directive);
+                    }
+                }
+            }
+            filter
+        })
+        .with(
+            tracing_subscriber::fmt::layer().with_writer(matrixbot_ezlogin::DuplexLog::get_writer),
+        )
+        .init();
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use eyre::Result;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::ruma::api::Direction;
+use matrix_sdk::{Client, Room, RoomState};
+use matrixbot_ezlogin::account_data::{self, Versioned};
+use matrixbot_ezlogin::{
+    CatchUpPolicy, MessageBuilder, RoomPermissions, Schedule, ScheduledTask, Scheduler, SyncOptions,
+};
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio_stream::StreamExt;
+use tracing::{info, instrument, warn};
+
+mod common;
+
+/// The job name stored with each scheduled task.
+const DIGEST_JOB: &str = "daily-digest";
+/// The room account data type that remembers where the last digest ended.
+const DIGEST_STATE: &str = "org.example.digest-bot.state";
+/// How far back the first digest of a room looks.
+const FIRST_DIGEST_PERIOD: Duration = Duration::from_secs(86400);
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    #[clap(flatten)]
+    Common(common::Command),
+    #[clap(about = "Run the bot")]
+    Run {
+        #[clap(
+            long = "data",
+            value_name = "PATH",
+            help = "Path to an existing Matrix session"
+        )]
+        data_dir: PathBuf,
+        #[clap(
+            long = "room",
+            value_name = "ROOM",
+            required = true,
+            help = "Room ID or alias to post a daily digest to, can be repeated"
+        )]
+        rooms: Vec<String>,
+        #[clap(
+            long,
+            value_name = "SCHEDULE",
+            default_value = "every day at 09:00",
+            help = "When to post the digest, for example, \"every day at 18:00\" or \"every 6 hours\""
+        )]
+        schedule: Schedule,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Parse before common::init puts the terminal in raw mode, because `--help` exits the process
+    let args: Args = clap::Parser::parse();
+    common::init()?;
+
+    match args.command {
+        Command::Common(command) => command.run().await?,
+        Command::Run {
+            data_dir,
+            rooms,
+            schedule,
+        } => run(&data_dir, &rooms, schedule).await?,
+    };
+    matrixbot_ezlogin::DuplexLog::shutdown().await;
+    Ok(())
+}
+
+/// Where the last digest of a room ended, stored in the room account data on the homeserver,
+/// so it survives a new data directory.
+///
+/// matrixbot-ezlogin has no key-value store, so this example keeps its state in room account data
+/// through [`account_data`] instead.
+#[derive(Default, Deserialize, Serialize)]
+struct DigestState {
+    /// The `origin_server_ts` of the latest event covered by the last digest, in milliseconds.
+    until: u64,
+}
+
+impl Versioned for DigestState {
+    const VERSION: u32 = 1;
+}
+
+async fn run(data_dir: &Path, rooms: &[String], schedule: Schedule) -> Result<()> {
+    let (client, sync_helper) = matrixbot_ezlogin::login(data_dir).await?;
+
+    // SyncOptions enables room members lazy-loading, and keeps the bot appearing offline.
+    let sync_options = SyncOptions::default();
+
+    // The digest reads history with /messages, so there is no need to process missed events through sync.
+    let report = sync_helper
+        .catch_up(&client, sync_options.clone(), CatchUpPolicy::Skip)
+        .await?;
+    info!("Skipped {} messages.", report.skipped_events);
+
+    // Tasks are stored in the state database, but the job handler must be registered after every start.
+    let scheduler = Scheduler::new(&sync_helper).job(DIGEST_JOB, post_digest);
+
+    // Schedule each room once, and reschedule rooms whose schedule was changed on the command line.
+    let tasks = scheduler.tasks()?;
+    for room in rooms {
+        // Joining is a no-op if the bot is already a member.
+        let room_id = matrixbot_ezlogin::rooms::join(&client, room)
+            .await?
+            .room_id()
+            .to_owned();
+        let mut scheduled = false;
+        for task in tasks.iter().filter(|task| task.payload == room_id.as_str()) {
+            if task.schedule == schedule && !scheduled {
+                scheduled = true;
+            } else {
+                scheduler.cancel(task.id)?;
+            }
+        }
+        if !scheduled {
+            info!("Scheduling a digest of room {} {}.", room_id, schedule);
+            scheduler.schedule(DIGEST_JOB, schedule, room_id.as_str())?;
+        }
+    }
+
+    info!("Starting sync.");
+    select! {
+        result = matrixbot_ezlogin::run_until_shutdown(&client, &sync_helper, sync_options) => result?,
+        result = scheduler.run(&client) => result?,
+    }
+
+    Ok(())
+}
+
+// The job handler. Errors are logged by the scheduler, and the digest is tried again at the next occurrence.
+#[instrument(skip_all, fields(room_id = %task.payload))]
+async fn post_digest(client: Client, task: ScheduledTask) -> Result<()> {
+    let room_id = RoomId::parse(&task.payload)?;
+    let Some(room) = client
+        .get_room(&room_id)
+        .filter(|room| room.state() == RoomState::Joined)
+    else {
+        warn!("Not a member of room {}, skipping its digest.", room_id);
+        return Ok(());
+    };
+
+    let state = account_data::get_room::<DigestState>(&room, DIGEST_STATE)
+        .await?
+        .unwrap_or_else(|| DigestState {
+            until: unix_millis(SystemTime::now() - FIRST_DIGEST_PERIOD),
+        });
+    let (summary, until) = summarize(&room, state.until).await?;
+
+    if !room.can_send("m.room.message").await? {
+        warn!("Not allowed to post the digest to room {}.", room_id);
+        return Ok(());
+    }
+    room.send(MessageBuilder::notice().push_markdown(&summary).build())
+        .await?;
+    info!("Posted the digest of room {}.", room_id);
+
+    // Only remember the position after posting, so a failed digest covers the same period next time.
+    account_data::set_room(&room, DIGEST_STATE, &DigestState { until }).await?;
+    Ok(())
+}
+
+// Pages backward from the latest event until the end of the last digest, counting messages per sender.
+// Returns the summary in Markdown, and the timestamp of the latest event it covers.
+async fn summarize(room: &Room, since: u64) -> Result<(String, u64)> {
+    let mut until = since;
+    let mut senders = HashMap::<String, u64>::new();
+    let mut undecryptable = 0u64;
+    let events = matrixbot_ezlogin::history::paginate(room, None, Direction::Backward);
+    tokio::pin!(events);
+    while let Some(event) = events.next().await {
+        let event = event?;
+        let raw = event.raw();
+        let Ok(Some(timestamp)) = raw.get_field::<u64>("origin_server_ts") else {
+            continue;
+        };
+        if timestamp <= since {
+            break;
+        }
+        until = until.max(timestamp);
+        match raw.get_field::<String>("type") {
+            Ok(Some(event_type)) if event_type == "m.room.message" => (),
+            // Encrypted events are only left encrypted if the bot doesn't have the room key.
+            Ok(Some(event_type)) if event_type == "m.room.encrypted" => {
+                undecryptable += 1;
+                continue;
+            }
+            _ => continue,
+        }
+        if let Ok(Some(sender)) = raw.get_field::<String>("sender")
+            && Some(sender.as_str()) != room.client().user_id().map(|user_id| user_id.as_str())
+        {
+            *senders.entry(sender).or_default() += 1;
+        }
+    }
+
+    let total = senders.values().sum::<u64>();
+    let mut summary = format!(
+        "**Digest:** {} message(s) from {} member(s) since the last digest.",
+        total,
+        senders.len()
+    );
+    let mut ranking = senders.into_iter().collect::<Vec<_>>();
+    ranking.sort_by(|(a_sender, a_count), (b_sender, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_sender.cmp(b_sender))
+    });
+    for (sender, count) in ranking.iter().take(5) {
+        summary.push_str(&format!("\n* {}: {}", sender, count));
+    }
+    if undecryptable != 0 {
+        summary.push_str(&format!(
+            "\n\n{} encrypted message(s) couldn't be decrypted, and were not counted.",
+            undecryptable
+        ));
+    }
+    Ok((summary, until))
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
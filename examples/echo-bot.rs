@@ -1,24 +1,23 @@
 use std::path::{Path, PathBuf};
-use std::time::Duration;
 
 use eyre::Result;
-use matrix_sdk::config::SyncSettings;
-use matrix_sdk::event_handler::RawEvent;
-use matrix_sdk::room::Receipts;
-use matrix_sdk::ruma::OwnedEventId;
-use matrix_sdk::ruma::api::client::filter::FilterDefinition;
+use matrix_sdk::event_handler::{Ctx, RawEvent};
 use matrix_sdk::ruma::events::relation::{InReplyTo, Thread};
 use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
 use matrix_sdk::ruma::events::room::member::{
     MembershipState, StrippedRoomMemberEvent, SyncRoomMemberEvent,
 };
 use matrix_sdk::ruma::events::room::message::{
-    MessageType, NoticeMessageEventContent, OriginalSyncRoomMessageEvent, Relation,
+    MessageType, OriginalSyncRoomMessageEvent, Relation,
 };
 use matrix_sdk::ruma::events::sticker::OriginalSyncStickerEvent;
 use matrix_sdk::{Client, Room, RoomState};
+use matrixbot_ezlogin::{
+    CatchUpPolicy, LoginOptions, ReadReceiptPolicy, ReadReceipts, SyncHelper, SyncOptions,
+};
 use tracing::{Instrument, error, info, instrument, warn};
-use tracing_subscriber::{EnvFilter, prelude::*};
+
+mod common;
 
 #[derive(clap::Parser)]
 struct Args {
@@ -28,22 +27,8 @@ struct Args {
 
 #[derive(clap::Subcommand)]
 enum Command {
-    #[clap(about = "Perform initial setup of Matrix account")]
-    Setup {
-        #[clap(
-            long = "data",
-            value_name = "PATH",
-            help = "Path to store Matrix data between sessions"
-        )]
-        data_dir: PathBuf,
-        #[clap(
-            long,
-            value_name = "DEVICE_NAME",
-            default_value = concat!("matrixbot-ezlogin/", env!("CARGO_BIN_NAME")),
-            help = "Device name to use for this session"
-        )]
-        device_name: String,
-    },
+    #[clap(flatten)]
+    Common(common::Command),
     #[clap(about = "Run the bot")]
     Run {
         #[clap(
@@ -52,83 +37,55 @@ enum Command {
             help = "Path to an existing Matrix session"
         )]
         data_dir: PathBuf,
-    },
-    #[clap(about = "Log out of the Matrix session, and delete the state database")]
-    Logout {
         #[clap(
-            long = "data",
-            value_name = "PATH",
-            help = "Path to an existing Matrix session"
+            long,
+            help = "Forget the sync position and perform a full initial sync"
         )]
-        data_dir: PathBuf,
+        fresh: bool,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    color_eyre::install()?;
-    matrixbot_ezlogin::DuplexLog::init();
-    tracing_subscriber::registry()
-        .with(tracing_error::ErrorLayer::default())
-        .with({
-            let mut filter = EnvFilter::new(concat!(
-                "warn,",
-                env!("CARGO_CRATE_NAME"),
-                "=debug,matrixbot_ezlogin=info"
-            ));
-            if let Some(env) = std::env::var_os(EnvFilter::DEFAULT_ENV) {
-                for segment in env.to_string_lossy().split(',') {
-                    if let Ok(directive) = segment.parse() {
-                        filter = filter.add_directive(directive);
-                    }
-                }
-            }
-            filter
-        })
-        .with(
-            tracing_subscriber::fmt::layer().with_writer(matrixbot_ezlogin::DuplexLog::get_writer),
-        )
-        .init();
-
+    // Parse before common::init puts the terminal in raw mode, because `--help` exits the process
     let args: Args = clap::Parser::parse();
+    common::init()?;
 
     match args.command {
-        Command::Setup {
-            data_dir,
-            device_name,
-        } => drop(matrixbot_ezlogin::setup_interactive(&data_dir, &device_name).await?),
-        Command::Run { data_dir } => run(&data_dir).await?,
-        Command::Logout { data_dir } => matrixbot_ezlogin::logout(&data_dir).await?,
+        Command::Common(command) => command.run().await?,
+        Command::Run { data_dir, fresh } => run(&data_dir, fresh).await?,
     };
+    matrixbot_ezlogin::DuplexLog::shutdown().await;
     Ok(())
 }
 
-async fn run(data_dir: &Path) -> Result<()> {
-    let (client, sync_helper) = matrixbot_ezlogin::login(data_dir).await?;
-
+async fn run(data_dir: &Path, fresh: bool) -> Result<()> {
     // Enable event cache to remember old messages.
-    // Can be used with `Room::load_or_fetch_event`.
-    // client.event_cache().subscribe()?;
-
-    // Attach custom data to event handlers.
-    // client.add_event_handler_context(data)
+    // Can be used with `matrixbot_ezlogin::load_or_fetch_event` and `matrixbot_ezlogin::replied_to_message`.
+    let mut options = LoginOptions::default();
+    options.enable_event_cache = true;
+    let (client, sync_helper) = matrixbot_ezlogin::login_with_options(data_dir, options).await?;
+    if fresh {
+        sync_helper.clear_sync_token()?;
+    }
 
     // We don't ignore joining and leaving events happened during downtime.
     client.add_event_handler(on_invite);
     client.add_event_handler(on_leave);
 
-    // Enable room members lazy-loading, it will speed up the initial sync a lot with accounts in lots of rooms.
-    // https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members
-    let sync_settings =
-        SyncSettings::default().filter(FilterDefinition::with_lazy_loading().into());
+    // SyncOptions enables room members lazy-loading, and keeps the bot appearing offline.
+    let sync_options = SyncOptions::default();
 
     info!(
         "Skipping messages since last logout. May take longer depending on the number of rooms joined."
     );
-    sync_helper
-        .sync_once(&client, sync_settings.clone())
+    let report = sync_helper
+        .catch_up(&client, sync_options.clone(), CatchUpPolicy::Skip)
         .await?;
+    info!("Skipped {} messages.", report.skipped_events);
 
+    // Mark incoming messages as read, so users know the bot is alive.
+    ReadReceipts::new(ReadReceiptPolicy::Immediately).register(&client);
     client.add_event_handler(on_message);
     client.add_event_handler(on_sticker);
     client.add_event_handler(on_utd);
@@ -149,30 +106,11 @@ async fn run(data_dir: &Path) -> Result<()> {
     );
 
     info!("Starting sync.");
-    sync_helper.sync(&client, sync_settings).await?;
+    matrixbot_ezlogin::run_until_shutdown(&client, &sync_helper, sync_options).await?;
 
     Ok(())
 }
 
-#[instrument(skip_all)]
-async fn set_read_marker(room: Room, event_id: OwnedEventId) {
-    if let Err(err) = room
-        .send_multiple_receipts(
-            Receipts::new()
-                .fully_read_marker(event_id.clone())
-                .public_read_receipt(event_id.clone()),
-        )
-        .await
-    {
-        error!(
-            "Failed to set the read marker of room {} to event {}: {}",
-            room.room_id(),
-            event_id,
-            err
-        );
-    }
-}
-
 // https://spec.matrix.org/v1.14/client-server-api/#mroommessage
 #[instrument(skip_all)]
 async fn on_message(event: OriginalSyncRoomMessageEvent, room: Room, client: Client) {
@@ -180,7 +118,6 @@ async fn on_message(event: OriginalSyncRoomMessageEvent, room: Room, client: Cli
         // Ignore my own message
         return;
     }
-    tokio::spawn(set_read_marker(room.clone(), event.event_id.clone()));
     if room.state() != RoomState::Joined {
         info!(
             "Ignoring room {}, event {}: Current room state is {:?}.",
@@ -212,25 +149,8 @@ async fn on_message(event: OriginalSyncRoomMessageEvent, room: Room, client: Cli
         return;
     }
 
-    let mut reply = event.content;
-    // Transform m.text into m.notice. Some bot implementations are designed to ignore m.notice, preventing infinite looping.
-    // Note that some clients may choose to render m.notice in a different text color.
-    if let MessageType::Text(text) = reply.msgtype {
-        let mut notice = NoticeMessageEventContent::plain(text.body);
-        notice.formatted = text.formatted;
-        reply.msgtype = MessageType::Notice(notice);
-    }
-    // We should use make_reply_to, but it embeds the original message body, which I don't want
-    reply.relates_to = match reply.relates_to {
-        Some(Relation::Replacement(_)) => unreachable!(),
-        Some(Relation::Thread(thread)) => Some(Relation::Thread(Thread::reply(
-            thread.event_id,
-            event.event_id.to_owned(),
-        ))),
-        _ => Some(Relation::Reply {
-            in_reply_to: InReplyTo::new(event.event_id.to_owned()),
-        }),
-    };
+    // Transform m.text into m.notice, and reply without embedding the original message body
+    let reply = matrixbot_ezlogin::reply_without_fallback(&event, event.content.clone());
 
     tokio::spawn(
         async move {
@@ -267,7 +187,6 @@ async fn on_sticker(event: OriginalSyncStickerEvent, room: Room, client: Client)
         // Ignore my own message
         return;
     }
-    tokio::spawn(set_read_marker(room.clone(), event.event_id.clone()));
     if room.state() != RoomState::Joined {
         info!(
             "Ignoring room {}, event {}: Current room state is {:?}.",
@@ -392,31 +311,11 @@ async fn on_invite(event: StrippedRoomMemberEvent, room: Room, client: Client) {
         room.room_id()
     );
 
+    // Joining is retried for about 1 hour, so don't block other event handlers.
     tokio::spawn(
         async move {
-            for retry in 0.. {
-                info!("Joining room {}.", room.room_id());
-                match room.join().await {
-                    Ok(_) => {
-                        info!("Joined room {}.", room.room_id());
-                        return;
-                    }
-                    Err(err) => {
-                        // https://github.com/matrix-org/synapse/issues/4345
-                        if retry >= 16 {
-                            error!("Failed to join room {}: {}", room.room_id(), err);
-                            error!("Too many retries, giving up after 1 hour.");
-                            return;
-                        } else {
-                            const BASE: f64 = 1.6180339887498947;
-                            let duration = BASE.powi(retry);
-                            warn!("Failed to join room {}: {}", room.room_id(), err);
-                            warn!("This is common, will retry in {:.1}s.", duration);
-                            tokio::time::sleep(Duration::from_secs_f64(duration)).await;
-                        }
-                    }
-                }
-            }
+            // The failure is already logged.
+            _ = matrixbot_ezlogin::rooms::join(&client, room.room_id().as_str()).await;
         }
         .in_current_span(),
     );
@@ -429,15 +328,20 @@ async fn on_invite(event: StrippedRoomMemberEvent, room: Room, client: Client) {
 // Each m.room.member event occurs twice in SyncResponse, one as state event, another as timeline event.
 // As of matrix_sdk-0.11.0, this event handler matching SyncRoomMemberEvent is actually called twice whenever such an event happens.
 // (Reference: matrix_sdk::Client::call_sync_response_handlers, https://github.com/matrix-org/matrix-rust-sdk/pull/4947)
-// Thankfully, leaving a room twice does not return errors.
+// Thankfully, leaving a room twice does not return errors, but we still skip the duplicate using SyncHelper::seen.
 #[instrument(skip_all)]
-async fn on_leave(event: SyncRoomMemberEvent, room: Room) {
+async fn on_leave(event: SyncRoomMemberEvent, room: Room, sync_helper: Ctx<SyncHelper>) {
     if !matches!(
         event.membership(),
         MembershipState::Leave | MembershipState::Ban
     ) {
         return;
     }
+    match sync_helper.seen(event.event_id()) {
+        Ok(true) => return,
+        Ok(false) => (),
+        Err(err) => warn!("Failed to deduplicate event {}: {}", event.event_id(), err),
+    }
 
     match room.state() {
         RoomState::Joined => {
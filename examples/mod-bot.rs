@@ -0,0 +1,340 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use eyre::Result;
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId, OwnedUserId};
+use matrix_sdk::{Client, Room, RoomState};
+use matrixbot_ezlogin::{
+    Acl, CatchUpPolicy, CommandContext, Commands, MessageBuilder, Rest, RoomPermissions,
+    SyncOptions,
+};
+use tracing::{Instrument, error, info, instrument, warn};
+
+mod common;
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    #[clap(flatten)]
+    Common(common::Command),
+    #[clap(about = "Run the bot")]
+    Run {
+        #[clap(
+            long = "data",
+            value_name = "PATH",
+            help = "Path to an existing Matrix session"
+        )]
+        data_dir: PathBuf,
+        #[clap(
+            long,
+            value_name = "ROOM",
+            help = "Room ID or alias where moderators send commands and receive reports"
+        )]
+        admin_room: String,
+        #[clap(
+            long = "admin",
+            value_name = "USER_ID",
+            required = true,
+            help = "User allowed to send commands, can be repeated"
+        )]
+        admins: Vec<OwnedUserId>,
+    },
+}
+
+/// Who may moderate, and where.
+struct Moderators {
+    admin_room_id: OwnedRoomId,
+    admins: Vec<OwnedUserId>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Parse before common::init puts the terminal in raw mode, because `--help` exits the process
+    let args: Args = clap::Parser::parse();
+    common::init()?;
+
+    match args.command {
+        Command::Common(command) => command.run().await?,
+        Command::Run {
+            data_dir,
+            admin_room,
+            admins,
+        } => run(&data_dir, &admin_room, admins).await?,
+    };
+    matrixbot_ezlogin::DuplexLog::shutdown().await;
+    Ok(())
+}
+
+async fn run(data_dir: &Path, admin_room: &str, admins: Vec<OwnedUserId>) -> Result<()> {
+    let (client, sync_helper) = matrixbot_ezlogin::login(data_dir).await?;
+
+    // SyncOptions enables room members lazy-loading, and keeps the bot appearing offline.
+    let sync_options = SyncOptions::default();
+
+    // A moderation bot shouldn't act on stale messages sent while it was offline.
+    info!("Skipping messages since last logout.");
+    let report = sync_helper
+        .catch_up(&client, sync_options.clone(), CatchUpPolicy::Skip)
+        .await?;
+    info!("Skipped {} messages.", report.skipped_events);
+
+    // Joining is a no-op if the bot is already a member.
+    let admin_room = matrixbot_ezlogin::rooms::join(&client, admin_room).await?;
+    let moderators = Arc::new(Moderators {
+        admin_room_id: admin_room.room_id().to_owned(),
+        admins: admins.clone(),
+    });
+
+    // The denylist is stored in the state database, so it survives restarts.
+    // Messages from users or servers on the denylist are redacted in every room the bot moderates.
+    // The allowlist and room patterns only restrict who may use the commands.
+    let acl = Acl::load(&sync_helper)?;
+    client.add_event_handler({
+        let acl = acl.clone();
+        let moderators = moderators.clone();
+        move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+            let acl = acl.clone();
+            let moderators = moderators.clone();
+            async move { on_message(event, room, client, &acl, &moderators).await }
+        }
+    });
+
+    // Server notices and server ACL changes are worth a moderator's attention too.
+    matrixbot_ezlogin::forward_server_events(
+        &client,
+        moderators.admin_room_id.clone(),
+        sync_helper.rate_limiter(),
+    );
+
+    Commands::new("!")
+        .commands(acl.commands(admins))
+        .command(moderation_command("kick", moderators.clone()))
+        .command(moderation_command("ban", moderators.clone()))
+        .command(moderation_command("unban", moderators.clone()))
+        .command(redact_command(moderators.clone()))
+        .register(&client, &sync_helper);
+
+    info!("Starting sync.");
+    matrixbot_ezlogin::run_until_shutdown(&client, &sync_helper, sync_options).await?;
+
+    Ok(())
+}
+
+// Redact messages from users on the denylist, and report them to the admin room.
+//
+// https://spec.matrix.org/v1.14/client-server-api/#redactions
+#[instrument(skip_all)]
+async fn on_message(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    client: Client,
+    acl: &Acl,
+    moderators: &Moderators,
+) {
+    if event.sender == client.user_id().unwrap()
+        || room.state() != RoomState::Joined
+        || room.room_id() == moderators.admin_room_id
+        || !acl.is_denied(&event.sender)
+    {
+        return;
+    }
+    info!(
+        "Redacting event {} from denied user {} in room {}.",
+        event.event_id,
+        event.sender,
+        room.room_id()
+    );
+    // `redact` checks the bot's power level first, so a missing permission doesn't cost a request.
+    let text =
+        match matrixbot_ezlogin::redact(&room, &event, Some("Sender is on the denylist")).await {
+            Ok(_) => format!(
+                "Redacted a message from {} in room {}.",
+                event.sender,
+                room.room_id()
+            ),
+            Err(err) => {
+                error!("Failed to redact event {}: {}", event.event_id, err);
+                format!(
+                    "Failed to redact a message from {} in room {}: {}",
+                    event.sender,
+                    room.room_id(),
+                    err
+                )
+            }
+        };
+    tokio::spawn(report(client, moderators.admin_room_id.clone(), text).in_current_span());
+}
+
+// Commands are only accepted from admins, and only in the admin room, so moderators can't be impersonated in public rooms.
+async fn check_moderator(ctx: &CommandContext, moderators: &Moderators) -> Result<bool> {
+    if ctx.room.room_id() != moderators.admin_room_id {
+        return Ok(false);
+    }
+    if !moderators.admins.contains(&ctx.event.sender) {
+        ctx.reply("Only bot administrators can moderate.").await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+// The bot only moderates rooms it has joined.
+async fn target_room(ctx: &CommandContext, room_id: &OwnedRoomId) -> Result<Option<Room>> {
+    match ctx.client.get_room(room_id) {
+        Some(room) if room.state() == RoomState::Joined => Ok(Some(room)),
+        _ => {
+            ctx.reply(&format!("I am not in room {}.", room_id)).await?;
+            Ok(None)
+        }
+    }
+}
+
+// `!kick`, `!ban`, and `!unban` share the same arguments and checks.
+//
+// The bot acts on behalf of the moderator, so both must have the power level in the target room.
+// The checks read the cached m.room.power_levels state, so they don't need a request to the server.
+//
+// https://spec.matrix.org/v1.14/client-server-api/#mroompower_levels
+fn moderation_command(
+    name: &'static str,
+    moderators: Arc<Moderators>,
+) -> matrixbot_ezlogin::Command {
+    matrixbot_ezlogin::Command::new(
+        name,
+        move |ctx, (room_id, user_id, reason): (OwnedRoomId, OwnedUserId, Option<Rest>)| {
+            let moderators = moderators.clone();
+            async move {
+                if !check_moderator(&ctx, &moderators).await? {
+                    return Ok(());
+                }
+                let Some(room) = target_room(&ctx, &room_id).await? else {
+                    return Ok(());
+                };
+                let sender = &ctx.event.sender;
+                let (bot_allowed, sender_allowed) = match name {
+                    "kick" => (room.can_kick().await?, room.can_user_kick(sender).await?),
+                    _ => (room.can_ban().await?, room.can_user_ban(sender).await?),
+                };
+                if !sender_allowed {
+                    ctx.reply(&format!(
+                        "You are not allowed to {} in room {}.",
+                        name, room_id
+                    ))
+                    .await?;
+                    return Ok(());
+                }
+                if !bot_allowed {
+                    ctx.reply(&format!(
+                        "I am not allowed to {} in room {}.",
+                        name, room_id
+                    ))
+                    .await?;
+                    return Ok(());
+                }
+                let reason = reason.map(|Rest(reason)| reason);
+                let result = match name {
+                    "kick" => room.kick_user(&user_id, reason.as_deref()).await,
+                    "ban" => room.ban_user(&user_id, reason.as_deref()).await,
+                    _ => room.unban_user(&user_id, reason.as_deref()).await,
+                };
+                match result {
+                    Ok(()) => {
+                        info!("{} {} from room {} for {}.", name, user_id, room_id, sender);
+                        ctx.reply(&format!("Done: {} {} in room {}.", name, user_id, room_id))
+                            .await?;
+                    }
+                    Err(err) => {
+                        ctx.reply(&format!("Failed to {} {}: {}", name, user_id, err))
+                            .await?;
+                    }
+                }
+                Ok(())
+            }
+        },
+    )
+    .usage("<room_id> <user_id> [reason]")
+    .summary(match name {
+        "kick" => "Kicks a user from a room",
+        "ban" => "Bans a user from a room",
+        _ => "Unbans a user from a room",
+    })
+}
+
+fn redact_command(moderators: Arc<Moderators>) -> matrixbot_ezlogin::Command {
+    matrixbot_ezlogin::Command::new(
+        "redact",
+        move |ctx, (room_id, event_id, reason): (OwnedRoomId, OwnedEventId, Option<Rest>)| {
+            let moderators = moderators.clone();
+            async move {
+                if !check_moderator(&ctx, &moderators).await? {
+                    return Ok(());
+                }
+                let Some(room) = target_room(&ctx, &room_id).await? else {
+                    return Ok(());
+                };
+                if !room.can_user_redact_other(&ctx.event.sender).await? {
+                    ctx.reply(&format!(
+                        "You are not allowed to redact in room {}.",
+                        room_id
+                    ))
+                    .await?;
+                    return Ok(());
+                }
+                if !room.can_redact_other().await? {
+                    ctx.reply(&format!("I am not allowed to redact in room {}.", room_id))
+                        .await?;
+                    return Ok(());
+                }
+                let reason = reason.map(|Rest(reason)| reason);
+                match room.redact(&event_id, reason.as_deref(), None).await {
+                    Ok(_) => {
+                        info!(
+                            "Redacted event {} in room {} for {}.",
+                            event_id, room_id, ctx.event.sender
+                        );
+                        ctx.reply(&format!("Redacted event {}.", event_id)).await?;
+                    }
+                    Err(err) => {
+                        ctx.reply(&format!("Failed to redact event {}: {}", event_id, err))
+                            .await?;
+                    }
+                }
+                Ok(())
+            }
+        },
+    )
+    .usage("<room_id> <event_id> [reason]")
+    .summary("Redacts an event from a room")
+}
+
+async fn report(client: Client, admin_room_id: OwnedRoomId, text: String) {
+    let Some(admin_room) = client.get_room(&admin_room_id) else {
+        warn!("Not a member of admin room {}.", admin_room_id);
+        return;
+    };
+    match admin_room.can_send("m.room.message").await {
+        Ok(true) => (),
+        Ok(false) => {
+            warn!(
+                "Not allowed to send messages to admin room {}.",
+                admin_room_id
+            );
+            return;
+        }
+        Err(err) => {
+            warn!("Failed to check admin room {}: {}", admin_room_id, err);
+            return;
+        }
+    }
+    if let Err(err) = admin_room
+        .send(MessageBuilder::notice().push_text(&text).build())
+        .await
+    {
+        error!("Failed to report to admin room {}: {}", admin_room_id, err);
+    }
+}
@@ -0,0 +1,202 @@
+use std::path::{Path, PathBuf};
+
+use eyre::Result;
+use matrix_sdk::ruma::events::room::message::{OriginalSyncRoomMessageEvent, Relation};
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use matrix_sdk::{Client, Room, RoomState};
+use matrixbot_ezlogin::{
+    CatchUpPolicy, MessageBuilder, SendQueue, SyncHelper, SyncOptions, TextFlavor,
+};
+use tokio::select;
+use tracing::{Instrument, error, info, instrument};
+
+mod common;
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    #[clap(flatten)]
+    Common(common::Command),
+    #[clap(about = "Run the bot")]
+    Run {
+        #[clap(
+            long = "left-data",
+            value_name = "PATH",
+            help = "Path to the Matrix session of the first account"
+        )]
+        left_data_dir: PathBuf,
+        #[clap(
+            long,
+            value_name = "ROOM",
+            help = "Room ID or alias the first account relays"
+        )]
+        left_room: String,
+        #[clap(
+            long = "right-data",
+            value_name = "PATH",
+            help = "Path to the Matrix session of the second account"
+        )]
+        right_data_dir: PathBuf,
+        #[clap(
+            long,
+            value_name = "ROOM",
+            help = "Room ID or alias the second account relays"
+        )]
+        right_room: String,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Parse before common::init puts the terminal in raw mode, because `--help` exits the process
+    let args: Args = clap::Parser::parse();
+    common::init()?;
+
+    match args.command {
+        Command::Common(command) => command.run().await?,
+        Command::Run {
+            left_data_dir,
+            left_room,
+            right_data_dir,
+            right_room,
+        } => run(&left_data_dir, &left_room, &right_data_dir, &right_room).await?,
+    };
+    matrixbot_ezlogin::DuplexLog::shutdown().await;
+    Ok(())
+}
+
+/// One side of the relay: an account, and the room it relays.
+#[derive(Clone)]
+struct Side {
+    client: Client,
+    sync_helper: SyncHelper,
+    room_id: OwnedRoomId,
+    send_queue: SendQueue,
+}
+
+async fn run(
+    left_data_dir: &Path,
+    left_room: &str,
+    right_data_dir: &Path,
+    right_room: &str,
+) -> Result<()> {
+    // Each account has its own data directory, so its own state database, crypto store, and sync token.
+    // They can be on different homeservers.
+    let left = open_side(left_data_dir, left_room).await?;
+    let right = open_side(right_data_dir, right_room).await?;
+
+    // Messages relayed by either bot must not be relayed back.
+    let bots = [
+        left.client.user_id().unwrap().to_owned(),
+        right.client.user_id().unwrap().to_owned(),
+    ];
+    relay(&left, &right, bots.clone());
+    relay(&right, &left, bots);
+
+    // Both sync loops stop on SIGINT or SIGTERM, and flush their sync tokens.
+    // Messages still queued are stored in the state database, and sent after the next start.
+    info!("Starting sync.");
+    let sync_options = SyncOptions::default();
+    let sync = async {
+        tokio::try_join!(
+            matrixbot_ezlogin::run_until_shutdown(
+                &left.client,
+                &left.sync_helper,
+                sync_options.clone()
+            ),
+            matrixbot_ezlogin::run_until_shutdown(&right.client, &right.sync_helper, sync_options),
+        )
+    };
+    select! {
+        result = sync => {
+            result?;
+        }
+        result = left.send_queue.run(&left.client) => result?,
+        result = right.send_queue.run(&right.client) => result?,
+    }
+
+    Ok(())
+}
+
+async fn open_side(data_dir: &Path, room: &str) -> Result<Side> {
+    let (client, sync_helper) = matrixbot_ezlogin::login(data_dir).await?;
+
+    // Don't relay a backlog of old messages after a restart.
+    let report = sync_helper
+        .catch_up(&client, SyncOptions::default(), CatchUpPolicy::Skip)
+        .await?;
+    info!(
+        "Skipped {} messages for {}.",
+        report.skipped_events,
+        client.user_id().unwrap()
+    );
+
+    // Joining is a no-op if the bot is already a member.
+    let room_id = matrixbot_ezlogin::rooms::join(&client, room)
+        .await?
+        .room_id()
+        .to_owned();
+    let send_queue = SendQueue::new(&sync_helper);
+    Ok(Side {
+        client,
+        sync_helper,
+        room_id,
+        send_queue,
+    })
+}
+
+// Relays every message in the room of `from` to the room of `to`, through the send queue of `to`.
+// The queue survives restarts and retries failed sends, so a homeserver outage on one side doesn't lose messages.
+fn relay(from: &Side, to: &Side, bots: [OwnedUserId; 2]) {
+    let from_room_id = from.room_id.clone();
+    let to = to.clone();
+    from.client
+        .add_event_handler(move |event: OriginalSyncRoomMessageEvent, room: Room| {
+            let from_room_id = from_room_id.clone();
+            let to = to.clone();
+            let bots = bots.clone();
+            async move {
+                if room.room_id() != from_room_id
+                    || room.state() != RoomState::Joined
+                    || bots.contains(&event.sender)
+                {
+                    return;
+                }
+                // Edits would be relayed as new messages, so they are skipped.
+                if let Some(Relation::Replacement(_)) = event.content.relates_to {
+                    return;
+                }
+                relay_message(event, room, to).await;
+            }
+            .in_current_span()
+        });
+}
+
+#[instrument(skip_all)]
+async fn relay_message(event: OriginalSyncRoomMessageEvent, room: Room, to: Side) {
+    // Prefixed with the sender's name, and the message it replies to, if any.
+    let text = match matrixbot_ezlogin::render_message(&room, &event, TextFlavor::Plain).await {
+        Ok(text) => text,
+        Err(err) => {
+            error!("Failed to render event {}: {}", event.event_id, err);
+            return;
+        }
+    };
+    // Relayed messages are notices, so other bots in the destination room don't respond to them.
+    let content = MessageBuilder::notice().push_text(&text).build();
+    match to.send_queue.enqueue(&to.room_id, content) {
+        Ok(id) => info!(
+            "Queued event {} of room {} as message {} to room {}.",
+            event.event_id,
+            room.room_id(),
+            id,
+            to.room_id
+        ),
+        Err(err) => error!("Failed to queue event {}: {}", event.event_id, err),
+    }
+}
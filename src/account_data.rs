@@ -0,0 +1,138 @@
+//! Typed account data, to store small configuration on the homeserver.
+//!
+//! Unlike the local state database, account data is shared by every session of the bot account, so it survives a new data directory and can be shared by bots running on different machines.
+//!
+//! Values are stored as `{"version": <version>, "data": <value>}`, and [`Versioned::migrate`] upgrades values written by older versions of the bot.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use matrixbot_ezlogin::account_data::{self, Versioned};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Default, Deserialize, Serialize)]
+//! struct Settings {
+//!     greeting: String,
+//! }
+//!
+//! impl Versioned for Settings {
+//!     const VERSION: u32 = 1;
+//! }
+//!
+//! # async fn example(client: &matrix_sdk::Client) -> color_eyre::Result<()> {
+//! let mut settings = account_data::get::<Settings>(client, "com.example.bot.settings")
+//!     .await?
+//!     .unwrap_or_default();
+//! settings.greeting = "Hello!".to_owned();
+//! account_data::set(client, "com.example.bot.settings", &settings).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use eyre::{OptionExt, Result, bail};
+use matrix_sdk::Client;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::api::client::config::{get_global_account_data, get_room_account_data};
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::events::{GlobalAccountDataEventType, RoomAccountDataEventType};
+use matrix_sdk::ruma::serde::Raw;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+/// A type stored in account data with [`get`] and [`set`].
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// The current version of the format. Increase it whenever a change breaks deserialization of older values.
+    const VERSION: u32;
+
+    /// Converts a value written with an older `version`. The default implementation fails.
+    fn migrate(version: u32, data: Value) -> Result<Self> {
+        _ = data;
+        bail!(
+            "no migration from version {} to version {}",
+            version,
+            Self::VERSION
+        )
+    }
+}
+
+/// Fetches the account data of `event_type` from the homeserver. Returns [`None`] if it is not set.
+pub async fn get<T: Versioned>(client: &Client, event_type: &str) -> Result<Option<T>> {
+    let user_id = client.user_id().ok_or_eyre("not logged in")?.to_owned();
+    let request = get_global_account_data::v3::Request::new(
+        user_id,
+        GlobalAccountDataEventType::from(event_type),
+    );
+    match client.send(request).await {
+        Ok(response) => decode(response.account_data.json().get()).map(Some),
+        Err(err) if matches!(err.client_api_error_kind(), Some(ErrorKind::NotFound)) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Stores `value` as the account data of `event_type` on the homeserver.
+pub async fn set<T: Versioned>(client: &Client, event_type: &str, value: &T) -> Result<()> {
+    client
+        .account()
+        .set_account_data_raw(
+            GlobalAccountDataEventType::from(event_type),
+            Raw::from_json(encode(value)?),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Fetches the account data of `event_type` for `room` from the homeserver. Returns [`None`] if it is not set.
+pub async fn get_room<T: Versioned>(room: &Room, event_type: &str) -> Result<Option<T>> {
+    let request = get_room_account_data::v3::Request::new(
+        room.own_user_id().to_owned(),
+        room.room_id().to_owned(),
+        RoomAccountDataEventType::from(event_type),
+    );
+    match room.client().send(request).await {
+        Ok(response) => decode(response.account_data.json().get()).map(Some),
+        Err(err) if matches!(err.client_api_error_kind(), Some(ErrorKind::NotFound)) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Stores `value` as the account data of `event_type` for `room` on the homeserver.
+pub async fn set_room<T: Versioned>(room: &Room, event_type: &str, value: &T) -> Result<()> {
+    room.set_account_data_raw(
+        RoomAccountDataEventType::from(event_type),
+        Raw::from_json(encode(value)?),
+    )
+    .await?;
+    Ok(())
+}
+
+fn encode<T: Versioned>(value: &T) -> Result<Box<serde_json::value::RawValue>> {
+    Ok(serde_json::value::to_raw_value(&json!({
+        "version": T::VERSION,
+        "data": value,
+    }))?)
+}
+
+fn decode<T: Versioned>(json: &str) -> Result<T> {
+    let mut envelope = serde_json::from_str::<Value>(json)?;
+    let version = envelope
+        .get("version")
+        .and_then(Value::as_u64)
+        .and_then(|version| u32::try_from(version).ok())
+        .ok_or_eyre("account data has no version")?;
+    let data = envelope
+        .get_mut("data")
+        .map(Value::take)
+        .ok_or_eyre("account data has no data")?;
+    if version == T::VERSION {
+        Ok(serde_json::from_value(data)?)
+    } else if version < T::VERSION {
+        T::migrate(version, data)
+    } else {
+        bail!(
+            "account data version {} is newer than supported version {}",
+            version,
+            T::VERSION
+        )
+    }
+}
@@ -0,0 +1,69 @@
+use async_stream::try_stream;
+use matrix_sdk::Client;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::sync::SyncResponse;
+use tokio::sync::oneshot;
+use tokio_stream::{Stream, StreamExt};
+use tracing::trace;
+
+use crate::SyncHelper;
+
+/// Acknowledges that a [`SyncResponse`] yielded by [`SyncHelper::sync_stream_with_ack`] has been processed.
+///
+/// Dropping it without calling [`AckHandle::ack`] terminates the stream with an error, without saving the sync token.
+#[derive(Debug)]
+#[must_use = "the sync stream stops unless the response is acknowledged"]
+pub struct AckHandle {
+    sync_helper: SyncHelper,
+    response: SyncResponse,
+    ack_tx: oneshot::Sender<()>,
+}
+
+impl AckHandle {
+    /// Saves the sync token of the acknowledged [`SyncResponse`], and allows the stream to yield the next response.
+    pub fn ack(self) -> Result<(), matrix_sdk::Error> {
+        self.sync_helper.process_sync_response(&self.response)?;
+        // The stream may have been dropped, which is fine
+        _ = self.ack_tx.send(());
+        Ok(())
+    }
+}
+
+impl SyncHelper {
+    /// Similar to [`SyncHelper::sync_stream`], but only saves the sync token after the consumer calls [`AckHandle::ack`].
+    ///
+    /// The next [`SyncResponse`] is not yielded until the previous one is acknowledged, which applies backpressure to the sync loop.
+    ///
+    /// If the process crashes before a response is acknowledged, the same events are delivered again after restart.
+    /// This guarantees at-least-once processing, for example, for pipeline-style bots that push events into external queues.
+    pub async fn sync_stream_with_ack(
+        &self,
+        client: &Client,
+        sync_settings: impl Into<SyncSettings>,
+    ) -> impl Stream<Item = Result<(SyncResponse, AckHandle), matrix_sdk::Error>> {
+        let sync_stream = self.pausable_sync_stream(client, sync_settings.into());
+        try_stream! {
+            tokio::pin!(sync_stream);
+            loop {
+                let response = sync_stream
+                    .next()
+                    .await
+                    // sync_stream is infinite
+                    .unwrap()?;
+                trace!("Sync response: {:?}", response);
+                let (ack_tx, ack_rx) = oneshot::channel();
+                let handle = AckHandle {
+                    sync_helper: self.clone(),
+                    response: response.clone(),
+                    ack_tx,
+                };
+                yield (response, handle);
+                ack_rx.await.map_err(|_| {
+                    matrix_sdk::Error::UnknownError(
+                        "sync response was dropped without acknowledgement".into(),
+                    )
+                })?;
+            }
+        }
+    }
+}
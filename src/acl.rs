@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use eyre::Result;
+use matrix_sdk::ruma::{OwnedUserId, RoomId, UserId};
+use tracing::info;
+
+use crate::commands::next_token;
+use crate::mutex::MutexExt;
+use crate::{Command, CommandArg, CommandContext, Rest, SyncHelper};
+
+/// How often [`Acl::commands`] tells the same non-administrator that they can't edit the lists. Further attempts are ignored silently.
+const REFUSAL_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// When each non-administrator was last told that they can't edit the lists.
+type Refusals = Arc<Mutex<HashMap<OwnedUserId, Instant>>>;
+
+/// Which list of an [`Acl`] a pattern belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AclList {
+    /// If the allowlist is not empty, only matching users and rooms are allowed.
+    Allow,
+    /// Matching users and rooms are always denied, even if they are also on the allowlist.
+    Deny,
+}
+
+/// A persisted allowlist and denylist of users, servers, and rooms.
+///
+/// Each pattern is matched against the sender's user ID (`@spammer:example.com`), the sender's server name (`example.com`),
+/// and the room ID (`!room:example.com`). `*` matches any sequence of characters, and `?` matches one character, for example, `*.example.com`.
+///
+/// The lists are stored in the state database, so they survive process restarts.
+///
+/// Pass it to [`Commands::acl`](crate::Commands::acl) to ignore commands from denied users and rooms,
+/// and add [`Acl::commands`] to let the bot administrators edit the lists from a chat.
+///
+/// The lists only apply where they are passed in: [`Commands::acl`](crate::Commands::acl) and the [`AclCheck`](crate::AclCheck) middleware of a [`Dispatcher`](crate::Dispatcher).
+/// [`Router`](crate::Router), handlers installed with [`Client::add_event_handler`](matrix_sdk::Client::add_event_handler),
+/// and the crate's own handlers, such as verification and room key requests, don't consult them. Call [`Acl::is_allowed`] there yourself.
+#[derive(Clone, Debug)]
+pub struct Acl {
+    sync_helper: SyncHelper,
+    rules: Arc<Mutex<AclRules>>,
+}
+
+#[derive(Debug, Default)]
+struct AclRules {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl AclList {
+    fn as_str(self) -> &'static str {
+        match self {
+            AclList::Allow => "allow",
+            AclList::Deny => "deny",
+        }
+    }
+}
+
+impl Acl {
+    /// Loads the lists from the state database of `sync_helper`.
+    pub fn load(sync_helper: &SyncHelper) -> Result<Self> {
+        let mut rules = AclRules::default();
+        {
+            let db = sync_helper.session_db();
+            let mut stmt =
+                db.prepare_cached("SELECT list, pattern FROM acl ORDER BY list, pattern;")?;
+            let mut rows = stmt.query(())?;
+            while let Some(row) = rows.next()? {
+                let list: String = row.get(0)?;
+                let pattern = row.get(1)?;
+                match list.as_str() {
+                    "allow" => rules.allow.push(pattern),
+                    _ => rules.deny.push(pattern),
+                }
+            }
+        }
+        Ok(Self {
+            sync_helper: sync_helper.clone(),
+            rules: Arc::new(Mutex::new(rules)),
+        })
+    }
+
+    /// Adds a pattern to a list. Returns `false` if it was already there.
+    pub fn add(&self, list: AclList, pattern: &str) -> Result<bool> {
+        let inserted = self
+            .sync_helper
+            .session_db()
+            .prepare_cached("INSERT OR IGNORE INTO acl (list, pattern) VALUES (?, ?);")?
+            .execute((list.as_str(), pattern))?;
+        if inserted != 0 {
+            info!("Added {} to the {}list.", pattern, list.as_str());
+            self.lock().get_mut(list).push(pattern.to_owned());
+        }
+        Ok(inserted != 0)
+    }
+
+    /// Removes a pattern from a list. Returns `false` if it wasn't there.
+    pub fn remove(&self, list: AclList, pattern: &str) -> Result<bool> {
+        let deleted = self
+            .sync_helper
+            .session_db()
+            .prepare_cached("DELETE FROM acl WHERE list = ? AND pattern = ?;")?
+            .execute((list.as_str(), pattern))?;
+        if deleted != 0 {
+            info!("Removed {} from the {}list.", pattern, list.as_str());
+            self.lock().get_mut(list).retain(|p| p != pattern);
+        }
+        Ok(deleted != 0)
+    }
+
+    /// Returns the patterns of a list.
+    pub fn patterns(&self, list: AclList) -> Vec<String> {
+        self.lock().get_mut(list).clone()
+    }
+
+    /// Returns whether a user may interact with the bot in a room.
+    ///
+    /// Denied if any pattern on the denylist matches. Otherwise, allowed if the allowlist is empty or any pattern on it matches.
+    pub fn is_allowed(&self, user_id: &UserId, room_id: &RoomId) -> bool {
+        let rules = self.lock();
+        let matches = |pattern: &String| {
+            glob_match(pattern, user_id.as_str())
+                || glob_match(pattern, user_id.server_name().as_str())
+                || glob_match(pattern, room_id.as_str())
+        };
+        if rules.deny.iter().any(matches) {
+            return false;
+        }
+        rules.allow.is_empty() || rules.allow.iter().any(matches)
+    }
+
+    /// Returns whether a user is on the denylist, by user ID or server name, regardless of the allowlist and the room.
+    ///
+    /// Use it to act against denied users, for example, to redact their messages. [`Acl::is_allowed`] is not suitable for that,
+    /// because a non-empty allowlist would also deny everyone else.
+    pub fn is_denied(&self, user_id: &UserId) -> bool {
+        self.lock().deny.iter().any(|pattern| {
+            glob_match(pattern, user_id.as_str())
+                || glob_match(pattern, user_id.server_name().as_str())
+        })
+    }
+
+    /// Returns commands to edit the lists, which only `admins` may run:
+    ///
+    /// * `acl-list`
+    /// * `acl-add <allow|deny> <pattern>`
+    /// * `acl-remove <allow|deny> <pattern>`
+    ///
+    /// Others are told at most once per minute that they can't run them, so the refusal doesn't add to a spammer's traffic.
+    pub fn commands(&self, admins: Vec<OwnedUserId>) -> Vec<Command> {
+        let admins = Arc::new(admins);
+        let refusals = Refusals::default();
+        let acl = self.clone();
+        let (list_admins, list_refusals) = (admins.clone(), refusals.clone());
+        let list = Command::new("acl-list", move |ctx, ()| {
+            let acl = acl.clone();
+            let admins = list_admins.clone();
+            let refusals = list_refusals.clone();
+            async move {
+                if !check_admin(&ctx, &admins, &refusals).await? {
+                    return Ok(());
+                }
+                let mut text = String::new();
+                for list in [AclList::Allow, AclList::Deny] {
+                    let patterns = acl.patterns(list);
+                    text.push_str(&format!("{}list: ", list.as_str()));
+                    if patterns.is_empty() {
+                        text.push_str("(empty)\n");
+                    } else {
+                        text.push_str(&patterns.join(", "));
+                        text.push('\n');
+                    }
+                }
+                ctx.reply(text.trim_end()).await?;
+                Ok(())
+            }
+        })
+        .summary("Shows the allowlist and denylist");
+
+        let acl = self.clone();
+        let (add_admins, add_refusals) = (admins.clone(), refusals.clone());
+        let add = Command::new(
+            "acl-add",
+            move |ctx, (list, Rest(pattern)): (AclList, Rest)| {
+                let acl = acl.clone();
+                let admins = add_admins.clone();
+                let refusals = add_refusals.clone();
+                async move {
+                    if !check_admin(&ctx, &admins, &refusals).await? {
+                        return Ok(());
+                    }
+                    if acl.add(list, &pattern)? {
+                        ctx.reply(&format!("Added {} to the {}list.", pattern, list.as_str()))
+                            .await?;
+                    } else {
+                        ctx.reply(&format!(
+                            "{} is already on the {}list.",
+                            pattern,
+                            list.as_str()
+                        ))
+                        .await?;
+                    }
+                    Ok(())
+                }
+            },
+        )
+        .usage("<allow|deny> <pattern>")
+        .summary("Adds a user, server, or room pattern to a list");
+
+        let acl = self.clone();
+        let remove = Command::new(
+            "acl-remove",
+            move |ctx, (list, Rest(pattern)): (AclList, Rest)| {
+                let acl = acl.clone();
+                let admins = admins.clone();
+                let refusals = refusals.clone();
+                async move {
+                    if !check_admin(&ctx, &admins, &refusals).await? {
+                        return Ok(());
+                    }
+                    if acl.remove(list, &pattern)? {
+                        ctx.reply(&format!(
+                            "Removed {} from the {}list.",
+                            pattern,
+                            list.as_str()
+                        ))
+                        .await?;
+                    } else {
+                        ctx.reply(&format!("{} is not on the {}list.", pattern, list.as_str()))
+                            .await?;
+                    }
+                    Ok(())
+                }
+            },
+        )
+        .usage("<allow|deny> <pattern>")
+        .summary("Removes a pattern from a list");
+
+        vec![list, add, remove]
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, AclRules> {
+        self.rules.lock_unpoisoned()
+    }
+}
+
+impl AclRules {
+    fn get_mut(&mut self, list: AclList) -> &mut Vec<String> {
+        match list {
+            AclList::Allow => &mut self.allow,
+            AclList::Deny => &mut self.deny,
+        }
+    }
+}
+
+impl CommandArg for AclList {
+    fn take(args: &mut &str) -> std::result::Result<Self, String> {
+        match next_token(args) {
+            Some("allow") => Ok(AclList::Allow),
+            Some("deny") => Ok(AclList::Deny),
+            Some(token) => Err(format!(
+                "Invalid argument {:?}: expected allow or deny",
+                token
+            )),
+            None => Err("Missing argument.".to_owned()),
+        }
+    }
+}
+
+async fn check_admin(
+    ctx: &CommandContext,
+    admins: &[OwnedUserId],
+    refusals: &Refusals,
+) -> Result<bool> {
+    if admins.contains(&ctx.event.sender) {
+        return Ok(true);
+    }
+    {
+        let mut refusals = refusals.lock_unpoisoned();
+        let now = Instant::now();
+        refusals.retain(|_, time| now.duration_since(*time) < REFUSAL_COOLDOWN);
+        if refusals.contains_key(&ctx.event.sender) {
+            return Ok(false);
+        }
+        refusals.insert(ctx.event.sender.clone(), now);
+    }
+    ctx.reply("Only bot administrators can edit the access list.")
+        .await?;
+    Ok(false)
+}
+
+/// Matches `text` against a pattern where `*` matches any sequence of characters, and `?` matches one character.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    backtrack = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_stars() {
+        assert!(glob_match("*:example.org", "@alice:example.org"));
+        assert!(!glob_match("*:example.org", "@alice:example.org.evil"));
+        assert!(glob_match("@*:example.org", "@bob:example.org"));
+        assert!(!glob_match("@*:example.org", "@bob:evil.org"));
+        assert!(glob_match("@admin*", "@admin:example.org"));
+        assert!(glob_match("@admin*", "@admin"));
+        assert!(!glob_match("@admin*", "@bob:admin"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("**", "anything"));
+    }
+
+    #[test]
+    fn matches_question_marks() {
+        assert!(glob_match("@?:x", "@a:x"));
+        assert!(glob_match("@?:x", "@é:x"));
+        assert!(!glob_match("@?:x", "@ab:x"));
+        assert!(!glob_match("@?:x", "@:x"));
+    }
+
+    #[test]
+    fn matches_empty_pattern() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "@alice:example.org"));
+    }
+
+    #[test]
+    fn backtracks() {
+        assert!(glob_match("*.example.com", "a.example.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(glob_match("a*b*c", "abxbc"));
+        assert!(!glob_match("a*bc", "abcbd"));
+        assert!(glob_match("*ab", "aaab"));
+    }
+}
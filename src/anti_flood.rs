@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use eyre::Result;
+use matrix_sdk::ruma::OwnedUserId;
+use tracing::{debug, warn};
+
+use crate::middleware::MiddlewareFuture;
+use crate::mutex::MutexExt;
+use crate::rate_limit::Buckets;
+use crate::{
+    Clock, DispatchEvent, EventContext, MessageBuilder, Middleware, Next, RateLimit, RateLimiter,
+    SystemClock,
+};
+
+/// Prune expired cooldowns once there are more than this many.
+const MAX_COOLDOWNS: usize = 1024;
+
+/// Middleware for [`Dispatcher`](crate::Dispatcher) that limits how many events each sender can trigger, so an abusive user can't make a public bot spam replies or exhaust resources.
+///
+/// Each sender has a token bucket shared across all rooms. A sender who runs out of tokens is ignored for a cooldown period, 60 seconds by default,
+/// and is told so once with [`AntiFlood::notice`], if set.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+/// use matrixbot_ezlogin::{AntiFlood, Dispatcher, IgnoreOwnEvents, RateLimit};
+///
+/// # fn example(client: &matrix_sdk::Client, sync_helper: &matrixbot_ezlogin::SyncHelper) -> color_eyre::Result<()> {
+/// Dispatcher::<OriginalSyncRoomMessageEvent>::new()
+///     .with(IgnoreOwnEvents)
+///     .with(AntiFlood::new(RateLimit::new(5, 0.5))?.notice("You are sending too fast. Please wait a minute."))
+///     .register(client, sync_helper, "handler", |ctx| async move { Ok(()) });
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct AntiFlood {
+    limit: RateLimit,
+    cooldown: Duration,
+    notice: Option<Arc<str>>,
+    rate_limiter: Option<RateLimiter>,
+    senders: Arc<Mutex<Senders>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug)]
+struct Senders {
+    buckets: Buckets<OwnedUserId>,
+    cooldowns: HashMap<OwnedUserId, SystemTime>,
+}
+
+/// What to do with an event, decided under the lock.
+enum Verdict {
+    Pass,
+    Drop,
+    StartCooldown,
+}
+
+impl AntiFlood {
+    /// Creates an [`AntiFlood`] allowing `limit.burst` events at once, and `limit.per_second` events per second in the long run, from each sender.
+    ///
+    /// Fails if `limit.burst` is zero, or `limit.per_second` is not positive.
+    pub fn new(limit: RateLimit) -> Result<Self> {
+        limit.validate()?;
+        Ok(Self {
+            limit,
+            cooldown: Duration::from_secs(60),
+            notice: None,
+            rate_limiter: None,
+            senders: Arc::new(Mutex::new(Senders {
+                buckets: Buckets::new(limit),
+                cooldowns: HashMap::new(),
+            })),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// How long a sender is ignored after exceeding the limit.
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// A notice to reply with when a sender's cooldown starts. Without it, senders are ignored silently.
+    pub fn notice(mut self, text: impl Into<String>) -> Self {
+        self.notice = Some(Arc::from(text.into()));
+        self
+    }
+
+    /// Makes the [`AntiFlood::notice`] wait for `rate_limiter` before sending.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Refills the buckets and times the cooldowns on `clock`. Defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn check(&self, sender: &OwnedUserId) -> Verdict {
+        let now = self.clock.now();
+        let mut senders = self.senders.lock_unpoisoned();
+        if senders.cooldowns.len() > MAX_COOLDOWNS {
+            senders.cooldowns.retain(|_, until| now < *until);
+        }
+        if let Some(until) = senders.cooldowns.get(sender) {
+            if now < *until {
+                return Verdict::Drop;
+            }
+            senders.cooldowns.remove(sender);
+        }
+        let bucket = senders.buckets.get(sender.clone(), now);
+        if bucket.wait(self.limit).is_zero() {
+            bucket.tokens -= 1.0;
+            return Verdict::Pass;
+        }
+        // A cooldown too long to represent never ends, so every event is dropped without a notice
+        let Some(until) = now.checked_add(self.cooldown) else {
+            return Verdict::Drop;
+        };
+        senders.cooldowns.insert(sender.clone(), until);
+        Verdict::StartCooldown
+    }
+}
+
+impl<E: DispatchEvent> Middleware<E> for AntiFlood {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        let sender = ctx.event.sender().to_owned();
+        match self.check(&sender) {
+            Verdict::Pass => Box::pin(next.run(ctx)),
+            Verdict::Drop => {
+                debug!(
+                    "Ignoring room {}, event {}: {} is cooling down.",
+                    ctx.room.room_id(),
+                    ctx.event.event_id(),
+                    sender
+                );
+                Box::pin(async { Ok(()) })
+            }
+            Verdict::StartCooldown => {
+                warn!(
+                    "{} is flooding room {}, ignoring for {:?}.",
+                    sender,
+                    ctx.room.room_id(),
+                    self.cooldown
+                );
+                let Some(notice) = self.notice.clone() else {
+                    return Box::pin(async { Ok(()) });
+                };
+                let rate_limiter = self.rate_limiter.clone();
+                Box::pin(async move {
+                    crate::send::send_content(
+                        &ctx.room,
+                        MessageBuilder::notice().push_text(&notice).build(),
+                        rate_limiter.as_ref(),
+                    )
+                    .await?;
+                    Ok(())
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Debug for AntiFlood {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AntiFlood")
+            .field("limit", &self.limit)
+            .field("cooldown", &self.cooldown)
+            .field("notice", &self.notice)
+            .field("rate_limiter", &self.rate_limiter)
+            .field("clock", &self.clock)
+            .finish_non_exhaustive()
+    }
+}
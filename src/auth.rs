@@ -1,18 +1,29 @@
-use std::path::Path;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use eyre::{OptionExt, Result, bail};
 use matrix_sdk::authentication::matrix::MatrixSession;
+use matrix_sdk::encryption::backups::BackupState;
+use matrix_sdk::encryption::recovery::RecoveryState;
 use matrix_sdk::encryption::{
     BackupDownloadStrategy, CrossSigningResetAuthType, EncryptionSettings,
 };
+use matrix_sdk::ruma::api::client::discovery::get_supported_versions;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
 use matrix_sdk::ruma::api::client::uiaa;
-use matrix_sdk::{AuthSession, Client};
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedUserId};
+use matrix_sdk::{AuthSession, Client, ClientBuilder, SqliteStoreConfig};
 use rand::Rng;
-use rusqlite::OptionalExtension;
-use tracing::{info, instrument};
+use rusqlite::{OpenFlags, OptionalExtension};
+use tracing::{info, instrument, warn};
 
-use crate::SyncHelper;
 use crate::db::SQLiteHelper;
+use crate::{
+    Clock, DeviceName, EventCacheLimit, RateLimiter, RoomKeyRequestPolicy, RoomKeySharing,
+    StoreMaintenance, SyncHelper, SystemClock, VerificationPolicy,
+};
 
 /// Information to set up a Matrix bot using [`setup`].
 #[derive(Clone)]
@@ -74,6 +85,9 @@ macro_rules! delete_data_file {
 ///
 /// It creates a new session, saves it for later [`login`] use, then exits.
 ///
+/// Setting up again in the same `data_dir` replaces the session, and forgets its sync token, seen events, and conversation states.
+/// The access control lists, the scheduled tasks and reminders, and the send queue are kept.
+///
 /// Alternatively, [`setup_interactive`](crate::setup_interactive) provides an interactive version.
 #[instrument(skip_all)]
 pub async fn setup<
@@ -94,20 +108,42 @@ where
     BeforeCreateBackupCallback: Future<Output = Result<()>>,
     PrintRecoveryKeyCallback: FnOnce(String, bool) -> PrintRecoveryKeyReturn,
     PrintRecoveryKeyReturn: Future<Output = Result<()>>,
+{
+    // Never called with only 1 attempt
+    setup_with_password_retry(config, || std::future::ready(Ok(String::new())), 1).await
+}
+
+/// Same as [`setup`], but calls `ask_password` for another password if the homeserver rejects the credentials, up to `max_attempts` attempts in total.
+pub(crate) async fn setup_with_password_retry<
+    AskRecoveryKeyCallback,
+    BeforeCreateBackupCallback,
+    PrintRecoveryKeyCallback,
+    PrintRecoveryKeyReturn,
+    AskPasswordCallback,
+    AskPasswordReturn,
+>(
+    config: SetupConfig<
+        '_,
+        AskRecoveryKeyCallback,
+        BeforeCreateBackupCallback,
+        PrintRecoveryKeyCallback,
+    >,
+    mut ask_password: AskPasswordCallback,
+    max_attempts: usize,
+) -> Result<Client>
+where
+    AskRecoveryKeyCallback: Future<Output = Result<String>>,
+    BeforeCreateBackupCallback: Future<Output = Result<()>>,
+    PrintRecoveryKeyCallback: FnOnce(String, bool) -> PrintRecoveryKeyReturn,
+    PrintRecoveryKeyReturn: Future<Output = Result<()>>,
+    AskPasswordCallback: FnMut() -> AskPasswordReturn,
+    AskPasswordReturn: Future<Output = Result<String>>,
 {
     tokio::fs::create_dir_all(&config.data_dir).await?;
 
-    let session_db = SQLiteHelper::open(&config.data_dir.join("matrixbot-ezlogin.sqlite3"), true)?;
-    session_db.execute_batch(
-        "BEGIN TRANSACTION;
-DROP TABLE IF EXISTS matrix_session;
-DROP TABLE IF EXISTS sync_token;
-CREATE TABLE matrix_session (id INTEGER PRIMARY KEY CHECK (id = 0), homeserver TEXT NOT NULL, passphrase TEXT NOT NULL, session BLOB NOT NULL);
-CREATE TABLE sync_token (id INTEGER PRIMARY KEY CHECK (id = 0), token TEXT NOT NULL);
-COMMIT;
-PRAGMA optimize;
-VACUUM;",
-    )?;
+    let mut session_db =
+        SQLiteHelper::open(&config.data_dir.join("matrixbot-ezlogin.sqlite3"), true)?;
+    session_db.reset_schema()?;
     delete_data_file!(
         &config.data_dir,
         "matrix-sdk-crypto.sqlite3",
@@ -131,15 +167,47 @@ VACUUM;",
         .take(32)
         .map(char::from)
         .collect::<String>();
-    let client: Client = build_client(config.data_dir, config.homeserver, &db_passphrase).await?;
-    client
-        .matrix_auth()
-        .login_username(config.username, config.password)
-        .initial_device_display_name(config.device_name)
-        .await?;
+    let client: Client = build_client(
+        config.data_dir,
+        config.homeserver,
+        &db_passphrase,
+        RoomKeySharing::default(),
+        false,
+    )
+    .await?;
+    let mut password = config.password.to_owned();
+    let mut attempts = 1;
+    loop {
+        let result = client
+            .matrix_auth()
+            .login_username(config.username, &password)
+            .initial_device_display_name(config.device_name)
+            .await;
+        match result {
+            Ok(_) => break,
+            Err(err)
+                if attempts < max_attempts
+                    && matches!(
+                        err.client_api_error_kind(),
+                        Some(ErrorKind::Forbidden { .. })
+                    ) =>
+            {
+                warn!("The homeserver rejected the credentials: {}", err);
+                password = ask_password().await?;
+                attempts += 1;
+            }
+            Err(err) => Err(err)?,
+        }
+    }
+
+    // Otherwise, bot traffic would push to every device an operator later logs into this account
+    if let Err(err) = crate::set_push_notifications(&client, false).await {
+        warn!("Failed to disable push notifications: {}", err);
+    }
 
     match save_session(config, &session_db, db_passphrase, &client).await {
         Ok(_) => {
+            crate::trace_events::setup_finished(&client);
             info!("Setup finished.");
             Ok(client)
         }
@@ -151,6 +219,43 @@ VACUUM;",
     }
 }
 
+/// Optional features for [`login_with_options`].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct LoginOptions {
+    /// Serves Prometheus metrics at `http://<prometheus_listen_addr>/metrics`.
+    #[cfg(feature = "prometheus")]
+    pub prometheus_listen_addr: Option<std::net::SocketAddr>,
+    /// How to respond to room key requests from other devices of the same account.
+    pub room_key_requests: RoomKeyRequestPolicy,
+    /// Which devices receive the room keys of messages the bot sends.
+    pub room_key_sharing: RoomKeySharing,
+    /// How to respond to incoming interactive verification requests.
+    pub verification: VerificationPolicy,
+    /// Enables the event cache, which remembers events seen during sync, so [`load_or_fetch_event`](crate::load_or_fetch_event)
+    /// and [`replied_to_message`](crate::replied_to_message) usually don't need a request to the homeserver.
+    pub enable_event_cache: bool,
+    /// Empties the event cache periodically once it exceeds these limits, so it doesn't grow without bound on chatty accounts.
+    ///
+    /// Only used with [`enable_event_cache`](LoginOptions::enable_event_cache).
+    pub event_cache_limit: Option<EventCacheLimit>,
+    /// Reduces the memory footprint, for bots on small containers and single-board computers, at the cost of more disk reads.
+    ///
+    /// It uses smaller SQLite page caches and connection pools for the state, crypto, and event cache stores, and for the state database,
+    /// and ignores [`enable_event_cache`](LoginOptions::enable_event_cache). It doesn't change what sync returns, so no events are lost.
+    ///
+    /// [`SyncOptions::low_memory`](crate::SyncOptions::low_memory) also keeps sync responses small, but it drops events in busy rooms, read its warning first.
+    pub low_memory: bool,
+    /// Runs periodic maintenance on the state database and the SQLite files of the Matrix SDK, see [`SyncHelper::maintain_stores`].
+    pub maintenance: Option<StoreMaintenance>,
+    /// Renames the bot's device on every login, so the device list shows which process and host it belongs to.
+    pub device_name: Option<DeviceName>,
+    /// Limits outgoing messages, see [`SyncHelper::set_rate_limiter`].
+    pub rate_limiter: Option<RateLimiter>,
+    /// The clock for the returned [`SyncHelper`], see [`SyncHelper::with_clock`]. [`None`] means [`SystemClock`](crate::SystemClock).
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
 /// Log in and restore a Matrix session from a state database saved by [`setup`] or [`setup_interactive`](crate::setup_interactive).
 ///
 /// # Arguments
@@ -164,16 +269,209 @@ VACUUM;",
 ///   If you need to connect two processes to the same Matrix account, run [`setup`] or [`setup_interactive`](crate::setup_interactive) using two different `data_dir`.
 #[instrument(skip_all)]
 pub async fn login(data_dir: &Path) -> Result<(Client, SyncHelper)> {
+    login_with_options(data_dir, LoginOptions::default()).await
+}
+
+/// Same as [`login`], but enables optional features specified in [`LoginOptions`].
+#[instrument(skip_all)]
+pub async fn login_with_options(
+    data_dir: &Path,
+    options: LoginOptions,
+) -> Result<(Client, SyncHelper)> {
+    let result = login_inner(data_dir, options).await;
+    match &result {
+        Ok((client, _)) => crate::trace_events::login_finished(client),
+        Err(err) => crate::trace_events::login_failed(err),
+    }
+    result
+}
+
+async fn login_inner(data_dir: &Path, options: LoginOptions) -> Result<(Client, SyncHelper)> {
     let session_db = SQLiteHelper::open(&data_dir.join("matrixbot-ezlogin.sqlite3"), false)?;
-    let client = restore_session(data_dir, &session_db).await?;
-    let sync_helper = SyncHelper::from_opened_db(session_db)?;
+    if options.low_memory {
+        // 256 KiB instead of the default 2 MiB
+        session_db.execute_batch("PRAGMA cache_size = -256;")?;
+    }
+    let client = restore_session(
+        data_dir,
+        &session_db,
+        options.room_key_sharing,
+        options.low_memory,
+    )
+    .await?;
+    let sync_helper = SyncHelper::from_opened_db(
+        session_db,
+        data_dir,
+        options.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+    )?;
+
+    // Lets event handlers take a `Ctx<SyncHelper>` to follow the catch-up policy, see `SyncHelper::catch_up`
+    client.add_event_handler_context(sync_helper.clone());
+    crate::encryption_metrics::install(&client, &sync_helper);
+
+    if options.enable_event_cache && options.low_memory {
+        warn!("The event cache is disabled in low-memory mode.");
+    } else if options.enable_event_cache {
+        client.event_cache().subscribe()?;
+        if let Some(limit) = options.event_cache_limit {
+            let task = sync_helper.spawn_event_cache_pruning(&client, limit);
+            sync_helper.add_background_task(&task);
+        }
+    }
+
+    if let Some(maintenance) = options.maintenance {
+        let task = sync_helper.spawn_maintenance(maintenance);
+        sync_helper.add_background_task(&task);
+    }
+
+    if let Some(device_name) = &options.device_name {
+        crate::device_name::refresh(&client, device_name, sync_helper.clock.now()).await;
+    }
+
+    sync_helper.set_rate_limiter(options.rate_limiter);
+    crate::key_requests::install(&client, &sync_helper, &options.room_key_requests)?;
+    crate::verification::install(&client, &sync_helper, &options.verification);
+
+    #[cfg(feature = "prometheus")]
+    if let Some(listen_addr) = options.prometheus_listen_addr {
+        let server =
+            crate::serve_prometheus(listen_addr, client.clone(), sync_helper.clone()).await?;
+        sync_helper.add_background_task(&server.task);
+    }
 
     info!("Login finished.");
     Ok((client, sync_helper))
 }
 
+/// Restores a Matrix session for inspection, while the bot keeps running from the same `data_dir`, for sidecar tools such as a stats exporter or a debugging REPL.
+///
+/// Unlike [`login`], it doesn't take the exclusive lock of the state database, and doesn't return a [`SyncHelper`], so the bot's sync token stays untouched.
+/// The returned [`Client`] shares the bot's device and encryption keys, so it can read room state and decrypt history, for example, with [`history::messages`](crate::history::messages).
+///
+/// The SDK can't open its stores read-only, so the returned [`Client`] works on a snapshot of them in `data_dir/read-only-<pid>-<n>`,
+/// and never writes to the bot's stores. The snapshot contains the encryption keys, so it is deleted when the returned [`ReadOnlySession`] is dropped.
+/// [`logout`] also deletes snapshots left behind by tools that crashed.
+///
+/// Don't sync with the returned [`Client`], and don't send messages with it. Syncing consumes to-device events meant for the bot, such as room keys,
+/// and the bot never learns about the Megolm sessions the snapshot creates.
+#[instrument(skip_all)]
+pub async fn login_read_only(data_dir: &Path) -> Result<ReadOnlySession> {
+    // The bot holds an exclusive lock on its state database. An immutable connection ignores locks, and only reads the main file,
+    // which already contains the session since setup.
+    let session_db = rusqlite::Connection::open_with_flags(
+        format!(
+            "file:{}?immutable=1",
+            data_dir
+                .join("matrixbot-ezlogin.sqlite3")
+                .to_string_lossy()
+                .replace('%', "%25")
+                .replace('?', "%3f")
+                .replace('#', "%23")
+        ),
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX
+            | OpenFlags::SQLITE_OPEN_URI,
+    )?;
+    // Unique per session, because each session deletes its own snapshot when dropped
+    static SNAPSHOT_COUNTER: AtomicU64 = AtomicU64::new(0);
+    let snapshot_dir = data_dir.join(format!(
+        "read-only-{}-{}",
+        std::process::id(),
+        SNAPSHOT_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    tokio::task::spawn_blocking({
+        let data_dir = data_dir.to_owned();
+        let snapshot_dir = snapshot_dir.clone();
+        move || snapshot_sdk_stores(&data_dir, &snapshot_dir)
+    })
+    .await??;
+    // Own the snapshot before anything else can fail, so it is always deleted
+    let mut session = ReadOnlySession {
+        client: None,
+        snapshot_dir,
+    };
+    session.client = Some(
+        restore_session(
+            &session.snapshot_dir,
+            &session_db,
+            RoomKeySharing::default(),
+            false,
+        )
+        .await?,
+    );
+    info!("Read-only login finished.");
+    Ok(session)
+}
+
+/// A [`Client`] restored by [`login_read_only`], which dereferences to the [`Client`].
+///
+/// Dropping it deletes the snapshot of the stores. Drop the clones of its [`Client`] first, because they keep the snapshot open.
+#[derive(Debug)]
+pub struct ReadOnlySession {
+    // Only taken when dropped, to close the stores before deleting them
+    client: Option<Client>,
+    snapshot_dir: PathBuf,
+}
+
+impl Deref for ReadOnlySession {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        // Only None while being constructed or dropped
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl Drop for ReadOnlySession {
+    fn drop(&mut self) {
+        drop(self.client.take());
+        if let Err(err) = std::fs::remove_dir_all(&self.snapshot_dir)
+            && err.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!(
+                "Failed to delete the read-only snapshot {}: {}",
+                self.snapshot_dir.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Copies the SDK stores of `data_dir` into `snapshot_dir`, while the bot may be writing to them.
+fn snapshot_sdk_stores(data_dir: &Path, snapshot_dir: &Path) -> Result<()> {
+    match std::fs::remove_dir_all(snapshot_dir) {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+    std::fs::create_dir(snapshot_dir)?;
+    for name in [
+        "matrix-sdk-crypto.sqlite3",
+        "matrix-sdk-event-cache.sqlite3",
+        "matrix-sdk-state.sqlite3",
+    ] {
+        let source = data_dir.join(name);
+        if !source.exists() {
+            continue;
+        }
+        let conn = rusqlite::Connection::open_with_flags(
+            source,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        // VACUUM INTO reads a consistent snapshot, even if the bot commits a transaction meanwhile
+        conn.execute(
+            "VACUUM INTO ?1;",
+            (snapshot_dir.join(name).to_string_lossy(),),
+        )?;
+    }
+    Ok(())
+}
+
 /// Log out a Matrix session and delete the state database.
 ///
+/// Snapshots left behind by [`login_read_only`] are deleted too, except those of processes still running.
+/// On platforms other than Unix, it can't tell which processes are running, so stop every sidecar tool before logging out.
+///
 /// # Arguments
 ///
 /// * `data_dir`, The directory containing the bot's state database.
@@ -182,7 +480,7 @@ pub async fn login(data_dir: &Path) -> Result<(Client, SyncHelper)> {
 #[instrument(skip_all)]
 pub async fn logout(data_dir: &Path) -> Result<()> {
     let session_db = SQLiteHelper::open(&data_dir.join("matrixbot-ezlogin.sqlite3"), false)?;
-    let client = restore_session(data_dir, &session_db).await?;
+    let client = restore_session(data_dir, &session_db, RoomKeySharing::default(), false).await?;
 
     info!("Logging out.");
     client.logout().await?;
@@ -208,25 +506,189 @@ pub async fn logout(data_dir: &Path) -> Result<()> {
         "matrixbot-ezlogin.sqlite3-wal",
     );
 
+    // Snapshots of the stores by login_read_only contain the encryption keys too
+    let mut entries = tokio::fs::read_dir(data_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("read-only-"))
+            .and_then(|name| name.split('-').next())
+            .and_then(|pid| pid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        if process_exists(pid) {
+            warn!(
+                "Not deleting the read-only snapshot {}, because process {} is still using it.",
+                entry.path().display(),
+                pid
+            );
+            continue;
+        }
+        tokio::fs::remove_dir_all(entry.path()).await?;
+    }
+
     info!("Logout finished.");
     Ok(())
 }
 
-async fn build_client(data_dir: &Path, homeserver: &str, passphrase: &str) -> Result<Client> {
-    let mut client_builder = Client::builder()
-        .server_name_or_homeserver_url(homeserver)
-        .sqlite_store(data_dir, Some(passphrase))
+/// Returns whether process `pid` is running, to tell the snapshots of live [`login_read_only`] sessions from leftovers.
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: Signal 0 only checks whether the process exists, without sending anything.
+    unsafe { libc::kill(pid, 0) == 0 }
+    || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// On other platforms, [`logout`] can't tell whether a snapshot is in use, so every sidecar must be stopped before logging out.
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+    false
+}
+
+/// The result of [`status`].
+#[derive(Clone, Debug)]
+pub struct SessionStatus {
+    /// The user ID of the session.
+    pub user_id: OwnedUserId,
+    /// The device ID of the session.
+    pub device_id: Option<OwnedDeviceId>,
+    /// The display name of the device, if the access token is valid.
+    pub device_display_name: Option<String>,
+    /// The base URL of the homeserver.
+    pub homeserver: String,
+    /// Whether the homeserver still accepts the access token.
+    pub token_valid: bool,
+    /// Whether this device is cross-signed by its owner.
+    pub cross_signed: bool,
+    /// Whether server-side backup is enabled.
+    pub backup_enabled: bool,
+    /// The state of the server-side backup.
+    pub backup_state: BackupState,
+    /// The state of the recovery, which stores the cross-signing keys and the backup key on the server.
+    pub recovery_state: RecoveryState,
+}
+
+/// Restores a Matrix session and reports its state, without starting a sync.
+///
+/// It calls `/account/whoami` and `/devices/{deviceId}` to check whether the access token is still valid.
+/// Orchestration can call it to verify a `data_dir` before launching the bot.
+///
+/// # Arguments
+///
+/// * `data_dir`, The directory containing the bot's state database.
+///
+///   It must be already initialized by a successful [`setup`] or [`setup_interactive`](crate::setup_interactive) call.
+///
+///   The bot must not be running at the same time, because the state database can only be opened by one process at the same time.
+#[instrument(skip_all)]
+pub async fn status(data_dir: &Path) -> Result<SessionStatus> {
+    let session_db = SQLiteHelper::open(&data_dir.join("matrixbot-ezlogin.sqlite3"), false)?;
+    let client = restore_session(data_dir, &session_db, RoomKeySharing::default(), false).await?;
+
+    let (user_id, token_valid) = match client.whoami().await {
+        Ok(whoami) => (whoami.user_id, true),
+        Err(err)
+            if matches!(
+                err.client_api_error_kind(),
+                Some(ErrorKind::UnknownToken { .. })
+            ) =>
+        {
+            warn!("The access token was rejected: {}", err);
+            (
+                client
+                    .user_id()
+                    .ok_or_eyre("session is not restored")?
+                    .to_owned(),
+                false,
+            )
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let device_id = client.device_id().map(ToOwned::to_owned);
+    let device_display_name = match &device_id {
+        Some(device_id) if token_valid => client.get_device(device_id).await?.device.display_name,
+        _ => None,
+    };
+
+    let encryption = client.encryption();
+    let cross_signed = encryption
+        .get_own_device()
+        .await?
+        .is_some_and(|device| device.is_cross_signed_by_owner());
+    let backup_enabled = encryption.backups().are_enabled().await;
+
+    Ok(SessionStatus {
+        user_id,
+        device_id,
+        device_display_name,
+        homeserver: client.homeserver().to_string(),
+        token_valid,
+        cross_signed,
+        backup_enabled,
+        backup_state: encryption.backups().state(),
+        recovery_state: encryption.recovery().state(),
+    })
+}
+
+/// Resolves a homeserver through server discovery, and checks whether it responds to `/_matrix/client/versions`.
+///
+/// Returns the base URL of the homeserver.
+///
+/// [`setup_interactive`](crate::setup_interactive) calls it right after asking for the homeserver, so a typo is caught before asking for credentials.
+#[instrument(skip_all)]
+pub async fn check_homeserver(homeserver: &str) -> Result<String> {
+    let client = client_builder(homeserver).build().await?;
+    let response = client.send(get_supported_versions::Request::new()).await?;
+    if response.versions.is_empty() {
+        bail!(
+            "{} does not support any Matrix client API version",
+            client.homeserver()
+        );
+    }
+    Ok(client.homeserver().to_string())
+}
+
+fn client_builder(homeserver: &str) -> ClientBuilder {
+    let client_builder = Client::builder().server_name_or_homeserver_url(homeserver);
+    if let Some((_, proxy)) =
+        std::env::vars_os().find(|(k, _)| k.eq_ignore_ascii_case("https_proxy"))
+    {
+        return client_builder.proxy(proxy.to_string_lossy());
+    }
+    client_builder
+}
+
+async fn build_client(
+    data_dir: &Path,
+    homeserver: &str,
+    passphrase: &str,
+    room_key_sharing: RoomKeySharing,
+    low_memory: bool,
+) -> Result<Client> {
+    let client_builder = if low_memory {
+        client_builder(homeserver).sqlite_store_with_config_and_cache_path(
+            SqliteStoreConfig::with_low_memory_config(data_dir).passphrase(Some(passphrase)),
+            None::<&Path>,
+        )
+    } else {
+        client_builder(homeserver).sqlite_store(data_dir, Some(passphrase))
+    };
+    let client_builder = client_builder
         .with_enable_share_history_on_invite(true)
+        .with_room_key_recipient_strategy(room_key_sharing.into())
         .with_encryption_settings(EncryptionSettings {
             auto_enable_cross_signing: true,
             backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
             auto_enable_backups: true,
         });
-    if let Some((_, proxy)) =
-        std::env::vars_os().find(|(k, _)| k.eq_ignore_ascii_case("https_proxy"))
-    {
-        client_builder = client_builder.proxy(proxy.to_string_lossy());
-    }
     Ok(client_builder.build().await?)
 }
 
@@ -333,7 +795,12 @@ where
     Ok(())
 }
 
-async fn restore_session(data_dir: &Path, session_db: &rusqlite::Connection) -> Result<Client> {
+async fn restore_session(
+    data_dir: &Path,
+    session_db: &rusqlite::Connection,
+    room_key_sharing: RoomKeySharing,
+    low_memory: bool,
+) -> Result<Client> {
     let (homeserver, passphrase, session): (String, String, String) = session_db
         .query_row(
             "SELECT homeserver, passphrase, json(session) FROM matrix_session WHERE id = 0;",
@@ -346,7 +813,14 @@ async fn restore_session(data_dir: &Path, session_db: &rusqlite::Connection) ->
     let matrix_session = serde_json::from_str::<MatrixSession>(&session)?;
 
     info!("Logging into Matrix.");
-    let client = build_client(data_dir, &homeserver, &passphrase).await?;
+    let client = build_client(
+        data_dir,
+        &homeserver,
+        &passphrase,
+        room_key_sharing,
+        low_memory,
+    )
+    .await?;
     client
         .restore_session(AuthSession::Matrix(matrix_session))
         .await?;
@@ -0,0 +1,88 @@
+use std::time::SystemTime;
+
+use matrix_sdk::Room;
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, UInt};
+use matrix_sdk::sync::SyncResponse;
+use tracing::{debug, info, instrument};
+
+use crate::SyncHelper;
+
+/// Where [`SyncHelper::backfill`] stops paging backwards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackfillUntil {
+    /// Stop at this event, exclusive. Usually the room position from [`SyncHelper::get_room_position`].
+    Event(OwnedEventId),
+    /// Stop at the first event older than this time.
+    Time(SystemTime),
+}
+
+impl SyncHelper {
+    /// Lists the rooms whose timelines were truncated (`limited`) in a [`SyncResponse`], along with their `prev_batch` tokens.
+    ///
+    /// This happens when the bot was offline for too long, and the server skipped some events. Pass the token to [`SyncHelper::backfill`] to recover them.
+    pub fn limited_timelines(sync_response: &SyncResponse) -> Vec<(OwnedRoomId, String)> {
+        sync_response
+            .rooms
+            .joined
+            .iter()
+            .map(|(room_id, room)| (room_id, &room.timeline))
+            .chain(
+                sync_response
+                    .rooms
+                    .left
+                    .iter()
+                    .map(|(room_id, room)| (room_id, &room.timeline)),
+            )
+            .filter(|(_, timeline)| timeline.limited)
+            .filter_map(|(room_id, timeline)| {
+                Some((room_id.to_owned(), timeline.prev_batch.clone()?))
+            })
+            .collect()
+    }
+
+    /// Pages `/messages` backwards from `prev_batch` to recover events that were skipped by a `limited` timeline.
+    ///
+    /// It stops at `until`, after `max_events` events, or at the beginning of the room, whichever comes first.
+    ///
+    /// Encrypted events are decrypted if the keys are available. The returned events are in chronological order, oldest first.
+    #[instrument(skip_all, fields(room_id = %room.room_id()))]
+    pub async fn backfill(
+        &self,
+        room: &Room,
+        prev_batch: &str,
+        until: BackfillUntil,
+        max_events: usize,
+    ) -> Result<Vec<TimelineEvent>, matrix_sdk::Error> {
+        let mut events = Vec::new();
+        let mut from = Some(prev_batch.to_owned());
+        'paging: while let Some(token) = from.take() {
+            let mut options = MessagesOptions::backward();
+            options.from = Some(token);
+            options.limit = UInt::from(100u32);
+            let messages = room.messages(options).await?;
+            debug!("Received {} events.", messages.chunk.len());
+            for event in messages.chunk {
+                let reached = match &until {
+                    BackfillUntil::Event(event_id) => event.event_id().as_ref() == Some(event_id),
+                    BackfillUntil::Time(time) => event
+                        .raw()
+                        .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+                        .ok()
+                        .flatten()
+                        .and_then(|ts| ts.to_system_time())
+                        .is_some_and(|ts| ts < *time),
+                };
+                if reached || events.len() >= max_events {
+                    break 'paging;
+                }
+                events.push(event);
+            }
+            from = messages.end;
+        }
+        events.reverse();
+        info!("Backfilled {} events.", events.len());
+        Ok(events)
+    }
+}
@@ -0,0 +1,286 @@
+//! Management tool for a data directory created by matrixbot-ezlogin.
+//!
+//! The bot must not be running while this tool operates on the same data directory.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use eyre::{Result, bail};
+use matrixbot_ezlogin::DuplexLog;
+use tracing::info;
+use tracing_subscriber::{EnvFilter, prelude::*};
+
+#[derive(clap::Parser)]
+#[clap(about = "Manage a Matrix session created by matrixbot-ezlogin")]
+struct Args {
+    #[clap(
+        long = "data",
+        value_name = "PATH",
+        help = "Path to store Matrix data between sessions"
+    )]
+    data_dir: PathBuf,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    #[clap(about = "Perform initial setup of Matrix account")]
+    Setup {
+        #[clap(
+            long,
+            value_name = "DEVICE_NAME",
+            default_value = "matrixbot-ezlogin",
+            help = "Device name to use for this session"
+        )]
+        device_name: String,
+        #[clap(
+            long,
+            value_name = "HOMESERVER",
+            help = "Matrix homeserver, asked interactively if omitted"
+        )]
+        homeserver: Option<String>,
+        #[clap(
+            long,
+            value_name = "USERNAME",
+            help = "User name, asked interactively if omitted"
+        )]
+        username: Option<String>,
+        #[clap(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = ["device_name", "homeserver", "username"],
+            help = "Set up every [[bot]] in a TOML file without asking, relative data_dir paths are relative to --data"
+        )]
+        from_file: Option<PathBuf>,
+    },
+    #[clap(about = "Show the user, device, and encryption status of the session")]
+    Status,
+    #[clap(about = "Log out of the Matrix session, and delete the state database")]
+    Logout,
+    #[clap(about = "List all devices of the account")]
+    Devices,
+    #[clap(about = "Export the room keys into an encrypted file")]
+    ExportKeys {
+        #[clap(value_name = "FILE", help = "Path of the exported key file")]
+        path: PathBuf,
+    },
+    #[clap(about = "Send a text message to a room, then exit")]
+    Send {
+        #[clap(value_name = "ROOM", help = "Room ID or room alias")]
+        room: String,
+        #[clap(value_name = "MESSAGE", help = "Message text")]
+        body: String,
+    },
+    #[clap(about = "Check the session for common problems")]
+    Doctor,
+    #[clap(about = "Send an encrypted message and read it back, to prove that encryption works")]
+    SelfTest {
+        #[clap(
+            long,
+            value_name = "ROOM",
+            help = "Encrypted room ID or room alias to use, a throwaway room is created if omitted"
+        )]
+        room: Option<String>,
+    },
+    #[clap(about = "Open an interactive shell to inspect rooms and events, and send test messages")]
+    Repl {
+        #[clap(
+            long,
+            help = "Open the session read-only, so the bot can keep running; disables sending and syncing"
+        )]
+        read_only: bool,
+    },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    // Parse first, because `--help` and argument errors exit the process, which would leave the terminal in raw mode
+    let args: Args = clap::Parser::parse();
+    let data_dir = &args.data_dir;
+
+    DuplexLog::init();
+    tracing_subscriber::registry()
+        .with({
+            let mut filter = EnvFilter::new("warn,matrixbot_ezlogin=info");
+            if let Some(env) = std::env::var_os(EnvFilter::DEFAULT_ENV) {
+                for segment in env.to_string_lossy().split(',') {
+                    if let Ok(directive) = segment.parse() {
+                        filter = filter.add_directive(directive);
+                    }
+                }
+            }
+            filter
+        })
+        .with(tracing_subscriber::fmt::layer().with_writer(DuplexLog::get_writer))
+        .init();
+
+    let result = match args.command {
+        Command::Setup {
+            from_file: Some(path),
+            ..
+        } => setup_batch(data_dir, &path).await,
+        Command::Setup {
+            device_name,
+            homeserver,
+            username,
+            from_file: None,
+        } => {
+            let partial = matrixbot_ezlogin::Partial {
+                homeserver,
+                username,
+                ..Default::default()
+            };
+            matrixbot_ezlogin::setup_interactive_with(data_dir, &device_name, partial)
+                .await
+                .map(drop)
+        }
+        Command::Status => status(data_dir).await,
+        Command::Logout => matrixbot_ezlogin::logout(data_dir).await,
+        Command::Devices => devices(data_dir).await,
+        Command::ExportKeys { path } => export_keys(data_dir, &path).await,
+        Command::Send { room, body } => matrixbot_ezlogin::send_message(data_dir, &room, &body)
+            .await
+            .map(drop),
+        Command::Doctor => doctor(data_dir).await,
+        Command::SelfTest { room } => self_test(data_dir, room.as_deref()).await,
+        Command::Repl { read_only } => matrixbot_ezlogin::debug_repl(data_dir, read_only).await,
+    };
+    DuplexLog::shutdown().await;
+    result
+}
+
+async fn status(data_dir: &Path) -> Result<()> {
+    let status = matrixbot_ezlogin::status(data_dir).await?;
+    // The terminal is in raw mode, print through DuplexLog like the logs
+    let mut out = DuplexLog::get_writer();
+    writeln!(out, "User:        {}", status.user_id)?;
+    writeln!(
+        out,
+        "Device:      {} {}",
+        status
+            .device_id
+            .as_ref()
+            .map_or("(unknown)", |device_id| device_id.as_str()),
+        status.device_display_name.as_deref().unwrap_or_default()
+    )?;
+    writeln!(out, "Homeserver:  {}", status.homeserver)?;
+    writeln!(
+        out,
+        "Token:       {}",
+        if status.token_valid {
+            "valid"
+        } else {
+            "rejected"
+        }
+    )?;
+    writeln!(
+        out,
+        "Signing:     {}",
+        if status.cross_signed {
+            "cross-signed"
+        } else {
+            "not cross-signed"
+        }
+    )?;
+    writeln!(out, "Backup:      {:?}", status.backup_state)?;
+    writeln!(out, "Recovery:    {:?}", status.recovery_state)?;
+    if !status.token_valid {
+        bail!("the access token was rejected, run `setup` again");
+    }
+    Ok(())
+}
+
+async fn devices(data_dir: &Path) -> Result<()> {
+    let (client, _) = matrixbot_ezlogin::login(data_dir).await?;
+    let own_device_id = client.device_id().map(ToOwned::to_owned);
+    let user_id = client.user_id().map(ToOwned::to_owned);
+    let response = client.devices().await?;
+    let mut out = DuplexLog::get_writer();
+    for device in response.devices {
+        let verified = match &user_id {
+            Some(user_id) => client
+                .encryption()
+                .get_device(user_id, &device.device_id)
+                .await?
+                .is_some_and(|device| device.is_verified()),
+            None => false,
+        };
+        writeln!(
+            out,
+            "{}{}\t{}\t{}",
+            device.device_id,
+            if Some(&device.device_id) == own_device_id.as_ref() {
+                " (this session)"
+            } else {
+                ""
+            },
+            if verified { "verified" } else { "unverified" },
+            device.display_name.as_deref().unwrap_or_default()
+        )?;
+    }
+    Ok(())
+}
+
+async fn export_keys(data_dir: &Path, path: &Path) -> Result<()> {
+    let passphrase = DuplexLog::read_password("Passphrase to encrypt the key file: ").await?;
+    if DuplexLog::read_password("Repeat the passphrase: ").await? != passphrase {
+        bail!("passphrases don't match");
+    }
+    let (client, _) = matrixbot_ezlogin::login(data_dir).await?;
+    client
+        .encryption()
+        .export_room_keys(path.to_owned(), &passphrase, |_| true)
+        .await?;
+    info!("Exported room keys to {}.", path.display());
+    Ok(())
+}
+
+async fn doctor(data_dir: &Path) -> Result<()> {
+    let report = matrixbot_ezlogin::diagnose(data_dir).await;
+    write!(DuplexLog::get_writer(), "{}", report)?;
+    if !report.is_healthy() {
+        bail!("found problems, see the findings above");
+    }
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct Fleet {
+    bot: Vec<matrixbot_ezlogin::SetupProfile>,
+}
+
+async fn setup_batch(data_dir: &Path, path: &Path) -> Result<()> {
+    let mut fleet: Fleet = toml::from_str(&tokio::fs::read_to_string(path).await?)?;
+    for profile in &mut fleet.bot {
+        profile.data_dir = data_dir.join(&profile.data_dir);
+    }
+    let report = matrixbot_ezlogin::setup_batch(fleet.bot, |profile, recovery_key, new_backup| {
+        let result = if new_backup {
+            writeln!(
+                DuplexLog::get_writer(),
+                "Recovery key of {}, keep it in a safe place: {}",
+                profile.username,
+                recovery_key
+            )
+        } else {
+            Ok(())
+        };
+        std::future::ready(result.map_err(Into::into))
+    })
+    .await;
+    writeln!(DuplexLog::get_writer(), "{}", report)?;
+    if !report.is_success() {
+        bail!("some bots failed to set up, see the summary above");
+    }
+    Ok(())
+}
+
+async fn self_test(data_dir: &Path, room: Option<&str>) -> Result<()> {
+    let report = matrixbot_ezlogin::self_test(data_dir, room).await;
+    write!(DuplexLog::get_writer(), "{}", report)?;
+    if !report.is_healthy() {
+        bail!("self-test failed, see the findings above");
+    }
+    Ok(())
+}
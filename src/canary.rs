@@ -0,0 +1,263 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::{Client, RoomState};
+use rand::Rng;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, error, info, warn};
+
+use crate::mutex::MutexExt;
+use crate::{MessageBuilder, RateLimiter};
+
+const PING: &str = "canary ping ";
+const PONG: &str = "canary pong ";
+
+/// The health reported by a [`Canary`], whenever it changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CanaryStatus {
+    /// The partner's echo arrived and was decrypted again, after a failure.
+    Healthy,
+    /// The heartbeat couldn't be sent, or its echo didn't arrive in time. It contains a description of the failure.
+    Broken(String),
+}
+
+/// Configuration for a canary, which proves periodically that encryption and sync still work in production.
+///
+/// Every [`interval`](Canary::interval), the bot sends an encrypted heartbeat to [`room_id`](Canary::room_id). A partner,
+/// another bot or another account running [`Canary::echo`], answers it with an encrypted echo. If the echo doesn't arrive and decrypt
+/// within [`timeout`](Canary::timeout), encryption or sync is broken somewhere, even if no error shows up in the logs.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::Canary;
+///
+/// # fn example(client: &matrix_sdk::Client, canary_room: matrix_sdk::ruma::OwnedRoomId, admin_room: matrix_sdk::ruma::OwnedRoomId) {
+/// let mut canary = Canary::new(canary_room);
+/// canary.alert_room = Some(admin_room);
+/// canary.spawn(client);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Canary {
+    /// The encrypted room to send heartbeats to. The partner must have joined it too.
+    pub room_id: OwnedRoomId,
+    /// How often to send a heartbeat.
+    pub interval: Duration,
+    /// How long to wait for the partner's echo.
+    pub timeout: Duration,
+    /// A room to post status changes to.
+    pub alert_room: Option<OwnedRoomId>,
+    /// A callback that is called with every status change.
+    pub on_alert: Option<Arc<dyn Fn(&CanaryStatus) + Send + Sync>>,
+    /// A rate limiter that heartbeats and alerts wait for.
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl Canary {
+    /// Creates a [`Canary`] that sends a heartbeat to `room_id` every 10 minutes, and waits 2 minutes for each echo, without any alert.
+    pub fn new(room_id: OwnedRoomId) -> Self {
+        Self {
+            room_id,
+            interval: Duration::from_secs(600),
+            timeout: Duration::from_secs(120),
+            alert_room: None,
+            on_alert: None,
+            rate_limiter: None,
+        }
+    }
+
+    /// Spawns a Tokio task that sends heartbeats and watches for echoes, and alerts on every status change. Abort the returned task to stop.
+    pub fn spawn(&self, client: &Client) -> JoinHandle<()> {
+        let canary = self.clone();
+        let client = client.clone();
+        // The nonce of the heartbeat waiting for its echo
+        let pending = Arc::new(Mutex::new(None::<(String, oneshot::Sender<()>)>));
+        let handle = client.add_event_handler({
+            let room_id = canary.room_id.clone();
+            let pending = pending.clone();
+            move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+                let room_id = room_id.clone();
+                let pending = pending.clone();
+                async move {
+                    if room.room_id() != room_id || Some(&*event.sender) == client.user_id() {
+                        return;
+                    }
+                    let Some(nonce) = event.content.body().strip_prefix(PONG) else {
+                        return;
+                    };
+                    let mut pending = pending.lock_unpoisoned();
+                    if pending
+                        .as_ref()
+                        .is_some_and(|(expected, _)| expected == nonce)
+                        && let Some((_, tx)) = pending.take()
+                    {
+                        _ = tx.send(());
+                    }
+                }
+            }
+        });
+        tokio::spawn(
+            async move {
+                // Removes the event handler when the task is aborted
+                let _guard = client.event_handler_drop_guard(handle);
+                info!("Sending canary heartbeats to room {}.", canary.room_id);
+                let mut healthy = true;
+                let mut interval = tokio::time::interval(canary.interval);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    interval.tick().await;
+                    let result = canary.beat(&client, &pending).await;
+                    match result {
+                        Ok(()) if !healthy => {
+                            healthy = true;
+                            info!("Canary in room {} is healthy again.", canary.room_id);
+                            canary.alert(&client, CanaryStatus::Healthy).await;
+                        }
+                        Ok(()) => (),
+                        Err(reason) if healthy => {
+                            healthy = false;
+                            error!("Canary in room {} is broken: {}", canary.room_id, reason);
+                            canary.alert(&client, CanaryStatus::Broken(reason)).await;
+                        }
+                        Err(reason) => warn!(
+                            "Canary in room {} is still broken: {}",
+                            canary.room_id, reason
+                        ),
+                    }
+                }
+            }
+            .in_current_span(),
+        )
+    }
+
+    /// Registers an event handler on `client` that answers the heartbeats of other accounts in `room_id`, acting as the partner of their [`Canary`].
+    ///
+    /// Each echo waits for `rate_limiter` first, if any.
+    pub fn echo(
+        client: &Client,
+        room_id: OwnedRoomId,
+        rate_limiter: Option<RateLimiter>,
+    ) -> EventHandlerHandle {
+        client.add_event_handler(
+            move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+                let room_id = room_id.clone();
+                let rate_limiter = rate_limiter.clone();
+                async move {
+                    if room.room_id() != room_id
+                        || room.state() != RoomState::Joined
+                        || Some(&*event.sender) == client.user_id()
+                    {
+                        return;
+                    }
+                    let Some(nonce) = event.content.body().strip_prefix(PING) else {
+                        return;
+                    };
+                    let echo = MessageBuilder::notice()
+                        .push_text(&format!("{}{}", PONG, nonce))
+                        .build();
+                    if let Err(err) =
+                        crate::send::send_content(&room, echo, rate_limiter.as_ref()).await
+                    {
+                        error!(
+                            "Failed to echo a canary heartbeat in room {}: {}",
+                            room_id, err
+                        );
+                    }
+                }
+                .in_current_span()
+            },
+        )
+    }
+
+    /// Sends one heartbeat, and waits for its echo. On failure, returns what went wrong.
+    async fn beat(
+        &self,
+        client: &Client,
+        pending: &Mutex<Option<(String, oneshot::Sender<()>)>>,
+    ) -> Result<(), String> {
+        let Some(room) = client.get_room(&self.room_id) else {
+            return Err(format!("not a member of room {}", self.room_id));
+        };
+        match room.latest_encryption_state().await {
+            Ok(state) if state.is_encrypted() => (),
+            Ok(_) => return Err("the canary room is not encrypted".to_owned()),
+            Err(err) => return Err(format!("failed to read the encryption state: {}", err)),
+        }
+        let nonce = rand::rng()
+            .sample_iter(rand::distr::Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect::<String>();
+        let (tx, rx) = oneshot::channel();
+        *pending.lock_unpoisoned() = Some((nonce.clone(), tx));
+        let heartbeat = MessageBuilder::notice()
+            .push_text(&format!("{}{}", PING, nonce))
+            .build();
+        if let Err(err) =
+            crate::send::send_content(&room, heartbeat, self.rate_limiter.as_ref()).await
+        {
+            return Err(format!("failed to send a heartbeat: {}", err));
+        }
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(())) => Ok(()),
+            _ => Err(format!(
+                "no decryptable echo arrived within {}s",
+                self.timeout.as_secs()
+            )),
+        }
+    }
+
+    async fn alert(&self, client: &Client, status: CanaryStatus) {
+        if let Some(on_alert) = &self.on_alert {
+            on_alert(&status);
+        }
+        let Some(alert_room_id) = &self.alert_room else {
+            return;
+        };
+        let Some(alert_room) = client.get_room(alert_room_id) else {
+            warn!("Not a member of alert room {}.", alert_room_id);
+            return;
+        };
+        let text = match &status {
+            CanaryStatus::Healthy => format!(
+                "Canary in room {} is healthy again, encryption and sync work.",
+                self.room_id
+            ),
+            CanaryStatus::Broken(reason) => format!(
+                "Canary in room {} is broken, encryption or sync may not work: {}",
+                self.room_id, reason
+            ),
+        };
+        let result = crate::send::send_content(
+            &alert_room,
+            MessageBuilder::notice().push_text(&text).build(),
+            self.rate_limiter.as_ref(),
+        )
+        .await;
+        if let Err(err) = result {
+            error!(
+                "Failed to post a canary alert to room {}: {}",
+                alert_room_id, err
+            );
+        }
+    }
+}
+
+impl std::fmt::Debug for Canary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Canary")
+            .field("room_id", &self.room_id)
+            .field("interval", &self.interval)
+            .field("timeout", &self.timeout)
+            .field("alert_room", &self.alert_room)
+            .field("on_alert", &self.on_alert.as_ref().map(|_| ".."))
+            .field("rate_limiter", &self.rate_limiter)
+            .finish()
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+use matrix_sdk::Client;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, UInt};
+use matrix_sdk::sync::SyncResponse;
+use tracing::{info, instrument};
+
+use crate::SyncHelper;
+use crate::mutex::MutexExt;
+
+/// Decides what to do with events that occurred while the bot was offline.
+///
+/// Used by [`SyncHelper::catch_up`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CatchUpPolicy {
+    /// Skip all events that occurred while the bot was offline.
+    Skip,
+    /// Process all events that occurred while the bot was offline.
+    ProcessAll,
+    /// Only process events that occurred within the specified duration before now.
+    ProcessSince(Duration),
+}
+
+/// The result of [`SyncHelper::catch_up`].
+#[derive(Clone, Debug)]
+pub struct CatchUpReport {
+    /// The sync response of the initial sync.
+    pub response: SyncResponse,
+    /// Number of timeline events that were skipped according to the [`CatchUpPolicy`].
+    pub skipped_events: usize,
+    /// Number of timeline events that were processed according to the [`CatchUpPolicy`].
+    pub processed_events: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum CatchUpState {
+    Idle,
+    InProgress(Option<MilliSecondsSinceUnixEpoch>),
+    Finished(Option<MilliSecondsSinceUnixEpoch>),
+}
+
+impl SyncHelper {
+    /// Performs the initial sync after [`login`](crate::login), deciding which offline events to process according to a [`CatchUpPolicy`].
+    ///
+    /// Event handlers installed *after* [`catch_up`](SyncHelper::catch_up) never see events from the initial sync, regardless of the policy.
+    ///
+    /// Handlers registered *before* it through [`Dispatcher`](crate::Dispatcher), [`Commands`](crate::Commands), or [`Router`](crate::Router) with this [`SyncHelper`] are suppressed for the events that the policy skips.
+    ///
+    /// Handlers installed *before* it with [`Client::add_event_handler`] see all events from the initial sync.
+    /// They should call [`SyncHelper::should_process`] with the event's `origin_server_ts` to respect the policy.
+    /// [`login`](crate::login) registers the [`SyncHelper`] as an event handler context, so they can take it as a [`Ctx<SyncHelper>`](matrix_sdk::event_handler::Ctx) argument.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    /// use std::time::Duration;
+    ///
+    /// use color_eyre::eyre::Result;
+    /// use matrix_sdk::config::SyncSettings;
+    /// use matrix_sdk::event_handler::Ctx;
+    /// use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+    /// use matrixbot_ezlogin::{CatchUpPolicy, SyncHelper};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<()> {
+    ///     let (client, sync_helper) = matrixbot_ezlogin::login(Path::new("./TODO")).await?;
+    ///
+    ///     client.add_event_handler(
+    ///         |event: OriginalSyncRoomMessageEvent, sync_helper: Ctx<SyncHelper>| async move {
+    ///             if !sync_helper.should_process(event.origin_server_ts) {
+    ///                 return;
+    ///             }
+    ///             todo!()
+    ///         },
+    ///     );
+    ///
+    ///     let report = sync_helper
+    ///         .catch_up(
+    ///             &client,
+    ///             SyncSettings::default(),
+    ///             CatchUpPolicy::ProcessSince(Duration::from_secs(3600)),
+    ///         )
+    ///         .await?;
+    ///     println!("Skipped {} events.", report.skipped_events);
+    ///
+    ///     sync_helper.sync(&client, SyncSettings::default()).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[instrument(skip_all)]
+    pub async fn catch_up(
+        &self,
+        client: &Client,
+        sync_settings: impl Into<SyncSettings>,
+        policy: CatchUpPolicy,
+    ) -> Result<CatchUpReport, matrix_sdk::Error> {
+        let now = self.clock.now();
+        let cutoff = match policy {
+            CatchUpPolicy::Skip => Some(
+                MilliSecondsSinceUnixEpoch::from_system_time(now)
+                    .unwrap_or(MilliSecondsSinceUnixEpoch(UInt::MIN)),
+            ),
+            CatchUpPolicy::ProcessAll => None,
+            CatchUpPolicy::ProcessSince(duration) => Some(
+                now.checked_sub(duration)
+                    .and_then(MilliSecondsSinceUnixEpoch::from_system_time)
+                    .unwrap_or(MilliSecondsSinceUnixEpoch(UInt::MIN)),
+            ),
+        };
+        self.set_catch_up_state(CatchUpState::InProgress(cutoff));
+
+        info!(
+            "Catching up with events since last logout, policy: {:?}.",
+            policy
+        );
+        let response = match self.sync_once(client, sync_settings).await {
+            Ok(response) => response,
+            Err(err) => {
+                self.set_catch_up_state(CatchUpState::Idle);
+                return Err(err);
+            }
+        };
+        // Event handlers are spawned as separate tasks, and may still be running.
+        // Keep the cutoff until the next sync response arrives.
+        self.set_catch_up_state(CatchUpState::Finished(cutoff));
+
+        let mut skipped_events = 0;
+        let mut processed_events = 0;
+        let timelines = response
+            .rooms
+            .joined
+            .values()
+            .map(|room| &room.timeline)
+            .chain(response.rooms.left.values().map(|room| &room.timeline));
+        for event in timelines.flat_map(|timeline| timeline.events.iter()) {
+            let origin_server_ts = event
+                .raw()
+                .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+                .ok()
+                .flatten();
+            match (cutoff, origin_server_ts) {
+                (Some(cutoff), Some(ts)) if ts < cutoff => skipped_events += 1,
+                (Some(_), None) => skipped_events += 1,
+                _ => processed_events += 1,
+            }
+        }
+        info!(
+            "Caught up: {} events skipped, {} events processed.",
+            skipped_events, processed_events
+        );
+
+        Ok(CatchUpReport {
+            response,
+            skipped_events,
+            processed_events,
+        })
+    }
+
+    /// Returns whether an event handler should process an event, according to the [`CatchUpPolicy`] of the latest [`SyncHelper::catch_up`].
+    ///
+    /// Once the first sync response after [`SyncHelper::catch_up`] arrives, it always returns `true`.
+    pub fn should_process(&self, origin_server_ts: MilliSecondsSinceUnixEpoch) -> bool {
+        let state = self.inner.lock_unpoisoned().catch_up_state;
+        match state {
+            CatchUpState::Idle => true,
+            CatchUpState::InProgress(cutoff) | CatchUpState::Finished(cutoff) => {
+                cutoff.is_none_or(|cutoff| origin_server_ts >= cutoff)
+            }
+        }
+    }
+
+    pub(crate) fn finish_catch_up(&self) {
+        let mut inner = self.inner.lock_unpoisoned();
+        if let CatchUpState::Finished(_) = inner.catch_up_state {
+            inner.catch_up_state = CatchUpState::Idle;
+        }
+    }
+
+    fn set_catch_up_state(&self, state: CatchUpState) {
+        self.inner.lock_unpoisoned().catch_up_state = state;
+    }
+}
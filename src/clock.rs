@@ -0,0 +1,134 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::oneshot;
+
+use crate::mutex::MutexExt;
+
+/// A future returned by [`Clock::sleep`].
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A source of time for the retry and backoff logic of this crate.
+///
+/// Components that wait or schedule take a clock when they are built, and default to [`SystemClock`]:
+///
+/// * [`LoginOptions::clock`](crate::LoginOptions::clock), or [`SyncHelper::with_clock`](crate::SyncHelper::with_clock), for everything built from the [`SyncHelper`](crate::SyncHelper),
+///   such as [`SendQueue`](crate::SendQueue), [`Scheduler`](crate::Scheduler), [`Dialog`](crate::Dialog), [`Drain`](crate::Drain), the maintenance loops, and the device name.
+/// * The `clock` builder methods of [`RateLimiter`](crate::RateLimiter), [`AntiFlood`](crate::AntiFlood), [`ReadReceipts`](crate::ReadReceipts), and `EventExport`.
+/// * [`SkipRedacted::with_clock`](crate::SkipRedacted::with_clock), [`Sharding::open_with_clock`](crate::sharding::Sharding::open_with_clock),
+///   [`rooms::join_with_clock`](crate::rooms::join_with_clock), and [`with_typing_clock`](crate::with_typing_clock).
+///
+/// Other free functions that only take a [`Client`](matrix_sdk::Client) or a [`Room`](matrix_sdk::Room), such as [`history::messages`](crate::history::messages),
+/// wait for the homeserver's rate limits on the real clock.
+///
+/// Tests can pass a [`ManualClock`] to fast-forward time.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current wall-clock time.
+    fn now(&self) -> SystemTime;
+
+    /// Returns a future that completes after `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The real clock, using [`SystemTime`] and Tokio timers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves when told to, for tests.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+/// use std::sync::Arc;
+/// use std::time::{Duration, SystemTime};
+///
+/// use matrixbot_ezlogin::{ManualClock, SendQueue, SyncHelper};
+///
+/// # async fn example(client: matrix_sdk::Client) -> color_eyre::Result<()> {
+/// let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+/// let sync_helper = SyncHelper::with_clock(Path::new("./test-data"), Arc::new(clock.clone()))?;
+/// let send_queue = SendQueue::new(&sync_helper);
+/// // Start the code under test, then skip its retry backoff at once
+/// clock.advance(Duration::from_secs(3600));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    inner: Arc<Mutex<ManualClockInner>>,
+}
+
+#[derive(Debug)]
+struct ManualClockInner {
+    now: SystemTime,
+    sleepers: Vec<(SystemTime, oneshot::Sender<()>)>,
+}
+
+impl ManualClock {
+    /// Creates a [`ManualClock`] that stands still at `start`.
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ManualClockInner {
+                now: start,
+                sleepers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Moves the clock forward by `duration`, and wakes every sleeper whose deadline has passed.
+    pub fn advance(&self, duration: Duration) {
+        let mut inner = self.inner.lock_unpoisoned();
+        inner.now += duration;
+        let now = inner.now;
+        let (due, pending) = std::mem::take(&mut inner.sleepers)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+        inner.sleepers = pending;
+        for (_, tx) in due {
+            _ = tx.send(());
+        }
+    }
+
+    /// Returns the number of pending sleeps, so a test can wait until the code under test is sleeping before advancing the clock.
+    pub fn sleepers(&self) -> usize {
+        let mut inner = self.inner.lock_unpoisoned();
+        inner.sleepers.retain(|(_, tx)| !tx.is_closed());
+        inner.sleepers.len()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.inner.lock_unpoisoned().now
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let mut inner = self.inner.lock_unpoisoned();
+        if duration.is_zero() {
+            return Box::pin(std::future::ready(()));
+        }
+        let Some(deadline) = inner.now.checked_add(duration) else {
+            // Beyond what SystemTime can represent, so it never comes
+            return Box::pin(std::future::pending());
+        };
+        let (tx, rx) = oneshot::channel();
+        inner.sleepers.push((deadline, tx));
+        Box::pin(async move {
+            _ = rx.await;
+        })
+    }
+}
@@ -0,0 +1,579 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use eyre::Result;
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::message::{
+    MessageType, OriginalSyncRoomMessageEvent, Relation,
+};
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId, UserId};
+use matrix_sdk::{Client, RoomState};
+use tracing::{Instrument, debug, error, info, instrument};
+
+use crate::mutex::MutexExt;
+use crate::reply::strip_reply_fallback;
+use crate::{Acl, RateLimiter, SyncHelper};
+
+type CommandFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type BoxedHandler =
+    Arc<dyn Fn(CommandContext, &str) -> std::result::Result<CommandFuture, String> + Send + Sync>;
+
+/// A set of `!command` handlers, dispatched from room message events.
+///
+/// A message triggers a command if it starts with the prefix (`!echo hello`), or, if enabled with [`Commands::mention`],
+/// with the bot's user ID, localpart, or display name (`bot: echo hello`).
+///
+/// A `help` command listing every registered command is added automatically, unless a command with the same name is registered.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::{Command, Commands, Rest};
+///
+/// # async fn example(client: matrix_sdk::Client, sync_helper: matrixbot_ezlogin::SyncHelper) {
+/// Commands::new("!")
+///     .mention(true)
+///     .command(
+///         Command::new("echo", |ctx, (Rest(text),)| async move {
+///             ctx.reply(&text).await?;
+///             Ok(())
+///         })
+///         .usage("<text>")
+///         .summary("Repeats the text"),
+///     )
+///     .command(
+///         Command::new("add", |ctx, (a, b): (i64, i64)| async move {
+///             ctx.reply(&(a + b).to_string()).await?;
+///             Ok(())
+///         })
+///         .usage("<a> <b>")
+///         .summary("Adds two numbers")
+///         .rate_limit(std::time::Duration::from_secs(5)),
+///     )
+///     .register(&client, &sync_helper);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Commands {
+    prefix: String,
+    mention: bool,
+    commands: BTreeMap<String, Command>,
+    acl: Option<Acl>,
+    rate_limiter: Option<RateLimiter>,
+    last_used: Arc<Mutex<HashMap<(String, OwnedUserId), LastUse>>>,
+}
+
+/// When a user last ran a command, for [`Command::rate_limit`].
+#[derive(Clone, Copy, Debug)]
+struct LastUse {
+    time: Instant,
+    interval: Duration,
+    /// Whether the user was already told to wait, so they are told only once per interval.
+    warned: bool,
+}
+
+/// What [`Commands::check_rate_limit`] decided.
+enum Cooldown {
+    Ready,
+    Wait(Duration),
+    Silent,
+}
+
+/// How often each user can run the automatic `help` command.
+const HELP_RATE_LIMIT: Duration = Duration::from_secs(10);
+
+/// One command for [`Commands`].
+#[derive(Clone)]
+pub struct Command {
+    name: String,
+    usage: String,
+    summary: String,
+    rate_limit: Option<Duration>,
+    handler: BoxedHandler,
+}
+
+/// Everything a command handler needs to know about the triggering message.
+#[derive(Clone, Debug)]
+pub struct CommandContext {
+    /// The client that received the message.
+    pub client: Client,
+    /// The room where the message was sent.
+    pub room: Room,
+    /// The message that triggered the command.
+    pub event: OriginalSyncRoomMessageEvent,
+    /// The command name, in lower case.
+    pub name: String,
+    /// The rate limiter that [`CommandContext::reply`] waits for, see [`Commands::rate_limiter`].
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+/// Arguments of a command, parsed from the text after the command name.
+///
+/// It is implemented for tuples of up to 4 [`CommandArg`], and for `()` meaning no arguments.
+pub trait CommandArgs: Sized + Send + 'static {
+    /// Parses the arguments. Returns a human-readable message on error.
+    fn parse(args: &str) -> std::result::Result<Self, String>;
+}
+
+/// One argument of a command.
+///
+/// Arguments are separated by whitespace. [`Option`] makes a trailing argument optional, and [`Rest`] takes the remaining text.
+pub trait CommandArg: Sized + Send + 'static {
+    /// Takes one argument from the front of `args`, and advances `args` past it.
+    fn take(args: &mut &str) -> std::result::Result<Self, String>;
+}
+
+/// A [`CommandArg`] that takes all remaining text, with leading and trailing whitespace removed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rest(pub String);
+
+impl Commands {
+    /// Creates an empty set of commands, triggered by `prefix`, for example, `"!"`.
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            mention: false,
+            commands: BTreeMap::new(),
+            acl: None,
+            rate_limiter: None,
+            last_used: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether mentioning the bot at the start of a message also triggers a command. Defaults to `false`.
+    pub fn mention(mut self, enabled: bool) -> Self {
+        self.mention = enabled;
+        self
+    }
+
+    /// Adds a command. A command with the same name is replaced.
+    pub fn command(mut self, command: Command) -> Self {
+        self.commands.insert(command.name.clone(), command);
+        self
+    }
+
+    /// Adds several commands, for example, [`Acl::commands`].
+    pub fn commands(mut self, commands: impl IntoIterator<Item = Command>) -> Self {
+        for command in commands {
+            self = self.command(command);
+        }
+        self
+    }
+
+    /// Silently ignores commands from users and rooms that `acl` doesn't allow.
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Makes [`CommandContext::reply`] wait for `rate_limiter` before sending.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Registers an event handler on `client` that calls [`Commands::dispatch`] for every room message.
+    ///
+    /// Messages that the [`CatchUpPolicy`](crate::CatchUpPolicy) of [`SyncHelper::catch_up`] on `sync_helper` skips are not dispatched.
+    pub fn register(&self, client: &Client, sync_helper: &SyncHelper) -> EventHandlerHandle {
+        let commands = self.clone();
+        let sync_helper = sync_helper.clone();
+        client.add_event_handler(
+            move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+                let commands = commands.clone();
+                let sync_helper = sync_helper.clone();
+                async move {
+                    if !sync_helper.should_process(event.origin_server_ts) {
+                        return;
+                    }
+                    commands.dispatch(event, room, client).await;
+                }
+            },
+        )
+    }
+
+    /// Runs the command in `event`, if any.
+    ///
+    /// Returns whether the message was a command. Call it from your own event handler if you need to pre-process messages.
+    ///
+    /// The command handler runs in a separate Tokio task, so it may still be running when this returns.
+    #[instrument(skip_all)]
+    pub async fn dispatch(
+        &self,
+        event: OriginalSyncRoomMessageEvent,
+        room: Room,
+        client: Client,
+    ) -> bool {
+        let Some(own_user_id) = client.user_id().map(ToOwned::to_owned) else {
+            return false;
+        };
+        if event.sender == own_user_id || room.state() != RoomState::Joined {
+            return false;
+        }
+        if let Some(Relation::Replacement(_)) = event.content.relates_to {
+            return false;
+        }
+        if let Some(acl) = &self.acl
+            && !acl.is_allowed(&event.sender, room.room_id())
+        {
+            debug!(
+                "Ignoring room {}, event {}: Denied by the access list.",
+                room.room_id(),
+                event.event_id
+            );
+            return false;
+        }
+        let MessageType::Text(text) = &event.content.msgtype else {
+            return false;
+        };
+        let Some(invocation) = self.strip_trigger(&room, &own_user_id, &text.body).await else {
+            return false;
+        };
+        let (name, args) = invocation
+            .split_once(char::is_whitespace)
+            .unwrap_or((invocation, ""));
+        let name = name.to_lowercase();
+        let args = args.trim().to_owned();
+
+        let ctx = CommandContext {
+            client,
+            room,
+            event,
+            name: name.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+        };
+        let Some(command) = self.commands.get(&name) else {
+            if name != "help" {
+                return false;
+            }
+            match self.check_rate_limit(&name, &ctx.event.sender, HELP_RATE_LIMIT) {
+                Cooldown::Ready => self.reply_or_log(&ctx, &self.help()).await,
+                Cooldown::Wait(_) | Cooldown::Silent => (),
+            }
+            return true;
+        };
+
+        // Parsed before the rate limit is checked, so a mistyped command doesn't use up the user's turn
+        let future = match (command.handler)(ctx.clone(), &args) {
+            Ok(future) => future,
+            Err(err) => {
+                self.reply_or_log(
+                    &ctx,
+                    &format!(
+                        "{}\nUsage: {}{} {}",
+                        err, self.prefix, command.name, command.usage
+                    ),
+                )
+                .await;
+                return true;
+            }
+        };
+
+        if let Some(rate_limit) = command.rate_limit {
+            match self.check_rate_limit(&name, &ctx.event.sender, rate_limit) {
+                Cooldown::Ready => (),
+                Cooldown::Wait(wait) => {
+                    self.reply_or_log(
+                        &ctx,
+                        &format!(
+                            "Please wait {} seconds before using {}{} again.",
+                            wait.as_secs().max(1),
+                            self.prefix,
+                            name
+                        ),
+                    )
+                    .await;
+                    return true;
+                }
+                Cooldown::Silent => return true,
+            }
+        }
+
+        info!(
+            "Running command {} in room {}, event {}.",
+            name,
+            ctx.room.room_id(),
+            ctx.event.event_id
+        );
+        // Spawned, so a slow command doesn't hold up the sync loop
+        tokio::spawn(
+            async move {
+                if let Err(err) = future.await {
+                    error!(
+                        "Command {} failed in room {}, event {}: {:?}",
+                        name,
+                        ctx.room.room_id(),
+                        ctx.event.event_id,
+                        err
+                    );
+                }
+            }
+            .in_current_span(),
+        );
+        true
+    }
+
+    /// Records a use of command `name` by `sender`, unless they used it less than `interval` ago.
+    ///
+    /// Only the first refused use within the interval asks for a reply, so a user can't make the bot flood the room with warnings.
+    fn check_rate_limit(&self, name: &str, sender: &OwnedUserId, interval: Duration) -> Cooldown {
+        let now = Instant::now();
+        let mut last_used = self.last_used.lock_unpoisoned();
+        last_used.retain(|_, last| now.duration_since(last.time) < last.interval);
+        let key = (name.to_owned(), sender.clone());
+        let Some(last) = last_used.get_mut(&key) else {
+            last_used.insert(
+                key,
+                LastUse {
+                    time: now,
+                    interval,
+                    warned: false,
+                },
+            );
+            return Cooldown::Ready;
+        };
+        if last.warned {
+            return Cooldown::Silent;
+        }
+        last.warned = true;
+        Cooldown::Wait(interval.saturating_sub(now.duration_since(last.time)))
+    }
+
+    /// Returns the text of the automatic `help` command.
+    pub fn help(&self) -> String {
+        let mut help = String::from("Available commands:");
+        for command in self.commands.values() {
+            help.push_str(&format!("\n{}{}", self.prefix, command.name));
+            if !command.usage.is_empty() {
+                help.push(' ');
+                help.push_str(&command.usage);
+            }
+            if !command.summary.is_empty() {
+                help.push_str(" — ");
+                help.push_str(&command.summary);
+            }
+        }
+        if !self.commands.contains_key("help") {
+            help.push_str(&format!("\n{}help — Shows this message", self.prefix));
+        }
+        help
+    }
+
+    async fn strip_trigger<'a>(
+        &self,
+        room: &Room,
+        own_user_id: &UserId,
+        body: &'a str,
+    ) -> Option<&'a str> {
+        let body = strip_reply_fallback(body).trim_start();
+        if !self.prefix.is_empty()
+            && let Some(invocation) = body.strip_prefix(&self.prefix)
+        {
+            return Some(invocation.trim_start());
+        }
+        if !self.mention {
+            return None;
+        }
+        let mut names = vec![own_user_id.to_string(), own_user_id.localpart().to_owned()];
+        if let Ok(Some(member)) = room.get_member_no_sync(own_user_id).await
+            && let Some(display_name) = member.display_name()
+        {
+            names.push(display_name.to_owned());
+        }
+        names.iter().find_map(|name| {
+            let rest = body.strip_prefix(name.as_str())?;
+            let rest = rest
+                .strip_prefix([':', ','])
+                .unwrap_or(rest)
+                .strip_prefix(char::is_whitespace)?;
+            Some(rest.trim_start())
+        })
+    }
+
+    async fn reply_or_log(&self, ctx: &CommandContext, text: &str) {
+        if let Err(err) = ctx.reply(text).await {
+            error!(
+                "Failed to reply to room {}, event {}: {}",
+                ctx.room.room_id(),
+                ctx.event.event_id,
+                err
+            );
+        }
+    }
+}
+
+impl std::fmt::Debug for Commands {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Commands")
+            .field("prefix", &self.prefix)
+            .field("mention", &self.mention)
+            .field("commands", &self.commands)
+            .field("acl", &self.acl)
+            .field("rate_limiter", &self.rate_limiter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Command {
+    /// Creates a command called `name`, case-insensitive, whose arguments are parsed into `A` before calling `handler`.
+    ///
+    /// If the arguments don't parse, the bot replies with the error and the usage instead.
+    pub fn new<A, F, Fut>(name: impl Into<String>, handler: F) -> Self
+    where
+        A: CommandArgs,
+        F: Fn(CommandContext, A) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        Self {
+            name: name.into().to_lowercase(),
+            usage: String::new(),
+            summary: String::new(),
+            rate_limit: None,
+            handler: Arc::new(
+                move |ctx: CommandContext,
+                      args: &str|
+                      -> std::result::Result<CommandFuture, String> {
+                    let args = A::parse(args)?;
+                    Ok(Box::pin(handler(ctx, args)))
+                },
+            ),
+        }
+    }
+
+    /// Describes the arguments in the `help` command, for example, `"<user> [reason]"`.
+    pub fn usage(mut self, usage: impl Into<String>) -> Self {
+        self.usage = usage.into();
+        self
+    }
+
+    /// Describes what the command does in the `help` command.
+    pub fn summary(mut self, summary: impl Into<String>) -> Self {
+        self.summary = summary.into();
+        self
+    }
+
+    /// Each user can only run this command once per `interval`. Defaults to no limit.
+    pub fn rate_limit(mut self, interval: Duration) -> Self {
+        self.rate_limit = Some(interval);
+        self
+    }
+}
+
+impl std::fmt::Debug for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Command")
+            .field("name", &self.name)
+            .field("usage", &self.usage)
+            .field("summary", &self.summary)
+            .field("rate_limit", &self.rate_limit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CommandContext {
+    /// Replies to the triggering message with a plain text notice, in the same thread if any.
+    pub async fn reply(&self, text: &str) -> Result<OwnedEventId> {
+        crate::send::reply_notice(&self.room, &self.event, text, self.rate_limiter.as_ref()).await
+    }
+}
+
+pub(crate) fn next_token<'a>(args: &mut &'a str) -> Option<&'a str> {
+    let trimmed = args.trim_start();
+    if trimmed.is_empty() {
+        *args = trimmed;
+        return None;
+    }
+    let (token, rest) = trimmed
+        .split_once(char::is_whitespace)
+        .unwrap_or((trimmed, ""));
+    *args = rest;
+    Some(token)
+}
+
+macro_rules! impl_command_arg_from_str {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl CommandArg for $ty {
+                fn take(args: &mut &str) -> std::result::Result<Self, String> {
+                    let token = next_token(args).ok_or("Missing argument.")?;
+                    token
+                        .parse()
+                        .map_err(|err| format!("Invalid argument {:?}: {}", token, err))
+                }
+            }
+        )*
+    };
+}
+
+impl_command_arg_from_str!(
+    String,
+    bool,
+    char,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    f32,
+    f64,
+    OwnedUserId,
+    OwnedRoomId,
+    OwnedRoomAliasId,
+    OwnedEventId,
+);
+
+impl<T: CommandArg> CommandArg for Option<T> {
+    fn take(args: &mut &str) -> std::result::Result<Self, String> {
+        if args.trim().is_empty() {
+            return Ok(None);
+        }
+        T::take(args).map(Some)
+    }
+}
+
+impl CommandArg for Rest {
+    fn take(args: &mut &str) -> std::result::Result<Self, String> {
+        let rest = args.trim().to_owned();
+        *args = "";
+        Ok(Rest(rest))
+    }
+}
+
+impl CommandArgs for () {
+    fn parse(args: &str) -> std::result::Result<Self, String> {
+        if !args.trim().is_empty() {
+            return Err("This command takes no arguments.".to_owned());
+        }
+        Ok(())
+    }
+}
+
+macro_rules! impl_command_args_for_tuple {
+    ($($arg:ident),+) => {
+        impl<$($arg: CommandArg),+> CommandArgs for ($($arg,)+) {
+            fn parse(mut args: &str) -> std::result::Result<Self, String> {
+                let parsed = ($($arg::take(&mut args)?,)+);
+                if !args.trim().is_empty() {
+                    return Err("Too many arguments.".to_owned());
+                }
+                Ok(parsed)
+            }
+        }
+    };
+}
+
+impl_command_args_for_tuple!(A);
+impl_command_args_for_tuple!(A, B);
+impl_command_args_for_tuple!(A, B, C);
+impl_command_args_for_tuple!(A, B, C, D);
@@ -8,6 +8,48 @@ use tracing::info;
 
 static PRINT_SQLITE_VERSION_ONCE: Once = Once::new();
 
+/// Schema migrations of `matrixbot-ezlogin.sqlite3`, applied in order.
+///
+/// The number of applied migrations is stored in `PRAGMA user_version`.
+/// Never modify or reorder existing migrations, only append new ones.
+const MIGRATIONS: &[&str] = &[
+    // Keep the history of sync tokens
+    "CREATE TABLE sync_token_history (id INTEGER PRIMARY KEY AUTOINCREMENT, token TEXT NOT NULL, time INTEGER NOT NULL);
+INSERT INTO sync_token_history (token, time) SELECT token, CAST(unixepoch('subsec') * 1000 AS INTEGER) FROM sync_token;
+DROP TABLE sync_token;
+ALTER TABLE sync_token_history RENAME TO sync_token;",
+    // Per-room sync positions
+    "CREATE TABLE room_position (room_id TEXT PRIMARY KEY, event_id TEXT NOT NULL, time INTEGER NOT NULL);",
+    // Event deduplication
+    "CREATE TABLE seen_event (event_id TEXT PRIMARY KEY, time INTEGER NOT NULL);
+CREATE INDEX seen_event_time ON seen_event (time);",
+    // Access control lists
+    "CREATE TABLE acl (list TEXT NOT NULL CHECK (list IN ('allow', 'deny')), pattern TEXT NOT NULL, PRIMARY KEY (list, pattern));",
+    // Durable send queue
+    "CREATE TABLE send_queue (id INTEGER PRIMARY KEY AUTOINCREMENT, room_id TEXT NOT NULL, txn_id TEXT NOT NULL, event_type TEXT NOT NULL, content TEXT NOT NULL, attempts INTEGER NOT NULL, next_attempt INTEGER NOT NULL, time INTEGER NOT NULL);
+CREATE INDEX send_queue_room_id ON send_queue (room_id, id);",
+    // Scheduled tasks
+    "CREATE TABLE scheduled_task (id INTEGER PRIMARY KEY AUTOINCREMENT, job TEXT NOT NULL, schedule TEXT NOT NULL, payload TEXT NOT NULL, due INTEGER NOT NULL, time INTEGER NOT NULL);
+CREATE INDEX scheduled_task_due ON scheduled_task (due);",
+    // Conversation states
+    "CREATE TABLE dialog_state (dialog TEXT NOT NULL, room_id TEXT NOT NULL, user_id TEXT NOT NULL, state BLOB NOT NULL, expires INTEGER, time INTEGER NOT NULL, PRIMARY KEY (dialog, room_id, user_id));",
+    // Transaction IDs of outgoing messages
+    "CREATE TABLE txn_id (room_id TEXT NOT NULL, key TEXT NOT NULL, txn_id TEXT NOT NULL, time INTEGER NOT NULL, PRIMARY KEY (room_id, key));
+CREATE INDEX txn_id_time ON txn_id (time);",
+    // When the event cache was last emptied
+    "CREATE TABLE event_cache_prune (id INTEGER PRIMARY KEY CHECK (id = 0), time INTEGER NOT NULL);",
+    // Per-handler event deduplication
+    "CREATE TABLE handled_event (handler TEXT NOT NULL, event_id TEXT NOT NULL, time INTEGER NOT NULL, PRIMARY KEY (handler, event_id));
+CREATE INDEX handled_event_time ON handled_event (time);",
+    // Sync tokens deliberately cleared or rolled back, which the token mirror must not undo
+    "CREATE TABLE sync_token_reset (id INTEGER PRIMARY KEY CHECK (id = 0), time INTEGER NOT NULL);",
+];
+
+/// Tables that [`SQLiteHelper::reset_schema`] leaves alone, because their data belongs to the bot, not to its session.
+///
+/// Reminders are stored in `scheduled_task`.
+const PERSISTENT_TABLES: &[&str] = &["acl", "scheduled_task", "send_queue"];
+
 #[derive(Debug)]
 pub struct SQLiteHelper {
     conn: rusqlite::Connection,
@@ -55,6 +97,80 @@ PRAGMA optimize = 0x10002;",
 
         Ok(SQLiteHelper { conn })
     }
+
+    /// Prepares the schema for a freshly set up session.
+    ///
+    /// It empties every table of the previous session, if any, such as the sync token and the seen events,
+    /// but keeps the [`PERSISTENT_TABLES`]: the access control lists, the scheduled tasks and reminders, and the send queue.
+    pub fn reset_schema(&mut self) -> Result<()> {
+        let set_up = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = 'matrix_session');",
+            (),
+            |row| row.get::<_, bool>(0),
+        )?;
+        let tx = self.conn.transaction()?;
+        let tables = tx
+            .prepare(
+                "SELECT name FROM sqlite_schema WHERE type = 'table' AND name NOT LIKE 'sqlite_%';",
+            )?
+            .query_map((), |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if !set_up {
+            // Never set up, so anything here is left over from elsewhere
+            for table in tables {
+                tx.execute(
+                    &format!("DROP TABLE IF EXISTS \"{}\";", table.replace('"', "\"\"")),
+                    (),
+                )?;
+            }
+            tx.execute_batch(
+                "CREATE TABLE matrix_session (id INTEGER PRIMARY KEY CHECK (id = 0), homeserver TEXT NOT NULL, passphrase TEXT NOT NULL, session BLOB NOT NULL);
+CREATE TABLE sync_token (id INTEGER PRIMARY KEY CHECK (id = 0), token TEXT NOT NULL);
+PRAGMA user_version = 0;",
+            )?;
+        } else {
+            for table in tables {
+                if PERSISTENT_TABLES.contains(&table.as_str()) {
+                    continue;
+                }
+                tx.execute(
+                    &format!("DELETE FROM \"{}\";", table.replace('"', "\"\"")),
+                    (),
+                )?;
+            }
+        }
+        tx.commit()?;
+        self.migrate()?;
+        self.conn.execute_batch(
+            "PRAGMA optimize;
+VACUUM;",
+        )?;
+        Ok(())
+    }
+
+    /// Applies pending schema migrations.
+    pub fn migrate(&mut self) -> Result<()> {
+        let version: usize = self
+            .conn
+            .query_row("PRAGMA user_version;", (), |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            bail!(
+                "database schema version {} is newer than supported version {}, please upgrade matrixbot-ezlogin",
+                version,
+                MIGRATIONS.len()
+            );
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("Migrating database schema to version {}.", i + 1);
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(migration).wrap_err_with(|| {
+                format!("failed to migrate database schema to version {}", i + 1)
+            })?;
+            tx.execute_batch(&format!("PRAGMA user_version = {};", i + 1))?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
 }
 
 impl AsMut<rusqlite::Connection> for SQLiteHelper {
@@ -88,3 +204,29 @@ impl Drop for SQLiteHelper {
         _ = self.conn.execute("PRAGMA optimize;", ());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_keeps_persistent_tables() {
+        let mut db = SQLiteHelper::open(Path::new(":memory:"), true).unwrap();
+        db.reset_schema().unwrap();
+        db.execute_batch(
+            "INSERT INTO acl (list, pattern) VALUES ('deny', '@spammer:*');
+INSERT INTO sync_token (token, time) VALUES ('s1', 0);",
+        )
+        .unwrap();
+
+        db.reset_schema().unwrap();
+        let count = |table: &str| -> i64 {
+            db.query_row(&format!("SELECT COUNT(*) FROM {};", table), (), |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count("acl"), 1);
+        assert_eq!(count("sync_token"), 0);
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::{Duration, SystemTime};
+
+use eyre::Result;
+use matrix_sdk::ruma::EventId;
+use tracing::debug;
+
+use crate::SyncHelper;
+use crate::mutex::MutexExt;
+use crate::sync::{SyncHelperInner, unix_millis};
+
+pub(crate) const DEFAULT_SEEN_EVENT_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+const SEEN_EVENT_PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+impl SyncHelper {
+    /// Marks an event as seen, and returns whether it had already been seen before.
+    ///
+    /// The set of seen events is persisted in the state database, so it survives process restarts.
+    ///
+    /// Event handlers can use it to guard against events being delivered twice, for example:
+    /// * Each `m.room.member` event is delivered twice to handlers of [`SyncRoomMemberEvent`](matrix_sdk::ruma::events::room::member::SyncRoomMemberEvent), once as a state event and once as a timeline event.
+    /// * Events are delivered again after [`SyncHelper::rollback`].
+    ///
+    /// Entries older than [`SyncHelper::set_seen_event_ttl`] are pruned automatically.
+    pub fn seen(&self, event_id: &EventId) -> Result<bool> {
+        let mut inner = self.inner.lock_unpoisoned();
+        let now = self.clock.now();
+        prune_seen_events(&mut inner, now)?;
+        let inserted = inner
+            .session_db
+            .prepare_cached("INSERT OR IGNORE INTO seen_event (event_id, time) VALUES (?, ?);")?
+            .execute((event_id.as_str(), unix_millis(now)))?;
+        Ok(inserted == 0)
+    }
+
+    /// Returns whether `handler` has already handled an event, as recorded by [`SyncHelper::mark_handled`].
+    pub(crate) fn is_handled(&self, handler: &str, event_id: &EventId) -> Result<bool> {
+        let db = self.session_db();
+        let mut stmt =
+            db.prepare_cached("SELECT 1 FROM handled_event WHERE handler = ? AND event_id = ?;")?;
+        Ok(stmt.exists((handler, event_id.as_str()))?)
+    }
+
+    /// Records that `handler` has handled an event. Entries expire like those of [`SyncHelper::seen`].
+    pub(crate) fn mark_handled(&self, handler: &str, event_id: &EventId) -> Result<()> {
+        let mut inner = self.inner.lock_unpoisoned();
+        let now = self.clock.now();
+        prune_seen_events(&mut inner, now)?;
+        inner
+            .session_db
+            .prepare_cached(
+                "INSERT OR IGNORE INTO handled_event (handler, event_id, time) VALUES (?, ?, ?);",
+            )?
+            .execute((handler, event_id.as_str(), unix_millis(now)))?;
+        Ok(())
+    }
+
+    /// Sets how long seen events are remembered by [`SyncHelper::seen`]. Defaults to 7 days.
+    pub fn set_seen_event_ttl(&self, ttl: Duration) {
+        self.inner.lock_unpoisoned().seen_event_ttl = ttl;
+    }
+}
+
+fn prune_seen_events(inner: &mut SyncHelperInner, now: SystemTime) -> Result<()> {
+    if inner.seen_event_last_prune.is_some_and(|last_prune| {
+        now.duration_since(last_prune)
+            .is_ok_and(|elapsed| elapsed < SEEN_EVENT_PRUNE_INTERVAL)
+    }) {
+        return Ok(());
+    }
+    inner.seen_event_last_prune = Some(now);
+    let cutoff = unix_millis(
+        now.checked_sub(inner.seen_event_ttl)
+            .unwrap_or(SystemTime::UNIX_EPOCH),
+    );
+    let pruned = inner
+        .session_db
+        .prepare_cached("DELETE FROM seen_event WHERE time < ?;")?
+        .execute((cutoff,))?;
+    let pruned_handled = inner
+        .session_db
+        .prepare_cached("DELETE FROM handled_event WHERE time < ?;")?
+        .execute((cutoff,))?;
+    debug!(
+        "Pruned {} seen events, and {} handled events.",
+        pruned, pruned_handled
+    );
+    Ok(())
+}
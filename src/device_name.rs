@@ -0,0 +1,93 @@
+use std::time::SystemTime;
+
+use matrix_sdk::Client;
+use tracing::{info, warn};
+
+/// The device display name that [`login_with_options`](crate::login_with_options) sets on every start, see [`LoginOptions::device_name`](crate::LoginOptions::device_name).
+///
+/// Operators looking at the device list of the bot account, for example in Element, can then tell which process on which host each device belongs to.
+/// The name looks like `echo-bot v1.2.3 on myhost since 2025-01-31 12:00 UTC`.
+///
+/// Display names are visible to other users of the homeserver who share a room with the bot, so leave out anything confidential.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceName {
+    /// The first part of the name, usually the name of the bot.
+    pub prefix: String,
+    /// The version of the bot, usually `env!("CARGO_PKG_VERSION")` of the bot's crate. Omitted if empty.
+    pub version: String,
+    /// Includes the host name of the machine.
+    pub hostname: bool,
+    /// Includes the time the process logged in, in UTC.
+    pub start_time: bool,
+}
+
+impl DeviceName {
+    /// Creates a [`DeviceName`] with the host name and start time.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use matrixbot_ezlogin::DeviceName;
+    ///
+    /// let device_name = DeviceName::new("echo-bot", env!("CARGO_PKG_VERSION"));
+    /// ```
+    pub fn new(prefix: impl Into<String>, version: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            version: version.into(),
+            hostname: true,
+            start_time: true,
+        }
+    }
+
+    /// Formats the display name, with the current time as the start time.
+    pub fn render(&self) -> String {
+        self.render_at(SystemTime::now())
+    }
+
+    /// Formats the display name, with `start_time` as the start time.
+    pub fn render_at(&self, start_time: SystemTime) -> String {
+        let mut name = self.prefix.clone();
+        if !self.version.is_empty() {
+            name.push_str(" v");
+            name.push_str(&self.version);
+        }
+        if self.hostname
+            && let Some(hostname) = hostname()
+        {
+            name.push_str(" on ");
+            name.push_str(&hostname);
+        }
+        if self.start_time {
+            let start_time = chrono::DateTime::<chrono::Utc>::from(start_time);
+            name.push_str(&start_time.format(" since %Y-%m-%d %H:%M UTC").to_string());
+        }
+        name
+    }
+}
+
+/// Renames the bot's device. A failure is logged, but doesn't fail the login, because the name is only informational.
+pub(crate) async fn refresh(client: &Client, device_name: &DeviceName, start_time: SystemTime) {
+    let Some(device_id) = client.device_id() else {
+        return;
+    };
+    let name = device_name.render_at(start_time);
+    match client.rename_device(device_id, &name).await {
+        Ok(_) => info!("Renamed device {} to {:?}.", device_id, name),
+        Err(err) => warn!("Failed to rename device {}: {}", device_id, err),
+    }
+}
+
+/// Returns the host name without adding a dependency, trying the usual places on Linux, other Unix systems, and Windows.
+fn hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .into_iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .chain(
+            ["HOSTNAME", "COMPUTERNAME"]
+                .into_iter()
+                .filter_map(|name| std::env::var(name).ok()),
+        )
+        .map(|hostname| hostname.trim().to_owned())
+        .find(|hostname| !hostname.is_empty())
+}
@@ -0,0 +1,247 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use matrix_sdk::encryption::recovery::RecoveryState;
+use tracing::instrument;
+
+use crate::SyncHelper;
+
+/// Warn if the data directory has less free space than this.
+const LOW_DISK_SPACE: u64 = 512 << 20;
+/// Warn if the latest sync token is older than this.
+const OLD_SYNC_TOKEN: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// How serious a [`Finding`] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The check passed.
+    Ok,
+    /// The bot can run, but something may need attention.
+    Warning,
+    /// The bot can't run correctly until the problem is fixed.
+    Error,
+}
+
+/// The result of one check in [`diagnose`].
+#[derive(Clone, Debug)]
+pub struct Finding {
+    /// A short name of the check, for example, `"session"`.
+    pub check: &'static str,
+    /// How serious the finding is.
+    pub severity: Severity,
+    /// What was found, and what to do about it.
+    pub message: String,
+}
+
+/// The result of [`diagnose`].
+#[derive(Clone, Debug, Default)]
+pub struct DiagnosisReport {
+    /// Findings of every check that was run, in order.
+    pub findings: Vec<Finding>,
+}
+
+impl DiagnosisReport {
+    /// Returns whether no check found an error.
+    pub fn is_healthy(&self) -> bool {
+        self.findings
+            .iter()
+            .all(|finding| finding.severity < Severity::Error)
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        check: &'static str,
+        severity: Severity,
+        message: impl Into<String>,
+    ) {
+        self.findings.push(Finding {
+            check,
+            severity,
+            message: message.into(),
+        });
+    }
+}
+
+impl std::fmt::Display for DiagnosisReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for finding in &self.findings {
+            let tag = match finding.severity {
+                Severity::Ok => "[ OK ]",
+                Severity::Warning => "[WARN]",
+                Severity::Error => "[FAIL]",
+            };
+            writeln!(f, "{} {}: {}", tag, finding.check, finding.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks a data directory for common problems, without starting a sync.
+///
+/// It checks the free disk space, the integrity of the state database, the validity of the session, the verification state of the device,
+/// the server-side backup, and the age of the sync token.
+///
+/// The bot must not be running, because the state database can only be opened by one process at the same time.
+#[instrument(skip_all)]
+pub async fn diagnose(data_dir: &Path) -> DiagnosisReport {
+    let mut report = DiagnosisReport::default();
+
+    match free_disk_space(data_dir) {
+        Ok(Some(free)) if free < LOW_DISK_SPACE => report.push(
+            "disk",
+            Severity::Warning,
+            format!(
+                "only {} MiB free, the state database may fail to grow",
+                free >> 20
+            ),
+        ),
+        Ok(Some(free)) => report.push("disk", Severity::Ok, format!("{} MiB free", free >> 20)),
+        Ok(None) => (),
+        Err(err) => report.push(
+            "disk",
+            Severity::Warning,
+            format!("failed to check free space: {}", err),
+        ),
+    }
+
+    let (client, sync_helper) = match crate::login(data_dir).await {
+        Ok(logged_in) => logged_in,
+        Err(err) => {
+            report.push(
+                "session",
+                Severity::Error,
+                format!(
+                    "failed to restore the session: {}. Make sure the bot is not running, or run setup again",
+                    err
+                ),
+            );
+            return report;
+        }
+    };
+
+    match quick_check(&sync_helper) {
+        Ok(result) if result == "ok" => {
+            report.push("database", Severity::Ok, "integrity check passed")
+        }
+        Ok(result) => report.push(
+            "database",
+            Severity::Error,
+            format!("integrity check failed: {}", result),
+        ),
+        Err(err) => report.push(
+            "database",
+            Severity::Error,
+            format!("failed to check integrity: {}", err),
+        ),
+    }
+
+    match sync_helper.get_sync_token_history() {
+        Ok(history) => match history.first() {
+            Some((_, time)) => {
+                let age = SystemTime::now().duration_since(*time).unwrap_or_default();
+                let severity = if age > OLD_SYNC_TOKEN {
+                    Severity::Warning
+                } else {
+                    Severity::Ok
+                };
+                report.push(
+                    "sync token",
+                    severity,
+                    format!("last saved {:.1} hours ago", age.as_secs_f64() / 3600.0),
+                );
+            }
+            None => report.push(
+                "sync token",
+                Severity::Ok,
+                "no sync token yet, the next sync is an initial sync",
+            ),
+        },
+        Err(err) => report.push(
+            "sync token",
+            Severity::Error,
+            format!("failed to read: {}", err),
+        ),
+    }
+
+    match client.whoami().await {
+        Ok(whoami) => report.push(
+            "session",
+            Severity::Ok,
+            format!("access token is valid for {}", whoami.user_id),
+        ),
+        Err(err) => report.push(
+            "session",
+            Severity::Error,
+            format!("access token was rejected: {}. Run setup again", err),
+        ),
+    }
+
+    let encryption = client.encryption();
+    match encryption.get_own_device().await {
+        Ok(Some(device)) if device.is_cross_signed_by_owner() => {
+            report.push("device", Severity::Ok, "this device is cross-signed")
+        }
+        Ok(_) => report.push(
+            "device",
+            Severity::Error,
+            "this device is not cross-signed, other users may refuse to share keys. Run setup again",
+        ),
+        Err(err) => report.push(
+            "device",
+            Severity::Error,
+            format!("failed to read the device: {}", err),
+        ),
+    }
+
+    if encryption.backups().are_enabled().await {
+        report.push("backup", Severity::Ok, "server-side backup is enabled");
+    } else {
+        report.push(
+            "backup",
+            Severity::Error,
+            "server-side backup is not enabled, old messages can't be decrypted. Run setup again",
+        );
+    }
+    match encryption.recovery().state() {
+        RecoveryState::Enabled => report.push("recovery", Severity::Ok, "recovery is enabled"),
+        RecoveryState::Incomplete => report.push(
+            "recovery",
+            Severity::Warning,
+            "some secrets are missing from the server-side secret storage",
+        ),
+        state => report.push(
+            "recovery",
+            Severity::Warning,
+            format!("recovery state is {:?}", state),
+        ),
+    }
+
+    report
+}
+
+fn quick_check(sync_helper: &SyncHelper) -> rusqlite::Result<String> {
+    sync_helper
+        .session_db()
+        .query_row("PRAGMA quick_check;", (), |row| row.get(0))
+}
+
+/// Returns the space available to unprivileged users, or [`None`] if unsupported on this platform.
+#[cfg(unix)]
+fn free_disk_space(path: &Path) -> std::io::Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs is plain old data, so all zeros is a valid value.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is a valid C string, and stat is a valid pointer.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    #[allow(clippy::useless_conversion)]
+    Ok(Some(u64::from(stat.f_bavail) * u64::from(stat.f_frsize)))
+}
+
+#[cfg(not(unix))]
+fn free_disk_space(_path: &Path) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
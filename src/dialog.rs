@@ -0,0 +1,174 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use eyre::Result;
+use matrix_sdk::ruma::{RoomId, UserId};
+use rusqlite::OptionalExtension;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tracing::debug;
+
+use crate::SyncHelper;
+use crate::sync::unix_millis;
+
+/// Tracks the state of multi-step conversations, one per (room, user) pair, stored in the state database.
+///
+/// The state is any type implementing [`Serialize`] and [`DeserializeOwned`], typically an enum of the questions the bot is waiting for.
+/// A state expires if the user doesn't respond within the timeout, so an abandoned conversation doesn't capture the user's later messages.
+///
+/// Several dialogs can share one state database as long as their names differ.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrix_sdk::room::Room;
+/// use matrix_sdk::ruma::events::room::message::{OriginalSyncRoomMessageEvent, RoomMessageEventContent};
+/// use matrixbot_ezlogin::Dialog;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// enum Booking {
+///     AskCity,
+///     AskTime { city: String },
+/// }
+///
+/// # async fn example(dialog: Dialog<Booking>, room: Room, event: OriginalSyncRoomMessageEvent, text: String) -> color_eyre::Result<()> {
+/// // let dialog = Dialog::<Booking>::new(&sync_helper, "booking");
+/// let (reply, next) = match dialog.get(room.room_id(), &event.sender)? {
+///     None if text == "!book" => ("What city?".to_owned(), Some(Booking::AskCity)),
+///     None => return Ok(()),
+///     Some(Booking::AskCity) => ("What time?".to_owned(), Some(Booking::AskTime { city: text })),
+///     Some(Booking::AskTime { city }) => (format!("Booked {} at {}.", city, text), None),
+/// };
+/// match next {
+///     Some(state) => dialog.set(room.room_id(), &event.sender, &state)?,
+///     None => _ = dialog.clear(room.room_id(), &event.sender)?,
+/// }
+/// room.send(RoomMessageEventContent::notice_plain(reply)).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Dialog<S> {
+    sync_helper: SyncHelper,
+    name: String,
+    timeout: Option<Duration>,
+    _state: PhantomData<fn() -> S>,
+}
+
+impl<S> Dialog<S>
+where
+    S: Serialize + DeserializeOwned,
+{
+    /// Creates a [`Dialog`] named `name`, stored in the state database of `sync_helper`.
+    ///
+    /// By default, a state expires 10 minutes after it was last set.
+    pub fn new(sync_helper: &SyncHelper, name: impl Into<String>) -> Self {
+        Self {
+            sync_helper: sync_helper.clone(),
+            name: name.into(),
+            timeout: Some(Duration::from_secs(600)),
+            _state: PhantomData,
+        }
+    }
+
+    /// How long a state lasts after it was last set. [`None`] keeps it until cleared.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the current state of `user_id` in `room_id`, or [`None`] if there is no conversation or it has expired.
+    pub fn get(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<S>> {
+        let now = unix_millis(self.sync_helper.clock.now());
+        let db = self.sync_helper.session_db();
+        let row: Option<(String, Option<i64>)> = db
+            .prepare_cached(
+                "SELECT json(state), expires FROM dialog_state WHERE dialog = ? AND room_id = ? AND user_id = ?;",
+            )?
+            .query_row((&self.name, room_id.as_str(), user_id.as_str()), |row| {
+                row.try_into()
+            })
+            .optional()?;
+        let Some((state, expires)) = row else {
+            return Ok(None);
+        };
+        if expires.is_some_and(|expires| expires <= now) {
+            debug!(
+                "Dialog {} of {} in room {} expired.",
+                self.name, user_id, room_id
+            );
+            db.prepare_cached(
+                "DELETE FROM dialog_state WHERE dialog = ? AND room_id = ? AND user_id = ?;",
+            )?
+            .execute((&self.name, room_id.as_str(), user_id.as_str()))?;
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&state)?))
+    }
+
+    /// Stores the state of `user_id` in `room_id`, and restarts its timeout.
+    pub fn set(&self, room_id: &RoomId, user_id: &UserId, state: &S) -> Result<()> {
+        let now = self.sync_helper.clock.now();
+        let state = serde_json::to_string(state)?;
+        let expires = self.timeout.map(|timeout| unix_millis(now + timeout));
+        self.sync_helper
+            .session_db()
+            .prepare_cached(
+                "INSERT OR REPLACE INTO dialog_state (dialog, room_id, user_id, state, expires, time) VALUES (?, ?, ?, jsonb(?), ?, ?);",
+            )?
+            .execute((
+                &self.name,
+                room_id.as_str(),
+                user_id.as_str(),
+                &state,
+                expires,
+                unix_millis(now),
+            ))?;
+        Ok(())
+    }
+
+    /// Ends the conversation of `user_id` in `room_id`. Returns `false` if there was none.
+    pub fn clear(&self, room_id: &RoomId, user_id: &UserId) -> Result<bool> {
+        let deleted = self
+            .sync_helper
+            .session_db()
+            .prepare_cached(
+                "DELETE FROM dialog_state WHERE dialog = ? AND room_id = ? AND user_id = ?;",
+            )?
+            .execute((&self.name, room_id.as_str(), user_id.as_str()))?;
+        Ok(deleted != 0)
+    }
+
+    /// Deletes every expired state of this dialog, and returns how many were deleted.
+    ///
+    /// Expired states are never returned by [`Dialog::get`] anyway. Call it occasionally to keep the state database small.
+    pub fn prune_expired(&self) -> Result<usize> {
+        let deleted = self
+            .sync_helper
+            .session_db()
+            .prepare_cached("DELETE FROM dialog_state WHERE dialog = ? AND expires <= ?;")?
+            .execute((&self.name, unix_millis(self.sync_helper.clock.now())))?;
+        Ok(deleted)
+    }
+}
+
+impl<S> Clone for Dialog<S> {
+    fn clone(&self) -> Self {
+        Self {
+            sync_helper: self.sync_helper.clone(),
+            name: self.name.clone(),
+            timeout: self.timeout,
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<S> fmt::Debug for Dialog<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dialog")
+            .field("name", &self.name)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,58 @@
+//! Direct messages.
+
+use eyre::{Result, bail};
+use matrix_sdk::Client;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::ruma::events::room::member::MembershipState;
+use tokio::sync::Mutex;
+use tracing::{info, instrument};
+
+/// Serializes [`open`], so concurrent calls don't create duplicate rooms.
+static OPEN_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Returns the direct chat with `user_id`, creating one if needed.
+///
+/// An existing room is reused if `m.direct` account data lists it for `user_id`, the bot has joined it,
+/// and `user_id` is still joined or invited. Encrypted rooms are preferred.
+///
+/// Otherwise, a new encrypted room is created, `user_id` is invited with the `is_direct` flag, and the room is added to `m.direct`.
+/// The returned room is usable right away, but the other user only sees the messages after accepting the invite.
+#[instrument(skip(client))]
+pub async fn open(client: &Client, user_id: &UserId) -> Result<Room> {
+    if client.user_id() == Some(user_id) {
+        bail!("cannot open a direct chat with the bot itself");
+    }
+    let _guard = OPEN_LOCK.lock().await;
+
+    let mut fallback = None;
+    for room in client.joined_rooms() {
+        if !room
+            .direct_targets()
+            .iter()
+            .any(|target| target.as_str() == user_id.as_str())
+        {
+            continue;
+        }
+        let Some(member) = room.get_member_no_sync(user_id).await? else {
+            continue;
+        };
+        if !matches!(
+            member.membership(),
+            MembershipState::Join | MembershipState::Invite
+        ) {
+            continue;
+        }
+        if room.latest_encryption_state().await?.is_encrypted() {
+            return Ok(room);
+        }
+        fallback.get_or_insert(room);
+    }
+    if let Some(room) = fallback {
+        return Ok(room);
+    }
+
+    let room = client.create_dm(user_id).await?;
+    info!("Created direct chat {} with {}.", room.room_id(), user_id);
+    Ok(room)
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use eyre::Result;
+use matrix_sdk::Client;
+use tokio::select;
+use tracing::{info, instrument, warn};
+
+use crate::{ReadReceipts, SendQueue, SyncHelper};
+
+/// How often [`Drain::run`] checks whether the send queues are empty.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Winds a bot down without losing messages, for rolling deployments where the next process takes over right after this one exits.
+///
+/// [`Drain::run`] stops receiving new events by pausing sync once the ongoing sync response is handled, waits until the registered [`SendQueue`]s are empty,
+/// sends the pending read receipts, then writes the sync token and checkpoints every store, so the next process starts from a clean state.
+/// Waiting stops at the deadline, but the sync token and the stores are always flushed.
+///
+/// Messages still queued at the deadline are not lost, because [`SendQueue`] keeps them in the state database, and the next process sends them.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use matrixbot_ezlogin::{Drain, SendQueue, SyncOptions};
+///
+/// # async fn example(client: matrix_sdk::Client, sync_helper: matrixbot_ezlogin::SyncHelper) -> color_eyre::Result<()> {
+/// let send_queue = SendQueue::new(&sync_helper);
+/// tokio::spawn({
+///     let send_queue = send_queue.clone();
+///     let client = client.clone();
+///     async move { send_queue.run(&client).await }
+/// });
+///
+/// tokio::spawn({
+///     let client = client.clone();
+///     let sync_helper = sync_helper.clone();
+///     async move { sync_helper.sync(&client, SyncOptions::default()).await }
+/// });
+/// tokio::signal::ctrl_c().await?;
+///
+/// let report = Drain::new(Duration::from_secs(30))
+///     .send_queue(send_queue)
+///     .run(&client, &sync_helper)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Drain {
+    deadline: Duration,
+    send_queues: Vec<SendQueue>,
+    read_receipts: Vec<ReadReceipts>,
+}
+
+/// The result of [`Drain::run`].
+#[derive(Clone, Debug)]
+pub struct DrainReport {
+    /// Whether everything was flushed before the deadline.
+    pub completed: bool,
+    /// Number of messages left in the send queues, to be sent by the next process.
+    pub unsent_messages: usize,
+}
+
+impl Drain {
+    /// Creates a [`Drain`] that waits at most `deadline` for the outgoing messages.
+    pub fn new(deadline: Duration) -> Self {
+        Self {
+            deadline,
+            send_queues: Vec::new(),
+            read_receipts: Vec::new(),
+        }
+    }
+
+    /// Waits for `send_queue` to become empty. Its worker started with [`SendQueue::run`] must keep running during the drain.
+    pub fn send_queue(mut self, send_queue: SendQueue) -> Self {
+        self.send_queues.push(send_queue);
+        self
+    }
+
+    /// Sends the batched read receipts of `read_receipts`.
+    pub fn read_receipts(mut self, read_receipts: ReadReceipts) -> Self {
+        self.read_receipts.push(read_receipts);
+        self
+    }
+
+    /// Pauses sync after the ongoing sync response, flushes the outgoing messages and read receipts until the deadline, then flushes the sync token and the stores.
+    ///
+    /// The sync token and the stores are flushed even if flushing the outgoing messages fails. The first error is returned afterwards.
+    ///
+    /// Sync stays paused afterwards. Exit the process, or call [`SyncHelper::resume`] to cancel the drain.
+    #[instrument(skip_all)]
+    pub async fn run(&self, client: &Client, sync_helper: &SyncHelper) -> Result<DrainReport> {
+        info!("Draining.");
+        let mut error = None;
+        let completed = select! {
+            result = async {
+                sync_helper.pause_after_response().await;
+                self.flush_outgoing(sync_helper).await
+            } => match result {
+                Ok(()) => true,
+                Err(err) => {
+                    error = Some(err);
+                    false
+                }
+            },
+            _ = sync_helper.clock.sleep(self.deadline) => false,
+        };
+        // The deadline may have interrupted the wait for the ongoing sync response
+        sync_helper.pause();
+        let mut unsent_messages = 0;
+        for send_queue in &self.send_queues {
+            match send_queue.len() {
+                Ok(len) => unsent_messages += len,
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        if !completed {
+            warn!(
+                "Drain didn't complete, leaving {} queued message(s) for the next start.",
+                unsent_messages
+            );
+        }
+
+        let flushed = Self::flush_stores(client, sync_helper).await;
+        if let Some(err) = error {
+            return Err(err);
+        }
+        flushed?;
+        info!("Drain finished.");
+        Ok(DrainReport {
+            completed,
+            unsent_messages,
+        })
+    }
+
+    async fn flush_stores(client: &Client, sync_helper: &SyncHelper) -> Result<()> {
+        if let Some(mirror_task) = sync_helper.mirror_sync_token(client, true) {
+            _ = mirror_task.await;
+        }
+        sync_helper.flush()?;
+        let blocking_sync_helper = sync_helper.clone();
+        tokio::task::spawn_blocking(move || blocking_sync_helper.maintain_stores()).await??;
+        Ok(())
+    }
+
+    async fn flush_outgoing(&self, sync_helper: &SyncHelper) -> Result<()> {
+        for send_queue in &self.send_queues {
+            while !send_queue.is_empty()? {
+                sync_helper.clock.sleep(POLL_INTERVAL).await;
+            }
+        }
+        for read_receipts in &self.read_receipts {
+            read_receipts.flush().await;
+        }
+        Ok(())
+    }
+}
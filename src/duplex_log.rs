@@ -1,17 +1,61 @@
 use std::borrow::Cow;
-use std::io::{IsTerminal, Write};
-use std::sync::LazyLock;
+#[cfg(feature = "terminal")]
+use std::collections::BTreeMap;
+use std::io::Write;
+#[cfg(feature = "terminal")]
+use std::io::{BufRead, IsTerminal};
+#[cfg(feature = "terminal")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "terminal")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
 
+#[cfg(feature = "terminal")]
+use crossterm::event::{
+    DisableBracketedPaste, EnableBracketedPaste, Event, EventStream, KeyCode, KeyEvent,
+    KeyEventKind, KeyModifiers,
+};
+#[cfg(feature = "terminal")]
 use rustyline_async::{Readline, ReadlineError, ReadlineEvent, SharedWriter};
+#[cfg(feature = "terminal")]
 use scopeguard::guard;
+#[cfg(feature = "terminal")]
 use tokio::select;
+#[cfg(feature = "terminal")]
+use tokio::sync::{Notify, watch};
 use tokio::sync::{mpsc, oneshot};
+#[cfg(feature = "terminal")]
+use tokio_stream::StreamExt;
+
+#[cfg(not(feature = "terminal"))]
+use crate::NotAvailable;
+use crate::Progress;
+use crate::log_file::{LogFileOptions, RotatingFile};
+use crate::mutex::MutexExt;
 
 static DUPLEX_LOG: LazyLock<Option<DuplexLog>> = LazyLock::new(DuplexLog::init_global);
 
+type InterruptHandler = Box<dyn Fn() + Send + Sync>;
+
+static INTERRUPT_HANDLER: Mutex<Option<InterruptHandler>> = Mutex::new(None);
+
+static LOG_FILE: Mutex<Option<RotatingFile>> = Mutex::new(None);
+
+/// Notified after every write to the terminal, so [`DuplexLog::read_raw`] can print the messages while it waits for keys.
+#[cfg(feature = "terminal")]
+static OUTPUT_PENDING: Notify = Notify::const_new();
+
+/// Maximum number of entries kept in the input history file.
+#[cfg(feature = "terminal")]
+const MAX_HISTORY: usize = 1000;
+
 /// Provides a way to handle terminal input while also allowing other parts of the application to log messages.
 ///
-/// Internally, it starts a background task that uses [`rustyline_async`] to handle all the input/output.
+/// Internally, it starts a background task that uses `rustyline_async` to handle all the input/output.
+///
+/// If matrixbot-ezlogin is built without the `terminal` feature, all input functions return an error wrapping [`NotAvailable`](crate::NotAvailable),
+/// and [`DuplexLog::get_writer`] writes to [`stdout`](std::io::stdout).
 ///
 /// # Example
 ///
@@ -42,27 +86,77 @@ static DUPLEX_LOG: LazyLock<Option<DuplexLog>> = LazyLock::new(DuplexLog::init_g
 /// }
 /// ```
 pub struct DuplexLog {
-    request_tx: mpsc::Sender<(
-        Cow<'static, str>,
-        oneshot::Sender<Result<String, std::io::Error>>,
-    )>,
+    request_tx: mpsc::Sender<Request>,
+    #[cfg(feature = "terminal")]
     shared_writer: SharedWriter,
+    #[cfg(feature = "terminal")]
+    stopped: AtomicBool,
+    /// Status lines of active [`Progress`] handles, shown in place of an empty prompt
+    #[cfg(feature = "terminal")]
+    status_tx: watch::Sender<BTreeMap<u64, String>>,
+}
+
+#[cfg_attr(not(feature = "terminal"), allow(dead_code))]
+enum Request {
+    Readline(ReadlineRequest),
+    Select(SelectRequest),
+    EnableHistory(HistoryRequest),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Options for [`DuplexLog::read_pasted`].
+///
+/// Terminals tend to mangle pasted text: embedded newlines submit the input early, and line wrapping inserts extra spaces.
+#[derive(Clone, Debug, Default)]
+pub struct PasteOptions {
+    /// Do not echo the input to the terminal, and never store it in the input history.
+    pub secret: bool,
+    /// Remove all whitespace from the input, including embedded newlines.
+    ///
+    /// Useful for recovery keys, which are displayed in groups of 4 characters.
+    pub strip_whitespace: bool,
+    /// Keep reading lines until a line consisting only of this marker is entered, or until Ctrl-D.
+    ///
+    /// The marker line is not included in the input.
+    /// If [`None`], ENTER finishes the input, but newlines inside a bracketed paste don't.
+    pub end_marker: Option<Cow<'static, str>>,
+}
+
+#[cfg_attr(not(feature = "terminal"), allow(dead_code))]
+struct ReadlineRequest {
+    prompt: Cow<'static, str>,
+    raw: Option<PasteOptions>,
+    record_history: bool,
+    response_tx: oneshot::Sender<Result<String, std::io::Error>>,
+}
+
+#[cfg_attr(not(feature = "terminal"), allow(dead_code))]
+struct SelectRequest {
+    prompt: Cow<'static, str>,
+    items: Vec<String>,
+    response_tx: oneshot::Sender<Result<usize, std::io::Error>>,
+}
+
+#[cfg_attr(not(feature = "terminal"), allow(dead_code))]
+struct HistoryRequest {
+    path: PathBuf,
+    response_tx: oneshot::Sender<Result<(), std::io::Error>>,
 }
 
 impl DuplexLog {
+    #[cfg(not(feature = "terminal"))]
     fn init_global() -> Option<DuplexLog> {
-        if !std::io::stdin().is_terminal() {
-            return None;
-        }
-        let Ok((readline, shared_writer)) = Readline::new(String::new()) else {
-            return None;
-        };
-        let (request_tx, request_rx) = mpsc::channel(1);
-        tokio::spawn(Self::run_background_task(request_rx, readline));
-        Some(DuplexLog {
-            request_tx,
-            shared_writer,
-        })
+        None
+    }
+
+    #[cfg(not(feature = "terminal"))]
+    fn unavailable_error() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Unsupported, NotAvailable)
+    }
+
+    #[cfg(feature = "terminal")]
+    fn unavailable_error() -> std::io::Error {
+        std::io::Error::from(std::io::ErrorKind::UnexpectedEof)
     }
 
     /// Initializes the global instance.
@@ -75,58 +169,391 @@ impl DuplexLog {
 
     /// Asynchronously reads a line of input from the terminal with the given prompt.
     ///
-    /// It returns [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) if [`stdin`](std::io::stdin) is not a TTY.
+    /// It returns [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) if [`stdin`](std::io::stdin) is not a TTY,
+    /// or if Ctrl-D (or Ctrl-Z on Windows) is pressed on an empty line.
+    /// On Windows, the line is read key by key, so the arrow keys don't recall the input history.
     pub async fn readline<S>(prompt: S) -> Result<String, std::io::Error>
     where
         S: Into<Cow<'static, str>>,
     {
+        Self::request(prompt.into(), None, true).await
+    }
+
+    /// Same as [`DuplexLog::readline`], but the answer is never stored in the input history.
+    ///
+    /// Use this for prompts whose answers are sensitive, but are fine to be echoed to the terminal.
+    pub async fn readline_unrecorded<S>(prompt: S) -> Result<String, std::io::Error>
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        Self::request(prompt.into(), None, false).await
+    }
+
+    /// Asynchronously reads a password or other secret from the terminal with the given prompt.
+    ///
+    /// Unlike [`DuplexLog::readline`], the input is not echoed to the terminal, and is never stored in the input history.
+    ///
+    /// It returns [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) if [`stdin`](std::io::stdin) is not a TTY.
+    pub async fn read_password<S>(prompt: S) -> Result<String, std::io::Error>
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        let options = PasteOptions {
+            secret: true,
+            ..Default::default()
+        };
+        Self::request(prompt.into(), Some(options), false).await
+    }
+
+    /// Asynchronously reads pasted text from the terminal with the given prompt.
+    ///
+    /// Unlike [`DuplexLog::readline`], newlines inside a bracketed paste don't finish the input.
+    /// Line endings are normalized to `\n`, and leading and trailing whitespace is trimmed.
+    ///
+    /// It returns [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) if [`stdin`](std::io::stdin) is not a TTY.
+    pub async fn read_pasted<S>(prompt: S, options: PasteOptions) -> Result<String, std::io::Error>
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        let strip_whitespace = options.strip_whitespace;
+        let input = Self::request(prompt.into(), Some(options), false).await?;
+        if strip_whitespace {
+            Ok(input.split_whitespace().collect())
+        } else {
+            Ok(input.trim().to_owned())
+        }
+    }
+
+    /// Asks a yes/no question with the given prompt, and returns whether the answer is yes.
+    ///
+    /// Accepts `y`, `yes`, `n`, and `no`, case-insensitively. Any other answer asks the question again.
+    ///
+    /// It returns [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) if [`stdin`](std::io::stdin) is not a TTY.
+    pub async fn confirm<S>(prompt: S) -> Result<bool, std::io::Error>
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        Self::confirm_with(
+            prompt.into(),
+            &["y", "yes"],
+            &["n", "no"],
+            "Please answer \"y\" or \"n\".",
+        )
+        .await
+    }
+
+    /// Same as [`DuplexLog::confirm`], but with custom answer words and retry message, for localization.
+    pub(crate) async fn confirm_with<W>(
+        prompt: Cow<'static, str>,
+        yes: &[W],
+        no: &[W],
+        invalid_answer: &str,
+    ) -> Result<bool, std::io::Error>
+    where
+        W: AsRef<str>,
+    {
+        let matches = |resp: &str, words: &[W]| {
+            words
+                .iter()
+                .any(|word| word.as_ref().to_lowercase() == resp)
+        };
+        loop {
+            let resp = Self::request(prompt.clone(), None, false).await?;
+            let resp = resp.trim().to_lowercase();
+            if matches(&resp, yes) {
+                return Ok(true);
+            }
+            if matches(&resp, no) {
+                return Ok(false);
+            }
+            _ = writeln!(Self::get_writer(), "{}", invalid_answer);
+        }
+    }
+
+    /// Asks the user to choose one of `items` with the given prompt, and returns the index of the chosen item.
+    ///
+    /// The items are listed with numbers. The user can move between them with the arrow keys, or type a number, then press ENTER.
+    ///
+    /// It returns [`InvalidInput`](std::io::ErrorKind::InvalidInput) if `items` is empty,
+    /// or [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) if [`stdin`](std::io::stdin) is not a TTY.
+    pub async fn select<S, I>(prompt: S, items: I) -> Result<usize, std::io::Error>
+    where
+        S: Into<Cow<'static, str>>,
+        I: IntoIterator,
+        I::Item: ToString,
+    {
+        let items = items
+            .into_iter()
+            .map(|item| item.to_string())
+            .collect::<Vec<_>>();
+        if items.is_empty() {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+        }
         let Some(inst) = DUPLEX_LOG.as_ref() else {
+            return Err(Self::unavailable_error());
+        };
+        let (response_tx, response_rx) = oneshot::channel();
+        let req = Request::Select(SelectRequest {
+            prompt: prompt.into(),
+            items,
+            response_tx,
+        });
+        if inst.request_tx.send(req).await.is_err() {
+            // run_background_task has stopped
             return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        response_rx
+            .await
+            // run_background_task always sends a response before stopping
+            .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)))
+    }
+
+    async fn request(
+        prompt: Cow<'static, str>,
+        raw: Option<PasteOptions>,
+        record_history: bool,
+    ) -> Result<String, std::io::Error> {
+        let Some(inst) = DUPLEX_LOG.as_ref() else {
+            return Err(Self::unavailable_error());
         };
         let (response_tx, response_rx) = oneshot::channel();
-        inst.request_tx
-            .send((prompt.into(), response_tx))
+        let req = Request::Readline(ReadlineRequest {
+            prompt,
+            raw,
+            record_history,
+            response_tx,
+        });
+        if inst.request_tx.send(req).await.is_err() {
+            // run_background_task has stopped
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        response_rx
             .await
-            // run_background_task should run forever
-            .unwrap();
+            // run_background_task always sends a response before stopping
+            .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)))
+    }
+
+    /// Loads the input history from a file, and appends every later answer of [`DuplexLog::readline`] to it.
+    ///
+    /// The history is opt-in. A good place for the file is inside the bot's `data_dir`.
+    /// Only the latest 1000 entries are kept.
+    ///
+    /// Answers of [`DuplexLog::readline_unrecorded`], [`DuplexLog::read_password`], [`DuplexLog::read_pasted`], and [`DuplexLog::confirm`] are never stored.
+    ///
+    /// It returns [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) if [`stdin`](std::io::stdin) is not a TTY.
+    pub async fn enable_history<P>(path: P) -> Result<(), std::io::Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let Some(inst) = DUPLEX_LOG.as_ref() else {
+            return Err(Self::unavailable_error());
+        };
+        let (response_tx, response_rx) = oneshot::channel();
+        let req = Request::EnableHistory(HistoryRequest {
+            path: path.into(),
+            response_tx,
+        });
+        if inst.request_tx.send(req).await.is_err() {
+            // run_background_task has stopped
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
         response_rx
             .await
-            // run_background_task always sends a response
-            .unwrap()
+            // run_background_task always sends a response before stopping
+            .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)))
     }
 
     /// Gets a writer that can be used to print messages to the terminal without interfering with the [`DuplexLog::readline`] prompt.
+    ///
+    /// If [`DuplexLog::set_log_file`] was called, the messages are also written to the log file.
     pub fn get_writer() -> Box<dyn Write> {
+        let terminal = Self::get_terminal_writer();
+        if LOG_FILE.lock_unpoisoned().is_none() {
+            return terminal;
+        }
+        Box::new(TeeWriter { terminal })
+    }
+
+    /// Same as [`DuplexLog::get_writer`], but never writes to the log file.
+    pub(crate) fn get_terminal_writer() -> Box<dyn Write> {
+        #[cfg(feature = "terminal")]
+        if let Some(inst) = DUPLEX_LOG.as_ref()
+            && !inst.stopped.load(Ordering::Acquire)
+        {
+            return Box::new(TerminalWriter {
+                inner: inst.shared_writer.clone(),
+            });
+        }
+        Box::new(std::io::stdout())
+    }
+
+    /// Duplicates everything written through [`DuplexLog::get_writer`] into a rotating log file.
+    ///
+    /// This works even if [`stdin`](std::io::stdin) is not a TTY, giving unattended bots durable logs.
+    /// Terminal color codes are removed from the log file.
+    ///
+    /// Calling it again replaces the previous log file.
+    pub fn set_log_file(options: LogFileOptions) -> Result<(), std::io::Error> {
+        let file = RotatingFile::open(options)?;
+        *LOG_FILE.lock_unpoisoned() = Some(file);
+        Ok(())
+    }
+
+    /// Creates a [`Progress`] handle, which renders a spinner or progress bar in the line below the log messages.
+    ///
+    /// The status line is replaced by the prompt when [`DuplexLog::readline`] is waiting for input, and is restored afterwards.
+    ///
+    /// If [`stdin`](std::io::stdin) is not a TTY, nothing is rendered until [`Progress::finish`] is called.
+    pub fn progress<S>(label: S) -> Progress
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        Progress::new(label.into())
+    }
+
+    #[cfg(feature = "terminal")]
+    pub(crate) fn set_status(id: u64, status: Option<String>) {
+        let Some(inst) = DUPLEX_LOG.as_ref() else {
+            return;
+        };
+        inst.status_tx.send_modify(|statuses| match status {
+            Some(status) => _ = statuses.insert(id, status),
+            None => _ = statuses.remove(&id),
+        });
+    }
+
+    #[cfg(not(feature = "terminal"))]
+    pub(crate) fn set_status(_id: u64, _status: Option<String>) {}
+
+    /// Stops the background task, and restores the terminal to its original mode.
+    ///
+    /// Call this before your application exits, so the terminal isn't left in raw mode.
+    ///
+    /// Afterwards, [`DuplexLog::readline`] returns [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof), and [`DuplexLog::get_writer`] writes to [`stdout`](std::io::stdout) directly.
+    pub async fn shutdown() {
         let Some(inst) = DUPLEX_LOG.as_ref() else {
-            return Box::new(std::io::stdout());
+            return;
         };
-        Box::new(inst.shared_writer.clone())
+        let (done_tx, done_rx) = oneshot::channel();
+        if inst
+            .request_tx
+            .send(Request::Shutdown(done_tx))
+            .await
+            .is_ok()
+        {
+            _ = done_rx.await;
+        }
+    }
+
+    /// Sets a function to be called when the user presses Ctrl-C at a [`DuplexLog::readline`] prompt.
+    ///
+    /// By default, the process exits immediately with [`std::process::exit`], which skips all destructors.
+    /// Setting a handler lets your application decide how to terminate, for example, by notifying a shutdown signal.
+    ///
+    /// Either way, the background task stops and the terminal is restored before the handler is called.
+    pub fn set_interrupt_handler<F>(handler: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        *INTERRUPT_HANDLER.lock_unpoisoned() = Some(Box::new(handler));
+    }
+}
+
+#[cfg(feature = "terminal")]
+impl DuplexLog {
+    fn init_global() -> Option<DuplexLog> {
+        if !std::io::stdin().is_terminal() {
+            return None;
+        }
+        // cmd.exe doesn't interpret virtual terminal sequences by default, and ancient consoles don't support them at all
+        #[cfg(windows)]
+        if !crossterm::ansi_support::supports_ansi() {
+            return None;
+        }
+        let Ok((readline, shared_writer)) = Readline::new(String::new()) else {
+            return None;
+        };
+        let (request_tx, request_rx) = mpsc::channel(1);
+        let (status_tx, status_rx) = watch::channel(BTreeMap::new());
+        tokio::spawn(Self::run_background_task(
+            request_rx,
+            status_rx,
+            readline,
+            shared_writer.clone(),
+        ));
+        Some(DuplexLog {
+            request_tx,
+            shared_writer,
+            stopped: AtomicBool::new(false),
+            status_tx,
+        })
     }
 
     async fn run_background_task(
-        mut request_rx: mpsc::Receiver<(
-            Cow<'static, str>,
-            oneshot::Sender<Result<String, std::io::Error>>,
-        )>,
+        mut request_rx: mpsc::Receiver<Request>,
+        mut status_rx: watch::Receiver<BTreeMap<u64, String>>,
         readline: Readline,
+        mut shared_writer: SharedWriter,
     ) {
         let mut readline = guard(readline, |mut readline| {
             _ = readline.flush();
         });
         let mut pending_response_tx = None;
+        let mut history_path = None;
+        let mut shutdown_done_tx = None;
         let mut running = true;
         while running {
             select! {
                 req = request_rx.recv() => {
-                    let Some((prompt, response_tx)) = req else {
-                        continue;
+                    let req = match req {
+                        Some(Request::Readline(req)) => req,
+                        Some(Request::Select(req)) => {
+                            // Stop polling readline, so it doesn't handle the arrow keys.
+                            let resp = Self::read_select(&mut readline, &mut shared_writer, &req.prompt, &req.items).await;
+                            _ = readline.update_prompt(&Self::status_line(&status_rx));
+                            if matches!(&resp, Err(err) if err.kind() == std::io::ErrorKind::Interrupted) {
+                                running = false;
+                            }
+                            _ = req.response_tx.send(resp);
+                            continue;
+                        }
+                        Some(Request::EnableHistory(req)) => {
+                            let resp = Self::load_history(&mut readline, &req.path);
+                            if resp.is_ok() {
+                                history_path = Some(req.path);
+                            }
+                            _ = req.response_tx.send(resp);
+                            continue;
+                        }
+                        Some(Request::Shutdown(done_tx)) => {
+                            shutdown_done_tx = Some(done_tx);
+                            break;
+                        }
+                        None => continue,
                     };
-                    _ = readline.update_prompt(&prompt);
-                    pending_response_tx = Some(response_tx);
+                    // Readline ignores Ctrl-Z, so on Windows, plain lines are read key by key as well.
+                    let raw = req.raw.clone().or_else(|| cfg!(windows).then(PasteOptions::default));
+                    if let Some(options) = &raw {
+                        // Stop polling readline, so it doesn't echo secrets or submit on pasted newlines.
+                        let resp = Self::read_raw(&mut readline, &mut shared_writer, &req.prompt, options).await;
+                        if let Ok(line) = &resp
+                            && req.record_history
+                        {
+                            Self::record_history(&mut readline, history_path.as_deref(), line);
+                        }
+                        _ = readline.update_prompt(&Self::status_line(&status_rx));
+                        if matches!(&resp, Err(err) if err.kind() == std::io::ErrorKind::Interrupted) {
+                            running = false;
+                        }
+                        _ = req.response_tx.send(resp);
+                        continue;
+                    }
+                    _ = readline.update_prompt(&req.prompt);
+                    pending_response_tx = Some((req.response_tx, req.record_history));
                 }
                 line = readline.readline() => {
                     let resp = match line {
-                        Ok(ReadlineEvent::Line(s)) => Ok(s),
+                        Ok(ReadlineEvent::Line(s)) => Ok(trim_line_ending(s)),
                         Ok(ReadlineEvent::Eof) => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)),
                         Ok(ReadlineEvent::Interrupted) => {
                             running = false;
@@ -138,15 +565,376 @@ impl DuplexLog {
                             unreachable!()
                         }
                     };
-                    let Some(response_tx) = pending_response_tx.take() else {
+                    let Some((response_tx, record_history)) = pending_response_tx.take() else {
                         continue;
                     };
-                    _ = readline.update_prompt("");
+                    if let Ok(line) = &resp
+                        && record_history
+                    {
+                        Self::record_history(&mut readline, history_path.as_deref(), line);
+                    }
+                    _ = readline.update_prompt(&Self::status_line(&status_rx));
                     _ = response_tx.send(resp);
                 }
+                Ok(()) = status_rx.changed() => {
+                    // A pending prompt takes precedence over the status line
+                    if pending_response_tx.is_none() {
+                        _ = readline.update_prompt(&Self::status_line(&status_rx));
+                    }
+                }
             }
         }
+        if let Some((response_tx, _)) = pending_response_tx.take() {
+            _ = response_tx.send(Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+        }
+        if let Some(inst) = DUPLEX_LOG.as_ref() {
+            inst.stopped.store(true, Ordering::Release);
+        }
+        request_rx.close();
         drop(readline);
-        std::process::exit(1);
+        if let Some(done_tx) = shutdown_done_tx {
+            _ = done_tx.send(());
+            return;
+        }
+        match INTERRUPT_HANDLER.lock_unpoisoned().as_ref() {
+            Some(handler) => handler(),
+            None => std::process::exit(1),
+        }
+    }
+
+    fn status_line(status_rx: &watch::Receiver<BTreeMap<u64, String>>) -> String {
+        status_rx
+            .borrow()
+            .values()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+
+    fn load_history(readline: &mut Readline, path: &Path) -> Result<(), std::io::Error> {
+        let mut entries = match std::fs::File::open(path) {
+            Ok(file) => std::io::BufReader::new(file)
+                .lines()
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        entries.retain(|entry| !entry.trim().is_empty());
+        if entries.len() > MAX_HISTORY {
+            entries.drain(..entries.len() - MAX_HISTORY);
+        }
+        // Rewrite the file, so it doesn't grow forever
+        let mut file = Self::open_history(path, true)?;
+        for entry in &entries {
+            writeln!(file, "{}", entry)?;
+        }
+        readline.set_max_history(MAX_HISTORY);
+        for entry in entries {
+            _ = readline.add_history_entry(entry);
+        }
+        Ok(())
+    }
+
+    fn record_history(readline: &mut Readline, history_path: Option<&Path>, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        _ = readline.add_history_entry(line.to_owned());
+        if let Some(path) = history_path {
+            _ = Self::append_history(path, line);
+        }
+    }
+
+    fn append_history(path: &Path, line: &str) -> Result<(), std::io::Error> {
+        let mut file = Self::open_history(path, false)?;
+        writeln!(file, "{}", line)
+    }
+
+    fn open_history(path: &Path, truncate: bool) -> Result<std::fs::File, std::io::Error> {
+        let mut options = std::fs::OpenOptions::new();
+        if truncate {
+            options.write(true).truncate(true);
+        } else {
+            options.append(true);
+        }
+        options.create(true);
+        // The history may contain user names and homeserver addresses
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)
+    }
+
+    async fn read_raw(
+        readline: &mut Readline,
+        shared_writer: &mut SharedWriter,
+        prompt: &str,
+        options: &PasteOptions,
+    ) -> Result<String, std::io::Error> {
+        // Readline has already put the terminal into raw mode, so nothing is echoed unless we print it.
+        _ = readline.update_prompt(prompt);
+        _ = crossterm::execute!(std::io::stdout(), EnableBracketedPaste);
+        let _paste_guard = guard((), |_| {
+            _ = crossterm::execute!(std::io::stdout(), DisableBracketedPaste);
+        });
+        let mut events = EventStream::new();
+        let mut input = String::new();
+        // Start of the line currently being edited
+        let mut line_start = 0;
+        let resp = loop {
+            let event = select! {
+                event = events.next() => event,
+                () = OUTPUT_PENDING.notified() => {
+                    // Print the messages above the prompt, then render the prompt again
+                    _ = readline.flush();
+                    if options.secret {
+                        _ = readline.update_prompt(prompt);
+                    } else {
+                        _ = readline.update_prompt(&format!("{}{}", prompt, &input[line_start..]));
+                    }
+                    continue;
+                }
+            };
+            let Some(event) = event else {
+                break Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+            };
+            match event {
+                Ok(Event::Key(KeyEvent {
+                    code,
+                    modifiers,
+                    kind: KeyEventKind::Press | KeyEventKind::Repeat,
+                    ..
+                })) => match code {
+                    KeyCode::Enter => {
+                        let Some(end_marker) = &options.end_marker else {
+                            break Ok(trim_line_ending(input));
+                        };
+                        if input[line_start..].trim() == end_marker.trim() {
+                            input.truncate(line_start);
+                            break Ok(input);
+                        }
+                        if !options.secret {
+                            _ = writeln!(shared_writer, "{}{}", prompt, &input[line_start..]);
+                        }
+                        input.push('\n');
+                        line_start = input.len();
+                    }
+                    KeyCode::Backspace => {
+                        if input.len() > line_start {
+                            input.pop();
+                        }
+                    }
+                    KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                        break Err(std::io::Error::from(std::io::ErrorKind::Interrupted));
+                    }
+                    KeyCode::Char(_) if is_eof_key(code, modifiers) => {
+                        if input.is_empty() {
+                            break Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+                        } else if options.end_marker.is_some() {
+                            break Ok(input);
+                        }
+                    }
+                    KeyCode::Char(c)
+                        if !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+                    {
+                        input.push(c);
+                    }
+                    _ => (),
+                },
+                Ok(Event::Paste(text)) => {
+                    input.push_str(&text.replace("\r\n", "\n").replace('\r', "\n"));
+                    if let Some(pos) = input.rfind('\n') {
+                        line_start = pos + 1;
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => break Err(err),
+            }
+            if !options.secret {
+                _ = readline.update_prompt(&format!("{}{}", prompt, &input[line_start..]));
+            }
+        };
+        _ = readline.update_prompt("");
+        match &resp {
+            Ok(input) if !options.secret && options.end_marker.is_none() => {
+                _ = writeln!(shared_writer, "{}{}", prompt, input);
+            }
+            _ => _ = writeln!(shared_writer, "{}", prompt),
+        }
+        resp
+    }
+
+    async fn read_select(
+        readline: &mut Readline,
+        shared_writer: &mut SharedWriter,
+        prompt: &str,
+        items: &[String],
+    ) -> Result<usize, std::io::Error> {
+        for (i, item) in items.iter().enumerate() {
+            _ = writeln!(shared_writer, "  {}) {}", i + 1, item);
+        }
+        let render = |selected: usize| {
+            format!(
+                "{}[{}/{}] {} ",
+                prompt,
+                selected + 1,
+                items.len(),
+                items[selected]
+            )
+        };
+        let mut selected = 0;
+        // Digits typed so far, to jump to an item by number
+        let mut number = String::new();
+        _ = readline.update_prompt(&render(selected));
+        let mut events = EventStream::new();
+        let resp = loop {
+            let Some(event) = events.next().await else {
+                break Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+            };
+            match event {
+                Ok(Event::Key(KeyEvent {
+                    code,
+                    modifiers,
+                    kind: KeyEventKind::Press | KeyEventKind::Repeat,
+                    ..
+                })) => {
+                    match code {
+                        KeyCode::Enter => break Ok(selected),
+                        KeyCode::Up | KeyCode::Left => {
+                            selected = selected.checked_sub(1).unwrap_or(items.len() - 1);
+                            number.clear();
+                        }
+                        KeyCode::Down | KeyCode::Right | KeyCode::Tab => {
+                            selected = (selected + 1) % items.len();
+                            number.clear();
+                        }
+                        KeyCode::Home => {
+                            selected = 0;
+                            number.clear();
+                        }
+                        KeyCode::End => {
+                            selected = items.len() - 1;
+                            number.clear();
+                        }
+                        KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                            break Err(std::io::Error::from(std::io::ErrorKind::Interrupted));
+                        }
+                        KeyCode::Char(_) if is_eof_key(code, modifiers) => {
+                            break Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+                        }
+                        KeyCode::Char(c @ '0'..='9') => {
+                            number.push(c);
+                            if !number.parse().is_ok_and(|n| (1..=items.len()).contains(&n)) {
+                                // Start over with this digit
+                                number = c.to_string();
+                            }
+                            if let Ok(n) = number.parse::<usize>()
+                                && (1..=items.len()).contains(&n)
+                            {
+                                selected = n - 1;
+                            }
+                        }
+                        _ => continue,
+                    }
+                    _ = readline.update_prompt(&render(selected));
+                }
+                Ok(_) => (),
+                Err(err) => break Err(err),
+            }
+        };
+        _ = readline.update_prompt("");
+        match &resp {
+            Ok(selected) => _ = writeln!(shared_writer, "{}", render(*selected)),
+            Err(_) => _ = writeln!(shared_writer, "{}", prompt),
+        }
+        resp
+    }
+}
+
+/// Wraps the [`SharedWriter`], so a pending [`DuplexLog::read_raw`] knows when to print the messages.
+#[cfg(feature = "terminal")]
+struct TerminalWriter {
+    inner: SharedWriter,
+}
+
+#[cfg(feature = "terminal")]
+impl Write for TerminalWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        OUTPUT_PENDING.notify_one();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct TeeWriter {
+    terminal: Box<dyn Write>,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.terminal.write_all(buf)?;
+        if let Some(file) = LOG_FILE.lock_unpoisoned().as_mut() {
+            // A full disk shouldn't stop the terminal output
+            _ = file.write_all(&strip_ansi_escapes(buf));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(file) = LOG_FILE.lock_unpoisoned().as_mut() {
+            _ = file.flush();
+        }
+        self.terminal.flush()
+    }
+}
+
+/// Removes CSI escape sequences, such as colors, from terminal output.
+fn strip_ansi_escapes(buf: &[u8]) -> Cow<'_, [u8]> {
+    if !buf.contains(&0x1b) {
+        return Cow::Borrowed(buf);
+    }
+    let mut result = Vec::with_capacity(buf.len());
+    let mut iter = buf.iter().copied().peekable();
+    while let Some(b) = iter.next() {
+        if b == 0x1b && iter.peek() == Some(&b'[') {
+            iter.next();
+            // Skip parameter and intermediate bytes, until the final byte
+            for b in iter.by_ref() {
+                if (0x40..=0x7e).contains(&b) {
+                    break;
+                }
+            }
+            continue;
+        }
+        result.push(b);
+    }
+    Cow::Owned(result)
+}
+
+/// Removes the `\r` left over from a CRLF line ending.
+#[cfg(feature = "terminal")]
+fn trim_line_ending(mut line: String) -> String {
+    if line.ends_with('\r') {
+        line.pop();
+    }
+    line
+}
+
+/// Returns whether the key means the end of input.
+///
+/// It is Ctrl-D on all platforms, plus Ctrl-Z on Windows, following the convention of cmd.exe.
+#[cfg(feature = "terminal")]
+fn is_eof_key(code: KeyCode, modifiers: KeyModifiers) -> bool {
+    if !modifiers.contains(KeyModifiers::CONTROL) {
+        return false;
+    }
+    match code {
+        KeyCode::Char('d') => true,
+        #[cfg(windows)]
+        KeyCode::Char('z') => true,
+        _ => false,
     }
 }
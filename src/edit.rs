@@ -0,0 +1,214 @@
+use std::future::Future;
+
+use eyre::{Result, bail};
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::Room;
+use matrix_sdk::room::edit::EditedContent;
+use matrix_sdk::ruma::events::room::message::{
+    OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
+    RoomMessageEventContentWithoutRelation,
+};
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+};
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedUserId};
+use matrix_sdk::{Client, RoomState};
+use tracing::{Instrument, debug, error, warn};
+
+use crate::RateLimiter;
+
+/// An edit of a room message, delivered by [`on_edit`].
+#[derive(Clone, Debug)]
+pub struct MessageEdit {
+    /// The client that received the edit.
+    pub client: Client,
+    /// The room where the edit was sent.
+    pub room: Room,
+    /// The edit event itself.
+    pub event: OriginalSyncRoomMessageEvent,
+    /// The message being edited.
+    pub original_event_id: OwnedEventId,
+    /// The content of the message before any edit.
+    pub old_content: RoomMessageEventContent,
+    /// The new content of the message.
+    pub new_content: RoomMessageEventContentWithoutRelation,
+}
+
+/// Edits a message previously sent by the bot, replacing its content with `new_content`.
+///
+/// The edit keeps the mentions and the thread of the original message. Fails if `event_id` was not sent by the bot.
+///
+/// It waits for `rate_limiter` before sending, if any.
+pub async fn edit_message(
+    room: &Room,
+    event_id: &EventId,
+    new_content: impl Into<RoomMessageEventContentWithoutRelation>,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<OwnedEventId> {
+    let content = room
+        .make_edit_event(event_id, EditedContent::RoomMessage(new_content.into()))
+        .await?;
+    let event_type = content.event_type().to_string();
+    crate::send::send_raw(
+        room,
+        &event_type,
+        serde_json::to_value(&content)?,
+        None,
+        rate_limiter,
+    )
+    .await
+}
+
+/// Returns the latest content of a room message, taking edits into account.
+///
+/// It relies on the homeserver bundling the latest edit with the original event. Edits from anyone other than the original sender are ignored.
+pub async fn latest_content(
+    room: &Room,
+    event_id: &EventId,
+) -> Result<RoomMessageEventContentWithoutRelation> {
+    let original = room.event(event_id, None).await?;
+    let original_event = original.raw().deserialize()?;
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(original_event),
+    )) = original_event
+    else {
+        bail!("event {} is not a room message", event_id);
+    };
+
+    // The bundled edit may still be encrypted, so fetch it again by its event ID to have it decrypted
+    let replace_event_id = original
+        .raw()
+        .get_field::<serde_json::Value>("unsigned")?
+        .and_then(|unsigned| {
+            unsigned
+                .pointer("/m.relations/m.replace/event_id")
+                .and_then(|event_id| event_id.as_str())
+                .and_then(|event_id| EventId::parse(event_id).ok())
+        });
+    if let Some(replace_event_id) = replace_event_id {
+        match fetch_replacement(room, &replace_event_id, event_id).await {
+            Ok(Some((sender, new_content))) if sender == original_event.sender => {
+                return Ok(new_content);
+            }
+            Ok(_) => warn!(
+                "Ignoring invalid edit {} of event {}.",
+                replace_event_id, event_id
+            ),
+            Err(err) => warn!(
+                "Failed to fetch edit {} of event {}: {}",
+                replace_event_id, event_id, err
+            ),
+        }
+    }
+    Ok(original_event.content.into())
+}
+
+/// Registers an event handler on `client` that calls `handler` whenever someone else edits a message in a joined room.
+///
+/// Edits made by anyone other than the original sender are dropped, as clients don't display them either.
+/// Edits of messages that can't be fetched, for example, because they are too old, are dropped too, because their sender can't be checked.
+///
+/// Each edit is handled in a separate Tokio task, so a slow handler doesn't hold up the sync loop.
+///
+/// # Example
+///
+/// ```no_run
+/// # fn example(client: &matrix_sdk::Client) {
+/// matrixbot_ezlogin::on_edit(client, |edit| async move {
+///     tracing::info!("Edited from {:?} to {:?}.", edit.old_content.body(), edit.new_content.body());
+///     Ok(())
+/// });
+/// # }
+/// ```
+pub fn on_edit<F, Fut>(client: &Client, handler: F) -> EventHandlerHandle
+where
+    F: Fn(MessageEdit) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    client.add_event_handler(
+        move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+            let handler = handler.clone();
+            async move {
+                if Some(&*event.sender) == client.user_id() || room.state() != RoomState::Joined {
+                    return;
+                }
+                let Some(Relation::Replacement(replacement)) = &event.content.relates_to else {
+                    return;
+                };
+                let original_event_id = replacement.event_id.clone();
+                let new_content = replacement.new_content.clone();
+
+                let original = match room.event(&original_event_id, None).await {
+                    Ok(original) => original.raw().deserialize(),
+                    Err(err) => {
+                        debug!(
+                            "Ignoring edit {} of event {}: Failed to fetch the original: {}",
+                            event.event_id, original_event_id, err
+                        );
+                        return;
+                    }
+                };
+                let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                    SyncMessageLikeEvent::Original(original),
+                ))) = original
+                else {
+                    debug!(
+                        "Ignoring edit {} of event {}: The original is not a room message.",
+                        event.event_id, original_event_id
+                    );
+                    return;
+                };
+                if original.sender != event.sender {
+                    warn!(
+                        "Ignoring edit {} of event {}: Sent by {}, not the original sender {}.",
+                        event.event_id, original_event_id, event.sender, original.sender
+                    );
+                    return;
+                }
+
+                let (room_id, event_id) = (room.room_id().to_owned(), event.event_id.clone());
+                let edit = MessageEdit {
+                    client,
+                    room,
+                    event,
+                    original_event_id,
+                    old_content: original.content,
+                    new_content,
+                };
+                tokio::spawn(
+                    async move {
+                        if let Err(err) = handler(edit).await {
+                            error!(
+                                "Edit handler failed in room {}, event {}: {:?}",
+                                room_id, event_id, err
+                            );
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+        },
+    )
+}
+
+/// Fetches an edit of `original_event_id`, and returns its sender and new content.
+async fn fetch_replacement(
+    room: &Room,
+    event_id: &EventId,
+    original_event_id: &EventId,
+) -> Result<Option<(OwnedUserId, RoomMessageEventContentWithoutRelation)>> {
+    let event = room.event(event_id, None).await?.raw().deserialize()?;
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(event),
+    )) = event
+    else {
+        return Ok(None);
+    };
+    let Some(Relation::Replacement(replacement)) = event.content.relates_to else {
+        return Ok(None);
+    };
+    if replacement.event_id != original_event_id {
+        return Ok(None);
+    }
+    Ok(Some((event.sender, replacement.new_content)))
+}
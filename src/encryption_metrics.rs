@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use matrix_sdk::encryption::backups::BackupState;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::events::room::encrypted::{
+    EncryptedEventScheme, OriginalSyncRoomEncryptedEvent,
+};
+use matrix_sdk::{Client, RoomMemberships};
+use tokio_stream::StreamExt;
+use tracing::{Instrument, warn};
+
+use crate::SyncHelper;
+use crate::mutex::MutexExt;
+
+/// At most this many undecryptable Megolm sessions are remembered to detect late decryptions.
+const MAX_PENDING_SESSIONS: usize = 10000;
+
+/// How long [`record_send`] trusts its last check of whether a room has unverified devices.
+const UNVERIFIED_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Whether each room had unverified devices, and when it was checked.
+static UNVERIFIED_CACHE: LazyLock<Mutex<HashMap<OwnedRoomId, (Instant, bool)>>> =
+    LazyLock::new(Default::default);
+
+/// Undecryptable Megolm sessions, with the number of events waiting for each of them, and whether its key was imported from the backup.
+type PendingSessions = Arc<Mutex<HashMap<(OwnedRoomId, String), (u64, bool)>>>;
+
+/// Installs the event handler and background tasks that feed the encryption counters of [`EventMetrics`](crate::EventMetrics).
+///
+/// The tasks are registered with `sync_helper`, so [`SyncHelper::abort_background_tasks`] stops them.
+pub(crate) fn install(client: &Client, sync_helper: &SyncHelper) {
+    let pending = PendingSessions::default();
+
+    // The SDK documentation said nothing about how to catch unable-to-decrypt (UTD) events.
+    // But it seems this handler can capture them.
+    let handler_pending = pending.clone();
+    let watched_rooms = Arc::new(Mutex::new(HashSet::new()));
+    let handler_sync_helper = sync_helper.clone();
+    client.add_event_handler(move |event: OriginalSyncRoomEncryptedEvent, room: Room| {
+        let pending = handler_pending.clone();
+        let watched_rooms = watched_rooms.clone();
+        let sync_helper = handler_sync_helper.clone();
+        async move {
+            crate::metrics::record_utd();
+            let EncryptedEventScheme::MegolmV1AesSha2(content) = &event.content.scheme else {
+                return;
+            };
+            let key = (room.room_id().to_owned(), content.session_id.clone());
+            {
+                let mut pending = pending.lock_unpoisoned();
+                if pending.len() >= MAX_PENDING_SESSIONS && !pending.contains_key(&key) {
+                    return;
+                }
+                pending.entry(key.clone()).or_default().0 += 1;
+            }
+            // The SDK downloads the missing key from the backup by itself (BackupDownloadStrategy::AfterDecryptionFailure).
+            // Only watch what it imports, so this handler never sends requests.
+            if watched_rooms.lock_unpoisoned().insert(key.0.clone()) {
+                let task = tokio::spawn(watch_backup_imports(room, pending).in_current_span());
+                sync_helper.add_background_task(&task);
+            }
+        }
+    });
+
+    let client = client.clone();
+    let clock = sync_helper.clock();
+    let task = tokio::spawn(
+        async move {
+            let Some(room_keys) = client.encryption().room_keys_received_stream().await else {
+                warn!("Encryption is not initialized, late decryptions are not counted.");
+                return;
+            };
+            tokio::pin!(room_keys);
+            while let Some(room_keys) = room_keys.next().await {
+                let Ok(room_keys) = room_keys else {
+                    // Some updates were dropped because this task lagged behind, it only makes the counters less accurate
+                    continue;
+                };
+                let mut late_decryptions = 0;
+                let mut new_keys = 0;
+                {
+                    let mut pending = pending.lock_unpoisoned();
+                    for room_key in &room_keys {
+                        match pending
+                            .remove(&(room_key.room_id.clone(), room_key.session_id.clone()))
+                        {
+                            Some((events, downloaded)) => {
+                                late_decryptions += events;
+                                // Keys imported from the backup are already backed up
+                                new_keys += u64::from(!downloaded);
+                            }
+                            None => new_keys += 1,
+                        }
+                    }
+                }
+                if late_decryptions != 0 {
+                    crate::metrics::record_late_decryptions(late_decryptions);
+                }
+                let backups = client.encryption().backups();
+                if new_keys != 0 && backups.state() == BackupState::Enabled {
+                    crate::metrics::record_backup_pending(new_keys, clock.now());
+                    match backups.wait_for_steady_state().await {
+                        // A steady state means every pending key was uploaded
+                        Ok(()) => crate::metrics::record_backup_upload(),
+                        Err(err) => warn!("Failed to back up room keys: {}", err),
+                    }
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    sync_helper.add_background_task(&task);
+}
+
+/// Counts the keys of undecryptable sessions in `room` that the SDK imports from the server-side backup.
+async fn watch_backup_imports(room: Room, pending: PendingSessions) {
+    let imports = room
+        .client()
+        .encryption()
+        .backups()
+        .room_keys_for_room_stream(room.room_id());
+    tokio::pin!(imports);
+    while let Some(imports) = imports.next().await {
+        let Ok(imports) = imports else {
+            // Some updates were dropped because this task lagged behind, it only makes the counters less accurate
+            continue;
+        };
+        let mut pending = pending.lock_unpoisoned();
+        for session_id in imports.into_values().flatten() {
+            // If the key was already received, it was counted as a new key to back up, the counters are only approximate
+            if let Some((_, downloaded)) = pending.get_mut(&(room.room_id().to_owned(), session_id))
+            {
+                *downloaded = true;
+                crate::metrics::record_backup_download();
+            }
+        }
+    }
+}
+
+/// Counts a message sent to `room` if it is encrypted and some device of a joined member is unverified.
+///
+/// Whether a room has unverified devices is cached for [`UNVERIFIED_CACHE_TTL`], so busy rooms don't read every member's devices on every send.
+pub(crate) async fn record_send(room: &Room) {
+    let cached = UNVERIFIED_CACHE
+        .lock_unpoisoned()
+        .get(room.room_id())
+        .filter(|(time, _)| time.elapsed() < UNVERIFIED_CACHE_TTL)
+        .map(|&(_, unverified)| unverified);
+    let unverified = match cached {
+        Some(unverified) => unverified,
+        None => {
+            let unverified = has_unverified_devices(room).await;
+            UNVERIFIED_CACHE
+                .lock_unpoisoned()
+                .insert(room.room_id().to_owned(), (Instant::now(), unverified));
+            unverified
+        }
+    };
+    if unverified {
+        crate::metrics::record_unverified_device_send();
+    }
+}
+
+/// Returns whether `room` is encrypted and some device of a joined member is unverified, reading only the local stores.
+async fn has_unverified_devices(room: &Room) -> bool {
+    if !room
+        .latest_encryption_state()
+        .await
+        .is_ok_and(|state| state.is_encrypted())
+    {
+        return false;
+    }
+    let Ok(members) = room.members_no_sync(RoomMemberships::JOIN).await else {
+        return false;
+    };
+    let encryption = room.client().encryption();
+    for member in members {
+        let Ok(devices) = encryption.get_user_devices(member.user_id()).await else {
+            continue;
+        };
+        if devices.devices().any(|device| !device.is_verified()) {
+            return true;
+        }
+    }
+    false
+}
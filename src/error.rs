@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::{IdParseError, OwnedRoomId};
+
+/// Error returned by [`SyncHelper::sync_once_with_timeout`](crate::SyncHelper::sync_once_with_timeout).
+///
+/// It classifies the failure, so startup code can decide between retrying, re-running [`setup`](crate::setup), and alerting.
+#[derive(Debug)]
+pub enum SyncError {
+    /// The server could not be reached, or returned a transient error. Retrying later may succeed.
+    Network(matrix_sdk::Error),
+    /// The access token was rejected. The session needs to be set up again.
+    Auth(matrix_sdk::Error),
+    /// No sync response arrived within the specified duration.
+    Timeout(Duration),
+    /// Any other error, for example, failing to write the state database.
+    Other(matrix_sdk::Error),
+}
+
+impl From<matrix_sdk::Error> for SyncError {
+    fn from(err: matrix_sdk::Error) -> Self {
+        match err.client_api_error_kind() {
+            Some(
+                ErrorKind::UnknownToken { .. }
+                | ErrorKind::MissingToken { .. }
+                | ErrorKind::Forbidden { .. }
+                | ErrorKind::UserDeactivated { .. },
+            ) => Self::Auth(err),
+            _ if matches!(err, matrix_sdk::Error::Http(_)) => Self::Network(err),
+            _ => Self::Other(err),
+        }
+    }
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Network(err) => write!(f, "sync failed due to network error: {}", err),
+            Self::Auth(err) => write!(f, "sync failed due to authentication error: {}", err),
+            Self::Timeout(duration) => {
+                write!(f, "sync timed out after {:.1}s", duration.as_secs_f64())
+            }
+            Self::Other(err) => write!(f, "sync failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Network(err) | Self::Auth(err) | Self::Other(err) => Some(err),
+            Self::Timeout(_) => None,
+        }
+    }
+}
+
+/// Error returned by [`rooms::join`](crate::rooms::join).
+#[derive(Debug)]
+pub enum JoinError {
+    /// The argument is neither a valid room ID nor a valid room alias.
+    InvalidRoom(IdParseError),
+    /// The server refused the join, for example, because the alias doesn't exist, the room is invite-only, or the bot is banned.
+    /// Retrying won't help.
+    Unjoinable(matrix_sdk::Error),
+    /// Every attempt failed with a transient error.
+    TooManyRetries(matrix_sdk::Error),
+}
+
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidRoom(err) => write!(f, "invalid room ID or alias: {}", err),
+            Self::Unjoinable(err) => write!(f, "room is unjoinable: {}", err),
+            Self::TooManyRetries(err) => write!(f, "too many retries joining room: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidRoom(err) => Some(err),
+            Self::Unjoinable(err) | Self::TooManyRetries(err) => Some(err),
+        }
+    }
+}
+
+/// Error returned by the input functions of [`DuplexLog`](crate::DuplexLog), if matrixbot-ezlogin is built without the `terminal` feature.
+///
+/// It is wrapped in a [`std::io::Error`] of kind [`Unsupported`](std::io::ErrorKind::Unsupported), and can be detected with [`std::io::Error::get_ref`] and [`downcast_ref`](std::error::Error::downcast_ref).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotAvailable;
+
+impl std::fmt::Display for NotAvailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "terminal input is not available, because matrixbot-ezlogin is built without the `terminal` feature"
+        )
+    }
+}
+
+impl std::error::Error for NotAvailable {}
+
+/// Error returned when the bot's power level doesn't allow an action, checked before sending a request.
+///
+/// Returned, for example, by [`send_message`](crate::send_message) and [`redact`](crate::redact).
+/// It can be detected with [`eyre::Report::downcast_ref`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotAllowed {
+    /// The room where the action was attempted.
+    pub room_id: OwnedRoomId,
+    /// What the bot tried to do, for example, `send m.room.message events`.
+    pub action: String,
+}
+
+impl std::fmt::Display for NotAllowed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not allowed to {} in room {}", self.action, self.room_id)
+    }
+}
+
+impl std::error::Error for NotAllowed {}
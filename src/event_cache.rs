@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use eyre::Result;
+use matrix_sdk::Client;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::EventId;
+use matrix_sdk::ruma::events::room::message::{OriginalSyncRoomMessageEvent, Relation};
+use rusqlite::{OpenFlags, OptionalExtension};
+use serde::de::DeserializeOwned;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, error, info, instrument};
+
+use crate::SyncHelper;
+use crate::mutex::MutexExt;
+use crate::sync::{from_unix_millis, unix_millis};
+
+/// Limits on the size of `matrix-sdk-event-cache.sqlite3`, enforced by [`SyncHelper::prune_event_cache`].
+///
+/// The Matrix SDK can only empty the event cache as a whole, so exceeding a limit empties it, and it fills up again from later syncs.
+/// Events that are no longer cached are fetched from the homeserver when needed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventCacheLimit {
+    /// Empties the event cache once the data in it exceeds this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Empties the event cache once it was last emptied this long ago.
+    pub max_age: Option<Duration>,
+    /// How often [`SyncHelper::spawn_event_cache_pruning`] checks the limits.
+    pub check_interval: Duration,
+}
+
+impl Default for EventCacheLimit {
+    /// Empties the event cache once it exceeds 256 MiB, checking every hour.
+    fn default() -> Self {
+        Self {
+            max_bytes: Some(256 << 20),
+            max_age: None,
+            check_interval: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Returns the event `event_id` of `room`, decrypted if possible, and deserialized as `E`.
+///
+/// With [`LoginOptions::enable_event_cache`](crate::LoginOptions::enable_event_cache), events seen during sync are served from the event cache.
+/// Otherwise, or if the event is not cached, it is fetched from the homeserver.
+pub async fn load_or_fetch_event<E: DeserializeOwned>(
+    room: &Room,
+    event_id: &EventId,
+) -> Result<E> {
+    let event = room.load_or_fetch_event(event_id, None).await?;
+    Ok(serde_json::from_str(event.raw().json().get())?)
+}
+
+/// Returns the message that `event` replies to, or [`None`] if it isn't a reply, or the replied-to event isn't a room message.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+///
+/// # async fn example(room: &matrix_sdk::Room, event: &OriginalSyncRoomMessageEvent) -> color_eyre::Result<()> {
+/// if let Some(replied_to) = matrixbot_ezlogin::replied_to_message(room, event).await? {
+///     tracing::info!("{} replied to: {}", event.sender, replied_to.content.body());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub async fn replied_to_message(
+    room: &Room,
+    event: &OriginalSyncRoomMessageEvent,
+) -> Result<Option<OriginalSyncRoomMessageEvent>> {
+    let in_reply_to = match &event.content.relates_to {
+        Some(Relation::Reply { in_reply_to }) => in_reply_to,
+        // In a thread, a reply fallback points to the latest message, which isn't a real reply
+        Some(Relation::Thread(thread)) if !thread.is_falling_back => {
+            let Some(in_reply_to) = &thread.in_reply_to else {
+                return Ok(None);
+            };
+            in_reply_to
+        }
+        _ => return Ok(None),
+    };
+    let replied_to = room
+        .load_or_fetch_event(&in_reply_to.event_id, None)
+        .await?;
+    if replied_to.raw().get_field::<String>("type")?.as_deref() != Some("m.room.message") {
+        return Ok(None);
+    }
+    // Redacted messages don't deserialize as original events
+    Ok(serde_json::from_str(replied_to.raw().json().get()).ok())
+}
+
+impl SyncHelper {
+    /// Empties the event cache of `client` if it exceeds `limit`. Returns whether it was emptied.
+    ///
+    /// The freed space is reused by later events, but the file doesn't shrink until it is vacuumed.
+    #[instrument(skip_all)]
+    pub async fn prune_event_cache(
+        &self,
+        client: &Client,
+        limit: &EventCacheLimit,
+    ) -> Result<bool> {
+        let now = self.clock.now();
+        let (data_dir, last_prune) = {
+            let inner = self.inner.lock_unpoisoned();
+            let last_prune = inner
+                .session_db
+                .prepare_cached("SELECT time FROM event_cache_prune WHERE id = 0;")?
+                .query_row((), |row| row.get(0))
+                .optional()?
+                .map(from_unix_millis);
+            if last_prune.is_none() {
+                // Start counting the age from the first check
+                inner
+                    .session_db
+                    .prepare_cached("INSERT INTO event_cache_prune (id, time) VALUES (0, ?);")?
+                    .execute((unix_millis(now),))?;
+            }
+            (inner.data_dir.clone(), last_prune.unwrap_or(now))
+        };
+
+        let age = now.duration_since(last_prune).unwrap_or_default();
+        let too_old = limit.max_age.is_some_and(|max_age| age >= max_age);
+        let too_large = match limit.max_bytes {
+            Some(max_bytes) => {
+                let path = data_dir.join("matrix-sdk-event-cache.sqlite3");
+                // The SDK keeps the file open in WAL mode, so a separate read-only connection can still read its size.
+                let conn = rusqlite::Connection::open_with_flags(
+                    path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )?;
+                let used_bytes: u64 = conn.query_row(
+                    "SELECT (page_count - freelist_count) * page_size FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size();",
+                    (),
+                    |row| row.get(0),
+                )?;
+                used_bytes > max_bytes
+            }
+            None => false,
+        };
+        if !too_old && !too_large {
+            return Ok(false);
+        }
+
+        info!("Emptying the event cache, last emptied {:?} ago.", age);
+        client.event_cache().clear_all_rooms().await?;
+        self.session_db()
+            .prepare_cached("UPDATE event_cache_prune SET time = ? WHERE id = 0;")?
+            .execute((unix_millis(now),))?;
+        Ok(true)
+    }
+
+    /// Spawns a Tokio task that calls [`SyncHelper::prune_event_cache`] every [`EventCacheLimit::check_interval`]. Abort the returned task to stop.
+    ///
+    /// Usually you don't need to call it, set [`LoginOptions::event_cache_limit`](crate::LoginOptions::event_cache_limit) instead.
+    pub fn spawn_event_cache_pruning(
+        &self,
+        client: &Client,
+        limit: EventCacheLimit,
+    ) -> JoinHandle<()> {
+        let sync_helper = self.clone();
+        let client = client.clone();
+        tokio::spawn(
+            async move {
+                loop {
+                    if let Err(err) = sync_helper.prune_event_cache(&client, &limit).await {
+                        error!("Failed to prune the event cache: {:?}", err);
+                    }
+                    sync_helper.clock.sleep(limit.check_interval).await;
+                }
+            }
+            .in_current_span(),
+        )
+    }
+}
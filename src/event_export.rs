@@ -0,0 +1,226 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::{Client, RoomState};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::mpsc;
+use tracing::{Instrument, debug, error, info, warn};
+
+use crate::{Clock, SystemClock};
+
+/// How many events can wait for delivery before new ones are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// Forwards selected Matrix events as JSON `POST` requests to an HTTP endpoint, so systems that don't speak Matrix can consume them.
+///
+/// Each request body is `{"room_id": "...", "event": {...}}`, where `event` is the event as received from sync, decrypted if possible.
+/// With [`EventExport::secret`], the body is signed with HMAC-SHA256, and the signature is sent as `X-Matrix-Signature-256: sha256=<hex>`,
+/// in the same format as GitHub webhooks.
+///
+/// Events are delivered one at a time, in the order received. Failed deliveries are retried with increasing delays,
+/// and dropped after [`EventExport::max_attempts`].
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::EventExport;
+///
+/// # fn example(client: &matrix_sdk::Client) {
+/// EventExport::new("https://example.org/matrix-events")
+///     .secret(std::env::var("EXPORT_SECRET").unwrap())
+///     .event_types(["m.room.message", "m.reaction"])
+///     .register(client);
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct EventExport {
+    url: String,
+    secret: Option<String>,
+    rooms: Vec<OwnedRoomId>,
+    event_types: Vec<String>,
+    max_attempts: u32,
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl EventExport {
+    /// Creates an [`EventExport`] that forwards every timeline event of every joined room to `url`.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            secret: None,
+            rooms: Vec::new(),
+            event_types: Vec::new(),
+            max_attempts: 8,
+            timeout: Duration::from_secs(30),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Signs each request with `secret`, so the endpoint can verify that it comes from the bot.
+    pub fn secret(mut self, secret: impl Into<String>) -> Self {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    /// Only forwards events from these rooms.
+    pub fn rooms(mut self, rooms: impl IntoIterator<Item = OwnedRoomId>) -> Self {
+        self.rooms = rooms.into_iter().collect();
+        self
+    }
+
+    /// Only forwards events whose type matches one of the patterns, where `*` matches any sequence of characters, and `?` matches one character,
+    /// for example, `m.room.*`.
+    pub fn event_types<S: Into<String>>(mut self, patterns: impl IntoIterator<Item = S>) -> Self {
+        self.event_types = patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// How many times to try delivering each event before dropping it. Defaults to 8, which spans about 4 minutes.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// How long to wait for the endpoint to respond to each request. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Waits between delivery attempts on `clock`. Defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Registers an event handler on `client` that queues matching events, and spawns a Tokio task that delivers them.
+    ///
+    /// The task stops after the handler is removed and the queue is drained.
+    pub fn register(self, client: &Client) -> EventHandlerHandle {
+        let (tx, rx) = mpsc::channel::<String>(QUEUE_SIZE);
+        info!("Forwarding events to {}.", self.url);
+        let rooms = self.rooms.clone();
+        let event_types = self.event_types.clone();
+        tokio::spawn(self.deliver(rx).in_current_span());
+        client.add_event_handler(move |raw: Raw<AnySyncTimelineEvent>, room: Room| {
+            let tx = tx.clone();
+            let rooms = rooms.clone();
+            let event_types = event_types.clone();
+            async move {
+                if room.state() != RoomState::Joined
+                    || !(rooms.is_empty() || rooms.iter().any(|room_id| room_id == room.room_id()))
+                {
+                    return;
+                }
+                let Ok(Some(event_type)) = raw.get_field::<String>("type") else {
+                    return;
+                };
+                if !(event_types.is_empty()
+                    || event_types
+                        .iter()
+                        .any(|pattern| crate::acl::glob_match(pattern, &event_type)))
+                {
+                    return;
+                }
+                let body = json!({
+                    "room_id": room.room_id(),
+                    "event": raw.json(),
+                })
+                .to_string();
+                if tx.try_send(body).is_err() {
+                    warn!(
+                        "Event export queue is full, dropping a {} event of room {}.",
+                        event_type,
+                        room.room_id()
+                    );
+                }
+            }
+        })
+    }
+
+    async fn deliver(self, mut rx: mpsc::Receiver<String>) {
+        let http = match reqwest::Client::builder().timeout(self.timeout).build() {
+            Ok(http) => http,
+            Err(err) => {
+                error!("Failed to create an HTTP client for event export: {}", err);
+                return;
+            }
+        };
+        while let Some(body) = rx.recv().await {
+            let mut attempt = 1;
+            loop {
+                let result = self.post(&http, &body).await;
+                let permanent = match &result {
+                    Ok(()) => break,
+                    // Client errors won't go away by retrying, except timeouts and rate limits
+                    Err(Some(status)) => {
+                        status.is_client_error() && !matches!(status.as_u16(), 408 | 429)
+                    }
+                    Err(None) => false,
+                };
+                if permanent || attempt >= self.max_attempts {
+                    error!(
+                        "Failed to deliver an event to {} after {} attempt(s), dropping it.",
+                        self.url, attempt
+                    );
+                    break;
+                }
+                let delay = Duration::from_secs(1 << attempt.min(8));
+                debug!("Will retry delivering an event in {:?}.", delay);
+                self.clock.sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+
+    /// Sends one request. On failure, returns the response status, if any.
+    async fn post(
+        &self,
+        http: &reqwest::Client,
+        body: &str,
+    ) -> Result<(), Option<reqwest::StatusCode>> {
+        let mut request = http
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(body.to_owned());
+        if let Some(secret) = &self.secret {
+            request = request.header("X-Matrix-Signature-256", sign(secret, body));
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => {
+                warn!(
+                    "Failed to deliver an event to {}: status {}",
+                    self.url,
+                    response.status()
+                );
+                Err(Some(response.status()))
+            }
+            Err(err) => {
+                warn!("Failed to deliver an event to {}: {}", self.url, err);
+                Err(None)
+            }
+        }
+    }
+}
+
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        // HMAC accepts keys of any length
+        .unwrap();
+    mac.update(body.as_bytes());
+    let mut signature = String::from("sha256=");
+    for byte in mac.finalize().into_bytes() {
+        _ = write!(signature, "{:02x}", byte);
+    }
+    signature
+}
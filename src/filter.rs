@@ -0,0 +1,119 @@
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::ruma::api::client::filter::{
+    Filter, FilterDefinition, LazyLoadOptions, RoomEventFilter,
+};
+
+/// Returns a [`FilterDefinition`] tuned for bots, equivalent to [`BotFilter::new`]`.build()`.
+///
+/// Use it with [`SyncSettings::filter`](matrix_sdk::config::SyncSettings::filter):
+///
+/// ```
+/// use matrix_sdk::config::SyncSettings;
+///
+/// let sync_settings = SyncSettings::default().filter(matrixbot_ezlogin::bot_filter().into());
+/// ```
+pub fn bot_filter() -> FilterDefinition {
+    BotFilter::new().build()
+}
+
+/// Builds a [`FilterDefinition`] tuned for bots.
+///
+/// By default:
+/// * Room members are lazy-loaded, which speeds up the initial sync a lot with accounts in lots of rooms.
+/// * Presence events are dropped.
+/// * Read receipts and typing notifications are dropped.
+/// * The timeline limit is left to the server's default.
+///
+/// # Example
+///
+/// ```
+/// use matrixbot_ezlogin::BotFilter;
+///
+/// let filter = BotFilter::new().typing(true).timeline_limit(Some(50)).build();
+/// ```
+#[derive(Clone, Debug)]
+pub struct BotFilter {
+    lazy_load_members: bool,
+    presence: bool,
+    receipts: bool,
+    typing: bool,
+    timeline_limit: Option<u32>,
+}
+
+impl BotFilter {
+    /// Creates a [`BotFilter`] with the default settings.
+    pub fn new() -> Self {
+        Self {
+            lazy_load_members: true,
+            presence: false,
+            receipts: false,
+            typing: false,
+            timeline_limit: None,
+        }
+    }
+
+    /// Whether to lazy-load room members. Defaults to `true`.
+    ///
+    /// <https://spec.matrix.org/v1.14/client-server-api/#lazy-loading-room-members>
+    pub fn lazy_load_members(mut self, enabled: bool) -> Self {
+        self.lazy_load_members = enabled;
+        self
+    }
+
+    /// Whether to receive presence events. Defaults to `false`.
+    pub fn presence(mut self, enabled: bool) -> Self {
+        self.presence = enabled;
+        self
+    }
+
+    /// Whether to receive read receipts. Defaults to `false`.
+    pub fn receipts(mut self, enabled: bool) -> Self {
+        self.receipts = enabled;
+        self
+    }
+
+    /// Whether to receive typing notifications. Defaults to `false`.
+    pub fn typing(mut self, enabled: bool) -> Self {
+        self.typing = enabled;
+        self
+    }
+
+    /// Maximum number of timeline events per room in each sync response. [`None`] means the server's default.
+    pub fn timeline_limit(mut self, limit: Option<u32>) -> Self {
+        self.timeline_limit = limit;
+        self
+    }
+
+    /// Builds the [`FilterDefinition`].
+    pub fn build(self) -> FilterDefinition {
+        let mut filter = FilterDefinition::default();
+        if self.lazy_load_members {
+            filter.room.state.lazy_load_options = LazyLoadOptions::Enabled {
+                include_redundant_members: false,
+            };
+        }
+        if !self.presence {
+            filter.presence = Filter::ignore_all();
+        }
+        match (self.receipts, self.typing) {
+            (true, true) => (),
+            (false, false) => filter.room.ephemeral = RoomEventFilter::ignore_all(),
+            (true, false) => filter.room.ephemeral.not_types = vec!["m.typing".to_owned()],
+            (false, true) => filter.room.ephemeral.not_types = vec!["m.receipt".to_owned()],
+        }
+        filter.room.timeline.limit = self.timeline_limit.map(UInt::from);
+        filter
+    }
+}
+
+impl Default for BotFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<BotFilter> for FilterDefinition {
+    fn from(filter: BotFilter) -> Self {
+        filter.build()
+    }
+}
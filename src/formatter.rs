@@ -0,0 +1,679 @@
+use std::fmt::Write as _;
+
+use eyre::Result;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::message::{
+    MessageFormat, MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+};
+
+use crate::MessageBuilder;
+
+/// How long a quoted reply context can be before it's cut off.
+const MAX_QUOTE_CHARS: usize = 100;
+
+/// Elements nested deeper than this are flattened into their parent, so rendering and dropping the tree can't overflow the stack.
+/// The Matrix specification recommends the same limit to clients.
+const MAX_DEPTH: usize = 100;
+
+/// The text format of the non-Matrix side of a bridge, used by [`html_to_text`] and the other rendering functions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TextFlavor {
+    /// Plain text, for example, for IRC. Emphasis is dropped, and links show their target in parentheses.
+    #[default]
+    Plain,
+    /// Markdown, for example, for ticketing systems. Special characters in the text are escaped.
+    Markdown,
+}
+
+/// Converts the HTML of a formatted Matrix message into clean text.
+///
+/// Paragraphs, line breaks, lists, quotes, and code blocks keep their layout. Mention pills turn into the mentioned name,
+/// and reply fallbacks (`<mx-reply>`) are removed.
+pub fn html_to_text(html: &str, flavor: TextFlavor) -> String {
+    let nodes = parse_html(html);
+    let mut out = String::new();
+    render_nodes(&nodes, flavor, &mut out);
+    tidy(&out)
+}
+
+/// Renders the content of a message into clean text, without its reply fallback.
+///
+/// The formatted body is used if it's HTML, the plain body otherwise. Media messages render as a short description, for example, `[Image: cat.jpg]`.
+pub fn render_content(content: &RoomMessageEventContent, flavor: TextFlavor) -> String {
+    let (label, formatted) = match &content.msgtype {
+        MessageType::Text(text) => (None, text.formatted.as_ref()),
+        MessageType::Notice(notice) => (None, notice.formatted.as_ref()),
+        MessageType::Emote(emote) => (None, emote.formatted.as_ref()),
+        MessageType::Image(_) => (Some("Image"), None),
+        MessageType::Video(_) => (Some("Video"), None),
+        MessageType::Audio(_) => (Some("Audio"), None),
+        MessageType::File(_) => (Some("File"), None),
+        MessageType::Location(_) => (Some("Location"), None),
+        _ => (None, None),
+    };
+    if let Some(label) = label {
+        return format!("[{}: {}]", label, content.body());
+    }
+    match formatted {
+        Some(formatted) if formatted.format == MessageFormat::Html => {
+            html_to_text(&formatted.body, flavor)
+        }
+        _ => {
+            let body = strip_plain_reply_fallback(content.body());
+            match flavor {
+                TextFlavor::Plain => body.to_owned(),
+                TextFlavor::Markdown => escape_markdown(body),
+            }
+        }
+    }
+}
+
+/// Renders a message into clean text for another system, including who sent it, and what it replies to.
+///
+/// The result looks like `<Alice> Hello`, or `* Alice waves` for emotes. A reply is preceded by a quote of the replied-to message,
+/// for example, `> <Bob> Is anyone here?`.
+///
+/// # Example
+///
+/// Relaying messages to an IRC-like system:
+///
+/// ```no_run
+/// use matrix_sdk::Room;
+/// use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+/// use matrixbot_ezlogin::TextFlavor;
+///
+/// # async fn relay(line: &str) {}
+/// # async fn example(event: OriginalSyncRoomMessageEvent, room: Room) -> color_eyre::Result<()> {
+/// let text = matrixbot_ezlogin::render_message(&room, &event, TextFlavor::Plain).await?;
+/// for line in text.lines() {
+///     relay(line).await;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub async fn render_message(
+    room: &Room,
+    event: &OriginalSyncRoomMessageEvent,
+    flavor: TextFlavor,
+) -> Result<String> {
+    let mut out = String::new();
+    if let Some(replied_to) = crate::replied_to_message(room, event).await? {
+        let mut quote = render_content(&replied_to.content, flavor).replace('\n', " ");
+        if let Some((cut, _)) = quote.char_indices().nth(MAX_QUOTE_CHARS) {
+            quote.truncate(cut);
+            quote.push('…');
+        }
+        _ = writeln!(
+            out,
+            "> {}{}",
+            sender_prefix(room, &replied_to, flavor).await,
+            quote
+        );
+    }
+    out.push_str(&sender_prefix(room, event, flavor).await);
+    out.push_str(&render_content(&event.content, flavor));
+    Ok(out)
+}
+
+/// Converts text from another system into a message, the reverse of [`render_content`].
+///
+/// Markdown is rendered into HTML with the `markdown` feature. Without it, or for [`TextFlavor::Plain`], the text is sent as is.
+pub fn text_to_message(text: &str, flavor: TextFlavor) -> MessageBuilder {
+    match flavor {
+        #[cfg(feature = "markdown")]
+        TextFlavor::Markdown => MessageBuilder::text().push_markdown(text),
+        _ => MessageBuilder::text().push_text(text),
+    }
+}
+
+async fn sender_prefix(
+    room: &Room,
+    event: &OriginalSyncRoomMessageEvent,
+    flavor: TextFlavor,
+) -> String {
+    let name = match room.get_member_no_sync(&event.sender).await {
+        Ok(Some(member)) => member.name().to_owned(),
+        _ => event.sender.to_string(),
+    };
+    let name = match flavor {
+        TextFlavor::Plain => name,
+        TextFlavor::Markdown => escape_markdown(&name),
+    };
+    if matches!(event.content.msgtype, MessageType::Emote(_)) {
+        format!("* {} ", name)
+    } else {
+        format!("<{}> ", name)
+    }
+}
+
+/// Removes the `> ` quoted lines and the blank line that old clients put in front of a reply.
+fn strip_plain_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    let mut rest = body;
+    while let Some(line_end) = rest.find('\n') {
+        let line = &rest[..line_end];
+        rest = &rest[line_end + 1..];
+        if line.is_empty() {
+            return rest;
+        }
+        if !line.starts_with('>') {
+            break;
+        }
+    }
+    // Not a reply fallback after all
+    body
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut list_number = false;
+    for c in text.chars() {
+        push_escaped(&mut result, c, &mut list_number);
+    }
+    result
+}
+
+/// Appends `c` to `out`, escaped if it is special in Markdown.
+///
+/// `#`, `>`, `-`, and `+` are only special at the start of a line, and `.` or `)` after a number there.
+/// `list_number` tracks whether the line so far is such a number.
+fn push_escaped(out: &mut String, c: char, list_number: &mut bool) {
+    let indent = out.trim_end_matches([' ', '\t']);
+    let line_start = indent.is_empty() || indent.ends_with('\n');
+    if needs_escape(c)
+        || (line_start && matches!(c, '#' | '>' | '-' | '+'))
+        || (*list_number && matches!(c, '.' | ')'))
+    {
+        out.push('\\');
+    }
+    *list_number = c.is_ascii_digit() && (line_start || *list_number);
+    out.push(c);
+}
+
+fn needs_escape(c: char) -> bool {
+    matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '<' | '~')
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Element {
+        name: String,
+        attrs: Vec<(String, String)>,
+        children: Vec<Node>,
+    },
+}
+
+impl Node {
+    fn attr(&self, key: &str) -> Option<&str> {
+        match self {
+            Node::Element { attrs, .. } => attrs
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str()),
+            Node::Text(_) => None,
+        }
+    }
+}
+
+fn is_void(name: &str) -> bool {
+    matches!(name, "br" | "hr" | "img")
+}
+
+/// Parses HTML leniently into a tree. Unclosed elements are closed at the end, and stray closing tags are ignored.
+///
+/// Elements beyond [`MAX_DEPTH`] are dropped, but their text is kept.
+fn parse_html(html: &str) -> Vec<Node> {
+    // Each open element, with its name, attributes, and the children so far
+    let mut stack: Vec<(String, Vec<(String, String)>, Vec<Node>)> =
+        vec![(String::new(), Vec::new(), Vec::new())];
+    let mut rest = html;
+    while !rest.is_empty() {
+        let Some(start) = rest.find('<') else {
+            push_text(&mut stack, rest);
+            break;
+        };
+        push_text(&mut stack, &rest[..start]);
+        rest = &rest[start..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(end) = tag_end(rest) else {
+            push_text(&mut stack, rest);
+            break;
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim().to_ascii_lowercase();
+            // The root at index 0 is never closed
+            if let Some(pos) = stack.iter().skip(1).rposition(|(open, _, _)| *open == name) {
+                let pos = pos + 1;
+                while stack.len() > pos {
+                    close_element(&mut stack);
+                }
+            }
+            continue;
+        }
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let (name, attrs) = parse_tag(tag);
+        if name.is_empty() || name.starts_with('!') || name.starts_with('?') {
+            continue;
+        }
+        if stack.len() > MAX_DEPTH {
+            continue;
+        }
+        if self_closing || is_void(&name) {
+            let last = stack.len() - 1;
+            stack[last].2.push(Node::Element {
+                name,
+                attrs,
+                children: Vec::new(),
+            });
+        } else {
+            stack.push((name, attrs, Vec::new()));
+        }
+    }
+    while stack.len() > 1 {
+        close_element(&mut stack);
+    }
+    stack
+        .pop()
+        .map(|(_, _, children)| children)
+        .unwrap_or_default()
+}
+
+fn push_text(stack: &mut [(String, Vec<(String, String)>, Vec<Node>)], text: &str) {
+    if !text.is_empty() {
+        let last = stack.len() - 1;
+        stack[last].2.push(Node::Text(decode_entities(text)));
+    }
+}
+
+fn close_element(stack: &mut Vec<(String, Vec<(String, String)>, Vec<Node>)>) {
+    let Some((name, attrs, children)) = stack.pop() else {
+        return;
+    };
+    if let Some((_, _, parent)) = stack.last_mut() {
+        parent.push(Node::Element {
+            name,
+            attrs,
+            children,
+        });
+    }
+}
+
+/// Finds the `>` that ends the tag at the start of `html`, skipping quoted attribute values.
+fn tag_end(html: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in html.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+fn parse_tag(tag: &str) -> (String, Vec<(String, String)>) {
+    let tag = tag.trim();
+    let name_end = tag
+        .find(|c: char| c.is_ascii_whitespace())
+        .unwrap_or(tag.len());
+    let name = tag[..name_end].to_ascii_lowercase();
+    let mut attrs = Vec::new();
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let key = rest[..key_end].to_ascii_lowercase();
+        rest = rest[key_end..].trim_start();
+        let Some(value) = rest.strip_prefix('=') else {
+            attrs.push((key, String::new()));
+            continue;
+        };
+        let value = value.trim_start();
+        let (value, after) = match value.chars().next() {
+            Some(q @ ('"' | '\'')) => {
+                let value = &value[1..];
+                let end = value.find(q).unwrap_or(value.len());
+                (&value[..end], value.get(end + 1..).unwrap_or_default())
+            }
+            _ => {
+                let end = value
+                    .find(|c: char| c.is_ascii_whitespace())
+                    .unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            }
+        };
+        attrs.push((key, decode_entities(value)));
+        rest = after.trim_start();
+    }
+    (name, attrs)
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                entity => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn render_nodes(nodes: &[Node], flavor: TextFlavor, out: &mut String) {
+    for node in nodes {
+        render_node(node, flavor, out);
+    }
+}
+
+fn render_node(node: &Node, flavor: TextFlavor, out: &mut String) {
+    let markdown = flavor == TextFlavor::Markdown;
+    let (name, children) = match node {
+        Node::Text(text) => {
+            // Outside of `<pre>`, HTML collapses whitespace
+            let mut last_space = out.is_empty() || out.ends_with(['\n', ' ']);
+            let mut list_number = false;
+            for c in text.chars() {
+                if c.is_whitespace() && c != '\u{a0}' {
+                    if !last_space {
+                        out.push(' ');
+                    }
+                    last_space = true;
+                    list_number = false;
+                } else {
+                    last_space = false;
+                    if markdown {
+                        push_escaped(out, c, &mut list_number);
+                    } else {
+                        out.push(c);
+                    }
+                }
+            }
+            return;
+        }
+        Node::Element { name, children, .. } => (name.as_str(), children),
+    };
+    match name {
+        "mx-reply" | "head" | "script" | "style" => (),
+        "br" => out.push('\n'),
+        "hr" => {
+            block_break(out);
+            out.push_str("---\n\n");
+        }
+        "p" | "div" | "table" | "details" => {
+            block_break(out);
+            render_nodes(children, flavor, out);
+            block_break(out);
+        }
+        "tr" | "summary" | "caption" => {
+            line_break(out);
+            render_nodes(children, flavor, out);
+            line_break(out);
+        }
+        "td" | "th" => {
+            render_nodes(children, flavor, out);
+            out.push(' ');
+        }
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            block_break(out);
+            if markdown {
+                let level = name[1..].parse().unwrap_or(1);
+                out.push_str(&"#".repeat(level));
+                out.push(' ');
+            }
+            render_nodes(children, flavor, out);
+            block_break(out);
+        }
+        "b" | "strong" => wrap(children, flavor, markdown.then_some("**"), out),
+        "i" | "em" => wrap(children, flavor, markdown.then_some("*"), out),
+        "del" | "s" | "strike" => wrap(children, flavor, markdown.then_some("~~"), out),
+        "code" => {
+            let code = plain_text(children);
+            if markdown {
+                let fence = if code.contains('`') { "``" } else { "`" };
+                _ = write!(out, "{0}{1}{0}", fence, code);
+            } else {
+                out.push_str(&code);
+            }
+        }
+        "pre" => {
+            block_break(out);
+            let language = children
+                .iter()
+                .find_map(|child| child.attr("class"))
+                .and_then(|class| class.strip_prefix("language-"))
+                .unwrap_or_default();
+            let code = plain_text(children);
+            let code = code.trim_end_matches('\n');
+            if markdown {
+                _ = write!(out, "```{}\n{}\n```", language, code);
+            } else {
+                out.push_str(code);
+            }
+            block_break(out);
+        }
+        "blockquote" => {
+            block_break(out);
+            let mut inner = String::new();
+            render_nodes(children, flavor, &mut inner);
+            for line in tidy(&inner).lines() {
+                _ = writeln!(out, "> {}", line);
+            }
+            block_break(out);
+        }
+        "ul" | "ol" => {
+            if out.ends_with(|c: char| c != '\n') {
+                out.push('\n');
+            }
+            let start = node
+                .attr("start")
+                .and_then(|start| start.parse::<u64>().ok())
+                .unwrap_or(1);
+            let items = children
+                .iter()
+                .filter(|child| matches!(child, Node::Element { name, .. } if name == "li"));
+            for (i, item) in items.enumerate() {
+                let bullet = if name == "ol" {
+                    format!("{}. ", start + i as u64)
+                } else {
+                    "- ".to_owned()
+                };
+                let mut inner = String::new();
+                if let Node::Element { children, .. } = item {
+                    render_nodes(children, flavor, &mut inner);
+                }
+                for (j, line) in tidy(&inner).lines().enumerate() {
+                    if j == 0 {
+                        _ = writeln!(out, "{}{}", bullet, line);
+                    } else {
+                        _ = writeln!(out, "{:width$}{}", "", line, width = bullet.len());
+                    }
+                }
+            }
+            out.push('\n');
+        }
+        "a" => {
+            let href = node.attr("href").unwrap_or_default();
+            let mut text = String::new();
+            render_nodes(children, flavor, &mut text);
+            // Mention pills show the mentioned name
+            if href.starts_with("https://matrix.to/#/") || href.is_empty() || text == href {
+                out.push_str(&text);
+            } else if markdown {
+                _ = write!(out, "[{}]({})", text, href);
+            } else {
+                _ = write!(out, "{} ({})", text, href);
+            }
+        }
+        "img" => {
+            let alt = node
+                .attr("alt")
+                .or_else(|| node.attr("title"))
+                .unwrap_or("image");
+            out.push_str(alt);
+        }
+        _ => render_nodes(children, flavor, out),
+    }
+}
+
+fn wrap(children: &[Node], flavor: TextFlavor, marker: Option<&str>, out: &mut String) {
+    let marker = marker.unwrap_or_default();
+    out.push_str(marker);
+    render_nodes(children, flavor, out);
+    out.push_str(marker);
+}
+
+/// The text content, with whitespace preserved, for code.
+fn plain_text(nodes: &[Node]) -> String {
+    let mut out = String::new();
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Element { name, .. } if name == "br" => out.push('\n'),
+            Node::Element { children, .. } => out.push_str(&plain_text(children)),
+        }
+    }
+    out
+}
+
+fn line_break(out: &mut String) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+fn block_break(out: &mut String) {
+    line_break(out);
+    if !out.is_empty() && !out.ends_with("\n\n") {
+        out.push('\n');
+    }
+}
+
+/// Removes trailing spaces, extra blank lines, and leading and trailing blank lines.
+fn tidy(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank = false;
+    for line in text.trim_matches('\n').lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank = true;
+            continue;
+        }
+        if !out.is_empty() {
+            out.push('\n');
+            if blank {
+                out.push('\n');
+            }
+        }
+        blank = false;
+        out.push_str(line);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lenient_html() {
+        let nodes = parse_html("<p class='a'>x &amp; y<br/><b>bold</p></i>tail");
+        assert_eq!(nodes.len(), 2);
+        let Node::Element {
+            name,
+            attrs,
+            children,
+        } = &nodes[0]
+        else {
+            panic!("expected an element, got {:?}", nodes[0]);
+        };
+        assert_eq!(name, "p");
+        assert_eq!(attrs, &[("class".to_owned(), "a".to_owned())]);
+        assert!(matches!(&children[0], Node::Text(text) if text == "x & y"));
+        assert!(matches!(&children[1], Node::Element { name, .. } if name == "br"));
+        assert!(matches!(&children[2], Node::Element { name, .. } if name == "b"));
+        assert!(matches!(&nodes[1], Node::Text(text) if text == "tail"));
+    }
+
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(decode_entities("&lt;&#65;&#x42;&bogus;&"), "<AB&bogus;&");
+    }
+
+    #[test]
+    fn caps_nesting_depth() {
+        let html = "<b>".repeat(100_000) + "deep";
+        let nodes = parse_html(&html);
+        let mut depth = 0;
+        let mut level = &nodes;
+        while let Some(Node::Element { children, .. }) = level.first() {
+            depth += 1;
+            level = children;
+        }
+        assert_eq!(depth, MAX_DEPTH);
+        assert!(html_to_text(&html, TextFlavor::Plain).ends_with("deep"));
+    }
+
+    #[test]
+    fn renders_plain_text() {
+        assert_eq!(
+            html_to_text(
+                "<mx-reply>quoted</mx-reply><p>Hello <a href=\"https://example.org\">world</a></p><ul><li>one</li><li>two</li></ul>",
+                TextFlavor::Plain
+            ),
+            "Hello world (https://example.org)\n\n- one\n- two"
+        );
+    }
+
+    #[test]
+    fn escapes_markdown_line_starts() {
+        assert_eq!(
+            escape_markdown("# a\n> b\n- c\n  + d\n12. e\n1) f\n3 g.\na # b"),
+            "\\# a\n\\> b\n\\- c\n  \\+ d\n12\\. e\n1\\) f\n3 g.\na # b"
+        );
+        assert_eq!(
+            html_to_text(
+                "<p># not a heading</p><p>1. not a list</p>",
+                TextFlavor::Markdown
+            ),
+            "\\# not a heading\n\n1\\. not a list"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! Reading room history through `/messages`.
+
+use std::time::{Duration, SystemTime};
+
+use async_stream::try_stream;
+use eyre::Result;
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::room::{MessagesOptions, Room};
+use matrix_sdk::ruma::api::Direction;
+use matrix_sdk::ruma::api::client::error::{ErrorKind, RetryAfter};
+use tokio_stream::Stream;
+use tracing::warn;
+
+/// How many events [`paginate`] requests per page.
+const PAGE_SIZE: u32 = 100;
+
+/// One page of events, returned by [`messages`].
+#[derive(Clone, Debug)]
+pub struct Page {
+    /// The events, in the order of the requested direction, decrypted where possible.
+    pub events: Vec<TimelineEvent>,
+    /// The token to pass as `from` for the next page, or [`None`] if there are no more events in this direction.
+    pub next: Option<String>,
+}
+
+/// Fetches up to `limit` events of `room`, starting from the pagination token `from`, in `direction`.
+///
+/// Without `from`, [`Direction::Backward`] starts from the latest event, and [`Direction::Forward`] from the room creation.
+/// Encrypted events are decrypted if the bot has the keys. If the homeserver rate-limits the request, it is retried after the requested delay.
+pub async fn messages(
+    room: &Room,
+    from: Option<&str>,
+    direction: Direction,
+    limit: u32,
+) -> Result<Page> {
+    loop {
+        let mut options = MessagesOptions::new(direction);
+        options.from = from.map(ToOwned::to_owned);
+        options.limit = limit.into();
+        let err = match room.messages(options).await {
+            Ok(messages) => {
+                // An unchanged token also means the end, so a caller looping on `next` won't spin forever
+                let next = messages.end.filter(|end| Some(end.as_str()) != from);
+                return Ok(Page {
+                    events: messages.chunk,
+                    next,
+                });
+            }
+            Err(err) => err,
+        };
+        let Some(ErrorKind::LimitExceeded { retry_after }) = err.client_api_error_kind() else {
+            return Err(err.into());
+        };
+        let delay = match retry_after {
+            Some(RetryAfter::Delay(delay)) => *delay,
+            Some(RetryAfter::DateTime(time)) => {
+                time.duration_since(SystemTime::now()).unwrap_or_default()
+            }
+            None => Duration::from_secs(1),
+        };
+        warn!(
+            "Rate limited while reading room {}, waiting {:?}.",
+            room.room_id(),
+            delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Returns a [`Stream`] of every event of `room`, starting from the pagination token `from`, in `direction`, fetching pages as needed.
+///
+/// # Example
+///
+/// Counting messages per sender, from the latest message back to the room creation:
+///
+/// ```no_run
+/// use std::collections::HashMap;
+///
+/// use matrix_sdk::ruma::api::Direction;
+/// use tokio_stream::StreamExt;
+///
+/// # async fn example(room: &matrix_sdk::Room) -> color_eyre::Result<()> {
+/// let mut counts = HashMap::<String, usize>::new();
+/// let events = matrixbot_ezlogin::history::paginate(room, None, Direction::Backward);
+/// tokio::pin!(events);
+/// while let Some(event) = events.next().await {
+///     if let Ok(Some(sender)) = event?.raw().get_field::<String>("sender") {
+///         *counts.entry(sender).or_default() += 1;
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn paginate(
+    room: &Room,
+    from: Option<String>,
+    direction: Direction,
+) -> impl Stream<Item = Result<TimelineEvent>> + use<> {
+    let room = room.clone();
+    try_stream! {
+        let mut from = from;
+        loop {
+            let page = messages(&room, from.as_deref(), direction, PAGE_SIZE).await?;
+            for event in page.events {
+                yield event;
+            }
+            let Some(next) = page.next else {
+                break;
+            };
+            from = Some(next);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use eyre::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, debug, warn};
+
+/// How long a client may take to send its whole request, so slow clients can't hold connections open.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a client may take to receive the response.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many connections [`serve`] handles at the same time. Further connections wait in the listen backlog.
+#[cfg_attr(
+    not(any(feature = "prometheus", feature = "webhooks")),
+    allow(dead_code)
+)]
+const MAX_CONNECTIONS: usize = 64;
+
+/// A handle to a background HTTP server, returned by [`serve_webhooks`](crate::serve_webhooks) and [`serve_prometheus`](crate::serve_prometheus).
+///
+/// Dropping the handle leaves the server running until the process exits.
+#[cfg_attr(
+    not(any(feature = "prometheus", feature = "webhooks")),
+    allow(dead_code)
+)]
+#[derive(Debug)]
+pub struct ServerHandle {
+    shutdown: Arc<Notify>,
+    pub(crate) task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Stops accepting connections, and waits until the listening socket is closed.
+    ///
+    /// Requests that are already being handled run to completion in the background.
+    #[cfg_attr(
+        not(any(feature = "prometheus", feature = "webhooks")),
+        allow(dead_code)
+    )]
+    pub async fn shutdown(self) {
+        self.shutdown.notify_one();
+        _ = self.task.await;
+    }
+}
+
+/// Accepts connections on `listener` in a background task, and calls `handler` for each of them in a separate task, at most [`MAX_CONNECTIONS`] at a time.
+#[cfg_attr(
+    not(any(feature = "prometheus", feature = "webhooks")),
+    allow(dead_code)
+)]
+pub(crate) fn serve<F, Fut>(listener: TcpListener, handler: F) -> ServerHandle
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let shutdown = Arc::new(Notify::new());
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    let task = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            loop {
+                let permit = tokio::select! {
+                    _ = shutdown.notified() => break,
+                    permit = connections.clone().acquire_owned() => {
+                        // The semaphore is never closed
+                        permit.unwrap()
+                    }
+                };
+                let (stream, peer_addr) = tokio::select! {
+                    _ = shutdown.notified() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            warn!("Failed to accept a connection: {}", err);
+                            continue;
+                        }
+                    },
+                };
+                let connection = handler(stream);
+                tokio::spawn(
+                    async move {
+                        if let Err(err) = connection.await {
+                            debug!("Failed to serve {}: {}", peer_addr, err);
+                        }
+                        drop(permit);
+                    }
+                    .in_current_span(),
+                );
+            }
+        }
+        .in_current_span()
+    });
+    ServerHandle { shutdown, task }
+}
+
+#[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) query: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    #[cfg(feature = "webhooks")]
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads one request with a body of at most `max_size` bytes.
+///
+/// Returns `None` if the client closed the connection, or the request is too large.
+pub(crate) async fn read_request(
+    stream: &mut TcpStream,
+    max_size: usize,
+) -> Result<Option<Request>> {
+    Ok(tokio::time::timeout(READ_TIMEOUT, read_request_inner(stream, max_size)).await??)
+}
+
+async fn read_request_inner(stream: &mut TcpStream, max_size: usize) -> Result<Option<Request>> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0; 4096];
+    let header_end = loop {
+        if let Some(pos) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let len = stream.read(&mut buf).await?;
+        if len == 0 || request.len() > max_size {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..len]);
+    };
+    let header = String::from_utf8_lossy(&request[..header_end]).into_owned();
+    let mut lines = header.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_owned();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect::<Vec<_>>();
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    if content_length > max_size {
+        return Ok(None);
+    }
+    let mut body = request.split_off(header_end);
+    while body.len() < content_length {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buf[..len]);
+    }
+    body.truncate(content_length);
+    Ok(Some(Request {
+        method,
+        path: path.to_owned(),
+        query: query.to_owned(),
+        headers,
+        body,
+    }))
+}
+
+/// Sends a response with `status` such as `404 Not Found`, then closes the connection.
+pub(crate) async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    tokio::time::timeout(WRITE_TIMEOUT, async {
+        stream.write_all(&response).await?;
+        stream.shutdown().await
+    })
+    .await??;
+    Ok(())
+}
+
+/// Compares two secrets in a time that doesn't depend on where they differ, so they can't be guessed byte by byte.
+#[cfg(any(feature = "web-setup", feature = "webhooks"))]
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,57 @@
+use eyre::Result;
+use matrix_sdk::Client;
+use matrix_sdk::ruma::events::ignored_user_list::IgnoredUserListEventContent;
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use tracing::{info, instrument, warn};
+
+/// Returns the users on the account's ignore list (`m.ignored_user_list`), as last synced.
+///
+/// [`Dispatcher`](crate::Dispatcher) drops events from these users. The homeserver also stops sending most of their events.
+pub async fn ignored_users(client: &Client) -> Result<Vec<OwnedUserId>> {
+    let Some(raw) = client
+        .account()
+        .account_data::<IgnoredUserListEventContent>()
+        .await?
+    else {
+        return Ok(Vec::new());
+    };
+    Ok(raw.deserialize()?.ignored_users.into_keys().collect())
+}
+
+/// Adds `user_id` to the account's ignore list.
+///
+/// # Example
+///
+/// Implementing "bot, ignore @spammer:example.org":
+///
+/// ```no_run
+/// # async fn example(client: &matrix_sdk::Client, spammer: &matrix_sdk::ruma::UserId) -> color_eyre::Result<()> {
+/// matrixbot_ezlogin::ignore_user(client, spammer).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[instrument(skip(client))]
+pub async fn ignore_user(client: &Client, user_id: &UserId) -> Result<()> {
+    client.account().ignore_user(user_id).await?;
+    info!("Ignoring {}.", user_id);
+    Ok(())
+}
+
+/// Removes `user_id` from the account's ignore list.
+#[instrument(skip(client))]
+pub async fn unignore_user(client: &Client, user_id: &UserId) -> Result<()> {
+    client.account().unignore_user(user_id).await?;
+    info!("Stopped ignoring {}.", user_id);
+    Ok(())
+}
+
+/// Whether `user_id` is on the account's ignore list. Errors count as not ignored.
+pub(crate) async fn is_ignored(client: &Client, user_id: &UserId) -> bool {
+    match ignored_users(client).await {
+        Ok(ignored_users) => ignored_users.iter().any(|ignored| ignored == user_id),
+        Err(err) => {
+            warn!("Failed to read the ignore list: {}", err);
+            false
+        }
+    }
+}
@@ -1,10 +1,149 @@
+use std::borrow::Cow;
+use std::io::Write;
 use std::path::Path;
 
 use eyre::{Result, bail};
 use matrix_sdk::Client;
-use tracing::instrument;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, instrument};
 
-use crate::{DuplexLog, SetupConfig, setup};
+use crate::auth::setup_with_password_retry;
+use crate::{DuplexLog, PasteOptions, SetupConfig, check_homeserver};
+
+/// Number of password attempts before [`setup_interactive`] gives up.
+const MAX_PASSWORD_ATTEMPTS: usize = 3;
+
+/// Texts shown by [`setup_interactive_localized`].
+///
+/// Non-English deployments and white-labeled tools can translate or re-word them. Unchanged fields keep the English default.
+///
+/// # Example
+///
+/// ```
+/// use matrixbot_ezlogin::InteractiveStrings;
+///
+/// let strings = InteractiveStrings {
+///     homeserver: "Matrix-Homeserver: ".into(),
+///     username: "Benutzername: ".into(),
+///     password: "Passwort: ".into(),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Debug)]
+pub struct InteractiveStrings {
+    /// Prompt for the Matrix homeserver.
+    pub homeserver: Cow<'static, str>,
+    /// Message shown when the homeserver can't be reached. `{homeserver}` and `{error}` are replaced accordingly.
+    pub homeserver_unreachable: Cow<'static, str>,
+    /// Prompt for the user name.
+    pub username: Cow<'static, str>,
+    /// Prompt for the password.
+    pub password: Cow<'static, str>,
+    /// Message shown when the homeserver rejects the user name or password, before asking for the password again.
+    pub wrong_password: Cow<'static, str>,
+    /// Prompt for the recovery key of an existing server-side backup.
+    pub recovery_key: Cow<'static, str>,
+    /// Question before resetting the cryptographic identity to create a new server-side backup.
+    pub confirm_reset_identity: Cow<'static, str>,
+    /// Accepted answers meaning yes, compared case-insensitively.
+    pub yes_answers: Vec<Cow<'static, str>>,
+    /// Accepted answers meaning no, compared case-insensitively.
+    pub no_answers: Vec<Cow<'static, str>>,
+    /// Message shown when the answer is neither yes nor no.
+    pub invalid_answer: Cow<'static, str>,
+    /// Prompt after the recovery key is written to a file. `{path}` is replaced by the file path.
+    pub move_recovery_key: Cow<'static, str>,
+    /// Prompt to type or paste the new recovery key again, to confirm it has been saved.
+    pub confirm_recovery_key: Cow<'static, str>,
+    /// Message shown when the confirmed recovery key doesn't match. `{path}` is replaced by the file path.
+    pub recovery_key_mismatch: Cow<'static, str>,
+}
+
+impl Default for InteractiveStrings {
+    fn default() -> Self {
+        Self {
+            homeserver: "Matrix homeserver: ".into(),
+            homeserver_unreachable: "Cannot reach {homeserver}: {error}".into(),
+            username: "User name: ".into(),
+            password: "Password: ".into(),
+            wrong_password: "Wrong user name or password, please try again.".into(),
+            recovery_key: "Backup recovery key: ".into(),
+            confirm_reset_identity: "Are you ready to reset the cryptographic identity to enable server-side backup (y/n)? ".into(),
+            yes_answers: vec!["y".into(), "yes".into()],
+            no_answers: vec!["n".into(), "no".into()],
+            invalid_answer: "Please answer \"y\" or \"n\".".into(),
+            move_recovery_key: "Please move {path} to a safe place, then press ENTER to continue: "
+                .into(),
+            confirm_recovery_key: "Please type or paste the recovery key again to confirm: ".into(),
+            recovery_key_mismatch: "The recovery key doesn't match, please check {path} and try again."
+                .into(),
+        }
+    }
+}
+
+/// Pre-filled answers for [`setup_interactive_with`].
+///
+/// Fields that are [`None`] are asked through the terminal interactively.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+///
+/// use matrixbot_ezlogin::Partial;
+///
+/// #[tokio::main]
+/// async fn main() -> eyre::Result<()> {
+///     let partial = Partial {
+///         homeserver: Some("matrix.org".to_owned()),
+///         username: Some("example".to_owned()),
+///         ..Default::default()
+///     };
+///     // Only asks for the password
+///     matrixbot_ezlogin::setup_interactive_with(Path::new("./TODO"), "Bot", partial).await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct Partial {
+    /// The Matrix homeserver, see [`SetupConfig::homeserver`].
+    pub homeserver: Option<String>,
+    /// The user name, see [`SetupConfig::username`].
+    pub username: Option<String>,
+    /// The password, see [`SetupConfig::password`].
+    pub password: Option<String>,
+    /// The recovery key of an existing server-side backup.
+    ///
+    /// It is only used if the account already has a server-side backup.
+    pub recovery_key: Option<String>,
+    /// Texts shown when asking for the other fields.
+    pub strings: InteractiveStrings,
+    /// Overwrite and delete `recovery-key.txt` after the operator confirms the recovery key has been stored elsewhere.
+    ///
+    /// On SSDs and copy-on-write filesystems, overwriting may not erase the original data physically.
+    pub shred_recovery_key_file: bool,
+    /// Also show the new recovery key as a QR code in the terminal, so it can be scanned into a password manager.
+    ///
+    /// The QR code is never written into log files.
+    #[cfg(feature = "qrcode")]
+    pub recovery_key_qr_code: bool,
+}
+
+impl std::fmt::Debug for Partial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("Partial");
+        debug
+            .field("homeserver", &self.homeserver)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| ".."))
+            .field("recovery_key", &self.recovery_key.as_ref().map(|_| ".."))
+            .field("strings", &self.strings)
+            .field("shred_recovery_key_file", &self.shred_recovery_key_file);
+        #[cfg(feature = "qrcode")]
+        debug.field("recovery_key_qr_code", &self.recovery_key_qr_code);
+        debug.finish()
+    }
+}
 
 /// Set up a Matrix bot account by asking credentials through the terminal interactively.
 ///
@@ -21,39 +160,193 @@ use crate::{DuplexLog, SetupConfig, setup};
 /// * `device_name`: Any descriptive text to distinguish this session with other sessions logged in at different locations.
 #[instrument(skip_all)]
 pub async fn setup_interactive(data_dir: &Path, device_name: &str) -> Result<Client> {
-    let homeserver = DuplexLog::readline("Matrix homeserver: ").await?;
-    let username = DuplexLog::readline("User name: ").await?;
-    let password = DuplexLog::readline("Password: ").await?;
+    setup_interactive_localized(data_dir, device_name, &InteractiveStrings::default()).await
+}
+
+/// Same as [`setup_interactive`], but shows the texts from [`InteractiveStrings`].
+#[instrument(skip_all)]
+pub async fn setup_interactive_localized(
+    data_dir: &Path,
+    device_name: &str,
+    strings: &InteractiveStrings,
+) -> Result<Client> {
+    let partial = Partial {
+        strings: strings.clone(),
+        ..Default::default()
+    };
+    setup_interactive_with(data_dir, device_name, partial).await
+}
+
+/// Same as [`setup_interactive`], but only asks for the fields not supplied in [`Partial`].
+///
+/// This combines scripted and interactive provisioning, for example, reading the homeserver and user name from command line flags, and only asking for the password.
+#[instrument(skip_all)]
+pub async fn setup_interactive_with(
+    data_dir: &Path,
+    device_name: &str,
+    partial: Partial,
+) -> Result<Client> {
+    let strings = &partial.strings;
+    let homeserver = match partial.homeserver {
+        Some(homeserver) => check_homeserver(&homeserver).await?,
+        None => loop {
+            let homeserver = DuplexLog::readline(strings.homeserver.clone()).await?;
+            match check_homeserver(&homeserver).await {
+                Ok(homeserver) => break homeserver,
+                Err(err) => {
+                    _ = writeln!(
+                        DuplexLog::get_writer(),
+                        "{}",
+                        strings
+                            .homeserver_unreachable
+                            .replace("{homeserver}", &homeserver)
+                            .replace("{error}", &err.to_string())
+                    );
+                }
+            }
+        },
+    };
+    let username = match partial.username {
+        Some(username) => username,
+        None => DuplexLog::readline(strings.username.clone()).await?,
+    };
+    let password = match partial.password {
+        Some(password) => password,
+        None => DuplexLog::read_password(strings.password.clone()).await?,
+    };
+    let recovery_key = partial.recovery_key;
+    let shred_recovery_key_file = partial.shred_recovery_key_file;
+    #[cfg(feature = "qrcode")]
+    let recovery_key_qr_code = partial.recovery_key_qr_code;
     let config = SetupConfig {
         data_dir,
         homeserver: &homeserver,
         username: &username,
         password: &password,
         device_name,
-        ask_recovery_key: async { Ok(DuplexLog::readline("Backup recovery key: ").await?) },
+        ask_recovery_key: async {
+            if let Some(recovery_key) = recovery_key {
+                return Ok(recovery_key);
+            }
+            let options = PasteOptions {
+                secret: true,
+                strip_whitespace: true,
+                ..Default::default()
+            };
+            Ok(DuplexLog::read_pasted(strings.recovery_key.clone(), options).await?)
+        },
         before_create_backup: async {
-            if DuplexLog::readline("Are you ready to reset the cryptographic identity to enable server-side backup (y/n)? ")
-                .await
-                .map(|resp| resp.eq_ignore_ascii_case("y"))
-                .unwrap_or(false)
+            if DuplexLog::confirm_with(
+                strings.confirm_reset_identity.clone(),
+                &strings.yes_answers,
+                &strings.no_answers,
+                &strings.invalid_answer,
+            )
+            .await
+            .unwrap_or(false)
             {
                 Ok(())
             } else {
                 bail!("backup canceled by user")
             }
         },
-        print_recovery_key: async |mut recovery_key: String, _new_backup: bool| {
+        print_recovery_key: async |recovery_key: String, new_backup: bool| {
             let recovery_key_path = data_dir.join("recovery-key.txt");
-            recovery_key.push('\n');
-            tokio::fs::write(&recovery_key_path, &recovery_key).await?;
-            _ = DuplexLog::readline(format!(
-                "Please move {} to a safe place, then press ENTER to continue: ",
-                recovery_key_path.as_os_str().to_string_lossy()
-            ))
+            let recovery_key_path_str = recovery_key_path.as_os_str().to_string_lossy();
+            write_private_file(&recovery_key_path, format!("{}\n", recovery_key).as_bytes())
+                .await?;
+            #[cfg(feature = "qrcode")]
+            if recovery_key_qr_code {
+                print_qr_code(&recovery_key);
+            }
+            _ = DuplexLog::readline_unrecorded(
+                strings
+                    .move_recovery_key
+                    .replace("{path}", &recovery_key_path_str),
+            )
             .await;
+            // If it's not a new backup, the operator just typed it in
+            if new_backup {
+                // Losing the new recovery key means losing access to the backup
+                let expected = recovery_key.split_whitespace().collect::<String>();
+                let options = PasteOptions {
+                    secret: true,
+                    strip_whitespace: true,
+                    ..Default::default()
+                };
+                while DuplexLog::read_pasted(strings.confirm_recovery_key.clone(), options.clone())
+                    .await?
+                    != expected
+                {
+                    _ = writeln!(
+                        DuplexLog::get_writer(),
+                        "{}",
+                        strings
+                            .recovery_key_mismatch
+                            .replace("{path}", &recovery_key_path_str)
+                    );
+                }
+            }
+            if shred_recovery_key_file {
+                shred_file(&recovery_key_path).await?;
+                info!("Deleted {}.", recovery_key_path_str);
+            }
             Ok(())
         },
     };
-    let client = setup(config).await?;
+    let ask_password = move || async move {
+        _ = writeln!(DuplexLog::get_writer(), "{}", strings.wrong_password);
+        Ok::<_, eyre::Report>(DuplexLog::read_password(strings.password.clone()).await?)
+    };
+    let client = setup_with_password_retry(config, ask_password, MAX_PASSWORD_ATTEMPTS).await?;
     Ok(client)
 }
+
+/// Writes a file only readable by the current user, and makes sure it reaches the disk.
+async fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    // The file may already exist with looser permissions
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+        .await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Overwrites a file with zeros before deleting it.
+async fn shred_file(path: &Path) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    let len = file.metadata().await?.len();
+    file.write_all(&vec![0; len as usize]).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::remove_file(path).await?;
+    Ok(())
+}
+
+#[cfg(feature = "qrcode")]
+fn print_qr_code(data: &str) {
+    use qrcode::render::unicode::Dense1x2;
+
+    let code = match qrcode::QrCode::new(data.as_bytes()) {
+        Ok(code) => code,
+        Err(err) => {
+            tracing::warn!("Failed to render the recovery key as a QR code: {}", err);
+            return;
+        }
+    };
+    let image = code
+        .render::<Dense1x2>()
+        // Most terminals have a dark background, so invert the colors to keep dark modules on a light background
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .quiet_zone(true)
+        .build();
+    // Bypass the log file, which shouldn't contain the recovery key
+    _ = writeln!(DuplexLog::get_terminal_writer(), "{}", image);
+}
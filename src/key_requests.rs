@@ -0,0 +1,112 @@
+use eyre::{Result, bail};
+use matrix_sdk::Client;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::events::room_key_request::{Action, ToDeviceRoomKeyRequestEvent};
+use tracing::{Instrument, error, info, warn};
+
+use crate::{MessageBuilder, SyncHelper};
+
+/// How the bot responds to `m.room_key_request` from other devices of the same account.
+///
+/// The SDK answers room key requests while processing the sync response, before any event handler runs.
+/// So, whether keys are shared at all is decided at compile time by the `room-key-forwarding` feature, enabled by default.
+/// [`login_with_options`](crate::login_with_options) fails if the policy doesn't match the feature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoomKeyRequestPolicy {
+    /// Shares keys with the account's own cross-signing-verified devices. Requires the `room-key-forwarding` feature.
+    ShareWithVerified,
+    /// Never shares keys. Requires building without the `room-key-forwarding` feature.
+    DenyAll,
+    /// Posts every request to an admin room, so a human can see who is asking.
+    ///
+    /// With the `room-key-forwarding` feature, keys are still shared with verified devices. Without it, requests are only reported.
+    NotifyAdminRoom(OwnedRoomId),
+}
+
+impl Default for RoomKeyRequestPolicy {
+    fn default() -> Self {
+        if cfg!(feature = "room-key-forwarding") {
+            RoomKeyRequestPolicy::ShareWithVerified
+        } else {
+            RoomKeyRequestPolicy::DenyAll
+        }
+    }
+}
+
+pub(crate) fn install(
+    client: &Client,
+    sync_helper: &SyncHelper,
+    policy: &RoomKeyRequestPolicy,
+) -> Result<()> {
+    match policy {
+        RoomKeyRequestPolicy::ShareWithVerified => {
+            if !cfg!(feature = "room-key-forwarding") {
+                // TODO: If anyone needs programmable detection, transform these ad-hoc errors into named error types.
+                bail!(
+                    "RoomKeyRequestPolicy::ShareWithVerified requires the room-key-forwarding feature"
+                );
+            }
+            info!("Sharing room keys with verified devices of the same account on request.");
+        }
+        RoomKeyRequestPolicy::DenyAll => {
+            if cfg!(feature = "room-key-forwarding") {
+                // TODO: If anyone needs programmable detection, transform these ad-hoc errors into named error types.
+                bail!(
+                    "RoomKeyRequestPolicy::DenyAll requires building without the room-key-forwarding feature"
+                );
+            }
+            info!("Ignoring all room key requests.");
+        }
+        RoomKeyRequestPolicy::NotifyAdminRoom(admin_room_id) => {
+            info!(
+                "Reporting room key requests to room {}{}.",
+                admin_room_id,
+                if cfg!(feature = "room-key-forwarding") {
+                    ", and sharing room keys with verified devices of the same account"
+                } else {
+                    ""
+                }
+            );
+            let admin_room_id = admin_room_id.clone();
+            let sync_helper = sync_helper.clone();
+            client.add_event_handler(move |event: ToDeviceRoomKeyRequestEvent, client: Client| {
+                let admin_room_id = admin_room_id.clone();
+                let sync_helper = sync_helper.clone();
+                async move {
+                    if Some(&*event.sender) != client.user_id()
+                        || !matches!(event.content.action, Action::Request)
+                    {
+                        return;
+                    }
+                    let Some(room) = client.get_room(&admin_room_id) else {
+                        warn!("Not a member of admin room {}.", admin_room_id);
+                        return;
+                    };
+                    let mut message = MessageBuilder::notice().push_text(&format!(
+                        "Device {} requested a room key",
+                        event.content.requesting_device_id
+                    ));
+                    if let Some(body) = &event.content.body {
+                        message = message.push_text(&format!(
+                            " for room {}, session {}",
+                            body.room_id, body.session_id
+                        ));
+                    }
+                    message = message.push_text(".");
+                    let rate_limiter = sync_helper.rate_limiter();
+                    if let Err(err) =
+                        crate::send::send_content(&room, message.build(), rate_limiter.as_ref())
+                            .await
+                    {
+                        error!(
+                            "Failed to report a room key request to room {}: {}",
+                            admin_room_id, err
+                        );
+                    }
+                }
+                .in_current_span()
+            });
+        }
+    }
+    Ok(())
+}
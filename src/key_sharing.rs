@@ -0,0 +1,34 @@
+use matrix_sdk::crypto::CollectStrategy;
+
+/// Which devices receive the room keys of messages the bot sends in encrypted rooms.
+///
+/// Compliance-sensitive bots may need [`RoomKeySharing::OnlyVerifiedDevices`], so nothing is ever encrypted to an unverified device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoomKeySharing {
+    /// Shares keys with every device of every room member, verified or not. This is the SDK's default.
+    #[default]
+    AllDevices,
+    /// Shares keys with every device, but fails to send if a verified user has unverified devices, or if a verified user's identity has changed.
+    ///
+    /// Sending stays blocked until the problem is resolved, for example by verifying the new device.
+    ErrorOnVerifiedUserProblem,
+    /// Shares keys only with cross-signing-verified devices, and withholds them from every other device.
+    ///
+    /// Unverified devices receive an `m.room_key.withheld` notice instead, so their users see why they can't decrypt.
+    OnlyVerifiedDevices,
+    /// Shares keys only with devices signed by their owner's cross-signing identity, and fails to send if a verified user's identity has changed.
+    IdentityBased,
+}
+
+impl From<RoomKeySharing> for CollectStrategy {
+    fn from(value: RoomKeySharing) -> Self {
+        match value {
+            RoomKeySharing::AllDevices => CollectStrategy::AllDevices,
+            RoomKeySharing::ErrorOnVerifiedUserProblem => {
+                CollectStrategy::ErrorOnVerifiedUserProblem
+            }
+            RoomKeySharing::OnlyVerifiedDevices => CollectStrategy::OnlyTrustedDevices,
+            RoomKeySharing::IdentityBased => CollectStrategy::IdentityBasedStrategy,
+        }
+    }
+}
@@ -20,20 +20,188 @@
 //!
 //! This library provides the functions [`setup`] (or [`setup_interactive`]) and [`login`] to simplify these two steps.
 //!
-//! Additionally, [`DuplexLog`] helps handling duplex terminal input / output. [`SyncHelper`] helps remembering sync tokens between process restarts.
+//! Additionally, [`DuplexLog`] helps handling duplex terminal input / output. [`SyncHelper`] helps remembering sync tokens between process restarts. [`run_until_shutdown`] drives the sync loop until the process is asked to stop.
 //!
 //! The `examples` folder contains a simple echo-bot for you to experience the feature of matrixbot-ezlogin, and serves as a good starting point to develop a new Matrix bot.
+//! The mod-bot example is the starting point for a moderation bot, taking commands from an admin room.
 
+pub mod account_data;
+mod ack;
+mod acl;
+mod anti_flood;
 mod auth;
+mod backfill;
+mod canary;
+mod catch_up;
+mod clock;
+mod commands;
 mod db;
+mod dedup;
+mod device_name;
+mod diagnose;
+mod dialog;
+pub mod dm;
+mod drain;
 mod duplex_log;
+mod edit;
+mod encryption_metrics;
+mod error;
+mod event_cache;
+#[cfg(feature = "event-export")]
+mod event_export;
+mod filter;
+mod formatter;
+pub mod history;
+#[cfg(any(feature = "prometheus", feature = "web-setup", feature = "webhooks"))]
+mod http;
+mod ignore_list;
 mod interactive;
+mod key_requests;
+mod key_sharing;
+mod log_file;
+#[cfg(feature = "json-log")]
+mod log_format;
+mod maintenance;
+mod media;
+mod message_builder;
+mod metrics;
+mod middleware;
+mod mutex;
+mod pause;
+mod permissions;
+mod poll;
+pub mod presence;
+mod progress;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod push_rules;
+mod rate_limit;
+mod reaction;
+mod read_receipt;
+mod redact;
+mod reminder;
+#[cfg(feature = "repl")]
+mod repl;
+mod reply;
+mod room_config;
+mod room_position;
+pub mod rooms;
+mod router;
+mod runner;
+mod scheduler;
+mod self_test;
+mod send;
+mod send_queue;
+mod server_notice;
+mod setup_batch;
+pub mod sharding;
+mod spaces;
 mod sync;
+#[cfg(all(feature = "systemd", unix))]
+mod systemd;
+#[cfg(feature = "test-server")]
+mod test_server;
+mod token_mirror;
+pub mod trace_events;
+mod txn_id;
+mod typing;
+mod verification;
+mod watchdog;
+#[cfg(feature = "web-setup")]
+mod web_setup;
+#[cfg(feature = "webhooks")]
+mod webhook;
 
-pub use auth::{SetupConfig, login, logout, setup};
-pub use duplex_log::DuplexLog;
-pub use interactive::setup_interactive;
-pub use sync::SyncHelper;
+pub use ack::AckHandle;
+pub use acl::{Acl, AclList};
+pub use anti_flood::AntiFlood;
+pub use auth::{
+    LoginOptions, ReadOnlySession, SessionStatus, SetupConfig, check_homeserver, login,
+    login_read_only, login_with_options, logout, setup, status,
+};
+pub use backfill::BackfillUntil;
+pub use canary::{Canary, CanaryStatus};
+pub use catch_up::{CatchUpPolicy, CatchUpReport};
+pub use clock::{Clock, ManualClock, Sleep, SystemClock};
+pub use commands::{Command, CommandArg, CommandArgs, CommandContext, Commands, Rest};
+pub use device_name::DeviceName;
+pub use diagnose::{DiagnosisReport, Finding, Severity, diagnose};
+pub use dialog::Dialog;
+pub use drain::{Drain, DrainReport};
+pub use duplex_log::{DuplexLog, PasteOptions};
+pub use edit::{MessageEdit, edit_message, latest_content, on_edit};
+pub use error::{JoinError, NotAllowed, NotAvailable, SyncError};
+pub use event_cache::{EventCacheLimit, load_or_fetch_event, replied_to_message};
+#[cfg(feature = "event-export")]
+pub use event_export::EventExport;
+pub use filter::{BotFilter, bot_filter};
+pub use formatter::{TextFlavor, html_to_text, render_content, render_message, text_to_message};
+#[cfg(any(feature = "prometheus", feature = "webhooks"))]
+pub use http::ServerHandle;
+pub use ignore_list::{ignore_user, ignored_users, unignore_user};
+pub use interactive::{
+    InteractiveStrings, Partial, setup_interactive, setup_interactive_localized,
+    setup_interactive_with,
+};
+pub use key_requests::RoomKeyRequestPolicy;
+pub use key_sharing::RoomKeySharing;
+pub use log_file::LogFileOptions;
+#[cfg(feature = "json-log")]
+pub use log_format::{LogFormat, log_layer};
+pub use maintenance::StoreMaintenance;
+pub use media::{
+    DecryptedMedia, FetchOptions, FileSource, MediaOptions, ProgressCallback, fetch_media,
+    send_file,
+};
+pub use message_builder::MessageBuilder;
+pub use metrics::{
+    EventMetrics, Histogram, SyncMetrics, event_metrics, record_handler_call, record_send,
+};
+pub use middleware::{
+    AclCheck, CatchPanics, Deduplicate, DispatchEvent, Dispatcher, EventContext, IgnoreEdits,
+    IgnoreOwnEvents, JoinedRoomsOnly, LogEvents, Middleware, Next, RecordMetrics,
+};
+pub use permissions::RoomPermissions;
+pub use poll::{
+    Poll, PollAnswer, PollEvent, PollResults, end_poll, on_poll, respond_to_poll, start_poll,
+    tally_poll,
+};
+pub use progress::Progress;
+#[cfg(feature = "prometheus")]
+pub use prometheus::serve_prometheus;
+pub use push_rules::{push_notifications_enabled, set_push_notifications};
+pub use rate_limit::{RateLimit, RateLimiter};
+pub use reaction::{ReactionChange, on_reaction, react, reactions};
+pub use read_receipt::{ReadReceiptPolicy, ReadReceipts};
+pub use redact::{SkipRedacted, redact};
+pub use reminder::Reminder;
+#[cfg(feature = "repl")]
+pub use repl::debug_repl;
+pub use reply::{into_notice, reply_in_thread, reply_to, reply_without_fallback};
+pub use room_config::RoomConfig;
+pub use router::{Route, RouteContext, Router};
+pub use runner::{run_supervised, run_until_shutdown};
+pub use scheduler::{Schedule, ScheduledTask, Scheduler};
+pub use self_test::self_test;
+pub use send::send_message;
+pub use send_queue::SendQueue;
+pub use server_notice::{
+    ServerEvent, forward_server_events, is_server_notice_room, on_server_event,
+};
+pub use setup_batch::{SetupBatchReport, SetupProfile, setup_batch};
+pub use spaces::{
+    SpaceChildChange, SpaceRoom, join_space_children, on_space_child, space_hierarchy,
+};
+pub use sync::{SyncHelper, SyncOptions};
+#[cfg(feature = "test-server")]
+pub use test_server::{EzloginTestServer, TestAccount};
+pub use typing::{with_typing, with_typing_clock};
+pub use verification::VerificationPolicy;
+pub use watchdog::SyncWatchdog;
+#[cfg(feature = "web-setup")]
+pub use web_setup::setup_web;
+#[cfg(feature = "webhooks")]
+pub use webhook::{Webhook, WebhookFormat, serve_webhooks};
 
 /// Re-export Matrix SDK, which helps dealing with version conflicts.
 pub use matrix_sdk;
@@ -0,0 +1,116 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Options for [`DuplexLog::set_log_file`](crate::DuplexLog::set_log_file).
+///
+/// The current log file is rotated to `<path>.1` when it becomes too large or too old. Older files are shifted to `<path>.2`, `<path>.3`, and so on.
+#[derive(Clone, Debug)]
+pub struct LogFileOptions {
+    /// Path of the current log file.
+    pub path: PathBuf,
+    /// Rotate the log file before it grows larger than this size, in bytes.
+    pub max_size: Option<u64>,
+    /// Rotate the log file after it has been written to for this long.
+    pub max_age: Option<Duration>,
+    /// Number of rotated log files to keep, not counting the current one.
+    pub keep: usize,
+}
+
+impl LogFileOptions {
+    /// Creates a [`LogFileOptions`] that rotates every 16 MiB and keeps 4 rotated files.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_size: Some(16 << 20),
+            max_age: None,
+            keep: 4,
+        }
+    }
+}
+
+pub(crate) struct RotatingFile {
+    options: LogFileOptions,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(options: LogFileOptions) -> Result<Self, std::io::Error> {
+        let file = Self::open_file(&options)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            options,
+            file,
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn open_file(options: &LogFileOptions) -> Result<File, std::io::Error> {
+        if let Some(parent) = options.path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&options.path)
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.options.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        if let Some(max_size) = self.options.max_size
+            && self.size + incoming as u64 > max_size
+        {
+            return true;
+        }
+        self.options
+            .max_age
+            .is_some_and(|max_age| self.opened_at.elapsed() >= max_age)
+    }
+
+    fn rotate(&mut self) -> Result<(), std::io::Error> {
+        self.file.flush()?;
+        if self.options.keep == 0 {
+            std::fs::remove_file(&self.options.path)?;
+        } else {
+            // Missing files are fine, there may not be enough log files yet
+            _ = std::fs::remove_file(self.rotated_path(self.options.keep));
+            for n in (1..self.options.keep).rev() {
+                _ = std::fs::rename(self.rotated_path(n), self.rotated_path(n + 1));
+            }
+            std::fs::rename(&self.options.path, self.rotated_path(1))?;
+        }
+        self.file = Self::open_file(&self.options)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
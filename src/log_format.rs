@@ -0,0 +1,100 @@
+use std::io::IsTerminal;
+
+use tracing::Subscriber;
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::DuplexLog;
+
+/// Output format of [`log_layer`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines, written through [`DuplexLog::get_writer`].
+    Terminal,
+    /// One JSON object per line, written through [`DuplexLog::get_writer`].
+    ///
+    /// Span fields are included in the `span` object, and event fields are flattened into the top level.
+    JsonLines,
+    /// Native systemd-journald protocol, keeping every field as a separate journal field.
+    #[cfg(all(feature = "journald", unix))]
+    Journald {
+        /// Value of `SYSLOG_IDENTIFIER`. Defaults to the executable name.
+        syslog_identifier: Option<String>,
+        /// Prefix of journal field names mapped from tracing fields.
+        ///
+        /// For example, with prefix `"BOT"`, the tracing field `room_id` becomes the journal field `BOT_ROOM_ID`.
+        /// Without a prefix, it becomes `ROOM_ID`.
+        field_prefix: Option<String>,
+    },
+}
+
+impl LogFormat {
+    /// Chooses a suitable format for the current environment.
+    ///
+    /// * [`LogFormat::Terminal`] if both [`stdin`](std::io::stdin) and [`stdout`](std::io::stdout) are TTYs.
+    /// * [`LogFormat::Journald`] if [`stdout`](std::io::stdout) is connected to systemd-journald, and the `journald` feature is enabled.
+    /// * [`LogFormat::JsonLines`] otherwise.
+    pub fn detect() -> Self {
+        if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+            return LogFormat::Terminal;
+        }
+        #[cfg(all(feature = "journald", unix))]
+        if std::env::var_os("JOURNAL_STREAM").is_some() {
+            return LogFormat::Journald {
+                syslog_identifier: None,
+                field_prefix: None,
+            };
+        }
+        LogFormat::JsonLines
+    }
+}
+
+/// Creates a [`tracing_subscriber`] layer that writes logs in the specified [`LogFormat`].
+///
+/// Service deployments get machine-parseable logs, while interactive sessions keep the terminal-oriented output.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::{DuplexLog, LogFormat};
+/// use tracing_subscriber::{EnvFilter, prelude::*};
+///
+/// #[tokio::main]
+/// async fn main() -> std::io::Result<()> {
+///     DuplexLog::init();
+///     tracing_subscriber::registry()
+///         .with(EnvFilter::new("warn,matrixbot_ezlogin=debug"))
+///         .with(matrixbot_ezlogin::log_layer(LogFormat::detect())?)
+///         .init();
+///
+///     todo!()
+/// }
+/// ```
+pub fn log_layer<S>(format: LogFormat) -> Result<Box<dyn Layer<S> + Send + Sync>, std::io::Error>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    Ok(match format {
+        LogFormat::Terminal => tracing_subscriber::fmt::layer()
+            .with_writer(DuplexLog::get_writer)
+            .boxed(),
+        LogFormat::JsonLines => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(DuplexLog::get_writer)
+            .boxed(),
+        #[cfg(all(feature = "journald", unix))]
+        LogFormat::Journald {
+            syslog_identifier,
+            field_prefix,
+        } => {
+            let mut layer = tracing_journald::layer()?.with_field_prefix(field_prefix);
+            if let Some(syslog_identifier) = syslog_identifier {
+                layer = layer.with_syslog_identifier(syslog_identifier);
+            }
+            layer.boxed()
+        }
+    })
+}
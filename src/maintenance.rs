@@ -0,0 +1,116 @@
+use std::path::Path;
+use std::time::Duration;
+
+use eyre::Result;
+use rusqlite::OpenFlags;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, debug, error, info, instrument};
+
+use crate::SyncHelper;
+use crate::mutex::MutexExt;
+
+/// The SQLite files of the Matrix SDK in the data directory. The event cache file only exists if the event cache was ever enabled.
+const SDK_DATABASES: &[&str] = &[
+    "matrix-sdk-state.sqlite3",
+    "matrix-sdk-crypto.sqlite3",
+    "matrix-sdk-event-cache.sqlite3",
+];
+
+/// Configuration for [`SyncHelper::spawn_maintenance`].
+///
+/// Long-running bots accumulate large write-ahead logs and stale query planner statistics, because SQLite only cleans up
+/// when a connection closes. The maintenance task does it periodically instead, while the bot is idle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoreMaintenance {
+    /// How often to run maintenance.
+    pub interval: Duration,
+    /// How long no timeline events must arrive before maintenance starts. It waits for such an idle period after each [`interval`](StoreMaintenance::interval).
+    pub idle_for: Duration,
+}
+
+impl Default for StoreMaintenance {
+    /// Runs maintenance every 6 hours, once no timeline events arrived for 1 minute.
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(6 * 3600),
+            idle_for: Duration::from_secs(60),
+        }
+    }
+}
+
+impl SyncHelper {
+    /// Checkpoints the write-ahead log, updates the query planner statistics, and frees unused pages, in the state database and the SQLite files of the Matrix SDK.
+    ///
+    /// Unused pages are only freed from files created with `auto_vacuum = INCREMENTAL`, otherwise they are reused by later writes.
+    ///
+    /// It blocks on disk I/O, so call it from [`tokio::task::spawn_blocking`] in async code.
+    #[instrument(skip_all)]
+    pub fn maintain_stores(&self) -> Result<()> {
+        let data_dir = {
+            let inner = self.inner.lock_unpoisoned();
+            maintain(&inner.session_db)?;
+            inner.data_dir.clone()
+        };
+        for name in SDK_DATABASES {
+            maintain_file(&data_dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// Spawns a Tokio task that calls [`SyncHelper::maintain_stores`] according to `maintenance`. Abort the returned task to stop.
+    ///
+    /// Usually you don't need to call it, set [`LoginOptions::maintenance`](crate::LoginOptions::maintenance) instead.
+    pub fn spawn_maintenance(&self, maintenance: StoreMaintenance) -> JoinHandle<()> {
+        let sync_helper = self.clone();
+        tokio::spawn(
+            async move {
+                loop {
+                    sync_helper.clock.sleep(maintenance.interval).await;
+                    // Wait until a whole idle period passes without timeline events
+                    loop {
+                        let events = sync_helper.metrics().events;
+                        sync_helper.clock.sleep(maintenance.idle_for).await;
+                        if sync_helper.metrics().events == events {
+                            break;
+                        }
+                    }
+                    debug!("Running store maintenance.");
+                    let blocking_sync_helper = sync_helper.clone();
+                    match tokio::task::spawn_blocking(move || {
+                        blocking_sync_helper.maintain_stores()
+                    })
+                    .await
+                    {
+                        Ok(Ok(())) => info!("Store maintenance finished."),
+                        Ok(Err(err)) => error!("Store maintenance failed: {:?}", err),
+                        Err(err) => error!("Store maintenance panicked: {}", err),
+                    }
+                }
+            }
+            .in_current_span(),
+        )
+    }
+}
+
+fn maintain_file(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    // The Matrix SDK keeps its files open in WAL mode, so a separate connection can work on them at the same time.
+    let conn = rusqlite::Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    conn.busy_timeout(Duration::from_secs(5))?;
+    maintain(&conn)
+}
+
+fn maintain(conn: &rusqlite::Connection) -> Result<()> {
+    // PASSIVE never blocks the SDK's writers, and TRUNCATE would wait for them
+    conn.query_row("PRAGMA wal_checkpoint(PASSIVE);", (), |_| Ok(()))?;
+    conn.execute_batch(
+        "PRAGMA optimize;
+PRAGMA incremental_vacuum;",
+    )?;
+    Ok(())
+}
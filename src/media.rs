@@ -0,0 +1,524 @@
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use eyeball::SharedObservable;
+use eyre::{Result, bail, eyre};
+use matrix_sdk::attachment::AttachmentConfig;
+use matrix_sdk::crypto::AttachmentDecryptor;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::{MxcUri, OwnedEventId, OwnedMxcUri};
+use matrix_sdk::{Client, TransmissionProgress};
+use mime::Mime;
+use tracing::{Instrument, debug, info, instrument, warn};
+
+use crate::RateLimiter;
+
+const AUTHENTICATED_MEDIA: &[&str] = &["_matrix", "client", "v1", "media", "download"];
+const LEGACY_MEDIA: &[&str] = &["_matrix", "media", "v3", "download"];
+
+/// A file to send with [`send_file`].
+#[derive(Clone, Debug)]
+pub enum FileSource {
+    /// Reads the file from disk. The file name is taken from the path.
+    Path(PathBuf),
+    /// Sends a file that is already in memory.
+    Bytes {
+        /// The file name shown to users.
+        filename: String,
+        /// The file content.
+        data: Vec<u8>,
+    },
+}
+
+impl From<PathBuf> for FileSource {
+    fn from(path: PathBuf) -> Self {
+        FileSource::Path(path)
+    }
+}
+
+impl From<&Path> for FileSource {
+    fn from(path: &Path) -> Self {
+        FileSource::Path(path.to_owned())
+    }
+}
+
+/// Called with the number of bytes uploaded so far, and the total number of bytes.
+pub type ProgressCallback = Arc<dyn Fn(u64, u64) + Send + Sync>;
+
+/// Options for [`send_file`].
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub struct MediaOptions {
+    /// Overrides the MIME type, which is otherwise detected from the content and the file name.
+    pub mime_type: Option<Mime>,
+    /// Generates a thumbnail for images, so clients can show a preview before downloading the whole image.
+    #[cfg(feature = "thumbnails")]
+    pub thumbnail: bool,
+    /// Reports the upload progress.
+    pub progress: Option<ProgressCallback>,
+    /// Waits for this rate limiter before sending the message, see [`RateLimiter`].
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl MediaOptions {
+    /// Creates a [`MediaOptions`] with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl fmt::Debug for MediaOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("MediaOptions");
+        debug.field("mime_type", &self.mime_type);
+        #[cfg(feature = "thumbnails")]
+        debug.field("thumbnail", &self.thumbnail);
+        debug
+            .field("progress", &self.progress.as_ref().map(|_| ".."))
+            .field("rate_limiter", &self.rate_limiter)
+            .finish()
+    }
+}
+
+/// Uploads a file and sends it to `room` as an `m.image`, `m.video`, `m.audio`, or `m.file` message, depending on its MIME type.
+///
+/// In an encrypted room, the file is encrypted before uploading.
+/// Files larger than the homeserver's upload size limit are rejected before uploading.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+///
+/// use matrixbot_ezlogin::MediaOptions;
+///
+/// # async fn example(room: matrix_sdk::Room) -> color_eyre::Result<()> {
+/// matrixbot_ezlogin::send_file(&room, Path::new("report.pdf"), MediaOptions::new()).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[instrument(skip_all)]
+pub async fn send_file(
+    room: &Room,
+    source: impl Into<FileSource>,
+    options: MediaOptions,
+) -> Result<OwnedEventId> {
+    let (filename, data) = match source.into() {
+        FileSource::Path(path) => {
+            let filename = path
+                .file_name()
+                .map(|filename| filename.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "file".to_owned());
+            (filename, tokio::fs::read(&path).await?)
+        }
+        FileSource::Bytes { filename, data } => (filename, data),
+    };
+
+    let max_upload_size = room.client().load_or_fetch_max_upload_size().await?;
+    if data.len() as u64 > u64::from(max_upload_size) {
+        bail!(
+            "{} is {} bytes, larger than the upload size limit of {} bytes",
+            filename,
+            data.len(),
+            max_upload_size
+        );
+    }
+
+    let mime_type = options
+        .mime_type
+        .clone()
+        .unwrap_or_else(|| sniff_mime_type(&data, &filename));
+    #[allow(unused_mut)]
+    let mut config = AttachmentConfig::new();
+    #[cfg(feature = "thumbnails")]
+    if options.thumbnail && mime_type.type_() == mime::IMAGE {
+        config = thumbnail::attach(config, &data).await;
+    }
+
+    let progress = SharedObservable::new(TransmissionProgress::default());
+    if let Some(callback) = options.progress.clone() {
+        let mut subscriber = progress.subscribe();
+        tokio::spawn(
+            async move {
+                while let Some(progress) = subscriber.next().await {
+                    callback(progress.current as u64, progress.total as u64);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    crate::permissions::check_send(room, "m.room.message").await?;
+    info!(
+        "Sending {} ({}, {} bytes) to room {}.",
+        filename,
+        mime_type,
+        data.len(),
+        room.room_id()
+    );
+    crate::rate_limit::acquire(options.rate_limiter.as_ref(), room.room_id()).await;
+    let response = room
+        .send_attachment(&filename, &mime_type, data, config)
+        .with_send_progress_observable(progress)
+        .await?;
+    Ok(response.event_id)
+}
+
+/// Options for [`fetch_media`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct FetchOptions {
+    /// Refuses to download files larger than this size, in bytes. Defaults to 64 MiB.
+    pub max_size: Option<u64>,
+    /// Caches downloaded files in this directory, keyed by their MXC URI, and by their hash if encrypted.
+    ///
+    /// Files are cached after decryption, so make sure the directory is not readable by other users.
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl FetchOptions {
+    /// Creates a [`FetchOptions`] with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            max_size: Some(64 << 20),
+            cache_dir: None,
+        }
+    }
+}
+
+/// A file downloaded by [`fetch_media`].
+#[derive(Clone, Debug)]
+pub struct DecryptedMedia {
+    /// The MXC URI of the file.
+    pub mxc_uri: OwnedMxcUri,
+    /// The file name, if the sender provided one.
+    pub filename: Option<String>,
+    /// The MIME type, if the sender provided one.
+    pub mime_type: Option<String>,
+    /// The file content, already decrypted.
+    pub data: Vec<u8>,
+}
+
+/// Downloads the file attached to an `m.image`, `m.video`, `m.audio`, or `m.file` message.
+///
+/// It uses the authenticated media endpoints if the homeserver supports them.
+/// Encrypted attachments are decrypted, and their hashes are verified.
+///
+/// Returns an error for other message types, or if the file is larger than [`FetchOptions::max_size`].
+#[instrument(skip_all)]
+pub async fn fetch_media(
+    client: &Client,
+    msgtype: &MessageType,
+    options: &FetchOptions,
+) -> Result<DecryptedMedia> {
+    let (source, filename, mime_type, size) = match msgtype {
+        MessageType::Image(content) => (
+            &content.source,
+            content.filename(),
+            content.info.as_ref().and_then(|info| info.mimetype.clone()),
+            content.info.as_ref().and_then(|info| info.size),
+        ),
+        MessageType::Video(content) => (
+            &content.source,
+            content.filename(),
+            content.info.as_ref().and_then(|info| info.mimetype.clone()),
+            content.info.as_ref().and_then(|info| info.size),
+        ),
+        MessageType::Audio(content) => (
+            &content.source,
+            content.filename(),
+            content.info.as_ref().and_then(|info| info.mimetype.clone()),
+            content.info.as_ref().and_then(|info| info.size),
+        ),
+        MessageType::File(content) => (
+            &content.source,
+            content.filename(),
+            content.info.as_ref().and_then(|info| info.mimetype.clone()),
+            content.info.as_ref().and_then(|info| info.size),
+        ),
+        _ => {
+            bail!("message type {} has no attachment", msgtype.msgtype());
+        }
+    };
+    let mxc_uri = match source {
+        MediaSource::Plain(mxc_uri) => mxc_uri.clone(),
+        MediaSource::Encrypted(file) => file.url.clone(),
+    };
+    if let (Some(max_size), Some(size)) = (options.max_size, size)
+        && u64::from(size) > max_size
+    {
+        bail!(
+            "{} is {} bytes, larger than the limit of {} bytes",
+            mxc_uri,
+            size,
+            max_size
+        );
+    }
+
+    let cache_path = options
+        .cache_dir
+        .as_ref()
+        .map(|cache_dir| cache_dir.join(cache_file_name(source)));
+    if let Some(cache_path) = &cache_path {
+        match tokio::fs::read(cache_path).await {
+            Ok(data) => {
+                debug!("Loaded {} from the cache.", mxc_uri);
+                return Ok(DecryptedMedia {
+                    mxc_uri,
+                    filename: Some(filename.to_owned()),
+                    mime_type,
+                    data,
+                });
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => warn!("Failed to read {} from the cache: {}", mxc_uri, err),
+        }
+    }
+
+    info!("Downloading {}.", mxc_uri);
+    let data = download(client, &mxc_uri, options.max_size).await?;
+    let data = match source {
+        MediaSource::Plain(_) => data,
+        MediaSource::Encrypted(file) => {
+            // AES-CTR doesn't change the size, so the limit already applies to the ciphertext
+            let mut cursor = std::io::Cursor::new(data);
+            let mut decryptor =
+                AttachmentDecryptor::new(&mut cursor, file.as_ref().clone().into())?;
+            let mut decrypted = Vec::new();
+            decryptor.read_to_end(&mut decrypted)?;
+            decrypted
+        }
+    };
+
+    if let Some(cache_path) = &cache_path {
+        write_atomically(cache_path, &data).await?;
+    }
+    Ok(DecryptedMedia {
+        mxc_uri,
+        filename: Some(filename.to_owned()),
+        mime_type,
+        data,
+    })
+}
+
+/// Downloads `mxc_uri`, and stops as soon as it exceeds `max_size`, so a lying `info.size` can't exhaust the memory.
+///
+/// It tries the authenticated media endpoint first, then the legacy one for older homeservers.
+async fn download(client: &Client, mxc_uri: &MxcUri, max_size: Option<u64>) -> Result<Vec<u8>> {
+    let mut response = request_media(client, mxc_uri, AUTHENTICATED_MEDIA).await?;
+    // Homeservers without authenticated media don't know the endpoint
+    if matches!(response.status().as_u16(), 404 | 405) {
+        response = request_media(client, mxc_uri, LEGACY_MEDIA).await?;
+    }
+    let mut response = response.error_for_status()?;
+    if let (Some(max_size), Some(size)) = (max_size, response.content_length())
+        && size > max_size
+    {
+        bail!(
+            "{} is {} bytes, larger than the limit of {} bytes",
+            mxc_uri,
+            size,
+            max_size
+        );
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        data.extend_from_slice(&chunk);
+        if let Some(max_size) = max_size
+            && data.len() as u64 > max_size
+        {
+            bail!("{} is larger than the limit of {} bytes", mxc_uri, max_size);
+        }
+    }
+    Ok(data)
+}
+
+async fn request_media(
+    client: &Client,
+    mxc_uri: &MxcUri,
+    endpoint: &[&str],
+) -> Result<reqwest::Response> {
+    let (server_name, media_id) = mxc_uri.parts()?;
+    let mut url = client.homeserver();
+    url.path_segments_mut()
+        .map_err(|()| eyre!("homeserver URL {} can't have a path", client.homeserver()))?
+        .pop_if_empty()
+        .extend(endpoint)
+        .extend([server_name.as_str(), media_id]);
+    let mut request = client.http_client().get(url);
+    if let Some(access_token) = client.access_token() {
+        request = request.bearer_auth(access_token);
+    }
+    Ok(request.send().await?)
+}
+
+/// Writes a cache file through a temporary file, so a crash or a concurrent download never leaves a truncated file behind.
+async fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("invalid cache path {}", path.display());
+    };
+    tokio::fs::create_dir_all(dir).await?;
+    let temp_path = dir.join(format!(
+        ".{}.{:016x}.tmp",
+        name.to_string_lossy(),
+        rand::random::<u64>()
+    ));
+    if let Err(err) = tokio::fs::write(&temp_path, data).await {
+        _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(err.into());
+    }
+    if let Err(err) = tokio::fs::rename(&temp_path, path).await {
+        _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Turns a media source into a file name that is safe on every platform.
+///
+/// Encrypted files are also keyed by the hash of their ciphertext, so an event reusing the MXC URI with other keys can't read the cached file.
+fn cache_file_name(source: &MediaSource) -> String {
+    let (mxc_uri, hash) = match source {
+        MediaSource::Plain(mxc_uri) => (mxc_uri, None),
+        MediaSource::Encrypted(file) => (&file.url, file.hashes.get("sha256")),
+    };
+    let mut name = mxc_uri
+        .as_str()
+        .trim_start_matches("mxc://")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if let Some(hash) = hash {
+        name.push('-');
+        for byte in hash.as_bytes() {
+            name.push_str(&format!("{:02x}", byte));
+        }
+    }
+    name
+}
+
+/// Detects the MIME type from the magic bytes of the content, then from the file extension.
+fn sniff_mime_type(data: &[u8], filename: &str) -> Mime {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1aE\xdf\xa3", "video/webm"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+    ];
+    for (magic, mime_type) in MAGIC {
+        if data.starts_with(magic) {
+            return mime_type.parse().unwrap();
+        }
+    }
+    if data.len() >= 12 && &data[0..4] == b"RIFF" {
+        match &data[8..12] {
+            b"WEBP" => return "image/webp".parse().unwrap(),
+            b"WAVE" => return "audio/wav".parse().unwrap(),
+            _ => (),
+        }
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return "video/mp4".parse().unwrap();
+    }
+
+    let extension = Path::new(filename)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    let mime_type = match extension.as_deref() {
+        Some("txt" | "log") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("csv") => "text/csv",
+        Some("html" | "htm") => "text/html",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("mp3") => "audio/mpeg",
+        Some("m4a") => "audio/mp4",
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mov") => "video/quicktime",
+        _ => "application/octet-stream",
+    };
+    mime_type.parse().unwrap()
+}
+
+#[cfg(feature = "thumbnails")]
+mod thumbnail {
+    use std::io::Cursor;
+
+    use matrix_sdk::attachment::{AttachmentConfig, AttachmentInfo, BaseImageInfo, Thumbnail};
+    use matrix_sdk::ruma::UInt;
+    use tracing::warn;
+
+    const MAX_THUMBNAIL_SIZE: u32 = 800;
+
+    /// Adds the image dimensions and a JPEG thumbnail to `config`, or returns it unchanged if the image can't be decoded.
+    pub(super) async fn attach(config: AttachmentConfig, data: &[u8]) -> AttachmentConfig {
+        let data = data.to_owned();
+        let result = tokio::task::spawn_blocking(move || -> image::ImageResult<_> {
+            let image = image::load_from_memory(&data)?;
+            let thumbnail = image.thumbnail(MAX_THUMBNAIL_SIZE, MAX_THUMBNAIL_SIZE);
+            let mut encoded = Vec::new();
+            thumbnail
+                .to_rgb8()
+                .write_to(&mut Cursor::new(&mut encoded), image::ImageFormat::Jpeg)?;
+            Ok((
+                image.width(),
+                image.height(),
+                data.len(),
+                thumbnail.width(),
+                thumbnail.height(),
+                encoded,
+            ))
+        })
+        .await;
+        let (width, height, size, thumbnail_width, thumbnail_height, encoded) = match result {
+            Ok(Ok(result)) => result,
+            Ok(Err(err)) => {
+                warn!("Failed to generate a thumbnail: {}", err);
+                return config;
+            }
+            Err(err) => {
+                warn!("Failed to generate a thumbnail: {}", err);
+                return config;
+            }
+        };
+        let info = BaseImageInfo {
+            width: Some(UInt::from(width)),
+            height: Some(UInt::from(height)),
+            size: UInt::try_from(size).ok(),
+            ..Default::default()
+        };
+        let thumbnail = Thumbnail {
+            size: UInt::try_from(encoded.len()).unwrap_or_default(),
+            data: encoded,
+            content_type: mime::IMAGE_JPEG,
+            width: UInt::from(thumbnail_width),
+            height: UInt::from(thumbnail_height),
+        };
+        config
+            .info(AttachmentInfo::Image(info))
+            .thumbnail(Some(thumbnail))
+    }
+}
@@ -0,0 +1,163 @@
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::ruma::events::Mentions;
+use matrix_sdk::ruma::events::room::message::{
+    EmoteMessageEventContent, MessageType, NoticeMessageEventContent, RoomMessageEventContent,
+    TextMessageEventContent,
+};
+
+use crate::reply::html_escape;
+
+/// Composes an `m.text`, `m.notice`, or `m.emote` message with both a plain-text body and an HTML body.
+///
+/// Every message built with it carries intentional mentions ([MSC3952](https://github.com/matrix-org/matrix-spec-proposals/pull/3952)),
+/// even if empty. So, a display name appearing in the text doesn't ping anyone by accident; only [`MessageBuilder::push_mention`] does.
+///
+/// # Example
+///
+/// ```
+/// use matrix_sdk::ruma::user_id;
+/// use matrixbot_ezlogin::MessageBuilder;
+///
+/// let content = MessageBuilder::notice()
+///     .push_text("Hello, ")
+///     .push_mention(user_id!("@alice:example.com"), "Alice")
+///     .push_text("! 1 < 2")
+///     .build();
+/// ```
+#[derive(Clone, Debug)]
+pub struct MessageBuilder {
+    kind: MessageKind,
+    plain: String,
+    html: String,
+    has_html: bool,
+    mentions: Mentions,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum MessageKind {
+    Text,
+    Notice,
+    Emote,
+}
+
+impl MessageBuilder {
+    /// Starts an `m.text` message.
+    pub fn text() -> Self {
+        Self::new(MessageKind::Text)
+    }
+
+    /// Starts an `m.notice` message, the usual message type for bots.
+    ///
+    /// Some bot implementations are designed to ignore `m.notice`, preventing infinite looping.
+    pub fn notice() -> Self {
+        Self::new(MessageKind::Notice)
+    }
+
+    /// Starts an `m.emote` message, displayed like `/me` on IRC.
+    pub fn emote() -> Self {
+        Self::new(MessageKind::Emote)
+    }
+
+    fn new(kind: MessageKind) -> Self {
+        Self {
+            kind,
+            plain: String::new(),
+            html: String::new(),
+            has_html: false,
+            mentions: Mentions::new(),
+        }
+    }
+
+    /// Appends plain text, which is escaped in the HTML body.
+    pub fn push_text(mut self, text: &str) -> Self {
+        self.plain.push_str(text);
+        self.html.push_str(&html_escape(text).replace('\n', "<br>"));
+        self
+    }
+
+    /// Appends text with a separate HTML representation. `plain` is used by clients that don't render HTML.
+    ///
+    /// `html` is inserted as is, so make sure it is well-formed and doesn't contain untrusted input.
+    pub fn push_html(mut self, plain: &str, html: &str) -> Self {
+        self.plain.push_str(plain);
+        self.html.push_str(html);
+        self.has_html = true;
+        self
+    }
+
+    /// Appends Markdown, rendered into HTML. The Markdown source is used as the plain-text body.
+    #[cfg(feature = "markdown")]
+    pub fn push_markdown(mut self, markdown: &str) -> Self {
+        use matrix_sdk::ruma::events::room::message::FormattedBody;
+
+        self.plain.push_str(markdown);
+        match FormattedBody::markdown(markdown) {
+            Some(formatted) => {
+                self.html.push_str(&formatted.body);
+                self.has_html = true;
+            }
+            None => self
+                .html
+                .push_str(&html_escape(markdown).replace('\n', "<br>")),
+        }
+        self
+    }
+
+    /// Appends a pill that mentions `user_id`, and adds it to the intentional mentions.
+    ///
+    /// `display_name` is shown in the text. Use [`RoomMember::display_name`](matrix_sdk::room::RoomMember::display_name) or the user ID.
+    pub fn push_mention(mut self, user_id: &UserId, display_name: &str) -> Self {
+        self.plain.push_str(display_name);
+        self.html.push_str(&format!(
+            "<a href=\"https://matrix.to/#/{}\">{}</a>",
+            user_id,
+            html_escape(display_name)
+        ));
+        self.has_html = true;
+        self.mentions.user_ids.insert(user_id.to_owned());
+        self
+    }
+
+    /// Appends `@room`, and notifies everyone in the room if the bot has the permission.
+    pub fn push_room_mention(mut self) -> Self {
+        self.plain.push_str("@room");
+        self.html.push_str("@room");
+        self.mentions.room = true;
+        self
+    }
+
+    /// Builds the message content.
+    ///
+    /// The HTML body is only included if it differs from the plain-text body.
+    pub fn build(self) -> RoomMessageEventContent {
+        let msgtype = match (self.kind, self.has_html) {
+            (MessageKind::Text, false) => {
+                MessageType::Text(TextMessageEventContent::plain(self.plain))
+            }
+            (MessageKind::Text, true) => {
+                MessageType::Text(TextMessageEventContent::html(self.plain, self.html))
+            }
+            (MessageKind::Notice, false) => {
+                MessageType::Notice(NoticeMessageEventContent::plain(self.plain))
+            }
+            (MessageKind::Notice, true) => {
+                MessageType::Notice(NoticeMessageEventContent::html(self.plain, self.html))
+            }
+            (MessageKind::Emote, false) => {
+                MessageType::Emote(EmoteMessageEventContent::plain(self.plain))
+            }
+            (MessageKind::Emote, true) => {
+                MessageType::Emote(EmoteMessageEventContent::html(self.plain, self.html))
+            }
+        };
+        let mut content = RoomMessageEventContent::new(msgtype);
+        content.mentions = Some(self.mentions);
+        content
+    }
+}
+
+impl From<MessageBuilder> for RoomMessageEventContent {
+    fn from(builder: MessageBuilder) -> Self {
+        builder.build()
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime};
+
+use matrix_sdk::sync::SyncResponse;
+
+use crate::SyncHelper;
+use crate::mutex::MutexExt;
+
+/// Upper bounds of the buckets of [`SyncMetrics::events_per_response`].
+const EVENTS_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0];
+
+/// Upper bounds of the buckets of [`SyncMetrics::sync_duration_seconds`].
+const DURATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// A snapshot of sync statistics collected by [`SyncHelper`], returned by [`SyncHelper::metrics`].
+///
+/// With the `metrics` feature enabled, the same statistics are also reported to the [`metrics`](::metrics) crate facade, under names prefixed by `ezlogin_sync_`.
+#[derive(Clone, Debug)]
+pub struct SyncMetrics {
+    /// Number of successful sync responses.
+    pub sync_responses: u64,
+    /// Number of failed sync requests.
+    pub sync_errors: u64,
+    /// Number of times a sync token was written into the state database.
+    pub token_writes: u64,
+    /// Total number of timeline events received.
+    pub events: u64,
+    /// Distribution of the number of timeline events per sync response.
+    pub events_per_response: Histogram,
+    /// Distribution of the time each sync request took, in seconds, including long-polling.
+    pub sync_duration_seconds: Histogram,
+    /// The time the last successful sync response arrived.
+    pub last_sync_response: Option<SystemTime>,
+}
+
+/// A histogram with fixed buckets.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    /// Pairs of bucket upper bound and cumulative count of observations less than or equal to it.
+    pub buckets: Vec<(f64, u64)>,
+    /// Total number of observations.
+    pub count: u64,
+    /// Sum of all observations.
+    pub sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            buckets: bounds.iter().map(|&bound| (bound, 0)).collect(),
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in &mut self.buckets {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+impl Default for SyncMetrics {
+    fn default() -> Self {
+        Self {
+            sync_responses: 0,
+            sync_errors: 0,
+            token_writes: 0,
+            events: 0,
+            events_per_response: Histogram::new(EVENTS_BUCKETS),
+            sync_duration_seconds: Histogram::new(DURATION_BUCKETS),
+            last_sync_response: None,
+        }
+    }
+}
+
+impl SyncMetrics {
+    pub(crate) fn record_response(&mut self, sync_response: &SyncResponse, now: SystemTime) {
+        let events = count_timeline_events(sync_response);
+        self.sync_responses += 1;
+        self.events += events as u64;
+        self.events_per_response.observe(events as f64);
+        self.last_sync_response = Some(now);
+        #[cfg(feature = "metrics")]
+        {
+            ::metrics::counter!("ezlogin_sync_responses_total").increment(1);
+            ::metrics::counter!("ezlogin_sync_events_total").increment(events as u64);
+            ::metrics::histogram!("ezlogin_sync_events_per_response").record(events as f64);
+        }
+    }
+
+    pub(crate) fn record_duration(&mut self, duration: Duration) {
+        self.sync_duration_seconds.observe(duration.as_secs_f64());
+        #[cfg(feature = "metrics")]
+        ::metrics::histogram!("ezlogin_sync_duration_seconds").record(duration.as_secs_f64());
+    }
+
+    pub(crate) fn record_error(&mut self) {
+        self.sync_errors += 1;
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("ezlogin_sync_errors_total").increment(1);
+    }
+
+    pub(crate) fn record_token_write(&mut self) {
+        self.token_writes += 1;
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("ezlogin_sync_token_writes_total").increment(1);
+    }
+}
+
+pub(crate) fn count_timeline_events(sync_response: &SyncResponse) -> usize {
+    let rooms = &sync_response.rooms;
+    rooms
+        .joined
+        .values()
+        .map(|room| room.timeline.events.len())
+        .chain(rooms.left.values().map(|room| room.timeline.events.len()))
+        .sum()
+}
+
+impl SyncHelper {
+    /// Returns a snapshot of sync statistics collected since this [`SyncHelper`] was created.
+    pub fn metrics(&self) -> SyncMetrics {
+        self.metrics.lock_unpoisoned().clone()
+    }
+
+    pub(crate) fn with_metrics(&self, f: impl FnOnce(&mut SyncMetrics)) {
+        f(&mut self.metrics.lock_unpoisoned());
+    }
+}
+
+static EVENT_METRICS: LazyLock<Mutex<EventMetrics>> = LazyLock::new(Default::default);
+
+/// A snapshot of process-wide event statistics, returned by [`event_metrics`].
+///
+/// With the `metrics` feature enabled, the same statistics are also reported to the [`metrics`](::metrics) crate facade, under names prefixed by `ezlogin_`.
+#[derive(Clone, Debug, Default)]
+pub struct EventMetrics {
+    /// Number of event handler calls, by handler name, as recorded by [`record_handler_call`].
+    pub handler_calls: BTreeMap<String, u64>,
+    /// Number of successfully sent messages, as recorded by [`record_send`].
+    pub sends_succeeded: u64,
+    /// Number of messages that failed to send, as recorded by [`record_send`].
+    pub sends_failed: u64,
+    /// Number of events that were unable to decrypt (UTD) when they arrived.
+    pub utd_events: u64,
+    /// Number of UTD events whose room key arrived later, so they became decryptable.
+    pub late_decryptions: u64,
+    /// Number of room keys of UTD events that were downloaded from the server-side backup.
+    pub backup_key_downloads: u64,
+    /// Number of received room keys that were uploaded to the server-side backup.
+    pub backup_key_uploads: u64,
+    /// Number of received room keys that are waiting to be uploaded to the server-side backup.
+    pub backup_pending_keys: u64,
+    /// When the oldest of [`backup_pending_keys`](EventMetrics::backup_pending_keys) was received, if any.
+    ///
+    /// The time since then is how far the server-side backup lags behind.
+    pub backup_pending_since: Option<SystemTime>,
+    /// Number of messages sent to encrypted rooms where some device of a joined member is unverified.
+    pub unverified_device_sends: u64,
+    /// Number of times [`run_supervised`](crate::run_supervised) restarted the bot after a crash.
+    pub supervisor_restarts: u64,
+}
+
+/// Returns a snapshot of process-wide event statistics.
+pub fn event_metrics() -> EventMetrics {
+    EVENT_METRICS.lock_unpoisoned().clone()
+}
+
+/// Records a call to an event handler. Call it at the beginning of your event handlers.
+pub fn record_handler_call(name: &str) {
+    *EVENT_METRICS
+        .lock_unpoisoned()
+        .handler_calls
+        .entry(name.to_owned())
+        .or_default() += 1;
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("ezlogin_handler_calls_total", "handler" => name.to_owned()).increment(1);
+}
+
+/// Records the result of sending a message.
+pub fn record_send(success: bool) {
+    let mut metrics = EVENT_METRICS.lock_unpoisoned();
+    if success {
+        metrics.sends_succeeded += 1;
+    } else {
+        metrics.sends_failed += 1;
+    }
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("ezlogin_sends_total", "result" => if success { "success" } else { "failure" }).increment(1);
+}
+
+pub(crate) fn record_utd() {
+    EVENT_METRICS.lock_unpoisoned().utd_events += 1;
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("ezlogin_utd_events_total").increment(1);
+}
+
+pub(crate) fn record_late_decryptions(events: u64) {
+    EVENT_METRICS.lock_unpoisoned().late_decryptions += events;
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("ezlogin_late_decryptions_total").increment(events);
+}
+
+pub(crate) fn record_backup_download() {
+    EVENT_METRICS.lock_unpoisoned().backup_key_downloads += 1;
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("ezlogin_backup_key_downloads_total").increment(1);
+}
+
+pub(crate) fn record_backup_pending(keys: u64, now: SystemTime) {
+    let mut metrics = EVENT_METRICS.lock_unpoisoned();
+    metrics.backup_pending_keys += keys;
+    metrics.backup_pending_since.get_or_insert(now);
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!("ezlogin_backup_pending_keys").set(metrics.backup_pending_keys as f64);
+}
+
+pub(crate) fn record_backup_upload() {
+    let mut metrics = EVENT_METRICS.lock_unpoisoned();
+    let keys = metrics.backup_pending_keys;
+    metrics.backup_key_uploads += keys;
+    metrics.backup_pending_keys = 0;
+    metrics.backup_pending_since = None;
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!("ezlogin_backup_key_uploads_total").increment(keys);
+        ::metrics::gauge!("ezlogin_backup_pending_keys").set(0.0);
+    }
+}
+
+pub(crate) fn record_unverified_device_send() {
+    EVENT_METRICS.lock_unpoisoned().unverified_device_sends += 1;
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("ezlogin_unverified_device_sends_total").increment(1);
+}
+
+pub(crate) fn record_restart() {
+    EVENT_METRICS.lock_unpoisoned().supervisor_restarts += 1;
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("ezlogin_supervisor_restarts_total").increment(1);
+}
@@ -0,0 +1,476 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use eyre::{Result, eyre};
+use matrix_sdk::event_handler::{EventHandlerHandle, SyncEvent};
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::message::{OriginalSyncRoomMessageEvent, Relation};
+use matrix_sdk::ruma::events::sticker::OriginalSyncStickerEvent;
+use matrix_sdk::ruma::events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent};
+use matrix_sdk::ruma::{EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, UserId};
+use matrix_sdk::{Client, RoomState};
+use serde::de::DeserializeOwned;
+use tracing::{Instrument, debug, error};
+
+use crate::mutex::MutexExt;
+use crate::{Acl, SyncHelper};
+
+pub(crate) type MiddlewareFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type BoxedHandler<E> = Arc<dyn Fn(EventContext<E>) -> MiddlewareFuture + Send + Sync>;
+
+/// An event type that [`Dispatcher`] can handle.
+pub trait DispatchEvent: SyncEvent + DeserializeOwned + Clone + Send + Sync + 'static {
+    /// The sender of the event.
+    fn sender(&self) -> &UserId;
+    /// The ID of the event.
+    fn event_id(&self) -> &EventId;
+    /// When the event was sent, according to the sender's homeserver.
+    fn origin_server_ts(&self) -> MilliSecondsSinceUnixEpoch;
+    /// Whether the event replaces the content of an earlier event. Defaults to `false`.
+    fn is_edit(&self) -> bool {
+        false
+    }
+}
+
+/// The event, and where it comes from, passed through the middleware chain of a [`Dispatcher`].
+#[derive(Clone, Debug)]
+pub struct EventContext<E> {
+    /// The client that received the event.
+    pub client: Client,
+    /// The room where the event was sent.
+    pub room: Room,
+    /// The event.
+    pub event: E,
+    /// The handler name given to [`Dispatcher::register`].
+    pub handler: Arc<str>,
+}
+
+/// A layer of a [`Dispatcher`] that runs around every handler.
+///
+/// It can inspect or modify the [`EventContext`], and decides whether to call the rest of the chain with [`Next::run`].
+///
+/// It is implemented for async closures taking an [`EventContext`] and a [`Next`].
+pub trait Middleware<E>: Send + Sync + 'static {
+    /// Handles an event, usually by calling `next.run(ctx)` at some point.
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture;
+}
+
+impl<E, F, Fut> Middleware<E> for F
+where
+    F: Fn(EventContext<E>, Next<E>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        Box::pin(self(ctx, next))
+    }
+}
+
+/// The rest of the middleware chain, ending with the handler.
+pub struct Next<E> {
+    chain: Arc<[Arc<dyn Middleware<E>>]>,
+    index: usize,
+    handler: BoxedHandler<E>,
+}
+
+impl<E> Next<E> {
+    /// Runs the rest of the middleware chain, and the handler.
+    pub async fn run(self, ctx: EventContext<E>) -> Result<()> {
+        match self.chain.get(self.index).cloned() {
+            Some(middleware) => {
+                let next = Next {
+                    chain: self.chain,
+                    index: self.index + 1,
+                    handler: self.handler,
+                };
+                middleware.call(ctx, next).await
+            }
+            None => (self.handler)(ctx).await,
+        }
+    }
+}
+
+impl<E> fmt::Debug for Next<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Next")
+            .field("remaining", &(self.chain.len() - self.index))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Registers event handlers wrapped by an ordered chain of [`Middleware`].
+///
+/// Instead of repeating the same guard clauses at the top of every handler, add them once as middleware.
+/// Middleware added first runs outermost, so add [`CatchPanics`] and [`LogEvents`] before filters.
+///
+/// Events from users on the account's ignore list, see [`ignore_user`](crate::ignore_user), are dropped before the chain runs.
+/// So are events that the [`CatchUpPolicy`](crate::CatchUpPolicy) of [`SyncHelper::catch_up`] skips.
+///
+/// Errors returned by the chain are logged.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrix_sdk::ruma::events::room::message::{OriginalSyncRoomMessageEvent, RoomMessageEventContent};
+/// use matrixbot_ezlogin::{
+///     CatchPanics, Deduplicate, Dispatcher, IgnoreEdits, IgnoreOwnEvents, JoinedRoomsOnly, LogEvents,
+/// };
+///
+/// # fn example(client: &matrix_sdk::Client, sync_helper: &matrixbot_ezlogin::SyncHelper) {
+/// Dispatcher::<OriginalSyncRoomMessageEvent>::new()
+///     .with(CatchPanics)
+///     .with(LogEvents)
+///     .with(IgnoreOwnEvents)
+///     .with(JoinedRoomsOnly)
+///     .with(IgnoreEdits)
+///     .with(Deduplicate::new(sync_helper.clone()))
+///     .register(client, sync_helper, "echo", |ctx| async move {
+///         ctx.room
+///             .send(RoomMessageEventContent::notice_plain(ctx.event.content.body()))
+///             .await?;
+///         Ok(())
+///     });
+/// # }
+/// ```
+pub struct Dispatcher<E> {
+    chain: Vec<Arc<dyn Middleware<E>>>,
+}
+
+impl<E: DispatchEvent> Dispatcher<E> {
+    /// Creates a [`Dispatcher`] without middleware.
+    pub fn new() -> Self {
+        Self { chain: Vec::new() }
+    }
+
+    /// Appends a middleware to the chain.
+    pub fn with(mut self, middleware: impl Middleware<E>) -> Self {
+        self.chain.push(Arc::new(middleware));
+        self
+    }
+
+    /// Registers `handler` on `client`, wrapped by the current middleware chain.
+    ///
+    /// Middleware added after this call doesn't affect this handler. `name` identifies the handler in logs and metrics.
+    ///
+    /// Events that the [`CatchUpPolicy`](crate::CatchUpPolicy) of [`SyncHelper::catch_up`] on `sync_helper` skips don't reach the chain.
+    pub fn register<F, Fut>(
+        &self,
+        client: &Client,
+        sync_helper: &SyncHelper,
+        name: &str,
+        handler: F,
+    ) -> EventHandlerHandle
+    where
+        F: Fn(EventContext<E>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let chain: Arc<[Arc<dyn Middleware<E>>]> = self.chain.clone().into();
+        let handler: BoxedHandler<E> = Arc::new(move |ctx| Box::pin(handler(ctx)));
+        let name: Arc<str> = name.into();
+        let sync_helper = sync_helper.clone();
+        client.add_event_handler(move |event: E, room: Room, client: Client| {
+            let skipped = !sync_helper.should_process(event.origin_server_ts());
+            let next = Next {
+                chain: chain.clone(),
+                index: 0,
+                handler: handler.clone(),
+            };
+            let ctx = EventContext {
+                client,
+                room,
+                event,
+                handler: name.clone(),
+            };
+            async move {
+                let (handler, event_id) = (ctx.handler.clone(), ctx.event.event_id().to_owned());
+                if skipped {
+                    debug!(
+                        "Skipping event {}: it occurred while the bot was offline.",
+                        event_id
+                    );
+                    return;
+                }
+                if crate::ignore_list::is_ignored(&ctx.client, ctx.event.sender()).await {
+                    debug!(
+                        "Ignoring event {}: {} is on the ignore list.",
+                        event_id,
+                        ctx.event.sender()
+                    );
+                    return;
+                }
+                if let Err(err) = next.run(ctx).await {
+                    error!(
+                        "Handler {} failed on event {}: {:?}",
+                        handler, event_id, err
+                    );
+                }
+            }
+        })
+    }
+}
+
+impl<E: DispatchEvent> Default for Dispatcher<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> Clone for Dispatcher<E> {
+    fn clone(&self) -> Self {
+        Self {
+            chain: self.chain.clone(),
+        }
+    }
+}
+
+impl<E> fmt::Debug for Dispatcher<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("chain", &self.chain.len())
+            .finish()
+    }
+}
+
+/// Middleware that drops events sent by the bot itself.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IgnoreOwnEvents;
+
+impl<E: DispatchEvent> Middleware<E> for IgnoreOwnEvents {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        Box::pin(async move {
+            if Some(ctx.event.sender()) == ctx.client.user_id() {
+                return Ok(());
+            }
+            next.run(ctx).await
+        })
+    }
+}
+
+/// Middleware that drops events from rooms the bot is not joined to, for example, after leaving or being kicked.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JoinedRoomsOnly;
+
+impl<E: DispatchEvent> Middleware<E> for JoinedRoomsOnly {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        Box::pin(async move {
+            if ctx.room.state() != RoomState::Joined {
+                debug!(
+                    "Ignoring room {}, event {}: Current room state is {:?}.",
+                    ctx.room.room_id(),
+                    ctx.event.event_id(),
+                    ctx.room.state()
+                );
+                return Ok(());
+            }
+            next.run(ctx).await
+        })
+    }
+}
+
+/// Middleware that drops edits of earlier events.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IgnoreEdits;
+
+impl<E: DispatchEvent> Middleware<E> for IgnoreEdits {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        Box::pin(async move {
+            if ctx.event.is_edit() {
+                return Ok(());
+            }
+            next.run(ctx).await
+        })
+    }
+}
+
+/// Middleware that drops events the same handler has already handled successfully.
+///
+/// Events are keyed by the handler name given to [`Dispatcher::register`] and the event ID, so handlers sharing a [`SyncHelper`] don't hide events from each other.
+/// An event is recorded only after the rest of the chain succeeds, so a failed event is handled again when it's delivered again, for example, after [`SyncHelper::rollback`].
+/// Deliveries of the same event that arrive while it is still being handled are dropped.
+///
+/// Records expire like those of [`SyncHelper::seen`].
+#[derive(Clone, Debug)]
+pub struct Deduplicate {
+    sync_helper: SyncHelper,
+    in_flight: Arc<Mutex<HashSet<(Arc<str>, OwnedEventId)>>>,
+}
+
+impl Deduplicate {
+    /// Creates a [`Deduplicate`] that records handled events in the state database of `sync_helper`.
+    pub fn new(sync_helper: SyncHelper) -> Self {
+        Self {
+            sync_helper,
+            in_flight: Arc::default(),
+        }
+    }
+}
+
+impl<E: DispatchEvent> Middleware<E> for Deduplicate {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        let deduplicate = self.clone();
+        Box::pin(async move {
+            let key = (ctx.handler.clone(), ctx.event.event_id().to_owned());
+            if deduplicate.sync_helper.is_handled(&key.0, &key.1)?
+                || !deduplicate.in_flight.lock_unpoisoned().insert(key.clone())
+            {
+                debug!("Handler {} ignoring duplicate event {}.", key.0, key.1);
+                return Ok(());
+            }
+            let result = next
+                .run(ctx)
+                .await
+                .and_then(|()| deduplicate.sync_helper.mark_handled(&key.0, &key.1));
+            deduplicate.in_flight.lock_unpoisoned().remove(&key);
+            result
+        })
+    }
+}
+
+/// Middleware that drops events whose sender is not allowed by an [`Acl`] in the room.
+#[derive(Clone, Debug)]
+pub struct AclCheck(pub Acl);
+
+impl<E: DispatchEvent> Middleware<E> for AclCheck {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        let allowed = self.0.is_allowed(ctx.event.sender(), ctx.room.room_id());
+        Box::pin(async move {
+            if !allowed {
+                debug!(
+                    "Ignoring event {}: {} is not allowed in room {}.",
+                    ctx.event.event_id(),
+                    ctx.event.sender(),
+                    ctx.room.room_id()
+                );
+                return Ok(());
+            }
+            next.run(ctx).await
+        })
+    }
+}
+
+/// Middleware that logs every event passing through it, and how long the rest of the chain took.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogEvents;
+
+impl<E: DispatchEvent> Middleware<E> for LogEvents {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        Box::pin(async move {
+            let (handler, room_id, event_id) = (
+                ctx.handler.clone(),
+                ctx.room.room_id().to_owned(),
+                ctx.event.event_id().to_owned(),
+            );
+            debug!(
+                "Handler {} received room {}, event {}.",
+                handler, room_id, event_id
+            );
+            let start = Instant::now();
+            let result = next.run(ctx).await;
+            debug!(
+                "Handler {} finished room {}, event {} in {:?}.",
+                handler,
+                room_id,
+                event_id,
+                start.elapsed()
+            );
+            result
+        })
+    }
+}
+
+/// Middleware that counts handler calls with [`record_handler_call`](crate::record_handler_call).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecordMetrics;
+
+impl<E: DispatchEvent> Middleware<E> for RecordMetrics {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        crate::record_handler_call(&ctx.handler);
+        Box::pin(next.run(ctx))
+    }
+}
+
+/// Middleware that turns a panic in the rest of the chain into an error, so one bad event can't take down the bot.
+///
+/// The rest of the chain runs in a separate Tokio task.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CatchPanics;
+
+impl<E: DispatchEvent> Middleware<E> for CatchPanics {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        Box::pin(async move {
+            let handler = ctx.handler.clone();
+            match tokio::spawn(next.run(ctx).in_current_span()).await {
+                Ok(result) => result,
+                Err(err) if err.is_panic() => Err(eyre!("handler {} panicked", handler)),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+}
+
+impl DispatchEvent for OriginalSyncRoomMessageEvent {
+    fn sender(&self) -> &UserId {
+        &self.sender
+    }
+
+    fn event_id(&self) -> &EventId {
+        &self.event_id
+    }
+
+    fn origin_server_ts(&self) -> MilliSecondsSinceUnixEpoch {
+        self.origin_server_ts
+    }
+
+    fn is_edit(&self) -> bool {
+        matches!(self.content.relates_to, Some(Relation::Replacement(_)))
+    }
+}
+
+impl DispatchEvent for OriginalSyncStickerEvent {
+    fn sender(&self) -> &UserId {
+        &self.sender
+    }
+
+    fn event_id(&self) -> &EventId {
+        &self.event_id
+    }
+
+    fn origin_server_ts(&self) -> MilliSecondsSinceUnixEpoch {
+        self.origin_server_ts
+    }
+
+    fn is_edit(&self) -> bool {
+        matches!(self.content.relates_to, Some(Relation::Replacement(_)))
+    }
+}
+
+impl DispatchEvent for AnySyncMessageLikeEvent {
+    fn sender(&self) -> &UserId {
+        AnySyncMessageLikeEvent::sender(self)
+    }
+
+    fn event_id(&self) -> &EventId {
+        AnySyncMessageLikeEvent::event_id(self)
+    }
+
+    fn origin_server_ts(&self) -> MilliSecondsSinceUnixEpoch {
+        AnySyncMessageLikeEvent::origin_server_ts(self)
+    }
+}
+
+impl DispatchEvent for AnySyncTimelineEvent {
+    fn sender(&self) -> &UserId {
+        AnySyncTimelineEvent::sender(self)
+    }
+
+    fn event_id(&self) -> &EventId {
+        AnySyncTimelineEvent::event_id(self)
+    }
+
+    fn origin_server_ts(&self) -> MilliSecondsSinceUnixEpoch {
+        AnySyncTimelineEvent::origin_server_ts(self)
+    }
+}
@@ -0,0 +1,14 @@
+use std::sync::{Mutex, MutexGuard};
+
+pub(crate) trait MutexExt<T> {
+    /// Locks the mutex, panicking if some other task panicked while holding it.
+    fn lock_unpoisoned(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    fn lock_unpoisoned(&self) -> MutexGuard<'_, T> {
+        self.lock()
+            // lock() will only return an error after some other task panicked
+            .unwrap()
+    }
+}
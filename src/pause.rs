@@ -0,0 +1,143 @@
+use std::time::Instant;
+
+use async_stream::stream;
+use matrix_sdk::Client;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::sync::SyncResponse;
+use tokio::select;
+use tokio::sync::watch;
+use tokio_stream::{Stream, StreamExt};
+use tracing::info;
+
+use crate::SyncHelper;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PauseState {
+    Running,
+    /// Pauses once the ongoing sync response is handled, see [`SyncHelper::pause_after_response`].
+    AfterResponse,
+    Paused,
+}
+
+/// Counts a sync stream as in flight while it lives.
+struct Syncing<'a>(&'a watch::Sender<usize>);
+
+impl<'a> Syncing<'a> {
+    fn new(syncing: &'a watch::Sender<usize>) -> Self {
+        syncing.send_modify(|count| *count += 1);
+        Self(syncing)
+    }
+}
+
+impl Drop for Syncing<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+impl SyncHelper {
+    /// Suspends long-polling of [`SyncHelper::sync`], [`SyncHelper::sync_stream`], and other convenience methods, without tearing down the [`Client`].
+    ///
+    /// The ongoing sync request is dropped, closing its connection. Sending messages and other API calls still work while sync is paused.
+    ///
+    /// This is useful for maintenance windows, or for applying backpressure when a downstream queue is full.
+    pub fn pause(&self) {
+        if self.paused.send_replace(PauseState::Paused) != PauseState::Paused {
+            info!("Pausing sync.");
+        }
+    }
+
+    /// Pauses sync like [`SyncHelper::pause`], but lets the ongoing sync request finish first,
+    /// and returns once its event handlers have run and its sync token is saved.
+    ///
+    /// It can take as long as the long-polling timeout of the sync request.
+    pub async fn pause_after_response(&self) {
+        let requested = self.paused.send_if_modified(|state| {
+            let running = *state == PauseState::Running;
+            if running {
+                *state = PauseState::AfterResponse;
+            }
+            running
+        });
+        if requested {
+            info!("Pausing sync after the ongoing response.");
+        }
+        let mut syncing = self.syncing.subscribe();
+        // The sender lives as long as self
+        _ = syncing.wait_for(|count| *count == 0).await;
+        self.pause();
+    }
+
+    /// Resumes long-polling after [`SyncHelper::pause`], from the last saved sync token.
+    pub fn resume(&self) {
+        if self.paused.send_replace(PauseState::Running) != PauseState::Running {
+            info!("Resuming sync.");
+        }
+    }
+
+    /// Returns whether sync is currently paused by [`SyncHelper::pause`].
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow() == PauseState::Paused
+    }
+
+    /// Wraps [`Client::sync_stream`], dropping the sync stream while paused, and rebuilding it from the last saved sync token after resumed.
+    ///
+    /// It does not call [`SyncHelper::process_sync_response`].
+    pub(crate) fn pausable_sync_stream<'a>(
+        &'a self,
+        client: &'a Client,
+        sync_settings: SyncSettings,
+    ) -> impl Stream<Item = Result<SyncResponse, matrix_sdk::Error>> + 'a {
+        stream! {
+            loop {
+                let mut paused = self.paused.subscribe();
+                // The sender lives as long as self
+                _ = paused.wait_for(|state| *state == PauseState::Running).await;
+
+                let sync_stream = client
+                    .sync_stream(self.process_sync_settings(sync_settings.clone()))
+                    .await;
+                tokio::pin!(sync_stream);
+                let _syncing = Syncing::new(&self.syncing);
+                loop {
+                    let start_time = Instant::now();
+                    let response = select! {
+                        response = sync_stream.next() => response,
+                        _ = paused.wait_for(|state| *state == PauseState::Paused) => None,
+                    };
+                    let Some(response) = response else {
+                        break;
+                    };
+                    let duration = start_time.elapsed();
+                    self.with_metrics(|metrics| {
+                        metrics.record_duration(duration);
+                        if response.is_err() {
+                            metrics.record_error();
+                        }
+                    });
+                    match &response {
+                        Ok(sync_response) => crate::trace_events::sync_response(
+                            crate::metrics::count_timeline_events(sync_response),
+                            duration,
+                        ),
+                        Err(err) => crate::trace_events::sync_failed(err, duration),
+                    }
+                    yield response;
+                    // The caller asked for the next response, so it has handled this one
+                    _ = self.mirror_sync_token(client, false);
+                    let pausing = self.paused.send_if_modified(|state| {
+                        let after_response = *state == PauseState::AfterResponse;
+                        if after_response {
+                            *state = PauseState::Paused;
+                        }
+                        after_response
+                    });
+                    if pausing {
+                        info!("Pausing sync.");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::future::Future;
+
+use eyre::Result;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::{MessageLikeEventType, StateEventType};
+
+use crate::NotAllowed;
+
+/// Checks what the bot is allowed to do in a room, computed from the cached `m.room.power_levels` state, without a request to the server.
+///
+/// The send helpers of this crate consult these checks first, so a missing permission fails with a [`NotAllowed`] error
+/// instead of a `403 M_FORBIDDEN` from the server, and doesn't use up the rate limit.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::RoomPermissions;
+///
+/// # async fn example(room: &matrix_sdk::Room) -> color_eyre::Result<()> {
+/// if !room.can_send("m.room.message").await? {
+///     tracing::warn!("Muted in room {}.", room.room_id());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub trait RoomPermissions {
+    /// Whether the bot can send message events of `event_type`, for example, `"m.room.message"` or `"m.reaction"`.
+    ///
+    /// In encrypted rooms, every message event is sent as `m.room.encrypted`, so that event type is checked instead.
+    fn can_send(&self, event_type: &str) -> impl Future<Output = Result<bool>> + Send;
+    /// Whether the bot can send state events of `event_type`, for example, `"m.room.topic"`.
+    fn can_send_state(&self, event_type: &str) -> impl Future<Output = Result<bool>> + Send;
+    /// Whether the bot can redact its own events.
+    fn can_redact_own(&self) -> impl Future<Output = Result<bool>> + Send;
+    /// Whether the bot can redact events sent by other users.
+    fn can_redact_other(&self) -> impl Future<Output = Result<bool>> + Send;
+    /// Whether the bot can invite users.
+    fn can_invite(&self) -> impl Future<Output = Result<bool>> + Send;
+    /// Whether the bot can kick users with a lower power level.
+    fn can_kick(&self) -> impl Future<Output = Result<bool>> + Send;
+    /// Whether the bot can ban users with a lower power level.
+    fn can_ban(&self) -> impl Future<Output = Result<bool>> + Send;
+}
+
+impl RoomPermissions for Room {
+    async fn can_send(&self, event_type: &str) -> Result<bool> {
+        let event_type = if self.latest_encryption_state().await?.is_encrypted() {
+            "m.room.encrypted"
+        } else {
+            event_type
+        };
+        Ok(self
+            .can_user_send_message(self.own_user_id(), MessageLikeEventType::from(event_type))
+            .await?)
+    }
+
+    async fn can_send_state(&self, event_type: &str) -> Result<bool> {
+        Ok(self
+            .can_user_send_state(self.own_user_id(), StateEventType::from(event_type))
+            .await?)
+    }
+
+    async fn can_redact_own(&self) -> Result<bool> {
+        Ok(self.can_user_redact_own(self.own_user_id()).await?)
+    }
+
+    async fn can_redact_other(&self) -> Result<bool> {
+        Ok(self.can_user_redact_other(self.own_user_id()).await?)
+    }
+
+    async fn can_invite(&self) -> Result<bool> {
+        Ok(self.can_user_invite(self.own_user_id()).await?)
+    }
+
+    async fn can_kick(&self) -> Result<bool> {
+        Ok(self.can_user_kick(self.own_user_id()).await?)
+    }
+
+    async fn can_ban(&self) -> Result<bool> {
+        Ok(self.can_user_ban(self.own_user_id()).await?)
+    }
+}
+
+/// Fails if the bot can't send message events of `event_type` to `room`.
+pub(crate) async fn check_send(room: &Room, event_type: &str) -> Result<()> {
+    if !room.can_send(event_type).await? {
+        return Err(NotAllowed {
+            room_id: room.room_id().to_owned(),
+            action: format!("send {} events", event_type),
+        }
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,412 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+
+use eyre::{OptionExt, Result, bail};
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::{IncludeRelations, RelationsOptions, Room};
+use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::events::relation::RelationType;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedUserId};
+use matrix_sdk::{Client, RoomState};
+use serde_json::{Value, json};
+use tracing::{Instrument, error};
+
+use crate::RateLimiter;
+
+// MSC3381 is not stable yet, so only the unstable prefixes are in use.
+const POLL_START: &str = "org.matrix.msc3381.poll.start";
+const POLL_RESPONSE: &str = "org.matrix.msc3381.poll.response";
+const POLL_END: &str = "org.matrix.msc3381.poll.end";
+const POLL_DISCLOSED: &str = "org.matrix.msc3381.poll.disclosed";
+const POLL_UNDISCLOSED: &str = "org.matrix.msc3381.poll.undisclosed";
+const TEXT: &str = "org.matrix.msc1767.text";
+
+/// A poll, as defined by [MSC3381](https://github.com/matrix-org/matrix-spec-proposals/pull/3381).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Poll {
+    /// The question.
+    pub question: String,
+    /// The answers to choose from.
+    pub answers: Vec<PollAnswer>,
+    /// How many answers each user can choose.
+    pub max_selections: u32,
+    /// Whether clients show the results before the poll ends.
+    pub disclosed: bool,
+}
+
+/// One answer of a [`Poll`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PollAnswer {
+    /// The answer ID, used in responses.
+    pub id: String,
+    /// The answer text.
+    pub text: String,
+}
+
+/// The results of a poll, returned by [`tally_poll`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PollResults {
+    /// The voters for each answer ID. Every answer of the poll is present, even without votes.
+    pub votes: BTreeMap<String, Vec<OwnedUserId>>,
+    /// Whether the poll has ended. Responses after the end are not counted.
+    pub ended: bool,
+}
+
+/// A poll event, delivered by [`on_poll`].
+#[derive(Clone, Debug)]
+pub enum PollEvent {
+    /// Someone started a poll.
+    Start {
+        /// The poll start event.
+        poll_id: OwnedEventId,
+        /// Who started the poll.
+        sender: OwnedUserId,
+        /// The poll.
+        poll: Poll,
+    },
+    /// Someone voted, or changed their vote. An empty list of answers means a spoiled vote.
+    Response {
+        /// The poll start event.
+        poll_id: OwnedEventId,
+        /// Who voted.
+        sender: OwnedUserId,
+        /// The chosen answer IDs.
+        answers: Vec<String>,
+    },
+    /// Someone ended a poll.
+    End {
+        /// The poll start event.
+        poll_id: OwnedEventId,
+        /// Who ended the poll.
+        sender: OwnedUserId,
+    },
+}
+
+impl Poll {
+    /// Creates a disclosed, single-choice poll. The answers get the IDs `"1"`, `"2"`, and so on.
+    pub fn new(
+        question: impl Into<String>,
+        answers: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        Self {
+            question: question.into(),
+            answers: answers
+                .into_iter()
+                .enumerate()
+                .map(|(index, text)| PollAnswer {
+                    id: (index + 1).to_string(),
+                    text: text.into(),
+                })
+                .collect(),
+            max_selections: 1,
+            disclosed: true,
+        }
+    }
+
+    /// How many answers each user can choose. Defaults to 1.
+    pub fn max_selections(mut self, max_selections: u32) -> Self {
+        self.max_selections = max_selections.max(1);
+        self
+    }
+
+    /// Whether clients show the results before the poll ends. Defaults to `true`.
+    pub fn disclosed(mut self, disclosed: bool) -> Self {
+        self.disclosed = disclosed;
+        self
+    }
+
+    fn to_content(&self) -> Value {
+        let mut fallback = self.question.clone();
+        for (index, answer) in self.answers.iter().enumerate() {
+            fallback.push_str(&format!("\n{}. {}", index + 1, answer.text));
+        }
+        json!({
+            POLL_START: {
+                "question": { TEXT: self.question },
+                "kind": if self.disclosed { POLL_DISCLOSED } else { POLL_UNDISCLOSED },
+                "max_selections": self.max_selections,
+                "answers": self.answers.iter().map(|answer| json!({
+                    "id": answer.id,
+                    TEXT: answer.text,
+                })).collect::<Vec<_>>(),
+            },
+            TEXT: fallback,
+        })
+    }
+
+    fn from_content(content: &Value) -> Option<Self> {
+        let start = content.get(POLL_START)?;
+        Some(Self {
+            question: start
+                .pointer(&format!("/question/{}", TEXT))?
+                .as_str()?
+                .to_owned(),
+            answers: start
+                .get("answers")?
+                .as_array()?
+                .iter()
+                .filter_map(|answer| {
+                    Some(PollAnswer {
+                        id: answer.get("id")?.as_str()?.to_owned(),
+                        text: answer.get(TEXT)?.as_str()?.to_owned(),
+                    })
+                })
+                .collect(),
+            max_selections: start
+                .get("max_selections")
+                .and_then(Value::as_u64)
+                .unwrap_or(1)
+                .clamp(1, u32::MAX.into()) as u32,
+            disclosed: start.get("kind").and_then(Value::as_str) != Some(POLL_UNDISCLOSED),
+        })
+    }
+}
+
+/// Starts `poll` in `room`, and returns the poll ID, which is the event ID of the poll start event.
+///
+/// It waits for `rate_limiter` first, if any, as do [`respond_to_poll`] and [`end_poll`].
+pub async fn start_poll(
+    room: &Room,
+    poll: &Poll,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<OwnedEventId> {
+    crate::send::send_raw(room, POLL_START, poll.to_content(), None, rate_limiter).await
+}
+
+/// Votes for `answers` in the poll `poll_id`. An empty list spoils the vote.
+pub async fn respond_to_poll(
+    room: &Room,
+    poll_id: &EventId,
+    answers: &[&str],
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<OwnedEventId> {
+    let content = json!({
+        "m.relates_to": { "rel_type": "m.reference", "event_id": poll_id },
+        POLL_RESPONSE: { "answers": answers },
+    });
+    crate::send::send_raw(room, POLL_RESPONSE, content, None, rate_limiter).await
+}
+
+/// Ends the poll `poll_id`, which the bot must have started, and posts the results as the fallback text.
+pub async fn end_poll(
+    room: &Room,
+    poll_id: &EventId,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<OwnedEventId> {
+    let (poll, _) = fetch_poll(room, poll_id).await?;
+    let results = tally_poll(room, poll_id).await?;
+    let mut text = format!("The poll has ended. {}", poll.question);
+    for answer in &poll.answers {
+        let count = results.votes.get(&answer.id).map_or(0, Vec::len);
+        text.push_str(&format!("\n{}: {}", answer.text, count));
+    }
+    let content = json!({
+        "m.relates_to": { "rel_type": "m.reference", "event_id": poll_id },
+        POLL_END: {},
+        TEXT: text,
+    });
+    crate::send::send_raw(room, POLL_END, content, None, rate_limiter).await
+}
+
+/// Counts the votes of the poll `poll_id`.
+///
+/// Following MSC3381, only each user's latest response counts, responses after the poll ends are ignored,
+/// unknown answers are dropped, and extra answers beyond `max_selections` are cut off.
+/// Only the poll creator can end the poll.
+pub async fn tally_poll(room: &Room, poll_id: &EventId) -> Result<PollResults> {
+    let (poll, creator) = fetch_poll(room, poll_id).await?;
+
+    let mut responses = Vec::new();
+    let mut end_time = None;
+    let mut from = None;
+    loop {
+        let relations = room
+            .relations(
+                poll_id.to_owned(),
+                RelationsOptions {
+                    from,
+                    include_relations: IncludeRelations::RelationsOfType(RelationType::Reference),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        for event in relations.chunk {
+            let Ok(event) = serde_json::from_str::<Value>(event.raw().json().get()) else {
+                continue;
+            };
+            let (Some(event_type), Some(sender), Some(time)) = (
+                event.get("type").and_then(Value::as_str),
+                event
+                    .get("sender")
+                    .and_then(Value::as_str)
+                    .and_then(|sender| OwnedUserId::try_from(sender).ok()),
+                event.get("origin_server_ts").and_then(Value::as_u64),
+            ) else {
+                continue;
+            };
+            match event_type {
+                POLL_RESPONSE => {
+                    let answers = event
+                        .pointer(&format!("/content/{}/answers", POLL_RESPONSE))
+                        .and_then(Value::as_array)
+                        .map(|answers| {
+                            answers
+                                .iter()
+                                .filter_map(|answer| answer.as_str().map(ToOwned::to_owned))
+                                .collect::<Vec<_>>()
+                        });
+                    if let Some(answers) = answers {
+                        responses.push((time, sender, answers));
+                    }
+                }
+                POLL_END if sender == creator => {
+                    end_time = Some(end_time.map_or(time, |end_time: u64| end_time.min(time)));
+                }
+                _ => (),
+            }
+        }
+        match relations.next_batch {
+            Some(next_batch) => from = Some(next_batch),
+            None => break,
+        }
+    }
+
+    let mut latest = HashMap::<OwnedUserId, (u64, Vec<String>)>::new();
+    for (time, sender, answers) in responses {
+        if end_time.is_some_and(|end_time| time > end_time) {
+            continue;
+        }
+        if latest
+            .get(&sender)
+            .is_none_or(|(latest_time, _)| time >= *latest_time)
+        {
+            latest.insert(sender, (time, answers));
+        }
+    }
+
+    let mut results = PollResults {
+        votes: poll
+            .answers
+            .iter()
+            .map(|answer| (answer.id.clone(), Vec::new()))
+            .collect(),
+        ended: end_time.is_some(),
+    };
+    for (sender, (_, answers)) in latest {
+        let valid = answers
+            .into_iter()
+            .filter(|answer| results.votes.contains_key(answer))
+            .take(poll.max_selections as usize);
+        for answer in valid {
+            if let Some(voters) = results.votes.get_mut(&answer)
+                && !voters.contains(&sender)
+            {
+                voters.push(sender.clone());
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Registers an event handler on `client` that calls `handler` for every poll start, response, and end from someone else in a joined room.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::PollEvent;
+///
+/// # fn example(client: &matrix_sdk::Client) {
+/// matrixbot_ezlogin::on_poll(client, |room, event| async move {
+///     if let PollEvent::End { poll_id, .. } = event {
+///         let results = matrixbot_ezlogin::tally_poll(&room, &poll_id).await?;
+///         tracing::info!("Poll {} ended: {:?}", poll_id, results.votes);
+///     }
+///     Ok(())
+/// });
+/// # }
+/// ```
+pub fn on_poll<F, Fut>(client: &Client, handler: F) -> EventHandlerHandle
+where
+    F: Fn(Room, PollEvent) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    client.add_event_handler(
+        move |raw: Raw<AnySyncTimelineEvent>, room: Room, client: Client| {
+            let handler = handler.clone();
+            async move {
+                if room.state() != RoomState::Joined {
+                    return;
+                }
+                let Ok(event) = serde_json::from_str::<Value>(raw.json().get()) else {
+                    return;
+                };
+                let Some(poll_event) = parse_poll_event(&event) else {
+                    return;
+                };
+                let (PollEvent::Start { sender, .. }
+                | PollEvent::Response { sender, .. }
+                | PollEvent::End { sender, .. }) = &poll_event;
+                if Some(&**sender) == client.user_id() {
+                    return;
+                }
+                let room_id = room.room_id().to_owned();
+                if let Err(err) = handler(room, poll_event).in_current_span().await {
+                    error!("Poll handler failed in room {}: {:?}", room_id, err);
+                }
+            }
+        },
+    )
+}
+
+fn parse_poll_event(event: &Value) -> Option<PollEvent> {
+    let sender = OwnedUserId::try_from(event.get("sender")?.as_str()?).ok()?;
+    let content = event.get("content")?;
+    let related_poll_id = || {
+        let relates_to = content.get("m.relates_to")?;
+        if relates_to.get("rel_type")?.as_str()? != "m.reference" {
+            return None;
+        }
+        OwnedEventId::try_from(relates_to.get("event_id")?.as_str()?).ok()
+    };
+    match event.get("type")?.as_str()? {
+        POLL_START => Some(PollEvent::Start {
+            poll_id: OwnedEventId::try_from(event.get("event_id")?.as_str()?).ok()?,
+            sender,
+            poll: Poll::from_content(content)?,
+        }),
+        POLL_RESPONSE => Some(PollEvent::Response {
+            poll_id: related_poll_id()?,
+            sender,
+            answers: content
+                .pointer(&format!("/{}/answers", POLL_RESPONSE))?
+                .as_array()?
+                .iter()
+                .filter_map(|answer| answer.as_str().map(ToOwned::to_owned))
+                .collect(),
+        }),
+        POLL_END => Some(PollEvent::End {
+            poll_id: related_poll_id()?,
+            sender,
+        }),
+        _ => None,
+    }
+}
+
+async fn fetch_poll(room: &Room, poll_id: &EventId) -> Result<(Poll, OwnedUserId)> {
+    let event = room.event(poll_id, None).await?;
+    let event = serde_json::from_str::<Value>(event.raw().json().get())?;
+    if event.get("type").and_then(Value::as_str) != Some(POLL_START) {
+        bail!("event {} is not a poll", poll_id);
+    }
+    let creator = event
+        .get("sender")
+        .and_then(Value::as_str)
+        .and_then(|sender| OwnedUserId::try_from(sender).ok())
+        .ok_or_eyre("poll has no sender")?;
+    let poll = event
+        .get("content")
+        .and_then(Poll::from_content)
+        .ok_or_eyre("invalid poll")?;
+    Ok((poll, creator))
+}
@@ -0,0 +1,137 @@
+//! Presence and status messages.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use eyre::{OptionExt, Result};
+use matrix_sdk::Client;
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::api::client::presence::set_presence;
+use matrix_sdk::ruma::events::presence::PresenceEvent;
+use matrix_sdk::ruma::presence::PresenceState;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, error, warn};
+
+/// A presence update of a watched user, delivered by [`on_presence`].
+#[derive(Clone, Debug)]
+pub struct PresenceUpdate {
+    /// The client that received the update.
+    pub client: Client,
+    /// The user whose presence changed.
+    pub user_id: OwnedUserId,
+    /// The new presence state.
+    pub presence: PresenceState,
+    /// The status message, if any.
+    pub status_msg: Option<String>,
+    /// Whether the user is actively using a client right now.
+    pub currently_active: Option<bool>,
+    /// How long ago the user was last active.
+    pub last_active_ago: Option<Duration>,
+}
+
+/// Sets the bot's presence, for example, [`PresenceState::Online`] or [`PresenceState::Unavailable`], and its status message.
+///
+/// The homeserver marks the bot as unavailable after a few minutes unless the presence is refreshed. Use [`heartbeat`] to keep it.
+///
+/// Every sync request also sets the presence, to [`SyncOptions::set_presence`](crate::SyncOptions::set_presence), which is [`PresenceState::Offline`] by default.
+/// Set it to the same state, otherwise the next sync request overrides the presence set here.
+pub async fn set(client: &Client, presence: PresenceState, status_msg: Option<&str>) -> Result<()> {
+    let user_id = client.user_id().ok_or_eyre("not logged in")?.to_owned();
+    let mut request = set_presence::v3::Request::new(user_id, presence);
+    request.status_msg = status_msg.map(ToOwned::to_owned);
+    client.send(request).await?;
+    Ok(())
+}
+
+/// Spawns a Tokio task that calls [`set`] every `interval`, so the bot keeps showing `presence` and `status_msg`, for example, its version.
+///
+/// One minute is a good interval. Abort the returned task to stop. Errors are logged, but otherwise ignored.
+///
+/// Set [`SyncOptions::set_presence`](crate::SyncOptions::set_presence) to the same `presence`.
+/// It defaults to [`PresenceState::Offline`], and each sync request would flip the bot back to offline between two heartbeats.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrix_sdk::ruma::presence::PresenceState;
+/// use matrixbot_ezlogin::SyncOptions;
+/// use std::time::Duration;
+///
+/// # fn example(client: &matrix_sdk::Client) {
+/// let status_msg = format!("v{}, send !help for commands", env!("CARGO_PKG_VERSION"));
+/// let heartbeat = matrixbot_ezlogin::presence::heartbeat(
+///     client,
+///     PresenceState::Online,
+///     Some(status_msg),
+///     Duration::from_secs(60),
+/// );
+/// // Pass these to `SyncHelper::sync` or `run_until_shutdown`
+/// let sync_options = SyncOptions {
+///     set_presence: PresenceState::Online,
+///     ..Default::default()
+/// };
+/// # }
+/// ```
+pub fn heartbeat(
+    client: &Client,
+    presence: PresenceState,
+    status_msg: Option<String>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    let client = client.clone();
+    tokio::spawn(
+        async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(err) = set(&client, presence.clone(), status_msg.as_deref()).await {
+                    warn!("Failed to set presence: {}", err);
+                }
+            }
+        }
+        .in_current_span(),
+    )
+}
+
+/// Registers an event handler on `client` that calls `handler` whenever one of `users` changes presence.
+///
+/// Presence events are only received if [`SyncOptions::receive_presence`](crate::SyncOptions::receive_presence) is enabled,
+/// and only for users sharing a room with the bot.
+pub fn on_presence<F, Fut>(
+    client: &Client,
+    users: impl IntoIterator<Item = OwnedUserId>,
+    handler: F,
+) -> EventHandlerHandle
+where
+    F: Fn(PresenceUpdate) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let users: Arc<[OwnedUserId]> = users.into_iter().collect();
+    client.add_event_handler(move |event: PresenceEvent, client: Client| {
+        let handler = handler.clone();
+        let users = users.clone();
+        async move {
+            if !users.contains(&event.sender) {
+                return;
+            }
+            let update = PresenceUpdate {
+                client,
+                user_id: event.sender,
+                presence: event.content.presence,
+                status_msg: event.content.status_msg,
+                currently_active: event.content.currently_active,
+                last_active_ago: event
+                    .content
+                    .last_active_ago
+                    .map(|ago| Duration::from_millis(ago.into())),
+            };
+            let user_id = update.user_id.clone();
+            if let Err(err) = handler(update).in_current_span().await {
+                error!("Presence handler failed for {}: {:?}", user_id, err);
+            }
+        }
+    })
+}
@@ -0,0 +1,139 @@
+use std::borrow::Cow;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::DuplexLog;
+
+static NEXT_PROGRESS_ID: AtomicU64 = AtomicU64::new(0);
+
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+const BAR_WIDTH: usize = 20;
+const MIN_RENDER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A spinner or progress bar, created by [`DuplexLog::progress`].
+///
+/// Without a total, it renders a spinner. After [`Progress::set_total`], it renders a progress bar.
+/// Dropping the handle removes it from the terminal.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::DuplexLog;
+///
+/// # async fn download(_: &mut [u8]) -> usize { todo!() }
+/// #[tokio::main]
+/// async fn main() {
+///     let mut progress = DuplexLog::progress("Downloading");
+///     progress.set_total(Some(1024));
+///     let mut buf = [0; 1024];
+///     while progress.position() < 1024 {
+///         let n = download(&mut buf).await;
+///         progress.inc(n as u64);
+///     }
+///     progress.finish("done");
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Progress {
+    id: u64,
+    label: Cow<'static, str>,
+    position: u64,
+    total: Option<u64>,
+    spinner: usize,
+    last_render: Option<Instant>,
+}
+
+impl Progress {
+    pub(crate) fn new(label: Cow<'static, str>) -> Self {
+        let mut progress = Self {
+            id: NEXT_PROGRESS_ID.fetch_add(1, Ordering::Relaxed),
+            label,
+            position: 0,
+            total: None,
+            spinner: 0,
+            last_render: None,
+        };
+        progress.render(true);
+        progress
+    }
+
+    /// Returns the current position.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Sets the total amount of work. [`None`] switches back to a spinner.
+    pub fn set_total(&mut self, total: Option<u64>) {
+        self.total = total;
+        self.render(true);
+    }
+
+    /// Sets the current position.
+    pub fn set_position(&mut self, position: u64) {
+        self.position = position;
+        self.render(false);
+    }
+
+    /// Advances the current position by `delta`.
+    pub fn inc(&mut self, delta: u64) {
+        self.position = self.position.saturating_add(delta);
+        self.render(false);
+    }
+
+    /// Changes the label.
+    pub fn set_label<S>(&mut self, label: S)
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        self.label = label.into();
+        self.render(true);
+    }
+
+    /// Advances the spinner without changing the position, to show that the operation is still alive.
+    pub fn tick(&mut self) {
+        self.render(false);
+    }
+
+    /// Removes the spinner or progress bar, and prints a final message through [`DuplexLog::get_writer`].
+    pub fn finish(self, message: &str) {
+        _ = writeln!(DuplexLog::get_writer(), "{}: {}", self.label, message);
+        // Drop removes the status line
+    }
+
+    fn render(&mut self, force: bool) {
+        let now = Instant::now();
+        if !force
+            && self
+                .last_render
+                .is_some_and(|last_render| now.duration_since(last_render) < MIN_RENDER_INTERVAL)
+        {
+            return;
+        }
+        self.last_render = Some(now);
+        self.spinner = (self.spinner + 1) % SPINNER.len();
+        let status = match self.total {
+            Some(total) if total > 0 => {
+                let ratio = (self.position as f64 / total as f64).clamp(0.0, 1.0);
+                let filled = (ratio * BAR_WIDTH as f64).round() as usize;
+                format!(
+                    "{} [{}{}] {:.0}% ({}/{})",
+                    self.label,
+                    "#".repeat(filled),
+                    ".".repeat(BAR_WIDTH - filled),
+                    ratio * 100.0,
+                    self.position,
+                    total
+                )
+            }
+            _ => format!("{} {}", self.label, SPINNER[self.spinner]),
+        };
+        DuplexLog::set_status(self.id, Some(status));
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        DuplexLog::set_status(self.id, None);
+    }
+}
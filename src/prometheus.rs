@@ -0,0 +1,250 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use eyre::Result;
+use matrix_sdk::Client;
+use matrix_sdk::encryption::backups::BackupState;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, instrument};
+
+use crate::http::{self, ServerHandle};
+use crate::{Histogram, SyncHelper, event_metrics};
+
+/// Starts serving `/metrics` in the Prometheus text format on `listen_addr`, in a background task.
+///
+/// Usually you don't need to call it, set [`LoginOptions::prometheus_listen_addr`](crate::LoginOptions::prometheus_listen_addr) instead.
+#[instrument(skip(client, sync_helper))]
+pub async fn serve_prometheus(
+    listen_addr: SocketAddr,
+    client: Client,
+    sync_helper: SyncHelper,
+) -> Result<ServerHandle> {
+    let listener = TcpListener::bind(listen_addr).await?;
+    info!(
+        "Serving Prometheus metrics at http://{}/metrics",
+        listen_addr
+    );
+    Ok(http::serve(listener, move |stream| {
+        let client = client.clone();
+        let sync_helper = sync_helper.clone();
+        async move { handle_connection(stream, &client, &sync_helper).await }
+    }))
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    client: &Client,
+    sync_helper: &SyncHelper,
+) -> Result<()> {
+    // Scrapes have no body, so anything larger is not a scrape.
+    let Some(request) = http::read_request(&mut stream, 16384).await? else {
+        return Ok(());
+    };
+    if request.method != "GET" {
+        http::write_response(
+            &mut stream,
+            "405 Method Not Allowed",
+            &[("Allow", "GET")],
+            b"",
+        )
+        .await
+    } else if request.path != "/metrics" {
+        http::write_response(&mut stream, "404 Not Found", &[], b"").await
+    } else {
+        let body = render(client, sync_helper);
+        http::write_response(
+            &mut stream,
+            "200 OK",
+            &[("Content-Type", "text/plain; version=0.0.4; charset=utf-8")],
+            body.as_bytes(),
+        )
+        .await
+    }
+}
+
+fn render(client: &Client, sync_helper: &SyncHelper) -> String {
+    let mut out = String::new();
+    let sync = sync_helper.metrics();
+    write_counter(
+        &mut out,
+        "ezlogin_sync_responses_total",
+        "Number of successful sync responses.",
+        sync.sync_responses,
+    );
+    write_counter(
+        &mut out,
+        "ezlogin_sync_errors_total",
+        "Number of failed sync requests.",
+        sync.sync_errors,
+    );
+    write_counter(
+        &mut out,
+        "ezlogin_sync_token_writes_total",
+        "Number of sync token writes.",
+        sync.token_writes,
+    );
+    write_counter(
+        &mut out,
+        "ezlogin_sync_events_total",
+        "Number of timeline events received.",
+        sync.events,
+    );
+    write_histogram(
+        &mut out,
+        "ezlogin_sync_events_per_response",
+        "Number of timeline events per sync response.",
+        &sync.events_per_response,
+    );
+    write_histogram(
+        &mut out,
+        "ezlogin_sync_duration_seconds",
+        "Time each sync request took, including long-polling.",
+        &sync.sync_duration_seconds,
+    );
+    if let Some(last_sync_response) = sync.last_sync_response {
+        let timestamp = last_sync_response
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        write_gauge(
+            &mut out,
+            "ezlogin_sync_last_response_timestamp_seconds",
+            "Unix time of the last successful sync response.",
+            timestamp,
+        );
+    }
+
+    let events = event_metrics();
+    _ = writeln!(
+        out,
+        "# HELP ezlogin_handler_calls_total Number of event handler calls."
+    );
+    _ = writeln!(out, "# TYPE ezlogin_handler_calls_total counter");
+    for (handler, count) in &events.handler_calls {
+        _ = writeln!(
+            out,
+            "ezlogin_handler_calls_total{{handler=\"{}\"}} {}",
+            escape_label(handler),
+            count
+        );
+    }
+    _ = writeln!(out, "# HELP ezlogin_sends_total Number of sent messages.");
+    _ = writeln!(out, "# TYPE ezlogin_sends_total counter");
+    _ = writeln!(
+        out,
+        "ezlogin_sends_total{{result=\"success\"}} {}",
+        events.sends_succeeded
+    );
+    _ = writeln!(
+        out,
+        "ezlogin_sends_total{{result=\"failure\"}} {}",
+        events.sends_failed
+    );
+    write_counter(
+        &mut out,
+        "ezlogin_utd_events_total",
+        "Number of events that were unable to decrypt when they arrived.",
+        events.utd_events,
+    );
+    write_counter(
+        &mut out,
+        "ezlogin_late_decryptions_total",
+        "Number of undecryptable events whose room key arrived later.",
+        events.late_decryptions,
+    );
+    write_counter(
+        &mut out,
+        "ezlogin_backup_key_downloads_total",
+        "Number of room keys of undecryptable events downloaded from the server-side backup.",
+        events.backup_key_downloads,
+    );
+    write_counter(
+        &mut out,
+        "ezlogin_backup_key_uploads_total",
+        "Number of received room keys uploaded to the server-side backup.",
+        events.backup_key_uploads,
+    );
+    write_gauge(
+        &mut out,
+        "ezlogin_backup_pending_keys",
+        "Number of received room keys waiting to be uploaded to the server-side backup.",
+        events.backup_pending_keys as f64,
+    );
+    let backup_lag = events
+        .backup_pending_since
+        .and_then(|since| sync_helper.clock.now().duration_since(since).ok())
+        .unwrap_or_default();
+    write_gauge(
+        &mut out,
+        "ezlogin_backup_lag_seconds",
+        "Time since the oldest room key waiting for the server-side backup was received.",
+        backup_lag.as_secs_f64(),
+    );
+    write_counter(
+        &mut out,
+        "ezlogin_unverified_device_sends_total",
+        "Number of messages sent to encrypted rooms with unverified devices.",
+        events.unverified_device_sends,
+    );
+    write_counter(
+        &mut out,
+        "ezlogin_supervisor_restarts_total",
+        "Number of times the bot was restarted after a crash.",
+        events.supervisor_restarts,
+    );
+
+    let backup_state = client.encryption().backups().state();
+    _ = writeln!(
+        out,
+        "# HELP ezlogin_backup_state State of the server-side key backup."
+    );
+    _ = writeln!(out, "# TYPE ezlogin_backup_state gauge");
+    for (name, state) in [
+        ("unknown", BackupState::Unknown),
+        ("creating", BackupState::Creating),
+        ("enabling", BackupState::Enabling),
+        ("resuming", BackupState::Resuming),
+        ("enabled", BackupState::Enabled),
+        ("downloading", BackupState::Downloading),
+        ("disabling", BackupState::Disabling),
+    ] {
+        _ = writeln!(
+            out,
+            "ezlogin_backup_state{{state=\"{}\"}} {}",
+            name,
+            u8::from(backup_state == state)
+        );
+    }
+    out
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    _ = writeln!(out, "# HELP {} {}", name, help);
+    _ = writeln!(out, "# TYPE {} counter", name);
+    _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    _ = writeln!(out, "# HELP {} {}", name, help);
+    _ = writeln!(out, "# TYPE {} gauge", name);
+    _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    _ = writeln!(out, "# HELP {} {}", name, help);
+    _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bound, count) in &histogram.buckets {
+        _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+    }
+    _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+    _ = writeln!(out, "{}_sum {}", name, histogram.sum);
+    _ = writeln!(out, "{}_count {}", name, histogram.count);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,34 @@
+use eyre::Result;
+use matrix_sdk::Client;
+use matrix_sdk::ruma::api::client::push::{RuleKind, get_pushrule_enabled, set_pushrule_enabled};
+use matrix_sdk::ruma::push::PredefinedOverrideRuleId;
+use tracing::{info, instrument};
+
+/// Turns push notifications for the whole account on or off, for every device, including ones an operator later logs into with Element.
+///
+/// [`setup`](crate::setup) turns them off, so bot traffic doesn't generate thousands of push notifications.
+/// It works by toggling the `.m.rule.master` push rule, which Element shows as "Enable notifications for this account".
+#[instrument(skip(client))]
+pub async fn set_push_notifications(client: &Client, enabled: bool) -> Result<()> {
+    // The master rule suppresses every notification while it is enabled
+    let request = set_pushrule_enabled::v3::Request::new(
+        RuleKind::Override,
+        PredefinedOverrideRuleId::Master.to_string(),
+        !enabled,
+    );
+    client.send(request).await?;
+    info!(
+        "Push notifications {}.",
+        if enabled { "enabled" } else { "disabled" }
+    );
+    Ok(())
+}
+
+/// Returns whether push notifications are turned on for the account. See [`set_push_notifications`].
+pub async fn push_notifications_enabled(client: &Client) -> Result<bool> {
+    let request = get_pushrule_enabled::v3::Request::new(
+        RuleKind::Override,
+        PredefinedOverrideRuleId::Master.to_string(),
+    );
+    Ok(!client.send(request).await?.enabled)
+}
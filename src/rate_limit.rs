@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use eyre::{Result, bail};
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use tracing::debug;
+
+use crate::mutex::MutexExt;
+use crate::{Clock, SyncHelper, SystemClock};
+
+/// Prune idle keyed buckets once there are more than this many.
+const MAX_IDLE_BUCKETS: usize = 1024;
+/// How long to wait if the wait time can't be represented, for example, because the rate is tiny.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Parameters of a token bucket for [`RateLimiter`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Number of messages that can be sent at once after being idle.
+    pub burst: u32,
+    /// Number of messages per second that can be sent in the long run.
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    /// Creates a [`RateLimit`].
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self { burst, per_second }
+    }
+
+    /// Returns an error if the bucket could never hand out a token.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.burst == 0 {
+            bail!("rate limit burst must not be zero");
+        }
+        if !(self.per_second.is_finite() && self.per_second > 0.0) {
+            bail!(
+                "rate limit per_second must be positive, got {}",
+                self.per_second
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Token buckets that limit outgoing messages, both for the whole account and for each room.
+///
+/// It keeps a bot reacting to a busy room from getting the whole account rate-limited by the homeserver.
+/// Clones share the same buckets, so give one limiter to everything that sends on behalf of the same account:
+/// * [`SyncHelper::set_rate_limiter`], or [`LoginOptions::rate_limiter`](crate::LoginOptions::rate_limiter), for [`SendQueue`](crate::SendQueue),
+///   and for the notices sent by [`RoomKeyRequestPolicy`](crate::RoomKeyRequestPolicy) and [`VerificationPolicy`](crate::VerificationPolicy).
+/// * [`Commands::rate_limiter`](crate::Commands::rate_limiter), [`Router::rate_limiter`](crate::Router::rate_limiter), [`AntiFlood::rate_limiter`](crate::AntiFlood::rate_limiter),
+///   [`Canary::rate_limiter`](crate::Canary::rate_limiter), [`Webhook::rate_limiter`](crate::Webhook::rate_limiter), and [`MediaOptions::rate_limiter`](crate::MediaOptions::rate_limiter).
+/// * The `rate_limiter` argument of [`react`](crate::react), [`start_poll`](crate::start_poll) and the other poll functions, [`edit_message`](crate::edit_message),
+///   [`Canary::echo`](crate::Canary::echo), and [`forward_server_events`](crate::forward_server_events).
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::{RateLimit, RateLimiter};
+///
+/// # fn example(sync_helper: &matrixbot_ezlogin::SyncHelper) -> color_eyre::Result<()> {
+/// sync_helper.set_rate_limiter(Some(RateLimiter::new(
+///     RateLimit::new(20, 2.0),
+///     RateLimit::new(5, 0.5),
+/// )?));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    account_limit: RateLimit,
+    room_limit: RateLimit,
+    inner: Arc<Mutex<RateLimiterInner>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug)]
+struct RateLimiterInner {
+    account: Bucket,
+    rooms: Buckets<OwnedRoomId>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Bucket {
+    pub(crate) tokens: f64,
+    last_refill: SystemTime,
+}
+
+/// Token buckets sharing one [`RateLimit`], by key, for example, one per room.
+#[derive(Debug)]
+pub(crate) struct Buckets<K> {
+    limit: RateLimit,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl RateLimiter {
+    /// Creates a [`RateLimiter`] with limits for the whole account and for each room.
+    ///
+    /// Fails if the burst of either limit is zero, or its rate is not positive.
+    pub fn new(account_limit: RateLimit, room_limit: RateLimit) -> Result<Self> {
+        account_limit.validate()?;
+        room_limit.validate()?;
+        Ok(Self::new_unchecked(account_limit, room_limit))
+    }
+
+    fn new_unchecked(account_limit: RateLimit, room_limit: RateLimit) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Self {
+            account_limit,
+            room_limit,
+            inner: Arc::new(Mutex::new(RateLimiterInner {
+                account: Bucket::new(account_limit, clock.now()),
+                rooms: Buckets::new(room_limit),
+            })),
+            clock,
+        }
+    }
+
+    /// Refills the buckets and waits on `clock`. Defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Waits until a message can be sent to `room_id`, then takes a token from both buckets.
+    pub async fn acquire(&self, room_id: &RoomId) {
+        loop {
+            match self.try_acquire(room_id) {
+                Ok(()) => return,
+                Err(wait) => {
+                    debug!("Rate limited in room {}, waiting {:?}.", room_id, wait);
+                    self.clock.sleep(wait).await;
+                }
+            }
+        }
+    }
+
+    /// Takes a token from both buckets if possible. Otherwise, returns how long to wait before trying again.
+    pub fn try_acquire(&self, room_id: &RoomId) -> Result<(), Duration> {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock_unpoisoned();
+        let inner = &mut *inner;
+        inner.account.refill(self.account_limit, now);
+        let account_wait = inner.account.wait(self.account_limit);
+        let room = inner.rooms.get(room_id.to_owned(), now);
+        let room_wait = room.wait(self.room_limit);
+        if room_wait.is_zero() && account_wait.is_zero() {
+            room.tokens -= 1.0;
+            inner.account.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(room_wait.max(account_wait))
+    }
+}
+
+impl Default for RateLimiter {
+    /// Allows bursts of 10 messages per account and 5 per room, refilled at 1 message per second per account and 1 every 2 seconds per room.
+    fn default() -> Self {
+        Self::new_unchecked(RateLimit::new(10, 1.0), RateLimit::new(5, 0.5))
+    }
+}
+
+impl Bucket {
+    pub(crate) fn new(limit: RateLimit, now: SystemTime) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            last_refill: now,
+        }
+    }
+
+    pub(crate) fn refill(&mut self, limit: RateLimit, now: SystemTime) {
+        let elapsed = now.duration_since(self.last_refill).unwrap_or_default();
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * limit.per_second)
+            .min(f64::from(limit.burst.max(1)));
+        self.last_refill = now;
+    }
+
+    pub(crate) fn wait(&self, limit: RateLimit) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::try_from_secs_f64((1.0 - self.tokens) / limit.per_second)
+            .unwrap_or(MAX_WAIT)
+            .min(MAX_WAIT)
+    }
+}
+
+impl<K: Eq + Hash> Buckets<K> {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Returns the refilled bucket of `key`. Full buckets are the same as new ones, so they are pruned once there are many.
+    pub(crate) fn get(&mut self, key: K, now: SystemTime) -> &mut Bucket {
+        let limit = self.limit;
+        if self.buckets.len() > MAX_IDLE_BUCKETS {
+            self.buckets.retain(|_, bucket| {
+                bucket.refill(limit, now);
+                bucket.tokens < f64::from(limit.burst)
+            });
+        }
+        let bucket = self
+            .buckets
+            .entry(key)
+            .or_insert_with(|| Bucket::new(limit, now));
+        bucket.refill(limit, now);
+        bucket
+    }
+}
+
+impl SyncHelper {
+    /// Sets the rate limiter of the account, used by the send helpers that have access to this [`SyncHelper`]. Defaults to none.
+    pub fn set_rate_limiter(&self, rate_limiter: Option<RateLimiter>) {
+        self.inner.lock_unpoisoned().rate_limiter = rate_limiter;
+    }
+
+    /// Returns the rate limiter set by [`SyncHelper::set_rate_limiter`].
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.inner.lock_unpoisoned().rate_limiter.clone()
+    }
+}
+
+/// Waits for `rate_limiter`, if any, before sending to `room_id`.
+pub(crate) async fn acquire(rate_limiter: Option<&RateLimiter>, room_id: &RoomId) {
+    if let Some(rate_limiter) = rate_limiter {
+        rate_limiter.acquire(room_id).await;
+    }
+}
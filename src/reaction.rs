@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use eyre::Result;
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::{IncludeRelations, RelationsOptions, Room};
+use matrix_sdk::ruma::events::reaction::{OriginalSyncReactionEvent, ReactionEventContent};
+use matrix_sdk::ruma::events::relation::{Annotation, RelationType};
+use matrix_sdk::ruma::events::room::redaction::OriginalSyncRoomRedactionEvent;
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+};
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedUserId};
+use matrix_sdk::{Client, RoomState};
+use tracing::{Instrument, error};
+
+use crate::RateLimiter;
+use crate::mutex::MutexExt;
+
+/// How many reactions [`on_reaction`] remembers, so it can tell which reaction a redaction removes.
+const REMEMBERED_REACTIONS: usize = 4096;
+
+/// A reaction added or removed, delivered by [`on_reaction`].
+#[derive(Clone, Debug)]
+pub struct ReactionChange {
+    /// The client that received the change.
+    pub client: Client,
+    /// The room where the reaction was sent.
+    pub room: Room,
+    /// Who reacted.
+    pub sender: OwnedUserId,
+    /// The event reacted to.
+    pub target: OwnedEventId,
+    /// The reaction key, usually an emoji, for example, `"✅"`.
+    pub key: String,
+    /// The reaction event.
+    pub reaction_event_id: OwnedEventId,
+    /// `true` if the reaction was added, `false` if it was removed by redacting it.
+    pub added: bool,
+}
+
+/// Reacts to `event_id` in `room` with `key`, usually an emoji, after waiting for `rate_limiter`, if any.
+pub async fn react(
+    room: &Room,
+    event_id: &EventId,
+    key: &str,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<OwnedEventId> {
+    let content = ReactionEventContent::new(Annotation::new(event_id.to_owned(), key.to_owned()));
+    crate::send::send_content(room, content, rate_limiter).await
+}
+
+/// Returns who reacted to `event_id` with each key.
+///
+/// Removed reactions are not counted. Each user is listed at most once per key.
+pub async fn reactions(
+    room: &Room,
+    event_id: &EventId,
+) -> Result<BTreeMap<String, Vec<OwnedUserId>>> {
+    let mut aggregated = BTreeMap::<String, Vec<OwnedUserId>>::new();
+    let mut from = None;
+    loop {
+        let relations = room
+            .relations(
+                event_id.to_owned(),
+                RelationsOptions {
+                    from,
+                    include_relations: IncludeRelations::RelationsOfType(RelationType::Annotation),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        for event in relations.chunk {
+            let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(
+                SyncMessageLikeEvent::Original(event),
+            ))) = event.raw().deserialize()
+            else {
+                continue;
+            };
+            let users = aggregated.entry(event.content.relates_to.key).or_default();
+            if !users.contains(&event.sender) {
+                users.push(event.sender);
+            }
+        }
+        match relations.next_batch {
+            Some(next_batch) => from = Some(next_batch),
+            None => return Ok(aggregated),
+        }
+    }
+}
+
+/// Registers event handlers on `client` that call `handler` whenever someone else adds or removes a reaction in a joined room.
+///
+/// Removals are only reported for reactions added while the handler was registered, because a redacted reaction no longer says what it reacted to.
+///
+/// # Example
+///
+/// Approving an action when a moderator reacts with ✅:
+///
+/// ```no_run
+/// # fn example(client: &matrix_sdk::Client, moderators: Vec<matrix_sdk::ruma::OwnedUserId>) {
+/// matrixbot_ezlogin::on_reaction(client, move |change| {
+///     let moderators = moderators.clone();
+///     async move {
+///         if change.added && change.key == "✅" && moderators.contains(&change.sender) {
+///             tracing::info!("{} approved {}.", change.sender, change.target);
+///         }
+///         Ok(())
+///     }
+/// });
+/// # }
+/// ```
+pub fn on_reaction<F, Fut>(client: &Client, handler: F) -> [EventHandlerHandle; 2]
+where
+    F: Fn(ReactionChange) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let remembered = Arc::new(Mutex::new(RememberedReactions::default()));
+
+    let added = {
+        let handler = handler.clone();
+        let remembered = remembered.clone();
+        client.add_event_handler(
+            move |event: OriginalSyncReactionEvent, room: Room, client: Client| {
+                let handler = handler.clone();
+                let remembered = remembered.clone();
+                async move {
+                    if Some(&*event.sender) == client.user_id() || room.state() != RoomState::Joined
+                    {
+                        return;
+                    }
+                    let change = ReactionChange {
+                        client,
+                        room,
+                        sender: event.sender,
+                        target: event.content.relates_to.event_id,
+                        key: event.content.relates_to.key,
+                        reaction_event_id: event.event_id,
+                        added: true,
+                    };
+                    remembered.lock_unpoisoned().insert(&change);
+                    run_handler(&handler, change).await;
+                }
+            },
+        )
+    };
+
+    let removed = client.add_event_handler(
+        move |event: OriginalSyncRoomRedactionEvent, room: Room, client: Client| {
+            let handler = handler.clone();
+            let remembered = remembered.clone();
+            async move {
+                if room.state() != RoomState::Joined {
+                    return;
+                }
+                let Some(redacts) = event.content.redacts.or(event.redacts) else {
+                    return;
+                };
+                let Some((sender, target, key)) = remembered.lock_unpoisoned().remove(&redacts)
+                else {
+                    return;
+                };
+                let change = ReactionChange {
+                    client,
+                    room,
+                    sender,
+                    target,
+                    key,
+                    reaction_event_id: redacts,
+                    added: false,
+                };
+                run_handler(&handler, change).await;
+            }
+        },
+    );
+
+    [added, removed]
+}
+
+async fn run_handler<F, Fut>(handler: &F, change: ReactionChange)
+where
+    F: Fn(ReactionChange) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let (room_id, event_id) = (
+        change.room.room_id().to_owned(),
+        change.reaction_event_id.clone(),
+    );
+    if let Err(err) = handler(change).in_current_span().await {
+        error!(
+            "Reaction handler failed in room {}, event {}: {:?}",
+            room_id, event_id, err
+        );
+    }
+}
+
+/// A bounded map from reaction event IDs to their sender, target, and key.
+#[derive(Default)]
+struct RememberedReactions {
+    reactions: HashMap<OwnedEventId, (OwnedUserId, OwnedEventId, String)>,
+    order: VecDeque<OwnedEventId>,
+}
+
+impl RememberedReactions {
+    fn insert(&mut self, change: &ReactionChange) {
+        if self.order.len() >= REMEMBERED_REACTIONS
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.reactions.remove(&oldest);
+        }
+        self.order.push_back(change.reaction_event_id.clone());
+        self.reactions.insert(
+            change.reaction_event_id.clone(),
+            (
+                change.sender.clone(),
+                change.target.clone(),
+                change.key.clone(),
+            ),
+        );
+    }
+
+    fn remove(&mut self, event_id: &EventId) -> Option<(OwnedUserId, OwnedEventId, String)> {
+        // The stale entry in `order` is dropped once it reaches the front
+        self.reactions.remove(event_id)
+    }
+}
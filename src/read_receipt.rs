@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::{Receipts, Room};
+use matrix_sdk::ruma::events::AnySyncMessageLikeEvent;
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId};
+use matrix_sdk::{Client, RoomState};
+use tracing::{Instrument, debug, error, instrument};
+
+use crate::mutex::MutexExt;
+use crate::{Clock, SystemClock};
+
+/// When [`ReadReceipts`] sends read receipts and read markers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadReceiptPolicy {
+    /// Sends a read receipt for every event as soon as it arrives.
+    #[default]
+    Immediately,
+    /// Remembers the latest event of each room, and sends one read receipt per room at this interval.
+    ///
+    /// This drastically cuts the number of requests in busy rooms.
+    Batched(Duration),
+    /// Never sends read receipts.
+    Disabled,
+}
+
+/// Marks incoming events as read, so users can see the bot has received their messages.
+///
+/// Both the public read receipt and the fully-read marker are moved.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use matrixbot_ezlogin::{ReadReceiptPolicy, ReadReceipts};
+///
+/// # fn example(client: &matrix_sdk::Client) {
+/// let read_receipts = ReadReceipts::new(ReadReceiptPolicy::Batched(Duration::from_secs(5)));
+/// read_receipts.register(client);
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReadReceipts {
+    policy: ReadReceiptPolicy,
+    pending: Arc<Mutex<HashMap<OwnedRoomId, (Room, OwnedEventId)>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ReadReceipts {
+    /// Creates a [`ReadReceipts`] with a [`ReadReceiptPolicy`].
+    pub fn new(policy: ReadReceiptPolicy) -> Self {
+        Self {
+            policy,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Waits between batches on `clock`. Defaults to [`SystemClock`]. Call it before [`ReadReceipts::register`].
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Registers an event handler on `client` that marks every message-like event from other users as read, according to the policy.
+    ///
+    /// With [`ReadReceiptPolicy::Batched`], it also starts a background task that sends the batched receipts,
+    /// which stops once the event handler is removed and every clone of this [`ReadReceipts`] is dropped.
+    pub fn register(&self, client: &Client) -> EventHandlerHandle {
+        if let ReadReceiptPolicy::Batched(interval) = self.policy {
+            let pending = Arc::downgrade(&self.pending);
+            tokio::spawn(
+                Self::flush_periodically(pending, self.clock.clone(), interval).in_current_span(),
+            );
+        }
+        let read_receipts = self.clone();
+        client.add_event_handler(
+            move |event: AnySyncMessageLikeEvent, room: Room, client: Client| {
+                let read_receipts = read_receipts.clone();
+                async move {
+                    if Some(event.sender()) == client.user_id() || room.state() != RoomState::Joined
+                    {
+                        return;
+                    }
+                    read_receipts
+                        .mark_read(&room, event.event_id().to_owned())
+                        .await;
+                }
+            },
+        )
+    }
+
+    /// Marks `event_id` and everything before it in `room` as read, according to the policy.
+    ///
+    /// With [`ReadReceiptPolicy::Immediately`], the receipt is sent in a separate Tokio task.
+    pub async fn mark_read(&self, room: &Room, event_id: OwnedEventId) {
+        match self.policy {
+            ReadReceiptPolicy::Immediately => {
+                // Spawned, so a slow homeserver doesn't hold up the sync loop
+                let room = room.clone();
+                tokio::spawn(async move { send_receipts(&room, event_id).await }.in_current_span());
+            }
+            ReadReceiptPolicy::Batched(_) => {
+                self.pending
+                    .lock_unpoisoned()
+                    .insert(room.room_id().to_owned(), (room.clone(), event_id));
+            }
+            ReadReceiptPolicy::Disabled => (),
+        }
+    }
+
+    /// Sends the batched read receipts now, for example before shutting down.
+    pub async fn flush(&self) {
+        Self::flush_pending(&self.pending).await;
+    }
+
+    async fn flush_periodically(
+        pending: Weak<Mutex<HashMap<OwnedRoomId, (Room, OwnedEventId)>>>,
+        clock: Arc<dyn Clock>,
+        interval: Duration,
+    ) {
+        loop {
+            clock.sleep(interval).await;
+            let Some(pending) = pending.upgrade() else {
+                return;
+            };
+            Self::flush_pending(&pending).await;
+        }
+    }
+
+    async fn flush_pending(pending: &Mutex<HashMap<OwnedRoomId, (Room, OwnedEventId)>>) {
+        let batch = std::mem::take(&mut *pending.lock_unpoisoned());
+        if !batch.is_empty() {
+            debug!("Sending read receipts to {} rooms.", batch.len());
+        }
+        for (room, event_id) in batch.into_values() {
+            send_receipts(&room, event_id).await;
+        }
+    }
+}
+
+impl Default for ReadReceipts {
+    fn default() -> Self {
+        Self::new(ReadReceiptPolicy::default())
+    }
+}
+
+#[instrument(skip_all)]
+async fn send_receipts(room: &Room, event_id: OwnedEventId) {
+    if let Err(err) = room
+        .send_multiple_receipts(
+            Receipts::new()
+                .fully_read_marker(event_id.clone())
+                .public_read_receipt(event_id.clone()),
+        )
+        .await
+    {
+        error!(
+            "Failed to set the read marker of room {} to event {}: {}",
+            room.room_id(),
+            event_id,
+            err
+        );
+    }
+}
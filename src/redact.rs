@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use eyre::Result;
+use matrix_sdk::Client;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::redaction::OriginalSyncRoomRedactionEvent;
+use matrix_sdk::ruma::{EventId, OwnedEventId};
+use tracing::{Instrument, debug, error};
+
+use crate::middleware::MiddlewareFuture;
+use crate::mutex::MutexExt;
+use crate::{Clock, DispatchEvent, EventContext, Middleware, Next, NotAllowed, SystemClock};
+
+/// How long [`SkipRedacted`] remembers a redaction.
+const REDACTION_MEMORY: Duration = Duration::from_secs(300);
+
+type RedactedCallback = Arc<dyn Fn(Room, OwnedEventId, Option<String>) + Send + Sync>;
+
+/// Redacts `event` in `room`, after checking that the bot has the power level to do so.
+///
+/// Redacting the bot's own events and other users' events require different power levels. Failing the check returns an error without sending a request.
+pub async fn redact<E: DispatchEvent>(
+    room: &Room,
+    event: &E,
+    reason: Option<&str>,
+) -> Result<OwnedEventId> {
+    let own_user_id = room.own_user_id();
+    let allowed = if event.sender() == own_user_id {
+        room.can_user_redact_own(own_user_id).await?
+    } else {
+        room.can_user_redact_other(own_user_id).await?
+    };
+    if !allowed {
+        return Err(NotAllowed {
+            room_id: room.room_id().to_owned(),
+            action: format!("redact event {}", event.event_id()),
+        }
+        .into());
+    }
+    Ok(room.redact(event.event_id(), reason, None).await?.event_id)
+}
+
+/// Middleware for [`Dispatcher`](crate::Dispatcher) that drops events redacted shortly after they arrive, so the bot doesn't act on retracted content.
+///
+/// Each event is held back for a short delay, 500 milliseconds by default, which covers redactions in the same sync response.
+/// The rest of the chain then runs in a separate Tokio task, unless the event was redacted in the meantime.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+/// use matrixbot_ezlogin::{Dispatcher, SkipRedacted};
+///
+/// # fn example(client: &matrix_sdk::Client, sync_helper: &matrixbot_ezlogin::SyncHelper) {
+/// let skip_redacted = SkipRedacted::new(client).on_redacted(|room, event_id, reason| {
+///     tracing::info!("Event {} in room {} was redacted: {:?}", event_id, room.room_id(), reason);
+/// });
+/// Dispatcher::<OriginalSyncRoomMessageEvent>::new()
+///     .with(skip_redacted)
+///     .register(client, sync_helper, "handler", |ctx| async move { Ok(()) });
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SkipRedacted {
+    redacted: Arc<Mutex<HashMap<OwnedEventId, (Option<String>, SystemTime)>>>,
+    delay: Duration,
+    on_redacted: Option<RedactedCallback>,
+    clock: Arc<dyn Clock>,
+}
+
+impl SkipRedacted {
+    /// Creates a [`SkipRedacted`], and registers an event handler on `client` to watch for redactions.
+    pub fn new(client: &Client) -> Self {
+        Self::with_clock(client, Arc::new(SystemClock))
+    }
+
+    /// Same as [`SkipRedacted::new`], but holds events back and expires redactions on `clock`.
+    pub fn with_clock(client: &Client, clock: Arc<dyn Clock>) -> Self {
+        let redacted = Arc::new(Mutex::new(HashMap::<
+            OwnedEventId,
+            (Option<String>, SystemTime),
+        >::new()));
+        let weak = Arc::downgrade(&redacted);
+        let handler_clock = clock.clone();
+        client.add_event_handler(move |event: OriginalSyncRoomRedactionEvent| {
+            let weak = weak.clone();
+            let clock = handler_clock.clone();
+            async move {
+                let Some(redacted) = weak.upgrade() else {
+                    return;
+                };
+                let Some(redacts) = event.content.redacts.or(event.redacts) else {
+                    return;
+                };
+                let now = clock.now();
+                let mut redacted = redacted.lock_unpoisoned();
+                redacted.retain(|_, (_, time)| {
+                    now.duration_since(*time).unwrap_or_default() < REDACTION_MEMORY
+                });
+                redacted.insert(redacts, (event.content.reason, now));
+            }
+        });
+        Self {
+            redacted,
+            delay: Duration::from_millis(500),
+            on_redacted: None,
+            clock,
+        }
+    }
+
+    /// How long each event is held back before running the rest of the chain.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Calls `callback` with the room, the event ID, and the redaction reason, instead of the rest of the chain, when a redacted event is dropped.
+    pub fn on_redacted<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(Room, OwnedEventId, Option<String>) + Send + Sync + 'static,
+    {
+        self.on_redacted = Some(Arc::new(callback));
+        self
+    }
+
+    /// Returns whether a redaction of `event_id` was seen recently, and its reason.
+    pub fn redaction(&self, event_id: &EventId) -> Option<Option<String>> {
+        self.redacted
+            .lock_unpoisoned()
+            .get(event_id)
+            .map(|(reason, _)| reason.clone())
+    }
+}
+
+impl<E: DispatchEvent> Middleware<E> for SkipRedacted {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        let this = self.clone();
+        Box::pin(async move {
+            tokio::spawn(
+                async move {
+                    this.clock.sleep(this.delay).await;
+                    let event_id = ctx.event.event_id().to_owned();
+                    if let Some(reason) = this.redaction(&event_id) {
+                        debug!(
+                            "Ignoring room {}, event {}: Redacted.",
+                            ctx.room.room_id(),
+                            event_id
+                        );
+                        if let Some(on_redacted) = &this.on_redacted {
+                            on_redacted(ctx.room, event_id, reason);
+                        }
+                        return;
+                    }
+                    let handler = ctx.handler.clone();
+                    if let Err(err) = next.run(ctx).await {
+                        error!(
+                            "Handler {} failed on event {}: {:?}",
+                            handler, event_id, err
+                        );
+                    }
+                }
+                .in_current_span(),
+            );
+            Ok(())
+        })
+    }
+}
+
+impl fmt::Debug for SkipRedacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SkipRedacted")
+            .field("delay", &self.delay)
+            .field("on_redacted", &self.on_redacted.is_some())
+            .field("clock", &self.clock)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,105 @@
+use std::future::Future;
+use std::time::SystemTime;
+
+use eyre::Result;
+use matrix_sdk::Client;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use serde_json::json;
+
+use crate::{Schedule, ScheduledTask, Scheduler};
+
+/// The job name reserved for reminders.
+const REMINDER_JOB: &str = "matrixbot-ezlogin.reminder";
+
+/// A one-time reminder set by [`Scheduler::remind`].
+#[derive(Clone, Debug)]
+pub struct Reminder {
+    /// The task ID, returned by [`Scheduler::remind`].
+    pub id: i64,
+    /// The room the reminder belongs to.
+    pub room_id: OwnedRoomId,
+    /// Arbitrary data, for example, the text to remind of.
+    pub payload: String,
+    /// When the reminder was due.
+    ///
+    /// If the bot was not running at that time, the reminder is delivered as soon as it starts again, so this can be long ago.
+    /// Compare it with the current time to decide whether to apologize for the delay, or to drop outdated reminders.
+    pub due: SystemTime,
+}
+
+impl Scheduler {
+    /// Registers the handler that receives every reminder set by [`Scheduler::remind`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::time::{Duration, SystemTime};
+    ///
+    /// use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+    /// use matrixbot_ezlogin::Scheduler;
+    ///
+    /// # async fn example(client: matrix_sdk::Client, sync_helper: matrixbot_ezlogin::SyncHelper, room_id: &matrix_sdk::ruma::RoomId) -> color_eyre::Result<()> {
+    /// let scheduler = Scheduler::new(&sync_helper).reminders(|client, reminder| async move {
+    ///     if let Some(room) = client.get_room(&reminder.room_id) {
+    ///         room.send(RoomMessageEventContent::notice_plain(reminder.payload)).await?;
+    ///     }
+    ///     Ok(())
+    /// });
+    /// scheduler.remind(room_id, SystemTime::now() + Duration::from_secs(3600), "Stand up and stretch")?;
+    /// scheduler.run(&client).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reminders<F, Fut>(self, handler: F) -> Self
+    where
+        F: Fn(Client, Reminder) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.job(REMINDER_JOB, move |client, task| {
+            let fut = Reminder::from_task(&task).map(|reminder| handler(client, reminder));
+            async move { fut?.await }
+        })
+    }
+
+    /// Stores a one-time reminder for `room_id` at `when`, and returns its task ID.
+    ///
+    /// The reminder is written to the state database before returning. It is delivered to the handler registered with [`Scheduler::reminders`]
+    /// while [`Scheduler::run`] is running. A reminder missed while the bot was not running is delivered once, right after the next start.
+    ///
+    /// Reminders are based on the wall clock. If the system clock is adjusted, they still fire at the intended time, at most one minute late.
+    ///
+    /// Cancel a reminder with [`Scheduler::cancel`].
+    pub fn remind(
+        &self,
+        room_id: &RoomId,
+        when: SystemTime,
+        payload: impl Into<String>,
+    ) -> Result<i64> {
+        let payload = json!({
+            "room_id": room_id,
+            "payload": payload.into(),
+        });
+        self.schedule(REMINDER_JOB, Schedule::At(when), payload.to_string())
+    }
+
+    /// Returns every pending reminder, ordered by when they fire.
+    pub fn pending_reminders(&self) -> Result<Vec<Reminder>> {
+        self.tasks()?
+            .into_iter()
+            .filter(|task| task.job == REMINDER_JOB)
+            .map(|task| Reminder::from_task(&task))
+            .collect()
+    }
+}
+
+impl Reminder {
+    fn from_task(task: &ScheduledTask) -> Result<Self> {
+        let value = serde_json::from_str::<serde_json::Value>(&task.payload)?;
+        Ok(Reminder {
+            id: task.id,
+            room_id: serde_json::from_value(value["room_id"].clone())?,
+            payload: serde_json::from_value(value["payload"].clone())?,
+            due: task.due,
+        })
+    }
+}
@@ -0,0 +1,245 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use eyre::{Result, bail};
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::api::Direction;
+use matrix_sdk::{Client, RoomMemberships};
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::commands::next_token;
+use crate::{DuplexLog, MessageBuilder};
+
+const HELP: &str = "Commands:
+  rooms                    List the rooms the bot is in
+  members <room>           List the joined members of a room, with their power levels
+  events <room> [count]    Decrypt and print the latest events of a room, 10 by default
+  send <room> <text>       Send a plain text notice to a room
+  sync                     Sync once, without saving the sync token, and list the rooms with new events
+  help                     Show this help
+  quit                     Leave the REPL
+A room is a room ID (!room:example.org) or an alias (#room:example.org).";
+
+/// Runs an interactive shell over the session stored in `data_dir`, to diagnose what the bot sees.
+///
+/// It can list rooms and members, decrypt and print recent events, and send test messages. Type `help` for the list of commands.
+///
+/// With `read_only`, the session is restored with [`login_read_only`](crate::login_read_only), so the bot can keep running at the same time,
+/// and `events`, `send`, and `sync` are disabled. Otherwise, the bot must not be running, but the sync token is still left untouched.
+#[instrument(skip(data_dir))]
+pub async fn debug_repl(data_dir: &Path, read_only: bool) -> Result<()> {
+    // Declared first, so the snapshot is deleted after the client is dropped
+    let mut _read_only_session = None;
+    let client = if read_only {
+        let session = crate::login_read_only(data_dir).await?;
+        let client = Client::clone(&session);
+        _read_only_session = Some(session);
+        client
+    } else {
+        let (client, sync_helper) = crate::login(data_dir).await?;
+        // Catch up from the bot's position, so the room list is current
+        let mut sync_settings = SyncSettings::default().timeout(Duration::ZERO);
+        if let Some(token) = sync_helper.get_sync_token() {
+            sync_settings = sync_settings.token(token);
+        }
+        client.sync_once(sync_settings).await?;
+        client
+    };
+    print(&format!(
+        "Logged in as {}. Type \"help\" for the list of commands.",
+        client
+            .user_id()
+            .map(|user_id| user_id.as_str())
+            .unwrap_or_default()
+    ));
+
+    loop {
+        let line = match DuplexLog::readline("ezlogin> ").await {
+            Ok(line) => line,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut args = line.as_str();
+        let Some(command) = next_token(&mut args) else {
+            continue;
+        };
+        let result = match command.to_lowercase().as_str() {
+            "rooms" => rooms(&client).await,
+            "members" => members(&client, args, read_only).await,
+            // Fetching and decrypting events writes to the SDK stores
+            "events" if read_only => Err(eyre::eyre!("events is disabled in read-only mode")),
+            "events" => events(&client, args).await,
+            "send" if read_only => Err(eyre::eyre!("send is disabled in read-only mode")),
+            "send" => send(&client, args).await,
+            "sync" if read_only => Err(eyre::eyre!("sync is disabled in read-only mode")),
+            "sync" => sync(&client).await,
+            "help" | "?" => {
+                print(HELP);
+                Ok(())
+            }
+            "quit" | "exit" => return Ok(()),
+            _ => Err(eyre::eyre!(
+                "unknown command {:?}, type \"help\" for the list of commands",
+                command
+            )),
+        };
+        if let Err(err) = result {
+            print(&format!("Error: {}", err));
+        }
+    }
+}
+
+async fn rooms(client: &Client) -> Result<()> {
+    let mut rooms = client.rooms();
+    rooms.sort_by(|a, b| a.room_id().cmp(b.room_id()));
+    for room in rooms {
+        let encrypted = room
+            .latest_encryption_state()
+            .await
+            .is_ok_and(|state| state.is_encrypted());
+        print(&format!(
+            "{}\t{:?}\t{}\t{} member(s)\t{}",
+            room.room_id(),
+            room.state(),
+            if encrypted {
+                "encrypted"
+            } else {
+                "unencrypted"
+            },
+            room.joined_members_count(),
+            room.cached_display_name()
+                .map(|name| name.to_string())
+                .unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+async fn members(client: &Client, mut args: &str, read_only: bool) -> Result<()> {
+    let room = room_arg(client, &mut args).await?;
+    // Fetching members from the server writes them into the state store, which the running bot owns
+    let members = if read_only {
+        room.members_no_sync(RoomMemberships::JOIN).await?
+    } else {
+        room.members(RoomMemberships::JOIN).await?
+    };
+    for member in members {
+        print(&format!(
+            "{}\t{:?}\t{}",
+            member.user_id(),
+            member.power_level(),
+            member.display_name().unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+async fn events(client: &Client, mut args: &str) -> Result<()> {
+    let room = room_arg(client, &mut args).await?;
+    let count = match next_token(&mut args) {
+        Some(count) => count.parse()?,
+        None => 10,
+    };
+    let page = crate::history::messages(&room, None, Direction::Backward, count).await?;
+    // Print the oldest first, like a chat
+    for event in page.events.iter().rev() {
+        print(&describe(event));
+    }
+    Ok(())
+}
+
+async fn send(client: &Client, mut args: &str) -> Result<()> {
+    let room = room_arg(client, &mut args).await?;
+    let text = args.trim();
+    if text.is_empty() {
+        bail!("usage: send <room> <text>");
+    }
+    let event_id = crate::send::send_content(
+        &room,
+        MessageBuilder::notice().push_text(text).build(),
+        None,
+    )
+    .await?;
+    print(&format!("Sent event {}.", event_id));
+    Ok(())
+}
+
+async fn sync(client: &Client) -> Result<()> {
+    let response = client
+        .sync_once(SyncSettings::default().timeout(Duration::ZERO))
+        .await?;
+    for (room_id, room) in &response.rooms.joined {
+        print(&format!(
+            "{}\t{} timeline event(s){}",
+            room_id,
+            room.timeline.events.len(),
+            if room.timeline.limited {
+                ", more were skipped"
+            } else {
+                ""
+            }
+        ));
+    }
+    for room_id in response.rooms.invited.keys() {
+        print(&format!("{}\tinvited", room_id));
+    }
+    for room_id in response.rooms.left.keys() {
+        print(&format!("{}\tleft", room_id));
+    }
+    print(&format!("{} to-device event(s).", response.to_device.len()));
+    Ok(())
+}
+
+/// Takes a room ID or alias from `args`, and returns the room if the bot knows it.
+async fn room_arg(client: &Client, args: &mut &str) -> Result<Room> {
+    let Some(room) = next_token(args) else {
+        bail!("missing room ID or alias");
+    };
+    let room_id = crate::send::resolve_room(client, room).await?;
+    match client.get_room(&room_id) {
+        Some(room) => Ok(room),
+        None => bail!("the bot doesn't know room {}", room_id),
+    }
+}
+
+/// Formats one event as a line: time, sender, type, and body, noting whether it was decrypted.
+fn describe(event: &TimelineEvent) -> String {
+    let json = serde_json::from_str::<Value>(event.raw().json().get()).unwrap_or_default();
+    let field = |pointer: &str| {
+        json.pointer(pointer)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let time = json
+        .pointer("/origin_server_ts")
+        .and_then(Value::as_i64)
+        .and_then(chrono::DateTime::from_timestamp_millis)
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+        .unwrap_or_default();
+    let event_type = field("/type");
+    let encryption = if event.encryption_info().is_some() {
+        " (decrypted)"
+    } else if event_type == "m.room.encrypted" {
+        " (unable to decrypt)"
+    } else {
+        ""
+    };
+    format!(
+        "{} {} {} {}{}: {}",
+        time,
+        field("/event_id"),
+        field("/sender"),
+        event_type,
+        encryption,
+        field("/content/body")
+    )
+}
+
+fn print(text: &str) {
+    _ = writeln!(DuplexLog::get_writer(), "{}", text);
+}
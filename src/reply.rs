@@ -0,0 +1,149 @@
+use matrix_sdk::ruma::events::Mentions;
+use matrix_sdk::ruma::events::relation::{InReplyTo, Thread};
+use matrix_sdk::ruma::events::room::message::{
+    FormattedBody, MessageFormat, MessageType, NoticeMessageEventContent,
+    OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
+};
+
+/// Turns `content` into a rich reply to `event`, including the quoted fallback for clients that can't render rich replies.
+///
+/// If `event` is in a thread, the reply stays in the same thread. The sender of `event` is mentioned.
+///
+/// An `m.text` reply is turned into `m.notice`, so other bots don't reply back in an infinite loop.
+///
+/// <https://spec.matrix.org/v1.14/client-server-api/#rich-replies>
+pub fn reply_to(
+    event: &OriginalSyncRoomMessageEvent,
+    content: RoomMessageEventContent,
+) -> RoomMessageEventContent {
+    let mut reply = reply_without_fallback(event, content);
+    add_fallback(event, &mut reply);
+    reply.mentions = Some(Mentions::with_user_ids([event.sender.clone()]));
+    reply
+}
+
+/// Turns `content` into a reply to `event` inside a thread.
+///
+/// If `event` is already in a thread, the reply goes to that thread. Otherwise, a new thread is started with `event` as its root.
+///
+/// An `m.text` reply is turned into `m.notice`, so other bots don't reply back in an infinite loop.
+///
+/// <https://spec.matrix.org/v1.14/client-server-api/#threading>
+pub fn reply_in_thread(
+    event: &OriginalSyncRoomMessageEvent,
+    content: RoomMessageEventContent,
+) -> RoomMessageEventContent {
+    let mut reply = into_notice(content);
+    let root = match &event.content.relates_to {
+        Some(Relation::Thread(thread)) => thread.event_id.clone(),
+        _ => event.event_id.clone(),
+    };
+    reply.relates_to = Some(Relation::Thread(Thread::reply(
+        root,
+        event.event_id.clone(),
+    )));
+    reply
+}
+
+/// Turns `content` into a rich reply to `event`, without quoting `event` and without mentioning its sender.
+///
+/// If `event` is in a thread, the reply stays in the same thread.
+///
+/// An `m.text` reply is turned into `m.notice`, so other bots don't reply back in an infinite loop.
+pub fn reply_without_fallback(
+    event: &OriginalSyncRoomMessageEvent,
+    content: RoomMessageEventContent,
+) -> RoomMessageEventContent {
+    let mut reply = into_notice(content);
+    reply.relates_to = Some(match &event.content.relates_to {
+        Some(Relation::Thread(thread)) => Relation::Thread(Thread::reply(
+            thread.event_id.clone(),
+            event.event_id.clone(),
+        )),
+        _ => Relation::Reply {
+            in_reply_to: InReplyTo::new(event.event_id.clone()),
+        },
+    });
+    reply
+}
+
+/// Turns an `m.text` message into `m.notice`, keeping the formatted body. Other message types are returned unchanged.
+///
+/// Some bot implementations are designed to ignore `m.notice`, preventing infinite looping.
+/// Note that some clients may choose to render `m.notice` in a different text color.
+pub fn into_notice(mut content: RoomMessageEventContent) -> RoomMessageEventContent {
+    if let MessageType::Text(text) = content.msgtype {
+        let mut notice = NoticeMessageEventContent::plain(text.body);
+        notice.formatted = text.formatted;
+        content.msgtype = MessageType::Notice(notice);
+    }
+    content
+}
+
+/// Removes the quoted original message that older clients put in front of a reply.
+///
+/// <https://spec.matrix.org/v1.14/client-server-api/#fallbacks-for-rich-replies>
+pub(crate) fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    let mut rest = body;
+    while let Some(line) = rest.strip_prefix('>') {
+        rest = line.split_once('\n').map_or("", |(_, rest)| rest);
+    }
+    rest
+}
+
+fn add_fallback(event: &OriginalSyncRoomMessageEvent, reply: &mut RoomMessageEventContent) {
+    let (body, formatted) = match &mut reply.msgtype {
+        MessageType::Emote(content) => (&mut content.body, &mut content.formatted),
+        MessageType::Notice(content) => (&mut content.body, &mut content.formatted),
+        MessageType::Text(content) => (&mut content.body, &mut content.formatted),
+        _ => return,
+    };
+    let original = strip_reply_fallback(event.content.body()).trim_start();
+
+    let mut plain = String::new();
+    for (i, line) in original.lines().enumerate() {
+        if i == 0 {
+            plain.push_str(&format!("> <{}> {}\n", event.sender, line));
+        } else {
+            plain.push_str(&format!("> {}\n", line));
+        }
+    }
+    if plain.is_empty() {
+        plain.push_str(&format!("> <{}>\n", event.sender));
+    }
+    plain.push('\n');
+
+    let html_reply = match formatted.take() {
+        Some(formatted) if formatted.format == MessageFormat::Html => formatted.body,
+        _ => html_escape(body).replace('\n', "<br>"),
+    };
+    let html = format!(
+        "<mx-reply><blockquote>In reply to <a href=\"https://matrix.to/#/{}\">{}</a><br>{}</blockquote></mx-reply>{}",
+        event.sender,
+        event.sender,
+        html_escape(original).replace('\n', "<br>"),
+        html_reply
+    );
+
+    plain.push_str(body);
+    *body = plain;
+    *formatted = Some(FormattedBody::html(html));
+}
+
+pub(crate) fn html_escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use eyre::Result;
+use matrix_sdk::Client;
+use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::{AnySyncStateEvent, StateEventType};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, RoomId};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::mutex::MutexExt;
+
+/// Bot configuration stored in a custom state event of each room, so room moderators can configure the bot without access to the server it runs on.
+///
+/// The configuration is the content of the state event of the given type with an empty state key. Rooms without the event use [`Default::default`].
+/// Who can change it is controlled by the room's power levels, which by default require moderator level for state events.
+///
+/// Parsed configurations are cached, and updated whenever the state event changes.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::RoomConfig;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Clone, Default, Deserialize, Serialize)]
+/// struct Config {
+///     #[serde(default)]
+///     greeting: Option<String>,
+/// }
+///
+/// # async fn example(client: &matrix_sdk::Client, room: &matrix_sdk::Room) -> color_eyre::Result<()> {
+/// let config = RoomConfig::<Config>::new(client, "com.example.bot.config");
+/// if let Some(greeting) = config.config_for(room).await?.greeting {
+///     tracing::info!("Greeting in room {}: {}", room.room_id(), greeting);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct RoomConfig<T> {
+    event_type: Arc<str>,
+    cache: Arc<Mutex<HashMap<OwnedRoomId, T>>>,
+}
+
+impl<T> RoomConfig<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Default + Send + Sync + 'static,
+{
+    /// Creates a [`RoomConfig`] reading state events of `event_type`, for example, `com.example.bot.config`,
+    /// and registers an event handler on `client` to keep the cache up to date.
+    pub fn new(client: &Client, event_type: &str) -> Self {
+        let event_type = Arc::<str>::from(event_type);
+        let cache = Arc::new(Mutex::new(HashMap::<OwnedRoomId, T>::new()));
+        let weak = Arc::downgrade(&cache);
+        let handler_event_type = event_type.clone();
+        client.add_event_handler(move |raw: Raw<AnySyncStateEvent>, room: Room| {
+            let weak = weak.clone();
+            let event_type = handler_event_type.clone();
+            async move {
+                let Some(cache) = weak.upgrade() else {
+                    return;
+                };
+                let Ok(event) = serde_json::from_str::<Value>(raw.json().get()) else {
+                    return;
+                };
+                if event.get("type").and_then(Value::as_str) != Some(&*event_type)
+                    || event.get("state_key").and_then(Value::as_str) != Some("")
+                {
+                    return;
+                }
+                let event_id = event
+                    .get("event_id")
+                    .and_then(Value::as_str)
+                    .and_then(|event_id| OwnedEventId::try_from(event_id).ok());
+                let Some(config) = parse(&event, room.room_id(), event_id.as_deref()) else {
+                    return;
+                };
+                debug!("Updated {} in room {}.", event_type, room.room_id());
+                cache
+                    .lock_unpoisoned()
+                    .insert(room.room_id().to_owned(), config);
+            }
+        });
+        Self { event_type, cache }
+    }
+
+    /// Returns the configuration of `room`, or [`Default::default`] if the room has no valid configuration.
+    pub async fn config_for(&self, room: &Room) -> Result<T> {
+        if let Some(config) = self.cache.lock_unpoisoned().get(room.room_id()) {
+            return Ok(config.clone());
+        }
+
+        let raw = room
+            .get_state_event(StateEventType::from(&*self.event_type), "")
+            .await?;
+        let event = match &raw {
+            Some(RawAnySyncOrStrippedState::Sync(raw)) => {
+                serde_json::from_str::<Value>(raw.json().get()).ok()
+            }
+            Some(RawAnySyncOrStrippedState::Stripped(raw)) => {
+                serde_json::from_str::<Value>(raw.json().get()).ok()
+            }
+            None => None,
+        };
+        let config = event
+            .and_then(|event| parse(&event, room.room_id(), None))
+            .unwrap_or_default();
+        self.cache
+            .lock_unpoisoned()
+            .insert(room.room_id().to_owned(), config.clone());
+        Ok(config)
+    }
+
+    /// Writes the configuration of `room`. The bot needs the power level to send this state event.
+    pub async fn set(&self, room: &Room, config: &T) -> Result<OwnedEventId> {
+        let content = serde_json::to_value(config)?;
+        let response = room
+            .send_state_event_raw(&self.event_type, "", content)
+            .await?;
+        self.cache
+            .lock_unpoisoned()
+            .insert(room.room_id().to_owned(), config.clone());
+        Ok(response.event_id)
+    }
+}
+
+impl<T> Clone for RoomConfig<T> {
+    fn clone(&self) -> Self {
+        Self {
+            event_type: self.event_type.clone(),
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for RoomConfig<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RoomConfig")
+            .field("event_type", &self.event_type)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parses the content of a configuration event, logging invalid ones.
+fn parse<T: DeserializeOwned + Default>(
+    event: &Value,
+    room_id: &RoomId,
+    event_id: Option<&EventId>,
+) -> Option<T> {
+    let Some(content) = event.get("content") else {
+        return Some(T::default());
+    };
+    match serde_json::from_value(content.clone()) {
+        Ok(config) => Some(config),
+        Err(err) => {
+            warn!(
+                "Ignoring invalid configuration in room {}, event {:?}: {}",
+                room_id, event_id, err
+            );
+            None
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::SystemTime;
+
+use eyre::Result;
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId, RoomId};
+use matrix_sdk::sync::SyncResponse;
+use rusqlite::OptionalExtension;
+use tracing::trace;
+
+use crate::SyncHelper;
+use crate::mutex::MutexExt;
+use crate::sync::{from_unix_millis, unix_millis};
+
+impl SyncHelper {
+    /// Enables or disables automatic per-room position tracking. Disabled by default.
+    ///
+    /// When enabled, [`SyncHelper::process_sync_response`] records the last timeline event of each room as that room's position.
+    ///
+    /// Regardless of this setting, you can always record the position manually with [`SyncHelper::set_room_position`], for example after an event handler finishes processing an event.
+    pub fn set_track_room_positions(&self, enabled: bool) {
+        self.inner.lock_unpoisoned().track_room_positions = enabled;
+    }
+
+    /// Records the last processed event of a room.
+    ///
+    /// Unlike the global `sync_token`, per-room positions allow multi-worker bots to resume each room precisely.
+    pub fn set_room_position(&self, room_id: &RoomId, event_id: &OwnedEventId) -> Result<()> {
+        trace!("Room {} position: {}", room_id, event_id);
+        let db = self.session_db();
+        db.prepare_cached(
+            "INSERT OR REPLACE INTO room_position (room_id, event_id, time) VALUES (?, ?, ?);",
+        )?
+        .execute((
+            room_id.as_str(),
+            event_id.as_str(),
+            unix_millis(self.clock.now()),
+        ))?;
+        Ok(())
+    }
+
+    /// Retrieves the last processed event of a room, along with the time it was recorded.
+    pub fn get_room_position(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<(OwnedEventId, SystemTime)>> {
+        let db = self.session_db();
+        let position = db
+            .prepare_cached("SELECT event_id, time FROM room_position WHERE room_id = ?;")?
+            .query_row((room_id.as_str(),), |row| {
+                Ok((row.get::<_, String>(0)?, row.get(1)?))
+            })
+            .optional()?;
+        let Some((event_id, time)) = position else {
+            return Ok(None);
+        };
+        Ok(Some((event_id.try_into()?, from_unix_millis(time))))
+    }
+
+    /// Retrieves the last processed events of all rooms.
+    pub fn get_room_positions(&self) -> Result<Vec<(OwnedRoomId, OwnedEventId, SystemTime)>> {
+        let db = self.session_db();
+        let positions = db
+            .prepare_cached("SELECT room_id, event_id, time FROM room_position;")?
+            .query_map((), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        positions
+            .into_iter()
+            .map(|(room_id, event_id, time)| {
+                Ok((
+                    room_id.try_into()?,
+                    event_id.try_into()?,
+                    from_unix_millis(time),
+                ))
+            })
+            .collect()
+    }
+
+    /// Forgets the position of a room, for example after leaving it.
+    pub fn remove_room_position(&self, room_id: &RoomId) -> Result<()> {
+        let db = self.session_db();
+        db.prepare_cached("DELETE FROM room_position WHERE room_id = ?;")?
+            .execute((room_id.as_str(),))?;
+        Ok(())
+    }
+
+    pub(crate) fn update_room_positions(&self, sync_response: &SyncResponse) -> Result<()> {
+        let mut inner = self.inner.lock_unpoisoned();
+        if !inner.track_room_positions {
+            return Ok(());
+        }
+        let now = unix_millis(self.clock.now());
+        let tx = inner.session_db.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR REPLACE INTO room_position (room_id, event_id, time) VALUES (?, ?, ?);",
+            )?;
+            for (room_id, room) in &sync_response.rooms.joined {
+                let Some(event_id) = room
+                    .timeline
+                    .events
+                    .iter()
+                    .rev()
+                    .find_map(|event| event.event_id())
+                else {
+                    continue;
+                };
+                trace!("Room {} position: {}", room_id, event_id);
+                stmt.execute((room_id.as_str(), event_id.as_str(), now))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
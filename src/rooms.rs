@@ -0,0 +1,508 @@
+//! Creating, joining, and knocking on rooms.
+
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use eyre::Result;
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::room::Visibility;
+use matrix_sdk::ruma::api::client::room::create_room::v3::{
+    Request as CreateRoomRequest, RoomPreset,
+};
+use matrix_sdk::ruma::events::InitialStateEvent;
+use matrix_sdk::ruma::events::room::avatar::RoomAvatarEventContent;
+use matrix_sdk::ruma::events::room::encryption::RoomEncryptionEventContent;
+use matrix_sdk::ruma::events::room::member::{MembershipState, OriginalSyncRoomMemberEvent};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{
+    OwnedEventId, OwnedMxcUri, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName, OwnedUserId,
+    RoomAliasId, RoomOrAliasId,
+};
+use matrix_sdk::{Client, RoomState};
+use serde_json::json;
+use tracing::{Instrument, error, info, instrument, warn};
+
+use crate::mutex::MutexExt;
+use crate::{Clock, JoinError, NotAllowed, RoomPermissions, SyncHelper, SystemClock};
+
+/// How many times [`join`] retries, which adds up to about 1 hour.
+const JOIN_RETRIES: i32 = 16;
+/// Each retry of [`join`] waits this many times longer than the previous one, starting at 1 second.
+const JOIN_RETRY_BASE: f64 = 1.6180339887498947;
+
+/// The handler name under which [`on_knock`] records handled knocks, see [`SyncHelper::mark_handled`].
+const KNOCK_HANDLER: &str = "matrixbot_ezlogin::rooms::on_knock";
+
+/// Options for [`create`].
+#[derive(Clone, Debug)]
+pub struct CreateOptions {
+    /// The room name.
+    pub name: Option<String>,
+    /// The room topic.
+    pub topic: Option<String>,
+    /// The room avatar, uploaded beforehand, for example, with [`Client::media`].
+    pub avatar: Option<OwnedMxcUri>,
+    /// The local part of a room alias to publish, for example, `"support"` for `#support:example.org`.
+    pub alias: Option<String>,
+    /// The preset of join rules, history visibility, and guest access. Defaults to [`RoomPreset::PrivateChat`].
+    pub preset: RoomPreset,
+    /// Whether the room is listed in the server's room directory. Defaults to [`Visibility::Private`].
+    pub visibility: Visibility,
+    /// Whether to enable end-to-end encryption. Defaults to `true`.
+    ///
+    /// Encryption can't be turned off later. Consider disabling it for large public rooms.
+    pub encrypted: bool,
+    /// Users to invite.
+    pub invite: Vec<OwnedUserId>,
+    /// Whether the invites are for a direct chat. Defaults to `false`. See also [`dm::open`](crate::dm::open).
+    pub is_direct: bool,
+    /// Power levels of other users, for example, 50 for moderators and 100 for admins.
+    ///
+    /// The bot, as the room creator, keeps full power, so it can kick, ban, redact, and change the room state.
+    pub power_levels: BTreeMap<OwnedUserId, i64>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            name: None,
+            topic: None,
+            avatar: None,
+            alias: None,
+            preset: RoomPreset::PrivateChat,
+            visibility: Visibility::Private,
+            encrypted: true,
+            invite: Vec::new(),
+            is_direct: false,
+            power_levels: BTreeMap::new(),
+        }
+    }
+}
+
+/// Creates a room, and returns it once the bot has joined it.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::rooms::{self, CreateOptions};
+///
+/// # async fn example(client: &matrix_sdk::Client, admin: matrix_sdk::ruma::OwnedUserId) -> color_eyre::Result<()> {
+/// let room = rooms::create(
+///     client,
+///     CreateOptions {
+///         name: Some("Alerts".to_owned()),
+///         topic: Some("Alerts from the monitoring system".to_owned()),
+///         invite: vec![admin.clone()],
+///         power_levels: [(admin, 100)].into(),
+///         ..Default::default()
+///     },
+/// )
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[instrument(skip_all)]
+pub async fn create(client: &Client, options: CreateOptions) -> Result<Room> {
+    let mut request = CreateRoomRequest::new();
+    request.name = options.name;
+    request.topic = options.topic;
+    request.room_alias_name = options.alias;
+    request.preset = Some(options.preset);
+    request.visibility = options.visibility;
+    request.invite = options.invite;
+    request.is_direct = options.is_direct;
+
+    if options.encrypted {
+        request.initial_state.push(
+            InitialStateEvent::new(RoomEncryptionEventContent::with_recommended_defaults())
+                .to_raw_any(),
+        );
+    }
+    if let Some(avatar) = options.avatar {
+        let mut content = RoomAvatarEventContent::new();
+        content.url = Some(avatar);
+        request
+            .initial_state
+            .push(InitialStateEvent::new(content).to_raw_any());
+    }
+    if !options.power_levels.is_empty() {
+        // The override replaces the whole `users` map, so the bot has to keep its own power level
+        let mut users = options.power_levels;
+        if let Some(own_user_id) = client.user_id() {
+            users.entry(own_user_id.to_owned()).or_insert(100);
+        }
+        let content = json!({ "users": users });
+        request.power_level_content_override =
+            Some(Raw::from_json(serde_json::value::to_raw_value(&content)?));
+    }
+
+    let room = client.create_room(request).await?;
+    info!("Created room {}.", room.room_id());
+    Ok(room)
+}
+
+/// Joins a room by its ID (`!room:example.org`) or alias (`#room:example.org`), and returns it once joined.
+///
+/// Aliases are resolved first, so the join goes through the servers that the alias points to.
+/// For room IDs, the server in the ID is tried, unless the bot is already invited.
+///
+/// Joining over federation commonly fails transiently, especially right after an invite, so failed attempts are retried
+/// with increasing delays for about 1 hour. Errors that won't go away by retrying, such as an unknown alias, return [`JoinError::Unjoinable`] immediately.
+pub async fn join(client: &Client, alias_or_id: &str) -> Result<Room, JoinError> {
+    join_with_clock(client, alias_or_id, &SystemClock).await
+}
+
+/// Same as [`join`], but waits between retries on `clock`, so tests can skip the 1-hour retries with a [`ManualClock`](crate::ManualClock).
+#[instrument(skip(client, clock))]
+pub async fn join_with_clock(
+    client: &Client,
+    alias_or_id: &str,
+    clock: &dyn Clock,
+) -> Result<Room, JoinError> {
+    let target = OwnedRoomOrAliasId::try_from(alias_or_id).map_err(JoinError::InvalidRoom)?;
+    let mut retry = 0;
+    loop {
+        info!("Joining room {}.", target);
+        let (invited, err) = match try_join(client, &target).await {
+            Ok(room) => {
+                info!("Joined room {}.", room.room_id());
+                return Ok(room);
+            }
+            Err(err) => err,
+        };
+        if is_permanent(&err, invited) {
+            error!("Failed to join room {}: {}", target, err);
+            return Err(JoinError::Unjoinable(err));
+        }
+        // https://github.com/matrix-org/synapse/issues/4345
+        if retry >= JOIN_RETRIES {
+            error!("Failed to join room {}: {}", target, err);
+            error!("Too many retries, giving up after 1 hour.");
+            return Err(JoinError::TooManyRetries(err));
+        }
+        let duration = JOIN_RETRY_BASE.powi(retry);
+        warn!("Failed to join room {}: {}", target, err);
+        warn!("This is common, will retry in {:.1}s.", duration);
+        clock.sleep(Duration::from_secs_f64(duration)).await;
+        retry += 1;
+    }
+}
+
+/// Knocks on a room by its ID (`!room:example.org`) or alias (`#room:example.org`), asking its members to invite the bot.
+///
+/// The room must use the `knock` or `knock_restricted` join rule. Once someone accepts, the bot receives an invite,
+/// which [`join`] can accept.
+#[instrument(skip(client))]
+pub async fn knock(client: &Client, alias_or_id: &str, reason: Option<&str>) -> Result<Room> {
+    let target = OwnedRoomOrAliasId::try_from(alias_or_id)?;
+    let (_, via) = resolve(client, &target).await?;
+    let room = client
+        .knock(target, reason.map(ToOwned::to_owned), via)
+        .await?;
+    info!("Knocked on room {}.", room.room_id());
+    Ok(room)
+}
+
+/// A user knocking on a room the bot has joined, delivered by [`on_knock`].
+#[derive(Clone, Debug)]
+pub struct KnockRequest {
+    /// The client that received the knock.
+    pub client: Client,
+    /// The room being knocked on.
+    pub room: Room,
+    /// The user asking to be invited.
+    pub user_id: OwnedUserId,
+    /// The reason given by the user, if any.
+    pub reason: Option<String>,
+}
+
+/// What to do with a [`KnockRequest`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KnockDecision {
+    /// Invites the user.
+    Accept,
+    /// Rejects the knock, with an optional reason shown to the user.
+    Reject(Option<String>),
+    /// Leaves the knock pending, for example, for a human moderator.
+    Ignore,
+}
+
+/// Which knocks [`handle_knocks`] accepts automatically. Other knocks are left pending.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KnockPolicy {
+    /// Accepts every knock.
+    AcceptAll,
+    /// Accepts users whose ID matches one of the patterns, where `*` matches any sequence of characters, and `?` matches one character,
+    /// for example, `@*:example.org`.
+    AcceptMatching(Vec<String>),
+    /// Accepts users who have joined the given room or space, for example, the community space that the room belongs to.
+    AcceptMembersOf(OwnedRoomId),
+}
+
+/// Registers an event handler on `client` that calls `handler` whenever a user knocks on a room the bot has joined,
+/// and carries out the returned [`KnockDecision`].
+///
+/// Accepting requires the power level to invite, and rejecting requires the power level to kick.
+///
+/// Each knock event is delivered twice, once as state and once in the timeline. `handler` runs only once for it,
+/// and knocks that were handled successfully are recorded in the state database of `sync_helper`, like [`Deduplicate`](crate::Deduplicate) does.
+///
+/// # Example
+///
+/// Asking for a reason before accepting:
+///
+/// ```no_run
+/// use matrixbot_ezlogin::rooms::KnockDecision;
+///
+/// # fn example(client: &matrix_sdk::Client, sync_helper: &matrixbot_ezlogin::SyncHelper) {
+/// matrixbot_ezlogin::rooms::on_knock(client, sync_helper, |request| async move {
+///     Ok(match request.reason {
+///         Some(reason) if !reason.trim().is_empty() => KnockDecision::Accept,
+///         _ => KnockDecision::Reject(Some("Please tell us why you want to join.".to_owned())),
+///     })
+/// });
+/// # }
+/// ```
+pub fn on_knock<F, Fut>(client: &Client, sync_helper: &SyncHelper, handler: F) -> EventHandlerHandle
+where
+    F: Fn(KnockRequest) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<KnockDecision>> + Send + 'static,
+{
+    let sync_helper = sync_helper.clone();
+    let in_flight: Arc<Mutex<HashSet<OwnedEventId>>> = Arc::default();
+    client.add_event_handler(
+        move |event: OriginalSyncRoomMemberEvent, room: Room, client: Client| {
+            let handler = handler.clone();
+            let sync_helper = sync_helper.clone();
+            let in_flight = in_flight.clone();
+            async move {
+                if room.state() != RoomState::Joined
+                    || event.content.membership != MembershipState::Knock
+                {
+                    return;
+                }
+                let event_id = event.event_id;
+                // Both deliveries may run at the same time, so claim the event before checking the record
+                if !in_flight.lock_unpoisoned().insert(event_id.clone()) {
+                    return;
+                }
+                let room_id = room.room_id().to_owned();
+                let user_id = event.state_key;
+                let result = match sync_helper.is_handled(KNOCK_HANDLER, &event_id) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => {
+                        handle_knock(client, room, user_id.clone(), event.content.reason, handler)
+                            .in_current_span()
+                            .await
+                            .and_then(|()| sync_helper.mark_handled(KNOCK_HANDLER, &event_id))
+                    }
+                    Err(err) => Err(err),
+                };
+                in_flight.lock_unpoisoned().remove(&event_id);
+                if let Err(err) = result {
+                    error!(
+                        "Knock handler failed for {} in room {}: {:?}",
+                        user_id, room_id, err
+                    );
+                }
+            }
+        },
+    )
+}
+
+/// Accepts knocks according to `policy`, leaving the others pending for human moderators.
+pub fn handle_knocks(
+    client: &Client,
+    sync_helper: &SyncHelper,
+    policy: KnockPolicy,
+) -> EventHandlerHandle {
+    on_knock(client, sync_helper, move |request| {
+        let policy = policy.clone();
+        async move {
+            let accept = match &policy {
+                KnockPolicy::AcceptAll => true,
+                KnockPolicy::AcceptMatching(patterns) => patterns
+                    .iter()
+                    .any(|pattern| crate::acl::glob_match(pattern, request.user_id.as_str())),
+                KnockPolicy::AcceptMembersOf(room_id) => match request.client.get_room(room_id) {
+                    Some(community) => community
+                        .get_member_no_sync(&request.user_id)
+                        .await?
+                        .is_some_and(|member| *member.membership() == MembershipState::Join),
+                    None => {
+                        warn!("Not a member of room {}.", room_id);
+                        false
+                    }
+                },
+            };
+            Ok(if accept {
+                KnockDecision::Accept
+            } else {
+                KnockDecision::Ignore
+            })
+        }
+    })
+}
+
+async fn handle_knock<F, Fut>(
+    client: Client,
+    room: Room,
+    user_id: OwnedUserId,
+    reason: Option<String>,
+    handler: F,
+) -> Result<()>
+where
+    F: Fn(KnockRequest) -> Fut,
+    Fut: Future<Output = Result<KnockDecision>>,
+{
+    // The user may have been invited or rejected by someone else in the meantime
+    let Some(member) = room.get_member_no_sync(&user_id).await? else {
+        return Ok(());
+    };
+    if *member.membership() != MembershipState::Knock {
+        return Ok(());
+    }
+    let request = KnockRequest {
+        client,
+        room: room.clone(),
+        user_id: user_id.clone(),
+        reason,
+    };
+    match handler(request).await? {
+        KnockDecision::Accept => {
+            if !room.can_invite().await? {
+                return Err(NotAllowed {
+                    room_id: room.room_id().to_owned(),
+                    action: "invite users".to_owned(),
+                }
+                .into());
+            }
+            room.invite_user_by_id(&user_id).await?;
+            info!(
+                "Accepted the knock of {} on room {}.",
+                user_id,
+                room.room_id()
+            );
+        }
+        KnockDecision::Reject(reason) => {
+            if !room.can_kick().await? {
+                return Err(NotAllowed {
+                    room_id: room.room_id().to_owned(),
+                    action: "kick users".to_owned(),
+                }
+                .into());
+            }
+            room.kick_user(&user_id, reason.as_deref()).await?;
+            info!(
+                "Rejected the knock of {} on room {}.",
+                user_id,
+                room.room_id()
+            );
+        }
+        KnockDecision::Ignore => (),
+    }
+    Ok(())
+}
+
+/// Makes one attempt to join `target`. On failure, also returns whether the bot is invited.
+async fn try_join(
+    client: &Client,
+    target: &RoomOrAliasId,
+) -> Result<Room, (bool, matrix_sdk::Error)> {
+    let (room_id, via) = resolve(client, target).await.map_err(|err| (false, err))?;
+    if let Some(room) = client.get_room(&room_id)
+        && room.state() == RoomState::Invited
+    {
+        room.join().await.map_err(|err| (true, err))?;
+        return Ok(room);
+    }
+    client
+        .join_room_by_id_or_alias(<&RoomOrAliasId>::from(&*room_id), &via)
+        .await
+        .map_err(|err| (false, err))
+}
+
+/// Resolves `target` into a room ID, and the servers to join it through.
+///
+/// For aliases, these are the servers the alias points to. For room IDs, it is the server in the ID.
+async fn resolve(
+    client: &Client,
+    target: &RoomOrAliasId,
+) -> Result<(OwnedRoomId, Vec<OwnedServerName>), matrix_sdk::Error> {
+    match <&RoomAliasId>::try_from(target) {
+        Ok(alias) => {
+            let response = client.resolve_room_alias(alias).await?;
+            Ok((response.room_id, response.servers))
+        }
+        Err(room_id) => {
+            let via = room_id
+                .as_str()
+                .split_once(':')
+                .and_then(|(_, server)| OwnedServerName::try_from(server).ok());
+            Ok((room_id.to_owned(), via.into_iter().collect()))
+        }
+    }
+}
+
+fn is_permanent(err: &matrix_sdk::Error, invited: bool) -> bool {
+    match err.client_api_error_kind() {
+        // While an invite travels over federation, the room may still look forbidden or unknown
+        Some(ErrorKind::Forbidden { .. } | ErrorKind::NotFound) => !invited,
+        Some(
+            ErrorKind::BadJson
+            | ErrorKind::NotJson
+            | ErrorKind::InvalidParam
+            | ErrorKind::UnsupportedRoomVersion
+            | ErrorKind::IncompatibleRoomVersion { .. },
+        ) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use matrix_sdk::config::RequestConfig;
+    use matrix_sdk::ruma::api::MatrixVersion;
+
+    use super::*;
+    use crate::ManualClock;
+
+    #[tokio::test]
+    async fn retries_joining_on_the_clock() {
+        // Not logged in, so every attempt fails without reaching the network, and is retried like a transient error
+        let client = Client::builder()
+            .homeserver_url("http://127.0.0.1:9")
+            .server_versions([MatrixVersion::V1_0])
+            .request_config(RequestConfig::new().disable_retry())
+            .build()
+            .await
+            .unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = ManualClock::new(start);
+        let join = tokio::spawn({
+            let clock = clock.clone();
+            async move { join_with_clock(&client, "!room:example.com", &clock).await }
+        });
+
+        for retry in 0..JOIN_RETRIES {
+            while clock.sleepers() == 0 {
+                assert!(!join.is_finished(), "gave up after {} retries", retry);
+                tokio::task::yield_now().await;
+            }
+            let delay = Duration::from_secs_f64(JOIN_RETRY_BASE.powi(retry));
+            clock.advance(delay - Duration::from_millis(1));
+            assert_eq!(clock.sleepers(), 1, "retry {} came too early", retry);
+            clock.advance(Duration::from_millis(1));
+        }
+        let result = join.await.unwrap();
+        assert!(matches!(result, Err(JoinError::TooManyRetries(_))));
+        let elapsed = clock.now().duration_since(start).unwrap();
+        assert!(elapsed > Duration::from_secs(3000) && elapsed < Duration::from_secs(4200));
+    }
+}
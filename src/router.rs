@@ -0,0 +1,350 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use eyre::Result;
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::message::{OriginalSyncRoomMessageEvent, Relation};
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId};
+use matrix_sdk::{Client, RoomState};
+use regex::Regex;
+use tracing::{Instrument, debug, error, instrument};
+
+use crate::acl::glob_match;
+use crate::reply::strip_reply_fallback;
+use crate::{RateLimiter, SyncHelper};
+
+type RouteFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type BoxedRouteHandler = Arc<dyn Fn(RouteContext) -> RouteFuture + Send + Sync>;
+type BoxedPredicate = Arc<dyn Fn(&RouteContext) -> bool + Send + Sync>;
+
+/// Routes room messages to handlers by matching their room, sender, message type, or body.
+///
+/// Routes are tried in order of priority, highest first, and in the order they were added among equal priorities.
+/// The first matching route handles the message, unless it is marked with [`Route::fallthrough`], in which case the next matching route also runs.
+/// If no route handles the message, the fallback handler runs, if any.
+///
+/// Messages sent by the bot, edits, and messages in rooms the bot is not joined to are ignored.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::{Route, Router};
+///
+/// # fn example(client: &matrix_sdk::Client, sync_helper: &matrixbot_ezlogin::SyncHelper) -> color_eyre::Result<()> {
+/// Router::new()
+///     .route(
+///         Route::new(|ctx| async move {
+///             let city = ctx.captures[1].as_deref().unwrap_or_default();
+///             ctx.reply(&format!("Looking up the weather in {}...", city)).await?;
+///             Ok(())
+///         })
+///         .msgtype("m.text")
+///         .body(r"(?i)^weather in (\w+)$")?,
+///     )
+///     .route(
+///         Route::new(|ctx| async move {
+///             ctx.reply("Hello, admin!").await?;
+///             Ok(())
+///         })
+///         .sender("@admin:*")
+///         .priority(10)
+///         .fallthrough(true),
+///     )
+///     .register(client, sync_helper);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<BoxedRouteHandler>,
+    rate_limiter: Option<RateLimiter>,
+}
+
+/// One route of a [`Router`]. Every condition set on it must match.
+#[derive(Clone)]
+pub struct Route {
+    priority: i32,
+    fallthrough: bool,
+    room_id: Option<OwnedRoomId>,
+    sender: Option<String>,
+    msgtype: Option<String>,
+    body: Option<Regex>,
+    predicates: Vec<BoxedPredicate>,
+    handler: BoxedRouteHandler,
+}
+
+/// Everything a route handler needs to know about the matching message.
+#[derive(Clone, Debug)]
+pub struct RouteContext {
+    /// The client that received the message.
+    pub client: Client,
+    /// The room where the message was sent.
+    pub room: Room,
+    /// The message.
+    pub event: OriginalSyncRoomMessageEvent,
+    /// The capture groups of [`Route::body`], with the whole match at index 0. Empty if the route has no body pattern.
+    pub captures: Vec<Option<String>>,
+    /// The rate limiter that [`RouteContext::reply`] waits for, see [`Router::rate_limiter`].
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl Router {
+    /// Creates a [`Router`] without routes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route.
+    pub fn route(mut self, route: Route) -> Self {
+        // Insert after the routes of equal priority, to keep the insertion order among them
+        let index = self
+            .routes
+            .partition_point(|existing| existing.priority >= route.priority);
+        self.routes.insert(index, route);
+        self
+    }
+
+    /// Sets the handler for messages that no route handles.
+    pub fn fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(RouteContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.fallback = Some(Arc::new(move |ctx| Box::pin(handler(ctx))));
+        self
+    }
+
+    /// Makes [`RouteContext::reply`] wait for `rate_limiter` before sending.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Registers an event handler on `client` that calls [`Router::dispatch`] for every room message.
+    ///
+    /// Messages that the [`CatchUpPolicy`](crate::CatchUpPolicy) of [`SyncHelper::catch_up`] on `sync_helper` skips are not dispatched.
+    pub fn register(&self, client: &Client, sync_helper: &SyncHelper) -> EventHandlerHandle {
+        let router = self.clone();
+        let sync_helper = sync_helper.clone();
+        client.add_event_handler(
+            move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+                let router = router.clone();
+                let sync_helper = sync_helper.clone();
+                async move {
+                    if !sync_helper.should_process(event.origin_server_ts) {
+                        return;
+                    }
+                    router.dispatch(event, room, client).await;
+                }
+            },
+        )
+    }
+
+    /// Runs the handlers of the routes matching `event`.
+    ///
+    /// Returns whether any route or the fallback handled the message. Call it from your own event handler, or from a [`Dispatcher`](crate::Dispatcher), if you need to pre-process messages.
+    ///
+    /// The matching handlers run one after another in a separate Tokio task, so they may still be running when this returns.
+    #[instrument(skip_all)]
+    pub async fn dispatch(
+        &self,
+        event: OriginalSyncRoomMessageEvent,
+        room: Room,
+        client: Client,
+    ) -> bool {
+        if Some(&*event.sender) == client.user_id() || room.state() != RoomState::Joined {
+            return false;
+        }
+        if let Some(Relation::Replacement(_)) = event.content.relates_to {
+            return false;
+        }
+        let mut ctx = RouteContext {
+            client,
+            room,
+            event,
+            captures: Vec::new(),
+            rate_limiter: self.rate_limiter.clone(),
+        };
+        let mut matched = Vec::new();
+        for (index, route) in self.routes.iter().enumerate() {
+            let Some(captures) = route.matches(&ctx) else {
+                continue;
+            };
+            ctx.captures = captures;
+            if !route.predicates.iter().all(|predicate| predicate(&ctx)) {
+                continue;
+            }
+            debug!(
+                "Route {} matched room {}, event {}.",
+                index,
+                ctx.room.room_id(),
+                ctx.event.event_id
+            );
+            matched.push((route.handler.clone(), ctx.clone()));
+            if !route.fallthrough {
+                break;
+            }
+        }
+        if matched.is_empty() {
+            let Some(fallback) = &self.fallback else {
+                return false;
+            };
+            ctx.captures = Vec::new();
+            matched.push((fallback.clone(), ctx));
+        }
+        // Spawned, so a slow route doesn't hold up the sync loop
+        tokio::spawn(
+            async move {
+                for (handler, ctx) in matched {
+                    let (room_id, event_id) =
+                        (ctx.room.room_id().to_owned(), ctx.event.event_id.clone());
+                    if let Err(err) = handler(ctx).await {
+                        error!(
+                            "Route handler failed in room {}, event {}: {:?}",
+                            room_id, event_id, err
+                        );
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+        true
+    }
+}
+
+impl std::fmt::Debug for Router {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Router")
+            .field("routes", &self.routes)
+            .field("fallback", &self.fallback.is_some())
+            .field("rate_limiter", &self.rate_limiter)
+            .finish()
+    }
+}
+
+impl Route {
+    /// Creates a route that matches every message, handled by `handler`.
+    pub fn new<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(RouteContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        Self {
+            priority: 0,
+            fallthrough: false,
+            room_id: None,
+            sender: None,
+            msgtype: None,
+            body: None,
+            predicates: Vec::new(),
+            handler: Arc::new(move |ctx| Box::pin(handler(ctx))),
+        }
+    }
+
+    /// Routes with higher priority are tried first. Defaults to 0.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Whether the next matching route also runs after this one. Defaults to `false`.
+    pub fn fallthrough(mut self, fallthrough: bool) -> Self {
+        self.fallthrough = fallthrough;
+        self
+    }
+
+    /// Only matches messages in this room.
+    pub fn room(mut self, room_id: OwnedRoomId) -> Self {
+        self.room_id = Some(room_id);
+        self
+    }
+
+    /// Only matches messages whose sender matches a glob pattern, where `*` matches any sequence and `?` matches one character,
+    /// for example, `@*:example.org`.
+    pub fn sender(mut self, pattern: impl Into<String>) -> Self {
+        self.sender = Some(pattern.into());
+        self
+    }
+
+    /// Only matches messages of this message type, for example, `m.text`.
+    pub fn msgtype(mut self, msgtype: impl Into<String>) -> Self {
+        self.msgtype = Some(msgtype.into());
+        self
+    }
+
+    /// Only matches messages whose body, without the reply fallback, matches a regular expression.
+    ///
+    /// The capture groups are passed to the handler in [`RouteContext::captures`].
+    pub fn body(mut self, pattern: &str) -> Result<Self> {
+        self.body = Some(Regex::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Only matches messages for which `predicate` returns `true`. It runs after all other conditions.
+    pub fn filter<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&RouteContext) -> bool + Send + Sync + 'static,
+    {
+        self.predicates.push(Arc::new(predicate));
+        self
+    }
+
+    /// Returns the capture groups if the built-in conditions match.
+    fn matches(&self, ctx: &RouteContext) -> Option<Vec<Option<String>>> {
+        if self
+            .room_id
+            .as_ref()
+            .is_some_and(|room_id| room_id != ctx.room.room_id())
+        {
+            return None;
+        }
+        if self
+            .sender
+            .as_ref()
+            .is_some_and(|pattern| !glob_match(pattern, ctx.event.sender.as_str()))
+        {
+            return None;
+        }
+        if self
+            .msgtype
+            .as_ref()
+            .is_some_and(|msgtype| msgtype != ctx.event.content.msgtype())
+        {
+            return None;
+        }
+        let Some(body) = &self.body else {
+            return Some(Vec::new());
+        };
+        let captures = body.captures(strip_reply_fallback(ctx.event.content.body()))?;
+        Some(
+            captures
+                .iter()
+                .map(|capture| capture.map(|capture| capture.as_str().to_owned()))
+                .collect(),
+        )
+    }
+}
+
+impl std::fmt::Debug for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Route")
+            .field("priority", &self.priority)
+            .field("fallthrough", &self.fallthrough)
+            .field("room_id", &self.room_id)
+            .field("sender", &self.sender)
+            .field("msgtype", &self.msgtype)
+            .field("body", &self.body)
+            .field("predicates", &self.predicates.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl RouteContext {
+    /// Replies to the matching message with a plain text notice, in the same thread if any.
+    pub async fn reply(&self, text: &str) -> Result<OwnedEventId> {
+        crate::send::reply_notice(&self.room, &self.event, text, self.rate_limiter.as_ref()).await
+    }
+}
@@ -0,0 +1,240 @@
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use eyre::{Result, eyre};
+use matrix_sdk::Client;
+use matrix_sdk::config::SyncSettings;
+use tokio::select;
+use tokio::task::JoinSet;
+use tracing::{Instrument, error, info, instrument, warn};
+
+use crate::sync::SyncHelperInner;
+use crate::{Clock, SyncHelper, SyncOptions, SystemClock};
+
+/// The delay before the first restart by [`run_supervised`], doubled after every further restart.
+const RESTART_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest delay between restarts by [`run_supervised`].
+const RESTART_MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// A run of [`run_supervised`] lasting this long resets the backoff, so a crash after days of uptime restarts quickly.
+const RESTART_STABLE_AFTER: Duration = Duration::from_secs(600);
+/// How long [`run_supervised`] waits for the tasks of a crashed run to let go of its session.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often [`run_supervised`] checks whether the session of a crashed run is released.
+const RELEASE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs [`SyncHelper::sync`] until the process receives a shutdown signal.
+///
+/// On Unix, both `SIGINT` and `SIGTERM` are handled. On other platforms, Ctrl-C is handled.
+///
+/// With the `systemd` feature enabled, it sends `STOPPING=1` to systemd once the sync loop stops.
+///
+/// After the sync loop stops, it calls [`SyncHelper::flush`] to make sure the sync token is written to disk, then returns so your `main` function can do its own cleanup.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+///
+/// use color_eyre::eyre::Result;
+/// use matrix_sdk::config::SyncSettings;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<()> {
+///     let (client, sync_helper) = matrixbot_ezlogin::login(Path::new("./TODO")).await?;
+///
+///     // Install your bot logic handlers
+///     todo!();
+///
+///     matrixbot_ezlogin::run_until_shutdown(&client, &sync_helper, SyncSettings::default()).await?;
+///
+///     // Do your own cleanup here
+///     Ok(())
+/// }
+/// ```
+#[instrument(skip_all)]
+pub async fn run_until_shutdown(
+    client: &Client,
+    sync_helper: &SyncHelper,
+    sync_settings: impl Into<SyncSettings>,
+) -> Result<()> {
+    let result = select! {
+        result = sync_helper.sync(client, sync_settings) => result.map_err(Into::into),
+        result = wait_for_shutdown_signal() => result.map_err(Into::into),
+    };
+    info!("Stopping sync.");
+    #[cfg(all(feature = "systemd", unix))]
+    crate::systemd::notify_stopping();
+    if let Some(mirror_task) = sync_helper.mirror_sync_token(client, true) {
+        _ = mirror_task.await;
+    }
+    sync_helper.flush()?;
+    result
+}
+
+/// Runs a bot that restarts itself after crashes, until the process receives a shutdown signal.
+///
+/// Each run logs in from `data_dir`, calls `setup` to register event handlers on the new [`Client`] and to start its tasks, then runs [`run_until_shutdown`]
+/// with [`SyncOptions::default`]. If `setup` or the sync loop fails or panics, the client is torn down, and a new run starts after a delay,
+/// which starts at 1 second and doubles up to 5 minutes. A run that lasted 10 minutes resets the delay.
+///
+/// Handlers registered by `setup` run inside the sync loop, so their panics also restart the bot.
+///
+/// `setup` returns a [`JoinSet`] of the tasks it started, such as [`SendQueue::run`](crate::SendQueue::run) or [`Scheduler::run`](crate::Scheduler::run).
+/// They are not supervised, but they are aborted before each restart. Start every long-running task in this [`JoinSet`], instead of with [`tokio::spawn`]:
+/// a task that outlives its run keeps the old [`Client`] and [`SyncHelper`] alive, and the state database stays locked, so logging in again fails.
+///
+/// Failing to log in the first time returns the error, since it usually means `data_dir` isn't set up. Later failures are retried.
+///
+/// Every restart is counted in [`EventMetrics::supervisor_restarts`](crate::EventMetrics::supervisor_restarts).
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+///
+/// use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+/// use matrixbot_ezlogin::SendQueue;
+/// use tokio::task::JoinSet;
+///
+/// # async fn example() -> color_eyre::Result<()> {
+/// matrixbot_ezlogin::run_supervised(Path::new("./data"), |client, sync_helper| async move {
+///     client.add_event_handler(|event: OriginalSyncRoomMessageEvent| async move {
+///         tracing::info!("Received {}.", event.event_id);
+///     });
+///     let send_queue = SendQueue::new(&sync_helper);
+///     let mut tasks = JoinSet::new();
+///     tasks.spawn(async move { send_queue.run(&client).await });
+///     Ok(tasks)
+/// })
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[instrument(skip(setup))]
+pub async fn run_supervised<F, Fut>(data_dir: &Path, setup: F) -> Result<()>
+where
+    F: Fn(Client, SyncHelper) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<JoinSet<Result<()>>>> + Send + 'static,
+{
+    let mut backoff = RESTART_INITIAL_BACKOFF;
+    let mut first_run = true;
+    // The clock of the last login, for the restart backoff
+    let mut clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    loop {
+        let started = clock.now();
+        let err = match crate::login(data_dir).await {
+            Ok((client, sync_helper)) => {
+                clock = sync_helper.clock();
+                let session = Arc::downgrade(&sync_helper.inner);
+                match run_once(&setup, client, sync_helper).await {
+                    // Either a shutdown signal, or the sync loop stopped on its own
+                    Ok(()) => return Ok(()),
+                    Err(err) => {
+                        wait_for_release(&session, &*clock).await;
+                        err
+                    }
+                }
+            }
+            Err(err) if first_run => return Err(err),
+            Err(err) => err,
+        };
+        first_run = false;
+        if clock.now().duration_since(started).unwrap_or_default() >= RESTART_STABLE_AFTER {
+            backoff = RESTART_INITIAL_BACKOFF;
+        }
+        error!("Bot crashed: {:?}", err);
+        warn!("Restarting in {:?}.", backoff);
+        crate::metrics::record_restart();
+        select! {
+            _ = clock.sleep(backoff) => (),
+            result = wait_for_shutdown_signal() => return result.map_err(Into::into),
+        }
+        backoff = backoff.saturating_mul(2).min(RESTART_MAX_BACKOFF);
+    }
+}
+
+/// Runs `setup` and the sync loop of one run of [`run_supervised`], then tears the session down, dropping `client` and `sync_helper`.
+async fn run_once<F, Fut>(setup: &F, client: Client, sync_helper: SyncHelper) -> Result<()>
+where
+    F: Fn(Client, SyncHelper) -> Fut,
+    Fut: Future<Output = Result<JoinSet<Result<()>>>> + Send + 'static,
+{
+    // Separate tasks turn a panic into a JoinError, instead of unwinding through the supervisor.
+    // If `setup` fails, the tasks it already started are aborted by dropping its JoinSet.
+    let result = match tokio::spawn(setup(client.clone(), sync_helper.clone()).in_current_span())
+        .await
+    {
+        Ok(Ok(mut tasks)) => {
+            let run = tokio::spawn({
+                let client = client.clone();
+                let sync_helper = sync_helper.clone();
+                async move { run_until_shutdown(&client, &sync_helper, SyncOptions::default()).await }
+                    .in_current_span()
+            });
+            let result = match run.await {
+                Ok(result) => result,
+                Err(err) => Err(join_error(err)),
+            };
+            tasks.shutdown().await;
+            result
+        }
+        Ok(Err(err)) => Err(err),
+        Err(err) => Err(join_error(err)),
+    };
+    sync_helper.abort_background_tasks();
+    result
+}
+
+/// Waits until nothing holds the [`SyncHelper`] of a torn-down run any more, so its state database is unlocked for the next login.
+async fn wait_for_release(session: &Weak<Mutex<SyncHelperInner>>, clock: &dyn Clock) {
+    let mut waited = Duration::ZERO;
+    while session.strong_count() != 0 {
+        if waited >= RELEASE_TIMEOUT {
+            warn!(
+                "The previous session is still in use by some task, logging in again may fail. Start long-running tasks in the JoinSet returned by setup."
+            );
+            return;
+        }
+        clock.sleep(RELEASE_POLL_INTERVAL).await;
+        waited += RELEASE_POLL_INTERVAL;
+    }
+}
+
+fn join_error(err: tokio::task::JoinError) -> eyre::Report {
+    match err.try_into_panic() {
+        Ok(panic) => eyre!("panicked: {}", panic_message(&*panic)),
+        Err(err) => err.into(),
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "(non-string panic payload)"
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() -> Result<(), std::io::Error> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    select! {
+        _ = sigint.recv() => info!("Received SIGINT."),
+        _ = sigterm.recv() => info!("Received SIGTERM."),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() -> Result<(), std::io::Error> {
+    tokio::signal::ctrl_c().await?;
+    info!("Received Ctrl-C.");
+    Ok(())
+}
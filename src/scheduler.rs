@@ -0,0 +1,560 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local, NaiveTime, SecondsFormat, TimeDelta, Utc};
+use eyre::{Result, bail, eyre};
+use matrix_sdk::Client;
+use rusqlite::OptionalExtension;
+use tokio::select;
+use tokio::sync::Notify;
+use tracing::{Instrument, debug, error, info, instrument, warn};
+
+use crate::SyncHelper;
+use crate::sync::{from_unix_millis, unix_millis};
+
+/// The longest the scheduler sleeps before checking the wall clock again, so a clock adjustment delays a task by at most this long.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+/// How long to postpone a task whose job has no registered handler.
+const UNKNOWN_JOB_DELAY: Duration = Duration::from_secs(3600);
+
+type JobFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type BoxedJob = Arc<dyn Fn(Client, ScheduledTask) -> JobFuture + Send + Sync>;
+
+/// When a scheduled task fires.
+///
+/// It can be parsed from human-readable text, case-insensitively:
+///
+/// * `in 2 hours`, `in 30 minutes`: Once, after the duration.
+/// * `at 09:00`: Once, at the next 09:00 in local time.
+/// * `at 2025-01-01T09:00:00+08:00`: Once, at an RFC 3339 timestamp.
+/// * `every hour`, `every 15 minutes`: Repeatedly, at a fixed interval.
+/// * `every day at 09:00`: Repeatedly, every day at 09:00 in local time.
+///
+/// Supported units are `millisecond`, `second`, `minute`, `hour`, `day`, and `week`, in singular, plural, or abbreviated (`ms`, `s`, `min`, `h`, `d`, `w`) form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// Fires once at this time.
+    At(SystemTime),
+    /// Fires repeatedly at this interval.
+    Every(Duration),
+    /// Fires every day at this local time.
+    Daily(NaiveTime),
+}
+
+/// A task stored in the state database by [`Scheduler::schedule`].
+#[derive(Clone, Debug)]
+pub struct ScheduledTask {
+    /// The task ID, returned by [`Scheduler::schedule`].
+    pub id: i64,
+    /// The name of the job that handles this task.
+    pub job: String,
+    /// When the task fires.
+    pub schedule: Schedule,
+    /// Arbitrary data passed to the job, for example, JSON.
+    pub payload: String,
+    /// When the task was due to fire. It is in the past if the task fires late, for example, because the bot was not running.
+    pub due: SystemTime,
+}
+
+/// A persisted scheduler that fires registered jobs at scheduled times.
+///
+/// Tasks are stored in the state database, so they survive restarts. The handlers are not stored,
+/// so every job a task refers to must be registered with [`Scheduler::job`] again after each start.
+///
+/// Tasks that became due while the bot was not running fire once as soon as [`Scheduler::run`] starts.
+/// A repeating task then continues with its next occurrence in the future, so it never fires several times in a row to catch up.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+/// use matrixbot_ezlogin::{Schedule, Scheduler};
+///
+/// # async fn example(client: matrix_sdk::Client, sync_helper: matrixbot_ezlogin::SyncHelper) -> color_eyre::Result<()> {
+/// let scheduler = Scheduler::new(&sync_helper).job("good-morning", |client, task| async move {
+///     let room_id = matrix_sdk::ruma::RoomId::parse(&task.payload)?;
+///     if let Some(room) = client.get_room(&room_id) {
+///         room.send(RoomMessageEventContent::notice_plain("Good morning!")).await?;
+///     }
+///     Ok(())
+/// });
+/// if scheduler.tasks()?.is_empty() {
+///     scheduler.schedule("good-morning", "every day at 09:00".parse()?, "!room:example.org")?;
+/// }
+/// scheduler.run(&client).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Scheduler {
+    sync_helper: SyncHelper,
+    notify: Arc<Notify>,
+    jobs: HashMap<String, BoxedJob>,
+}
+
+impl Scheduler {
+    /// Creates a [`Scheduler`] stored in the state database of `sync_helper`, without any jobs.
+    pub fn new(sync_helper: &SyncHelper) -> Self {
+        Self {
+            sync_helper: sync_helper.clone(),
+            notify: Arc::new(Notify::new()),
+            jobs: HashMap::new(),
+        }
+    }
+
+    /// Registers the handler of a job. A job with the same name is replaced.
+    ///
+    /// If the handler returns an error, it is logged. A one-time task is not retried, and a repeating task continues with its next occurrence.
+    pub fn job<F, Fut>(mut self, name: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(Client, ScheduledTask) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.jobs.insert(
+            name.into(),
+            Arc::new(move |client, task| Box::pin(handler(client, task))),
+        );
+        self
+    }
+
+    /// Stores a task that fires `job` according to `schedule`, and returns its task ID.
+    ///
+    /// The interval of [`Schedule::Every`] must be a positive whole number of milliseconds.
+    pub fn schedule(
+        &self,
+        job: &str,
+        schedule: Schedule,
+        payload: impl Into<String>,
+    ) -> Result<i64> {
+        schedule.validate()?;
+        let now = self.sync_helper.clock.now();
+        let due = schedule
+            .next_after(now, now)
+            .ok_or_else(|| eyre!("schedule is out of range: {}", schedule))?;
+        let id = {
+            let db = self.sync_helper.session_db();
+            db
+                .prepare_cached(
+                    "INSERT INTO scheduled_task (job, schedule, payload, due, time) VALUES (?, ?, ?, ?, ?);",
+                )?
+                .execute((
+                    job,
+                    schedule.to_string(),
+                    payload.into(),
+                    unix_millis(due),
+                    unix_millis(now),
+                ))?;
+            db.last_insert_rowid()
+        };
+        debug!("Scheduled task {} of job {} {}.", id, job, schedule);
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    /// Removes a task. Returns `false` if no task has this ID.
+    pub fn cancel(&self, id: i64) -> Result<bool> {
+        let deleted = self
+            .sync_helper
+            .session_db()
+            .prepare_cached("DELETE FROM scheduled_task WHERE id = ?;")?
+            .execute((id,))?;
+        self.notify.notify_one();
+        Ok(deleted != 0)
+    }
+
+    /// Returns every stored task, ordered by when they fire next.
+    pub fn tasks(&self) -> Result<Vec<ScheduledTask>> {
+        let db = self.sync_helper.session_db();
+        let mut stmt = db.prepare_cached(
+            "SELECT id, job, schedule, payload, due FROM scheduled_task ORDER BY due, id;",
+        )?;
+        let tasks = stmt
+            .query_map((), row_to_task)?
+            .map(|row| row?)
+            .collect::<Result<_>>()?;
+        Ok(tasks)
+    }
+
+    /// Fires due tasks until an error occurs with the state database.
+    ///
+    /// Handlers run one at a time. Spawn a task from the handler if it takes long.
+    ///
+    /// Only run one [`Scheduler::run`] per state database.
+    #[instrument(skip_all)]
+    pub async fn run(&self, client: &Client) -> Result<()> {
+        loop {
+            let now = self.sync_helper.clock.now();
+            let Some((id, task)) = self.next_due(now)? else {
+                let delay = match self.next_due_time()? {
+                    Some(due) => due.duration_since(now).unwrap_or_default().min(MAX_SLEEP),
+                    None => MAX_SLEEP,
+                };
+                select! {
+                    _ = self.sync_helper.clock.sleep(delay) => (),
+                    _ = self.notify.notified() => (),
+                }
+                continue;
+            };
+            let task = match task {
+                Ok(task) => task,
+                Err(err) => {
+                    error!(
+                        "Failed to load task {}, postponing by {:?}: {:?}",
+                        id, UNKNOWN_JOB_DELAY, err
+                    );
+                    self.reschedule(id, now + UNKNOWN_JOB_DELAY)?;
+                    continue;
+                }
+            };
+
+            let Some(handler) = self.jobs.get(&task.job) else {
+                warn!(
+                    "No handler for job {} of task {}, postponing by {:?}.",
+                    task.job, task.id, UNKNOWN_JOB_DELAY
+                );
+                self.reschedule(task.id, now + UNKNOWN_JOB_DELAY)?;
+                continue;
+            };
+
+            info!("Running task {} of job {}.", task.id, task.job);
+            let schedule = task.schedule;
+            let (id, due) = (task.id, task.due);
+            if let Err(err) = handler(client.clone(), task).in_current_span().await {
+                error!("Task {} failed: {:?}", id, err);
+            }
+            match schedule {
+                Schedule::At(_) => {
+                    self.cancel(id)?;
+                }
+                _ => match schedule.next_after(due, self.sync_helper.clock.now()) {
+                    Some(next) => self.reschedule(id, next)?,
+                    None => {
+                        warn!("Task {} has no next occurrence, removing it.", id);
+                        self.cancel(id)?;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Returns the earliest due task. A task that fails to load is returned as an error together with its ID, so it can be skipped.
+    fn next_due(&self, now: SystemTime) -> Result<Option<(i64, Result<ScheduledTask>)>> {
+        self.sync_helper
+            .session_db()
+            .prepare_cached(
+                "SELECT id, job, schedule, payload, due FROM scheduled_task WHERE due <= ? ORDER BY due, id LIMIT 1;",
+            )?
+            .query_row((unix_millis(now),), |row| Ok((row.get(0)?, row_to_task(row)?)))
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn next_due_time(&self) -> Result<Option<SystemTime>> {
+        let due: Option<i64> = self
+            .sync_helper
+            .session_db()
+            .prepare_cached("SELECT MIN(due) FROM scheduled_task;")?
+            .query_row((), |row| row.get(0))?;
+        Ok(due.map(from_unix_millis))
+    }
+
+    fn reschedule(&self, id: i64, due: SystemTime) -> Result<()> {
+        self.sync_helper
+            .session_db()
+            .prepare_cached("UPDATE scheduled_task SET due = ? WHERE id = ?;")?
+            .execute((unix_millis(due), id))?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("sync_helper", &self.sync_helper)
+            .field("jobs", &self.jobs.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+fn row_to_task(row: &rusqlite::Row) -> rusqlite::Result<Result<ScheduledTask>> {
+    let id = row.get(0)?;
+    let job = row.get(1)?;
+    let schedule: String = row.get(2)?;
+    let payload = row.get(3)?;
+    let due = from_unix_millis(row.get(4)?);
+    Ok(schedule.parse().map(|schedule| ScheduledTask {
+        id,
+        job,
+        schedule,
+        payload,
+        due,
+    }))
+}
+
+impl Schedule {
+    /// Same as [`str::parse`], but relative times such as `in 5 minutes` count from `now`, for example, [`Clock::now`](crate::Clock::now).
+    pub fn parse_at(s: &str, now: SystemTime) -> Result<Self> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        let lower = words
+            .iter()
+            .map(|word| word.to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        let lower = lower.iter().map(String::as_str).collect::<Vec<_>>();
+        match lower.as_slice() {
+            ["in", duration @ ..] => {
+                let time = now
+                    .checked_add(parse_duration(duration)?)
+                    .ok_or_else(|| eyre!("time is out of range: {}", s))?;
+                Ok(Schedule::At(time))
+            }
+            ["at", _] => {
+                if let Ok(time) = NaiveTime::parse_from_str(words[1], "%H:%M") {
+                    return Ok(Schedule::At(next_local_time(time, now)));
+                }
+                let time = DateTime::parse_from_rfc3339(words[1])
+                    .map_err(|_| eyre!("invalid time: {}", words[1]))?;
+                Ok(Schedule::At(time.into()))
+            }
+            ["every", "day", "at", _] => {
+                let time = NaiveTime::parse_from_str(words[3], "%H:%M")
+                    .map_err(|_| eyre!("invalid time of day: {}", words[3]))?;
+                Ok(Schedule::Daily(time))
+            }
+            ["every", duration @ ..] => {
+                let interval = parse_duration(duration)?;
+                if interval.is_zero() {
+                    bail!("interval must not be zero");
+                }
+                Ok(Schedule::Every(interval))
+            }
+            _ => bail!("invalid schedule: {}", s),
+        }
+    }
+
+    /// Rejects intervals that would fire in a busy loop, or that can't be stored, because [`Schedule`]'s text form counts in milliseconds.
+    fn validate(&self) -> Result<()> {
+        if let Schedule::Every(interval) = *self {
+            if interval.is_zero() {
+                bail!("interval must not be zero");
+            }
+            if interval.subsec_nanos() % 1_000_000 != 0 {
+                bail!("interval must be whole milliseconds: {:?}", interval);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns when the task fires next, after it was last due at `previous` and it is now `now`.
+    ///
+    /// For a new task, both are the current time. Returns `None` if the time is out of range.
+    fn next_after(&self, previous: SystemTime, now: SystemTime) -> Option<SystemTime> {
+        match *self {
+            Schedule::At(time) => Some(time),
+            Schedule::Every(interval) => {
+                let next = previous.checked_add(interval)?;
+                if next > now {
+                    return Some(next);
+                }
+                // Skip the missed occurrences, but stay aligned to the original interval
+                let behind = now.duration_since(next).unwrap_or_default();
+                let skipped = (behind.as_millis() / interval.as_millis().max(1) + 1)
+                    .try_into()
+                    .unwrap_or(u32::MAX);
+                next.checked_add(interval.checked_mul(skipped)?)
+            }
+            Schedule::Daily(time) => Some(next_local_time(time, previous.max(now))),
+        }
+    }
+}
+
+fn next_local_time(time: NaiveTime, after: SystemTime) -> SystemTime {
+    let mut date = DateTime::<Local>::from(after).date_naive();
+    loop {
+        let naive = date.and_time(time);
+        // If the time is skipped by a daylight saving time change, fire one hour later
+        let candidate = naive.and_local_timezone(Local).earliest().or_else(|| {
+            (naive + TimeDelta::hours(1))
+                .and_local_timezone(Local)
+                .earliest()
+        });
+        if let Some(candidate) = candidate {
+            let candidate = SystemTime::from(candidate);
+            if candidate > after {
+                return candidate;
+            }
+        }
+        let Some(next_date) = date.succ_opt() else {
+            return after + Duration::from_secs(86400);
+        };
+        date = next_date;
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse_at(s, SystemTime::now())
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::At(time) => write!(
+                f,
+                "at {}",
+                DateTime::<Utc>::from(*time).to_rfc3339_opts(SecondsFormat::Millis, true)
+            ),
+            Schedule::Every(interval) => {
+                let millis = interval.as_millis();
+                let (amount, unit) = [
+                    (604_800_000, "weeks"),
+                    (86_400_000, "days"),
+                    (3_600_000, "hours"),
+                    (60_000, "minutes"),
+                    (1_000, "seconds"),
+                ]
+                .into_iter()
+                .find(|(unit_millis, _)| millis % unit_millis == 0)
+                .map(|(unit_millis, unit)| (millis / unit_millis, unit))
+                .unwrap_or((millis, "milliseconds"));
+                write!(f, "every {} {}", amount, unit)
+            }
+            Schedule::Daily(time) => write!(f, "every day at {}", time.format("%H:%M")),
+        }
+    }
+}
+
+fn parse_duration(words: &[&str]) -> Result<Duration> {
+    let (amount, unit) = match words {
+        [unit] => (1, *unit),
+        [amount, unit] => (
+            amount
+                .parse::<u64>()
+                .map_err(|_| eyre!("invalid number: {}", amount))?,
+            *unit,
+        ),
+        _ => bail!("invalid duration: {}", words.join(" ")),
+    };
+    let unit_millis = match unit {
+        "ms" | "millisecond" | "milliseconds" => 1,
+        "s" | "sec" | "secs" | "second" | "seconds" => 1_000,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60_000,
+        "h" | "hr" | "hrs" | "hour" | "hours" => 3_600_000,
+        "d" | "day" | "days" => 86_400_000,
+        "w" | "week" | "weeks" => 604_800_000,
+        _ => bail!("invalid time unit: {}", unit),
+    };
+    let millis = amount
+        .checked_mul(unit_millis)
+        .ok_or_else(|| eyre!("duration is too long: {}", words.join(" ")))?;
+    Ok(Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: Duration = Duration::from_millis(1_700_000_000_123);
+
+    fn now() -> SystemTime {
+        SystemTime::UNIX_EPOCH + NOW
+    }
+
+    #[test]
+    fn parses_schedules() {
+        assert_eq!(
+            Schedule::parse_at("In 2 Hours", now()).unwrap(),
+            Schedule::At(now() + Duration::from_secs(7200))
+        );
+        assert_eq!(
+            Schedule::parse_at("at 2025-01-01T09:00:00+08:00", now()).unwrap(),
+            Schedule::At(SystemTime::UNIX_EPOCH + Duration::from_secs(1_735_693_200))
+        );
+        assert_eq!(
+            Schedule::parse_at("every hour", now()).unwrap(),
+            Schedule::Every(Duration::from_secs(3600))
+        );
+        assert_eq!(
+            Schedule::parse_at("every 15 min", now()).unwrap(),
+            Schedule::Every(Duration::from_secs(900))
+        );
+        assert_eq!(
+            Schedule::parse_at("every day at 09:00", now()).unwrap(),
+            Schedule::Daily(NaiveTime::from_hms_opt(9, 0, 0).unwrap())
+        );
+        let Schedule::At(time) = Schedule::parse_at("at 09:00", now()).unwrap() else {
+            panic!("expected a one-time schedule");
+        };
+        assert!(time > now() && time <= now() + Duration::from_secs(86400 + 3600));
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for s in [
+            "",
+            "every 0 seconds",
+            "every -1 hours",
+            "in 2 fortnights",
+            "at 25:00",
+            "every day at noon",
+            "sometimes",
+        ] {
+            assert!(Schedule::parse_at(s, now()).is_err(), "accepted {:?}", s);
+        }
+        assert!(Schedule::Every(Duration::ZERO).validate().is_err());
+        assert!(
+            Schedule::Every(Duration::from_micros(1500))
+                .validate()
+                .is_err()
+        );
+        assert!(
+            Schedule::Every(Duration::from_millis(1500))
+                .validate()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn skips_missed_occurrences() {
+        let interval = Duration::from_secs(10);
+        let schedule = Schedule::Every(interval);
+        // On time
+        assert_eq!(
+            schedule.next_after(now(), now() + Duration::from_secs(5)),
+            Some(now() + interval)
+        );
+        // Several occurrences missed, staying aligned to the interval
+        assert_eq!(
+            schedule.next_after(now(), now() + Duration::from_secs(35)),
+            Some(now() + Duration::from_secs(40))
+        );
+        let time = now() + Duration::from_secs(60);
+        assert_eq!(Schedule::At(time).next_after(now(), now()), Some(time));
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        for schedule in [
+            Schedule::At(now()),
+            Schedule::Every(Duration::from_millis(1500)),
+            Schedule::Every(Duration::from_secs(90)),
+            Schedule::Every(Duration::from_secs(3600)),
+            Schedule::Every(Duration::from_secs(14 * 86400)),
+            Schedule::Daily(NaiveTime::from_hms_opt(23, 59, 0).unwrap()),
+        ] {
+            assert_eq!(
+                schedule.to_string().parse::<Schedule>().unwrap(),
+                schedule,
+                "{}",
+                schedule
+            );
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use matrix_sdk::Client;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::OwnedEventId;
+use rand::Rng;
+use serde_json::Value;
+use tracing::{instrument, warn};
+
+use crate::rooms::CreateOptions;
+use crate::{DiagnosisReport, MessageBuilder, Severity};
+
+/// How long to wait for the test message to come back through sync.
+const READ_BACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Proves that end-to-end encryption works, by sending an encrypted message and reading it back through sync.
+///
+/// It uses the room `canary_room`, a room ID or alias that the bot has joined, or creates a throwaway encrypted room and leaves it afterwards.
+/// Then it checks that the message was encrypted on the way and decrypted on the way back, and that the room key reached the server-side backup.
+///
+/// The bot's sync token is left untouched, so the bot doesn't miss any events. But the bot must not be running at the same time,
+/// because the state database can only be opened by one process at the same time.
+#[instrument(skip(data_dir))]
+pub async fn self_test(data_dir: &Path, canary_room: Option<&str>) -> DiagnosisReport {
+    let mut report = DiagnosisReport::default();
+
+    let (client, sync_helper) = match crate::login(data_dir).await {
+        Ok(logged_in) => logged_in,
+        Err(err) => {
+            report.push(
+                "session",
+                Severity::Error,
+                format!("failed to restore the session: {}", err),
+            );
+            return report;
+        }
+    };
+    report.push("session", Severity::Ok, "session restored");
+
+    // Catch up from the bot's position, but don't save the new token
+    let mut sync_settings = SyncSettings::default().timeout(Duration::ZERO);
+    if let Some(token) = sync_helper.get_sync_token() {
+        sync_settings = sync_settings.token(token);
+    }
+    let mut next_batch = match client.sync_once(sync_settings).await {
+        Ok(response) => response.next_batch,
+        Err(err) => {
+            report.push("sync", Severity::Error, format!("failed to sync: {}", err));
+            return report;
+        }
+    };
+    report.push("sync", Severity::Ok, "sync succeeded");
+
+    let room = match canary_room {
+        Some(canary_room) => match crate::send::resolve_room(&client, canary_room).await {
+            Ok(room_id) => match client.get_room(&room_id) {
+                Some(room) => room,
+                None => {
+                    report.push(
+                        "room",
+                        Severity::Error,
+                        format!("not a member of room {}", room_id),
+                    );
+                    return report;
+                }
+            },
+            Err(err) => {
+                report.push(
+                    "room",
+                    Severity::Error,
+                    format!("failed to resolve room {}: {}", canary_room, err),
+                );
+                return report;
+            }
+        },
+        None => {
+            let options = CreateOptions {
+                name: Some("matrixbot-ezlogin self-test".to_owned()),
+                ..Default::default()
+            };
+            match crate::rooms::create(&client, options).await {
+                Ok(room) => room,
+                Err(err) => {
+                    report.push(
+                        "room",
+                        Severity::Error,
+                        format!("failed to create a test room: {}", err),
+                    );
+                    return report;
+                }
+            }
+        }
+    };
+    report.push(
+        "room",
+        Severity::Ok,
+        format!("using room {}", room.room_id()),
+    );
+
+    match room.latest_encryption_state().await {
+        Ok(state) if state.is_encrypted() => {
+            report.push("encryption", Severity::Ok, "the room is encrypted")
+        }
+        Ok(_) => report.push(
+            "encryption",
+            Severity::Error,
+            "the room is not encrypted, use an encrypted canary room",
+        ),
+        Err(err) => report.push(
+            "encryption",
+            Severity::Error,
+            format!("failed to read the encryption state: {}", err),
+        ),
+    }
+
+    let nonce = rand::rng()
+        .sample_iter(rand::distr::Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect::<String>();
+    let body = format!("matrixbot-ezlogin self-test {}", nonce);
+    let sent = crate::send::send_content(
+        &room,
+        MessageBuilder::notice().push_text(&body).build(),
+        sync_helper.rate_limiter().as_ref(),
+    );
+    match sent.await {
+        Ok(event_id) => {
+            report.push("send", Severity::Ok, format!("sent event {}", event_id));
+            read_back(
+                &mut report,
+                &client,
+                &room,
+                &mut next_batch,
+                &event_id,
+                &body,
+            )
+            .await;
+        }
+        Err(err) => report.push("send", Severity::Error, format!("failed to send: {}", err)),
+    }
+
+    let backups = client.encryption().backups();
+    if backups.are_enabled().await {
+        match tokio::time::timeout(READ_BACK_TIMEOUT, backups.wait_for_steady_state()).await {
+            Ok(Ok(())) => report.push("backup", Severity::Ok, "room keys are backed up"),
+            Ok(Err(err)) => report.push(
+                "backup",
+                Severity::Error,
+                format!("failed to upload room keys: {}", err),
+            ),
+            Err(_) => report.push(
+                "backup",
+                Severity::Warning,
+                "room keys are still being uploaded",
+            ),
+        }
+    } else {
+        report.push(
+            "backup",
+            Severity::Error,
+            "server-side backup is not enabled, room keys are not backed up. Run setup again",
+        );
+    }
+
+    if canary_room.is_none() {
+        if let Err(err) = room.leave().await {
+            warn!("Failed to leave test room {}: {}", room.room_id(), err);
+        } else if let Err(err) = room.forget().await {
+            warn!("Failed to forget test room {}: {}", room.room_id(), err);
+        }
+    }
+    report
+}
+
+/// Syncs until the test message comes back, and checks that it was decrypted correctly.
+async fn read_back(
+    report: &mut DiagnosisReport,
+    client: &Client,
+    room: &Room,
+    next_batch: &mut String,
+    event_id: &OwnedEventId,
+    body: &str,
+) {
+    let started = Instant::now();
+    while started.elapsed() < READ_BACK_TIMEOUT {
+        let sync_settings = SyncSettings::default()
+            .token(next_batch.clone())
+            .timeout(Duration::from_secs(5));
+        let response = match client.sync_once(sync_settings).await {
+            Ok(response) => response,
+            Err(err) => {
+                report.push(
+                    "read back",
+                    Severity::Error,
+                    format!("failed to sync: {}", err),
+                );
+                return;
+            }
+        };
+        *next_batch = response.next_batch;
+        let Some(joined) = response.rooms.joined.get(room.room_id()) else {
+            continue;
+        };
+        let Some(event) = joined
+            .timeline
+            .events
+            .iter()
+            .find(|event| event.event_id().as_ref() == Some(event_id))
+        else {
+            continue;
+        };
+        let received_body = serde_json::from_str::<Value>(event.raw().json().get())
+            .ok()
+            .and_then(|event| Some(event.pointer("/content/body")?.as_str()?.to_owned()));
+        if event.encryption_info().is_none() {
+            report.push(
+                "read back",
+                Severity::Error,
+                "the message came back unencrypted, or couldn't be decrypted",
+            );
+        } else if received_body.as_deref() != Some(body) {
+            report.push(
+                "read back",
+                Severity::Error,
+                "the message came back with a different content",
+            );
+        } else {
+            report.push(
+                "read back",
+                Severity::Ok,
+                format!(
+                    "the message was decrypted after {:.1}s",
+                    started.elapsed().as_secs_f64()
+                ),
+            );
+        }
+        return;
+    }
+    report.push(
+        "read back",
+        Severity::Error,
+        format!(
+            "the message didn't come back within {}s",
+            READ_BACK_TIMEOUT.as_secs()
+        ),
+    );
+}
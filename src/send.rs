@@ -0,0 +1,143 @@
+use std::path::Path;
+use std::time::Duration;
+
+use eyre::{Result, bail};
+use matrix_sdk::Client;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::MessageLikeEventContent;
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::ruma::{
+    OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedRoomOrAliasId, OwnedTransactionId,
+};
+use tracing::{info, instrument};
+
+use crate::{MessageBuilder, RateLimiter};
+
+/// Sends one text message using the session saved in `data_dir`, then returns.
+///
+/// This is meant for cron jobs and shell scripts that reuse the bot's session for notifications.
+/// The message is encrypted if the room is encrypted.
+///
+/// The bot's sync token is left untouched, so the bot doesn't miss any events. But the bot must not be running at the same time,
+/// because the state database can only be opened by one process at the same time.
+///
+/// # Arguments
+///
+/// * `data_dir`, The directory containing the bot's state database.
+///
+/// * `room`, A room ID (`!room:example.com`) or a room alias (`#room:example.com`). The account must already be a member of this room.
+///
+/// * `body`, The plain text message to send.
+#[instrument(skip_all)]
+pub async fn send_message(data_dir: &Path, room: &str, body: &str) -> Result<OwnedEventId> {
+    let (client, sync_helper) = crate::login(data_dir).await?;
+    let room_id = resolve_room(&client, room).await?;
+
+    // Catch up from the bot's position to learn the current room members, but don't save the new token.
+    let mut sync_settings = SyncSettings::default().timeout(Duration::ZERO);
+    if let Some(token) = sync_helper.get_sync_token() {
+        sync_settings = sync_settings.token(token);
+    }
+    client.sync_once(sync_settings).await?;
+
+    let Some(room) = client.get_room(&room_id) else {
+        bail!("not a member of room {}", room_id);
+    };
+    crate::permissions::check_send(&room, "m.room.message").await?;
+    let content = serde_json::to_value(MessageBuilder::text().push_text(body).build())?;
+    let rate_limiter = sync_helper.rate_limiter();
+    let event_id = send_checked(
+        &room,
+        "m.room.message",
+        content,
+        None,
+        rate_limiter.as_ref(),
+    )
+    .await?;
+    info!("Message sent to {}.", room_id);
+    Ok(event_id)
+}
+
+/// Sends `content` to `room` the way every send helper of this crate does.
+///
+/// It checks the power level first, so a missing permission doesn't cost a request, then waits for `rate_limiter`, if any.
+pub(crate) async fn send_content(
+    room: &Room,
+    content: impl MessageLikeEventContent,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<OwnedEventId> {
+    let event_type = content.event_type().to_string();
+    send_raw(
+        room,
+        &event_type,
+        serde_json::to_value(&content)?,
+        None,
+        rate_limiter,
+    )
+    .await
+}
+
+/// Replies to `event` with a plain text notice, in the same thread if any, as [`CommandContext::reply`](crate::CommandContext::reply) and [`RouteContext::reply`](crate::RouteContext::reply) do.
+pub(crate) async fn reply_notice(
+    room: &Room,
+    event: &OriginalSyncRoomMessageEvent,
+    text: &str,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<OwnedEventId> {
+    let content =
+        crate::reply_without_fallback(event, MessageBuilder::notice().push_text(text).build());
+    send_content(room, content, rate_limiter).await
+}
+
+/// Like [`send_content`], for content that is already serialized, for example, of event types that Ruma doesn't know.
+///
+/// A transaction ID makes retries idempotent.
+pub(crate) async fn send_raw(
+    room: &Room,
+    event_type: &str,
+    content: serde_json::Value,
+    txn_id: Option<OwnedTransactionId>,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<OwnedEventId> {
+    crate::permissions::check_send(room, event_type).await?;
+    Ok(send_checked(room, event_type, content, txn_id, rate_limiter).await?)
+}
+
+/// Like [`send_raw`], without the power level check, and returning the SDK's error, for callers that handle both themselves.
+///
+/// Every send of this crate goes through here, so it emits the `ezlogin.send.*` tracing events.
+pub(crate) async fn send_checked(
+    room: &Room,
+    event_type: &str,
+    content: serde_json::Value,
+    txn_id: Option<OwnedTransactionId>,
+    rate_limiter: Option<&RateLimiter>,
+) -> matrix_sdk::Result<OwnedEventId> {
+    crate::rate_limit::acquire(rate_limiter, room.room_id()).await;
+    let mut request = room.send_raw(event_type, content);
+    if let Some(txn_id) = txn_id {
+        request = request.with_transaction_id(txn_id);
+    }
+    match request.await {
+        Ok(response) => {
+            crate::trace_events::send_succeeded(room.room_id(), &response.event_id);
+            crate::encryption_metrics::record_send(room).await;
+            Ok(response.event_id)
+        }
+        Err(err) => {
+            crate::trace_events::send_failed(room.room_id(), &err);
+            Err(err)
+        }
+    }
+}
+
+/// Resolves a room ID or a room alias into a room ID.
+pub(crate) async fn resolve_room(client: &Client, room: &str) -> Result<OwnedRoomId> {
+    let room = OwnedRoomOrAliasId::try_from(room)?;
+    if room.is_room_id() {
+        return Ok(OwnedRoomId::try_from(room.as_str())?);
+    }
+    let alias = OwnedRoomAliasId::try_from(room.as_str())?;
+    Ok(client.resolve_room_alias(&alias).await?.room_id)
+}
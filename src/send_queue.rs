@@ -0,0 +1,382 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use eyre::Result;
+use matrix_sdk::ruma::api::client::error::{ErrorKind, RetryAfter};
+use matrix_sdk::ruma::events::MessageLikeEventContent;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedTransactionId, RoomId, TransactionId};
+use matrix_sdk::{Client, RoomState};
+use rusqlite::OptionalExtension;
+use tokio::select;
+use tokio::sync::Notify;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::sync::{from_unix_millis, unix_millis};
+use crate::{RoomPermissions, SyncHelper};
+
+/// A durable queue of outgoing messages, stored in the state database.
+///
+/// Event handlers call [`SendQueue::enqueue`], which returns immediately. A worker started with [`SendQueue::run`] sends the messages in order,
+/// retrying with exponential backoff on errors, and honoring `M_LIMIT_EXCEEDED`. Messages that are still queued when the process exits are sent after the next start.
+///
+/// Each message keeps its transaction ID across retries, so the homeserver never delivers it twice.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+/// use matrixbot_ezlogin::SendQueue;
+///
+/// # async fn example(client: matrix_sdk::Client, sync_helper: matrixbot_ezlogin::SyncHelper, room_id: &matrix_sdk::ruma::RoomId) -> color_eyre::Result<()> {
+/// let send_queue = SendQueue::new(&sync_helper);
+/// tokio::spawn({
+///     let send_queue = send_queue.clone();
+///     let client = client.clone();
+///     async move { send_queue.run(&client).await }
+/// });
+///
+/// send_queue.enqueue(room_id, RoomMessageEventContent::notice_plain("Hello"))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct SendQueue {
+    sync_helper: SyncHelper,
+    notify: Arc<Notify>,
+    max_attempts: Option<u32>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+struct QueuedMessage {
+    id: i64,
+    room_id: OwnedRoomId,
+    txn_id: OwnedTransactionId,
+    event_type: String,
+    content: serde_json::Value,
+    attempts: u32,
+}
+
+enum Outcome {
+    Sent,
+    Retry(Option<Duration>),
+    GiveUp,
+}
+
+impl SendQueue {
+    /// Creates a [`SendQueue`] stored in the state database of `sync_helper`.
+    ///
+    /// By default, it retries forever, with backoff starting at 1 second and capped at 10 minutes.
+    pub fn new(sync_helper: &SyncHelper) -> Self {
+        Self {
+            sync_helper: sync_helper.clone(),
+            notify: Arc::new(Notify::new()),
+            max_attempts: None,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(600),
+        }
+    }
+
+    /// Drops a message after it fails this many times. [`None`] retries forever.
+    ///
+    /// Errors that won't go away by retrying, such as `M_FORBIDDEN` from the homeserver or the bot having left the room, drop the message immediately.
+    /// Lacking the power level to send is retried, because a moderator may raise it.
+    pub fn max_attempts(mut self, max_attempts: Option<u32>) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Sets the delay after the first failure, which doubles after every further failure up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Adds a message to the queue, and returns its queue ID.
+    ///
+    /// The message is written to the state database before returning, so it is sent even if the process exits right after.
+    pub fn enqueue<C>(&self, room_id: &RoomId, content: C) -> Result<i64>
+    where
+        C: MessageLikeEventContent,
+    {
+        let event_type = content.event_type().to_string();
+        let content = serde_json::to_string(&content)?;
+        let now = unix_millis(self.sync_helper.clock.now());
+        let id = {
+            let db = self.sync_helper.session_db();
+            db
+                .prepare_cached(
+                    "INSERT INTO send_queue (room_id, txn_id, event_type, content, attempts, next_attempt, time) VALUES (?, ?, ?, ?, 0, ?, ?);",
+                )?
+                .execute((
+                    room_id.as_str(),
+                    TransactionId::new().as_str(),
+                    &event_type,
+                    &content,
+                    now,
+                    now,
+                ))?;
+            db.last_insert_rowid()
+        };
+        debug!("Queued message {} to room {}.", id, room_id);
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    /// Returns the number of messages waiting to be sent.
+    pub fn len(&self) -> Result<usize> {
+        let len = self
+            .sync_helper
+            .session_db()
+            .prepare_cached("SELECT COUNT(*) FROM send_queue;")?
+            .query_row((), |row| row.get(0))?;
+        Ok(len)
+    }
+
+    /// Returns whether no message is waiting to be sent.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Sends queued messages until an error occurs with the state database.
+    ///
+    /// Messages in the same room are sent in the order they were queued. A failing message holds back the later messages in its room, but not in other rooms.
+    ///
+    /// Only run one worker per [`SendQueue`].
+    #[instrument(skip_all)]
+    pub async fn run(&self, client: &Client) -> Result<()> {
+        loop {
+            let now = self.sync_helper.clock.now();
+            let Some(message) = self.next_due(now)? else {
+                match self.next_attempt_time()? {
+                    Some(next_attempt) => {
+                        let delay = next_attempt.duration_since(now).unwrap_or_default();
+                        select! {
+                            _ = self.sync_helper.clock.sleep(delay) => (),
+                            _ = self.notify.notified() => (),
+                        }
+                    }
+                    None => self.notify.notified().await,
+                }
+                continue;
+            };
+
+            match self.send(client, &message).await {
+                Outcome::Sent | Outcome::GiveUp => self.delete(message.id)?,
+                Outcome::Retry(retry_after) => {
+                    let attempts = message.attempts.saturating_add(1);
+                    if self
+                        .max_attempts
+                        .is_some_and(|max_attempts| attempts >= max_attempts)
+                    {
+                        error!(
+                            "Dropping message {} to room {} after {} attempts.",
+                            message.id, message.room_id, attempts
+                        );
+                        self.delete(message.id)?;
+                        continue;
+                    }
+                    let delay = retry_after.unwrap_or_else(|| self.retry_delay(attempts));
+                    info!(
+                        "Retrying message {} to room {} in {:?}.",
+                        message.id, message.room_id, delay
+                    );
+                    self.reschedule(message.id, attempts, now + delay)?;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, client: &Client, message: &QueuedMessage) -> Outcome {
+        let room = match client.get_room(&message.room_id) {
+            Some(room) if room.state() == RoomState::Joined => room,
+            _ => {
+                warn!(
+                    "Dropping message {}: Not a member of room {}.",
+                    message.id, message.room_id
+                );
+                return Outcome::GiveUp;
+            }
+        };
+        // The power levels may change, so a missing permission is retried with backoff like any other error
+        if let Ok(false) = room.can_send(&message.event_type).await {
+            warn!(
+                "Not allowed to send {} events in room {}, holding back message {}.",
+                message.event_type, message.room_id, message.id
+            );
+            return Outcome::Retry(None);
+        }
+        let rate_limiter = self.sync_helper.rate_limiter();
+        let result = crate::send::send_checked(
+            &room,
+            &message.event_type,
+            message.content.clone(),
+            Some(message.txn_id.clone()),
+            rate_limiter.as_ref(),
+        )
+        .await;
+        let err = match result {
+            Ok(event_id) => {
+                info!(
+                    "Sent message {} to room {}, event {}.",
+                    message.id, message.room_id, event_id
+                );
+                return Outcome::Sent;
+            }
+            Err(err) => err,
+        };
+        if let Some(ErrorKind::LimitExceeded { retry_after }) = err.client_api_error_kind() {
+            warn!(
+                "Rate limited while sending message {} to room {}.",
+                message.id, message.room_id
+            );
+            return Outcome::Retry(retry_after.as_ref().map(|retry_after| {
+                match retry_after {
+                    RetryAfter::Delay(delay) => *delay,
+                    RetryAfter::DateTime(time) => time
+                        .duration_since(self.sync_helper.clock.now())
+                        .unwrap_or_default(),
+                }
+            }));
+        }
+        if let Some(api_error) = err.as_client_api_error()
+            && api_error.status_code.is_client_error()
+        {
+            error!(
+                "Dropping message {} to room {}: {}",
+                message.id, message.room_id, err
+            );
+            return Outcome::GiveUp;
+        }
+        warn!(
+            "Failed to send message {} to room {}: {}",
+            message.id, message.room_id, err
+        );
+        Outcome::Retry(None)
+    }
+
+    /// Returns how long to wait after the message failed `attempts` times.
+    fn retry_delay(&self, attempts: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+            .min(self.max_backoff)
+    }
+
+    fn next_due(&self, now: SystemTime) -> Result<Option<QueuedMessage>> {
+        let db = self.sync_helper.session_db();
+        let row = db
+            .prepare_cached(
+                "SELECT id, room_id, txn_id, event_type, content, attempts FROM send_queue AS q WHERE next_attempt <= ? AND NOT EXISTS (SELECT 1 FROM send_queue AS earlier WHERE earlier.room_id = q.room_id AND earlier.id < q.id) ORDER BY id LIMIT 1;",
+            )?
+            .query_row((unix_millis(now),), |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, u32>(5)?,
+                ))
+            })
+            .optional()?;
+        let Some((id, room_id, txn_id, event_type, content, attempts)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(QueuedMessage {
+            id,
+            room_id: room_id.try_into()?,
+            txn_id: txn_id.into(),
+            event_type,
+            content: serde_json::from_str(&content)?,
+            attempts,
+        }))
+    }
+
+    fn next_attempt_time(&self) -> Result<Option<SystemTime>> {
+        let next_attempt: Option<i64> = self
+            .sync_helper
+            .session_db()
+            .prepare_cached("SELECT MIN(next_attempt) FROM send_queue AS q WHERE NOT EXISTS (SELECT 1 FROM send_queue AS earlier WHERE earlier.room_id = q.room_id AND earlier.id < q.id);")?
+            .query_row((), |row| row.get(0))?;
+        Ok(next_attempt.map(from_unix_millis))
+    }
+
+    fn reschedule(&self, id: i64, attempts: u32, next_attempt: SystemTime) -> Result<()> {
+        self.sync_helper
+            .session_db()
+            .prepare_cached("UPDATE send_queue SET attempts = ?, next_attempt = ? WHERE id = ?;")?
+            .execute((attempts, unix_millis(next_attempt), id))?;
+        Ok(())
+    }
+
+    fn delete(&self, id: i64) -> Result<()> {
+        self.sync_helper
+            .session_db()
+            .prepare_cached("DELETE FROM send_queue WHERE id = ?;")?
+            .execute((id,))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+    use matrix_sdk::ruma::room_id;
+
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_limit() {
+        let sync_helper = SyncHelper::in_memory(Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH)));
+        let send_queue =
+            SendQueue::new(&sync_helper).backoff(Duration::from_secs(1), Duration::from_secs(10));
+        let delays =
+            [1, 2, 3, 4, 5, 100, u32::MAX].map(|attempts| send_queue.retry_delay(attempts));
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10, 10].map(Duration::from_secs));
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_backoff_on_the_clock() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let sync_helper = SyncHelper::in_memory(Arc::new(clock.clone()));
+        let send_queue = SendQueue::new(&sync_helper);
+        let id = send_queue
+            .enqueue(
+                room_id!("!room:example.com"),
+                RoomMessageEventContent::notice_plain("Hello"),
+            )
+            .unwrap();
+        let delay = send_queue.retry_delay(3);
+        send_queue.reschedule(id, 3, clock.now() + delay).unwrap();
+
+        // Not logged in, so it isn't in the room, and the worker drops the message as soon as it is due
+        let client = Client::builder()
+            .homeserver_url("http://127.0.0.1:9")
+            .build()
+            .await
+            .unwrap();
+        let worker = tokio::spawn({
+            let send_queue = send_queue.clone();
+            async move { send_queue.run(&client).await }
+        });
+
+        while clock.sleepers() == 0 {
+            assert!(!worker.is_finished());
+            tokio::task::yield_now().await;
+        }
+        clock.advance(delay - Duration::from_millis(1));
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(send_queue.len().unwrap(), 1);
+
+        clock.advance(Duration::from_millis(1));
+        while send_queue.len().unwrap() != 0 {
+            assert!(!worker.is_finished());
+            tokio::task::yield_now().await;
+        }
+        worker.abort();
+    }
+}
@@ -0,0 +1,199 @@
+use std::future::Future;
+
+use eyre::Result;
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncStateEvent, SyncMessageLikeEvent, SyncStateEvent,
+};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId, OwnedUserId};
+use matrix_sdk::{Client, RoomState};
+use tracing::{Instrument, error, warn};
+
+use crate::{MessageBuilder, RateLimiter};
+
+/// The room tag that homeservers put on server-notice rooms.
+const SERVER_NOTICE_TAG: &str = "m.server_notice";
+
+/// An event imposed by the homeserver or a room's server ACL, delivered by [`on_server_event`].
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    /// The homeserver sent a message to a server-notice room, for example, about a usage limit or updated terms of service.
+    Notice {
+        /// The message event.
+        event_id: OwnedEventId,
+        /// The homeserver's notice account.
+        sender: OwnedUserId,
+        /// The plain text of the notice.
+        body: String,
+        /// The `server_notice_type` of an `m.server_notice` message, for example, `m.server_notice.usage_limit_reached`.
+        notice_type: Option<String>,
+        /// Where to contact the homeserver administrator, if given.
+        admin_contact: Option<String>,
+    },
+    /// Someone changed the `m.room.server_acl` of a room, deciding which servers may participate in it.
+    AclChange {
+        /// The state event.
+        event_id: OwnedEventId,
+        /// Who changed the server ACL.
+        sender: OwnedUserId,
+        /// Server name patterns allowed to participate.
+        allow: Vec<String>,
+        /// Server name patterns denied from participating.
+        deny: Vec<String>,
+        /// Whether servers named by IP literals may participate.
+        allow_ip_literals: bool,
+    },
+}
+
+/// Whether `room` is a server-notice room, which the homeserver uses to contact the account.
+///
+/// Server-notice rooms can't be rejoined once left, so housekeeping must never leave or forget them.
+pub async fn is_server_notice_room(room: &Room) -> Result<bool> {
+    Ok(room
+        .tags()
+        .await?
+        .is_some_and(|tags| tags.keys().any(|tag| tag.as_ref() == SERVER_NOTICE_TAG)))
+}
+
+/// Registers an event handler on `client` that calls `handler` for every message in a server-notice room,
+/// and every server ACL change in a room the bot has joined.
+pub fn on_server_event<F, Fut>(client: &Client, handler: F) -> EventHandlerHandle
+where
+    F: Fn(Room, ServerEvent) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    client.add_event_handler(
+        move |raw: Raw<AnySyncTimelineEvent>, room: Room, client: Client| {
+            let handler = handler.clone();
+            async move {
+                if room.state() != RoomState::Joined {
+                    return;
+                }
+                let Ok(event) = raw.deserialize() else {
+                    return;
+                };
+                if Some(event.sender()) == client.user_id() {
+                    return;
+                }
+                let server_event = match event {
+                    AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                        SyncMessageLikeEvent::Original(event),
+                    )) => {
+                        match is_server_notice_room(&room).await {
+                            Ok(true) => (),
+                            Ok(false) => return,
+                            Err(err) => {
+                                warn!(
+                                    "Failed to read the tags of room {}: {}",
+                                    room.room_id(),
+                                    err
+                                );
+                                return;
+                            }
+                        }
+                        let (notice_type, admin_contact) = match &event.content.msgtype {
+                            MessageType::ServerNotice(notice) => (
+                                Some(notice.server_notice_type.as_str().to_owned()),
+                                notice.admin_contact.clone(),
+                            ),
+                            _ => (None, None),
+                        };
+                        ServerEvent::Notice {
+                            body: event.content.body().to_owned(),
+                            event_id: event.event_id,
+                            sender: event.sender,
+                            notice_type,
+                            admin_contact,
+                        }
+                    }
+                    AnySyncTimelineEvent::State(AnySyncStateEvent::RoomServerAcl(
+                        SyncStateEvent::Original(event),
+                    )) => ServerEvent::AclChange {
+                        event_id: event.event_id,
+                        sender: event.sender,
+                        allow: event.content.allow,
+                        deny: event.content.deny,
+                        allow_ip_literals: event.content.allow_ip_literals,
+                    },
+                    _ => return,
+                };
+                let room_id = room.room_id().to_owned();
+                if let Err(err) = handler(room, server_event).in_current_span().await {
+                    error!("Server event handler failed in room {}: {:?}", room_id, err);
+                }
+            }
+        },
+    )
+}
+
+/// Reports every server notice and server ACL change to the room `admin_room_id`, so the bot's operators see them.
+///
+/// Each report waits for `rate_limiter` first, if any.
+///
+/// # Example
+///
+/// ```no_run
+/// # fn example(client: &matrix_sdk::Client, admin_room_id: matrix_sdk::ruma::OwnedRoomId) {
+/// matrixbot_ezlogin::forward_server_events(client, admin_room_id, None);
+/// # }
+/// ```
+pub fn forward_server_events(
+    client: &Client,
+    admin_room_id: OwnedRoomId,
+    rate_limiter: Option<RateLimiter>,
+) -> EventHandlerHandle {
+    let own_client = client.clone();
+    on_server_event(client, move |room, server_event| {
+        let client = own_client.clone();
+        let admin_room_id = admin_room_id.clone();
+        let rate_limiter = rate_limiter.clone();
+        async move {
+            let Some(admin_room) = client.get_room(&admin_room_id) else {
+                warn!("Not a member of admin room {}.", admin_room_id);
+                return Ok(());
+            };
+            let text = match server_event {
+                ServerEvent::Notice {
+                    body,
+                    admin_contact,
+                    ..
+                } => match admin_contact {
+                    Some(admin_contact) => format!(
+                        "Server notice: {}\nAdministrator contact: {}",
+                        body, admin_contact
+                    ),
+                    None => format!("Server notice: {}", body),
+                },
+                ServerEvent::AclChange {
+                    sender,
+                    allow,
+                    deny,
+                    allow_ip_literals,
+                    ..
+                } => format!(
+                    "{} changed the server ACL of room {}: allow [{}], deny [{}], IP literals {}.",
+                    sender,
+                    room.room_id(),
+                    allow.join(", "),
+                    deny.join(", "),
+                    if allow_ip_literals {
+                        "allowed"
+                    } else {
+                        "denied"
+                    }
+                ),
+            };
+            crate::send::send_content(
+                &admin_room,
+                MessageBuilder::notice().push_text(&text).build(),
+                rate_limiter.as_ref(),
+            )
+            .await?;
+            Ok(())
+        }
+    })
+}
@@ -0,0 +1,178 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{Report, Result, bail};
+use rusqlite::{OpenFlags, OptionalExtension};
+use serde::Deserialize;
+use tracing::{error, info, instrument};
+
+use crate::SetupConfig;
+
+/// Credentials of one bot account for [`setup_batch`].
+///
+/// It can be deserialized from a configuration file, for example, one `[[bot]]` table of a TOML file:
+///
+/// ```toml
+/// [[bot]]
+/// name = "echo-bot"
+/// data_dir = "/var/lib/bots/echo-bot"
+/// homeserver = "matrix.example.org"
+/// username = "echo-bot"
+/// password = "..."
+/// ```
+#[derive(Clone, Deserialize)]
+pub struct SetupProfile {
+    /// A name to refer to this bot in logs and in the [`SetupBatchReport`]. Defaults to the user name.
+    #[serde(default)]
+    pub name: String,
+    /// The directory to store the bot's state database, see [`SetupConfig::data_dir`].
+    pub data_dir: PathBuf,
+    /// The Matrix homeserver, see [`SetupConfig::homeserver`].
+    pub homeserver: String,
+    /// The user name, see [`SetupConfig::username`].
+    pub username: String,
+    /// The password.
+    pub password: String,
+    /// The device name, see [`SetupConfig::device_name`]. Defaults to `matrixbot-ezlogin`.
+    #[serde(default = "default_device_name")]
+    pub device_name: String,
+    /// The recovery key, if the account already has a server-side backup. Without it, the setup of such an account fails.
+    #[serde(default)]
+    pub recovery_key: Option<String>,
+    /// Whether to create a server-side backup if the account has none, which resets its cryptographic identity. Defaults to `false`.
+    ///
+    /// Resetting makes other users' devices distrust the bot until it is verified again, so it must be allowed account by account.
+    /// Without it, the setup of an account without a backup fails.
+    #[serde(default)]
+    pub allow_reset: bool,
+}
+
+fn default_device_name() -> String {
+    "matrixbot-ezlogin".to_owned()
+}
+
+impl SetupProfile {
+    fn display_name(&self) -> &str {
+        if self.name.is_empty() {
+            &self.username
+        } else {
+            &self.name
+        }
+    }
+}
+
+/// The result of [`setup_batch`].
+#[derive(Debug, Default)]
+pub struct SetupBatchReport {
+    /// Names of the bots that were set up.
+    pub succeeded: Vec<String>,
+    /// Names of the bots that were skipped, because their data directory already contains a session.
+    pub skipped: Vec<String>,
+    /// Names of the bots that failed to set up, with the errors.
+    pub failed: Vec<(String, Report)>,
+}
+
+impl SetupBatchReport {
+    /// Returns whether no bot failed to set up.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl std::fmt::Display for SetupBatchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for name in &self.succeeded {
+            writeln!(f, "[ OK ] {}", name)?;
+        }
+        for name in &self.skipped {
+            writeln!(f, "[SKIP] {}: already set up", name)?;
+        }
+        for (name, err) in &self.failed {
+            writeln!(f, "[FAIL] {}: {}", name, err)?;
+        }
+        write!(
+            f,
+            "{} set up, {} skipped, {} failed.",
+            self.succeeded.len(),
+            self.skipped.len(),
+            self.failed.len()
+        )
+    }
+}
+
+/// Sets up many bot accounts in sequence without human intervention, for teams that onboard a fleet of bots at once.
+///
+/// Each profile is set up with [`setup`](crate::setup). A failure is recorded in the returned report, and the next profile is set up anyway.
+/// Profiles whose data directory already contains a session are skipped, so the batch can be re-run after fixing the failed ones.
+///
+/// `print_recovery_key` is called as `print_recovery_key(profile, recovery_key, new_backup)` for every account,
+/// and must keep the recovery key in a safe place, see [`SetupConfig::print_recovery_key`].
+/// Accounts without a server-side backup only get a new one if their profile sets [`SetupProfile::allow_reset`], because it resets their cryptographic identity.
+#[instrument(skip_all)]
+pub async fn setup_batch<F, Fut>(
+    profiles: Vec<SetupProfile>,
+    mut print_recovery_key: F,
+) -> SetupBatchReport
+where
+    F: FnMut(&SetupProfile, String, bool) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut report = SetupBatchReport::default();
+    for profile in profiles {
+        let name = profile.display_name().to_owned();
+        if has_session(&profile.data_dir) {
+            info!("Skipping {}, because it is already set up.", name);
+            report.skipped.push(name);
+            continue;
+        }
+        info!("Setting up {}.", name);
+        let result = crate::setup(SetupConfig {
+            data_dir: &profile.data_dir,
+            homeserver: &profile.homeserver,
+            username: &profile.username,
+            password: &profile.password,
+            device_name: &profile.device_name,
+            ask_recovery_key: async {
+                match &profile.recovery_key {
+                    Some(recovery_key) => Ok(recovery_key.clone()),
+                    None => {
+                        bail!("the account has a server-side backup, but no recovery_key is given")
+                    }
+                }
+            },
+            before_create_backup: async {
+                if !profile.allow_reset {
+                    bail!(
+                        "the account has no server-side backup, set allow_reset to reset its cryptographic identity"
+                    );
+                }
+                Ok(())
+            },
+            print_recovery_key: |recovery_key, new_backup| {
+                print_recovery_key(&profile, recovery_key, new_backup)
+            },
+        })
+        .await;
+        match result {
+            Ok(_) => report.succeeded.push(name),
+            Err(err) => {
+                error!("Failed to set up {}: {:?}", name, err);
+                report.failed.push((name, err));
+            }
+        }
+    }
+    report
+}
+
+/// Returns whether `data_dir` contains a session saved by a successful setup. A failed setup leaves the state database without one.
+fn has_session(data_dir: &Path) -> bool {
+    let path = data_dir.join("matrixbot-ezlogin.sqlite3");
+    if !path.exists() {
+        return false;
+    }
+    rusqlite::Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .and_then(|conn| {
+            conn.query_row("SELECT 1 FROM matrix_session WHERE id = 0;", (), |_| Ok(()))
+                .optional()
+        })
+        .is_ok_and(|row| row.is_some())
+}
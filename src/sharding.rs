@@ -0,0 +1,227 @@
+//! Horizontal scaling of one bot account over several processes, each with its own data directory and device.
+//!
+//! Every process syncs every room, but only handles the rooms assigned to its shard. The processes coordinate through a table in a shared SQLite file,
+//! for example, on a volume mounted into every container. Each process writes a heartbeat into it, and the live shards split the rooms between them
+//! with rendezvous hashing, so when a shard joins or leaves, only the rooms it gains or loses move.
+//!
+//! Shared SQLite files need a file system with working locks, which rules out most network file systems. A shard that stops without calling
+//! [`Sharding::leave`] keeps its rooms until its heartbeat expires, and during that time their events are handled by nobody.
+//!
+//! The assignment is not a lease. Each shard reloads the list of live shards on its own heartbeat, so after a shard joins or leaves,
+//! the others notice it at different times, up to a third of the TTL apart. In that window, a room that moves may be owned by two shards,
+//! which both handle its events, or by none, which miss them. Make handlers idempotent if double handling matters,
+//! and don't rely on sharding for mutual exclusion.
+//!
+//! Liveness compares heartbeat times written by different machines, so their clocks must agree. With a skew of more than the TTL,
+//! a shard whose clock runs ahead sees the others expire, and a shard whose clock runs behind keeps dead shards alive in its view.
+//! Keep the clocks synchronized, for example with NTP, and choose a TTL well above the expected skew.
+//!
+//! Only [`Dispatcher`](crate::Dispatcher) chains that include the [`Sharding`] middleware are filtered. Handlers registered directly with
+//! [`Client::add_event_handler`](matrix_sdk::Client::add_event_handler), including the ones this crate registers, such as [`on_edit`](crate::on_edit),
+//! run on every shard. Check [`Sharding::owns`] in them.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+//! use matrixbot_ezlogin::Dispatcher;
+//! use matrixbot_ezlogin::sharding::Sharding;
+//!
+//! # async fn example(client: &matrix_sdk::Client, sync_helper: &matrixbot_ezlogin::SyncHelper) -> color_eyre::Result<()> {
+//! let sharding = Sharding::open("/shared/shards.sqlite3".as_ref(), "shard-1", Duration::from_secs(30))?;
+//! sharding.spawn_heartbeat();
+//! Dispatcher::<OriginalSyncRoomMessageEvent>::new()
+//!     .with(sharding.clone())
+//!     .register(client, sync_helper, "echo", |ctx| async move {
+//!         // Only called for rooms owned by this shard
+//!         Ok(())
+//!     });
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use eyre::Result;
+use matrix_sdk::ruma::RoomId;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, debug, error, info, instrument};
+
+use crate::middleware::MiddlewareFuture;
+use crate::mutex::MutexExt;
+use crate::sync::unix_millis;
+use crate::{Clock, DispatchEvent, EventContext, Middleware, Next, SystemClock};
+
+/// Membership of this process in a group of shards sharing one bot account. See the [module documentation](self).
+///
+/// It is also a [`Middleware`] that drops events from rooms owned by other shards.
+#[derive(Clone, Debug)]
+pub struct Sharding {
+    inner: Arc<ShardingInner>,
+}
+
+#[derive(Debug)]
+struct ShardingInner {
+    conn: Mutex<rusqlite::Connection>,
+    shard_id: String,
+    ttl: Duration,
+    live_shards: Mutex<Vec<String>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Sharding {
+    /// Opens or creates the shared coordination file at `path`, and registers this process as `shard_id`.
+    ///
+    /// `shard_id` must be unique among the processes, and should stay the same across restarts, so the rooms don't move on every restart.
+    /// A shard is considered dead if it doesn't write a heartbeat for `ttl`.
+    pub fn open(path: &Path, shard_id: impl Into<String>, ttl: Duration) -> Result<Self> {
+        Self::open_with_clock(path, shard_id, ttl, Arc::new(SystemClock))
+    }
+
+    /// Same as [`Sharding::open`], but reads the time for heartbeats and waits between them on `clock`.
+    #[instrument(skip(path, shard_id, clock))]
+    pub fn open_with_clock(
+        path: &Path,
+        shard_id: impl Into<String>,
+        ttl: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        // Other shards hold the write lock briefly during their heartbeats
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS shards (shard_id TEXT PRIMARY KEY, heartbeat INTEGER NOT NULL) STRICT;",
+        )?;
+        let sharding = Self {
+            inner: Arc::new(ShardingInner {
+                conn: Mutex::new(conn),
+                shard_id: shard_id.into(),
+                ttl,
+                live_shards: Mutex::new(Vec::new()),
+                clock,
+            }),
+        };
+        sharding.heartbeat()?;
+        Ok(sharding)
+    }
+
+    /// Returns the ID of this shard.
+    pub fn shard_id(&self) -> &str {
+        &self.inner.shard_id
+    }
+
+    /// Writes the heartbeat of this shard, and reloads the list of live shards.
+    ///
+    /// It blocks on disk I/O, so call it from [`tokio::task::spawn_blocking`] in async code.
+    /// Usually you don't need to call it, use [`Sharding::spawn_heartbeat`] instead.
+    pub fn heartbeat(&self) -> Result<()> {
+        let now = unix_millis(self.inner.clock.now());
+        let live_shards = {
+            let conn = self.inner.conn.lock_unpoisoned();
+            conn.execute(
+                "INSERT INTO shards (shard_id, heartbeat) VALUES (?1, ?2) ON CONFLICT (shard_id) DO UPDATE SET heartbeat = excluded.heartbeat;",
+                (&self.inner.shard_id, now),
+            )?;
+            let mut stmt = conn.prepare_cached(
+                "SELECT shard_id FROM shards WHERE heartbeat >= ?1 ORDER BY shard_id;",
+            )?;
+            let expiry =
+                now.saturating_sub(self.inner.ttl.as_millis().try_into().unwrap_or(i64::MAX));
+            stmt.query_map((expiry,), |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?
+        };
+        let mut current = self.inner.live_shards.lock_unpoisoned();
+        if *current != live_shards {
+            info!("Live shards: {}.", live_shards.join(", "));
+            *current = live_shards;
+        }
+        Ok(())
+    }
+
+    /// Spawns a Tokio task that calls [`Sharding::heartbeat`] three times per `ttl`. Abort the returned task to stop.
+    pub fn spawn_heartbeat(&self) -> JoinHandle<()> {
+        let sharding = self.clone();
+        tokio::spawn(
+            async move {
+                loop {
+                    sharding.inner.clock.sleep(sharding.inner.ttl / 3).await;
+                    let blocking_sharding = sharding.clone();
+                    match tokio::task::spawn_blocking(move || blocking_sharding.heartbeat()).await {
+                        Ok(Ok(())) => (),
+                        Ok(Err(err)) => error!("Failed to write the shard heartbeat: {:?}", err),
+                        Err(err) => error!("Shard heartbeat panicked: {}", err),
+                    }
+                }
+            }
+            .in_current_span(),
+        )
+    }
+
+    /// Removes this shard from the shared table, so the other shards take over its rooms at their next heartbeat, instead of after `ttl`.
+    ///
+    /// Call it during a graceful shutdown, after aborting the task returned by [`Sharding::spawn_heartbeat`].
+    pub fn leave(&self) -> Result<()> {
+        self.inner.conn.lock_unpoisoned().execute(
+            "DELETE FROM shards WHERE shard_id = ?1;",
+            (&self.inner.shard_id,),
+        )?;
+        info!("Left the shard group.");
+        Ok(())
+    }
+
+    /// Returns the IDs of the live shards, as of the last heartbeat.
+    pub fn live_shards(&self) -> Vec<String> {
+        self.inner.live_shards.lock_unpoisoned().clone()
+    }
+
+    /// Returns the ID of the shard that owns `room_id`, as of the last heartbeat.
+    pub fn owner(&self, room_id: &RoomId) -> String {
+        let live_shards = self.inner.live_shards.lock_unpoisoned();
+        // Rendezvous hashing: the shard with the highest weight for the room wins
+        live_shards
+            .iter()
+            .max_by_key(|shard_id| weight(shard_id, room_id))
+            .unwrap_or(&self.inner.shard_id)
+            .clone()
+    }
+
+    /// Returns whether this shard owns `room_id`, as of the last heartbeat.
+    pub fn owns(&self, room_id: &RoomId) -> bool {
+        self.owner(room_id) == self.inner.shard_id
+    }
+}
+
+impl<E: DispatchEvent> Middleware<E> for Sharding {
+    fn call(&self, ctx: EventContext<E>, next: Next<E>) -> MiddlewareFuture {
+        let sharding = self.clone();
+        Box::pin(async move {
+            if !sharding.owns(ctx.room.room_id()) {
+                debug!(
+                    "Ignoring room {}: Owned by another shard.",
+                    ctx.room.room_id()
+                );
+                return Ok(());
+            }
+            next.run(ctx).await
+        })
+    }
+}
+
+/// Hashes a shard and a room with 64-bit FNV-1a, which is stable across Rust versions and platforms, unlike the hasher of the standard library.
+fn weight(shard_id: &str, room_id: &RoomId) -> u64 {
+    let hash = shard_id
+        .bytes()
+        .chain([0])
+        .chain(room_id.as_str().bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+    // FNV-1a mixes the last bytes poorly, so finish with the SplitMix64 finalizer to spread the rooms evenly
+    let hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
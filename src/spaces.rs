@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::future::Future;
+
+use eyre::Result;
+use matrix_sdk::event_handler::EventHandlerHandle;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::api::client::space::get_hierarchy;
+use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{
+    OwnedRoomAliasId, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, RoomOrAliasId,
+};
+use matrix_sdk::{Client, RoomState};
+use serde_json::Value;
+use tracing::{Instrument, error, info, instrument, warn};
+
+/// A room or subspace in a space hierarchy, returned by [`space_hierarchy`].
+#[derive(Clone, Debug)]
+pub struct SpaceRoom {
+    /// The room ID.
+    pub room_id: OwnedRoomId,
+    /// The space listing this room, or [`None`] for the root space itself.
+    pub parent: Option<OwnedRoomId>,
+    /// Servers to join the room through, as listed by the parent space.
+    pub via: Vec<OwnedServerName>,
+    /// Whether this room is a space itself.
+    pub is_space: bool,
+    /// The room name.
+    pub name: Option<String>,
+    /// The room topic.
+    pub topic: Option<String>,
+    /// The canonical alias of the room.
+    pub canonical_alias: Option<OwnedRoomAliasId>,
+    /// The join rule, for example, `"public"`, `"invite"`, or `"restricted"`.
+    pub join_rule: Option<String>,
+    /// The number of joined members.
+    pub num_joined_members: u64,
+}
+
+/// A child room added to or removed from a space, delivered by [`on_space_child`].
+#[derive(Clone, Debug)]
+pub struct SpaceChildChange {
+    /// The client that received the change.
+    pub client: Client,
+    /// The space.
+    pub space: Room,
+    /// Who changed the space.
+    pub sender: OwnedUserId,
+    /// The child room.
+    pub child: OwnedRoomId,
+    /// Servers to join the child room through. Empty if the child was removed.
+    pub via: Vec<OwnedServerName>,
+    /// `true` if the child was added or updated, `false` if it was removed.
+    pub added: bool,
+}
+
+/// Lists every room and subspace in the space `space_id`, recursively, including the space itself.
+///
+/// The server only includes rooms the bot can see, which are rooms the bot has joined, and rooms that are public or `restricted` to a space the bot has joined.
+#[instrument(skip(client))]
+pub async fn space_hierarchy(client: &Client, space_id: &RoomId) -> Result<Vec<SpaceRoom>> {
+    // The parent and `via` of each child, learned from the `m.space.child` state of the spaces listed before it
+    let mut parents = HashMap::<OwnedRoomId, (OwnedRoomId, Vec<OwnedServerName>)>::new();
+    let mut rooms = Vec::new();
+    let mut from = None;
+    loop {
+        let mut request = get_hierarchy::v1::Request::new(space_id.to_owned());
+        request.from = from;
+        let response = client.send(request).await?;
+        for chunk in response.rooms {
+            // The layout of the chunk differs between ruma versions, but its JSON follows the specification
+            let chunk = serde_json::to_value(&chunk)?;
+            let Some(room) = parse_chunk(&chunk, &mut parents) else {
+                continue;
+            };
+            rooms.push(room);
+        }
+        match response.next_batch {
+            Some(next_batch) => from = Some(next_batch),
+            None => return Ok(rooms),
+        }
+    }
+}
+
+/// Joins every room in the space `space_id` for which `filter` returns `true`, and returns the newly joined rooms.
+///
+/// Rooms already joined are skipped. Each room is tried once, and failures are logged, so it is safe to call again later to retry.
+/// Subspaces are passed to `filter` too. Joining a subspace doesn't join its children, but they are already listed by [`space_hierarchy`].
+///
+/// # Example
+///
+/// Joining every public non-space room:
+///
+/// ```no_run
+/// # async fn example(client: &matrix_sdk::Client, space_id: &matrix_sdk::ruma::RoomId) -> color_eyre::Result<()> {
+/// matrixbot_ezlogin::join_space_children(client, space_id, |room| {
+///     !room.is_space && room.join_rule.as_deref() == Some("public")
+/// })
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[instrument(skip(client, filter))]
+pub async fn join_space_children<F>(
+    client: &Client,
+    space_id: &RoomId,
+    filter: F,
+) -> Result<Vec<Room>>
+where
+    F: Fn(&SpaceRoom) -> bool,
+{
+    let mut joined = Vec::new();
+    for child in space_hierarchy(client, space_id).await? {
+        if child.room_id == space_id || !filter(&child) {
+            continue;
+        }
+        if client
+            .get_room(&child.room_id)
+            .is_some_and(|room| room.state() == RoomState::Joined)
+        {
+            continue;
+        }
+        info!("Joining room {} in space {}.", child.room_id, space_id);
+        match client
+            .join_room_by_id_or_alias(<&RoomOrAliasId>::from(&*child.room_id), &child.via)
+            .await
+        {
+            Ok(room) => joined.push(room),
+            Err(err) => warn!(
+                "Failed to join room {} in space {}: {}",
+                child.room_id, space_id, err
+            ),
+        }
+    }
+    Ok(joined)
+}
+
+/// Registers an event handler on `client` that calls `handler` whenever a child room is added to or removed from a joined space.
+pub fn on_space_child<F, Fut>(client: &Client, handler: F) -> EventHandlerHandle
+where
+    F: Fn(SpaceChildChange) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    client.add_event_handler(
+        move |raw: Raw<AnySyncTimelineEvent>, room: Room, client: Client| {
+            let handler = handler.clone();
+            async move {
+                if room.state() != RoomState::Joined || !room.is_space() {
+                    return;
+                }
+                // An `m.space.child` event without `via` removes the child, and ruma may fail to deserialize its empty content
+                let Ok(event) = serde_json::from_str::<Value>(raw.json().get()) else {
+                    return;
+                };
+                if event.get("type").and_then(Value::as_str) != Some("m.space.child") {
+                    return;
+                }
+                let (Some(child), Some(sender)) = (
+                    event
+                        .get("state_key")
+                        .and_then(Value::as_str)
+                        .and_then(|child| OwnedRoomId::try_from(child).ok()),
+                    event
+                        .get("sender")
+                        .and_then(Value::as_str)
+                        .and_then(|sender| OwnedUserId::try_from(sender).ok()),
+                ) else {
+                    return;
+                };
+                let via = parse_via(event.get("content"));
+                let room_id = room.room_id().to_owned();
+                let change = SpaceChildChange {
+                    client,
+                    space: room,
+                    sender,
+                    child,
+                    added: !via.is_empty(),
+                    via,
+                };
+                if let Err(err) = handler(change).in_current_span().await {
+                    error!("Space child handler failed in space {}: {:?}", room_id, err);
+                }
+            }
+        },
+    )
+}
+
+fn parse_chunk(
+    chunk: &Value,
+    parents: &mut HashMap<OwnedRoomId, (OwnedRoomId, Vec<OwnedServerName>)>,
+) -> Option<SpaceRoom> {
+    let room_id = OwnedRoomId::try_from(chunk.get("room_id")?.as_str()?).ok()?;
+    if let Some(children_state) = chunk.get("children_state").and_then(Value::as_array) {
+        for child_event in children_state {
+            let Some(child) = child_event
+                .get("state_key")
+                .and_then(Value::as_str)
+                .and_then(|child| OwnedRoomId::try_from(child).ok())
+            else {
+                continue;
+            };
+            let via = parse_via(child_event.get("content"));
+            if !via.is_empty() {
+                parents.entry(child).or_insert((room_id.clone(), via));
+            }
+        }
+    }
+    let (parent, via) = parents
+        .remove(&room_id)
+        .map_or((None, Vec::new()), |(parent, via)| (Some(parent), via));
+    let string = |field| {
+        chunk
+            .get(field)
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
+    };
+    Some(SpaceRoom {
+        parent,
+        via,
+        is_space: chunk.get("room_type").and_then(Value::as_str) == Some("m.space"),
+        name: string("name"),
+        topic: string("topic"),
+        canonical_alias: chunk
+            .get("canonical_alias")
+            .and_then(Value::as_str)
+            .and_then(|alias| OwnedRoomAliasId::try_from(alias).ok()),
+        join_rule: string("join_rule"),
+        num_joined_members: chunk
+            .get("num_joined_members")
+            .and_then(Value::as_u64)
+            .unwrap_or_default(),
+        room_id,
+    })
+}
+
+fn parse_via(content: Option<&Value>) -> Vec<OwnedServerName> {
+    content
+        .and_then(|content| content.get("via"))
+        .and_then(Value::as_array)
+        .map(|via| {
+            via.iter()
+                .filter_map(|server| server.as_str())
+                .filter_map(|server| OwnedServerName::try_from(server).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -1,23 +1,34 @@
-use std::path::Path;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
 
 use async_stream::try_stream;
 use eyre::Result;
 use matrix_sdk::config::SyncSettings;
+use matrix_sdk::ruma::presence::PresenceState;
 use matrix_sdk::sync::SyncResponse;
 use matrix_sdk::{Client, LoopCtrl};
 use rusqlite::OptionalExtension;
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinHandle};
 use tokio_stream::{Stream, StreamExt};
-use tracing::{debug, instrument, trace};
+use tracing::{debug, info, instrument, trace};
 
+use crate::catch_up::CatchUpState;
 use crate::db::SQLiteHelper;
+use crate::dedup::DEFAULT_SEEN_EVENT_TTL;
+use crate::mutex::MutexExt;
+use crate::pause::PauseState;
+use crate::token_mirror::TokenMirror;
+use crate::{BotFilter, Clock, RateLimiter, SyncError, SyncMetrics, SystemClock};
 
 /// Helps you maintain sync positions between process restarts.
 ///
 /// This allows you to distinguish events that occurred while the bot was offline from those that happened after it restarted.
 ///
-/// It maintains a `sync_token` in the state database.
+/// It maintains a `sync_token` in the state database, along with a history of previous tokens for [`SyncHelper::rollback`].
 ///
 /// # Important
 ///
@@ -83,15 +94,36 @@ use crate::db::SQLiteHelper;
 /// * Or, you can call the convenience methods [`SyncHelper::sync`], [`SyncHelper::sync_once`], or [`SyncHelper::sync_stream`], that automatically loads and saves `sync_token` for you.
 ///
 /// * Or, you can also mix and match the easy and hard ways in an application.
+///
+/// The convenience methods also respect [`SyncHelper::pause`] and [`SyncHelper::resume`].
+///
+/// They accept either a [`SyncSettings`], or a simpler [`SyncOptions`].
 #[derive(Clone, Debug)]
 pub struct SyncHelper {
-    inner: Arc<Mutex<SyncHelperInner>>,
+    pub(crate) inner: Arc<Mutex<SyncHelperInner>>,
+    pub(crate) paused: Arc<watch::Sender<PauseState>>,
+    pub(crate) syncing: Arc<watch::Sender<usize>>,
+    pub(crate) metrics: Arc<Mutex<SyncMetrics>>,
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
+const DEFAULT_SYNC_TOKEN_HISTORY_LIMIT: usize = 4096;
+
 #[derive(Debug)]
-struct SyncHelperInner {
-    session_db: SQLiteHelper,
-    sync_token: Option<String>,
+pub(crate) struct SyncHelperInner {
+    pub(crate) session_db: SQLiteHelper,
+    pub(crate) data_dir: PathBuf,
+    pub(crate) sync_token: Option<String>,
+    pub(crate) sync_token_history_limit: usize,
+    pub(crate) track_room_positions: bool,
+    pub(crate) seen_event_ttl: Duration,
+    pub(crate) seen_event_last_prune: Option<SystemTime>,
+    pub(crate) txn_id_last_prune: Option<SystemTime>,
+    pub(crate) catch_up_state: CatchUpState,
+    pub(crate) token_mirror: Option<TokenMirror>,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    /// Tasks started by [`login`](crate::login) that run until aborted, see [`SyncHelper::abort_background_tasks`].
+    pub(crate) background_tasks: Vec<AbortHandle>,
 }
 
 impl SyncHelper {
@@ -102,54 +134,235 @@ impl SyncHelper {
     /// * `data_dir`: The directory containing the bot's state database.
     ///
     ///   It must be the same as specified in [`login`](crate::login).
-    #[instrument(name = "SyncHelper", skip_all)]
     pub fn new(data_dir: &Path) -> Result<Self> {
-        Self::from_opened_db(SQLiteHelper::open(
-            &data_dir.join("matrixbot-ezlogin.sqlite3"),
-            false,
-        )?)
+        Self::with_clock(data_dir, Arc::new(SystemClock))
+    }
+
+    /// Same as [`SyncHelper::new`], but reads the time and waits through `clock`, for example, a [`ManualClock`](crate::ManualClock) in tests.
+    ///
+    /// Everything built from this [`SyncHelper`] uses the same clock, see [`Clock`].
+    #[instrument(name = "SyncHelper", skip_all)]
+    pub fn with_clock(data_dir: &Path, clock: Arc<dyn Clock>) -> Result<Self> {
+        Self::from_opened_db(
+            SQLiteHelper::open(&data_dir.join("matrixbot-ezlogin.sqlite3"), false)?,
+            data_dir,
+            clock,
+        )
     }
 
-    pub(crate) fn from_opened_db(session_db: SQLiteHelper) -> Result<Self> {
+    pub(crate) fn from_opened_db(
+        mut session_db: SQLiteHelper,
+        data_dir: &Path,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        session_db.migrate()?;
         let sync_token = session_db
-            .query_row("SELECT token FROM sync_token WHERE id = 0;", (), |row| {
-                row.get(0)
-            })
+            .query_row(
+                "SELECT token FROM sync_token ORDER BY id DESC LIMIT 1;",
+                (),
+                |row| row.get(0),
+            )
             .optional()?;
         Ok(Self {
             inner: Arc::new(Mutex::new(SyncHelperInner {
                 session_db,
+                data_dir: data_dir.to_owned(),
                 sync_token,
+                sync_token_history_limit: DEFAULT_SYNC_TOKEN_HISTORY_LIMIT,
+                track_room_positions: false,
+                seen_event_ttl: DEFAULT_SEEN_EVENT_TTL,
+                seen_event_last_prune: None,
+                txn_id_last_prune: None,
+                catch_up_state: CatchUpState::Idle,
+                token_mirror: None,
+                rate_limiter: None,
+                background_tasks: Vec::new(),
             })),
+            paused: Arc::new(watch::channel(PauseState::Running).0),
+            syncing: Arc::new(watch::channel(0).0),
+            metrics: Arc::new(Mutex::new(SyncMetrics::default())),
+            clock,
         })
     }
 
+    /// Opens a [`SyncHelper`] on a fresh state database in memory, for tests.
+    #[cfg(test)]
+    pub(crate) fn in_memory(clock: Arc<dyn Clock>) -> Self {
+        let mut session_db = SQLiteHelper::open(Path::new(":memory:"), true).unwrap();
+        session_db.reset_schema().unwrap();
+        Self::from_opened_db(session_db, Path::new("."), clock).unwrap()
+    }
+
+    /// Returns the clock passed to [`SyncHelper::with_clock`], or [`SystemClock`].
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    /// Locks the state database, for modules that keep their own tables in it.
+    pub(crate) fn session_db(&self) -> SessionDb<'_> {
+        SessionDb(self.inner.lock_unpoisoned())
+    }
+
+    /// Remembers `task` for [`SyncHelper::abort_background_tasks`].
+    pub(crate) fn add_background_task<T>(&self, task: &JoinHandle<T>) {
+        let mut inner = self.inner.lock_unpoisoned();
+        inner.background_tasks.retain(|task| !task.is_finished());
+        inner.background_tasks.push(task.abort_handle());
+    }
+
+    /// Aborts the tasks started by [`login`](crate::login), which hold the [`Client`] or this [`SyncHelper`] and never end by themselves.
+    ///
+    /// Without this, a torn-down session stays open, and the next [`login`](crate::login) from the same `data_dir` fails with "database is locked".
+    pub(crate) fn abort_background_tasks(&self) {
+        let tasks = std::mem::take(&mut self.inner.lock_unpoisoned().background_tasks);
+        for task in tasks {
+            task.abort();
+        }
+    }
+
     /// Retrieves the saved `sync_token`.
     pub fn get_sync_token(&self) -> Option<String> {
-        let token = self
-            .inner
-            .lock()
-            // lock() will only return an error after some other task panicked
-            .unwrap()
-            .sync_token
-            .clone();
+        let token = self.inner.lock_unpoisoned().sync_token.clone();
         debug!("Current sync token: {}", token.as_deref().unwrap_or("None"));
         token
     }
 
     /// Stores a new `sync_token` that the Matrix server provides as [`SyncResponse::next_batch`].
+    ///
+    /// Older tokens are kept in the history for [`SyncHelper::rollback`], up to [`SyncHelper::set_sync_token_history_limit`] entries.
     pub fn set_sync_token(&self, token: String) -> Result<()> {
         debug!("Next sync token: {}", token);
-        let mut inner = self
-            .inner
-            .lock()
-            // lock() will only return an error after some other task panicked
-            .unwrap();
+        let mut inner = self.inner.lock_unpoisoned();
+        let limit = inner.sync_token_history_limit.max(1);
         inner
             .session_db
-            .prepare_cached("INSERT OR REPLACE INTO sync_token (id, token) VALUES (0, ?);")?
-            .execute((&token,))?;
+            .prepare_cached("INSERT INTO sync_token (token, time) VALUES (?, ?);")?
+            .execute((&token, unix_millis(self.clock.now())))?;
+        // The new token is newer than any mirrored one, so the mirror can't undo the reset anymore
+        inner
+            .session_db
+            .prepare_cached("DELETE FROM sync_token_reset;")?
+            .execute(())?;
+        inner
+            .session_db
+            .prepare_cached(
+                "DELETE FROM sync_token WHERE id NOT IN (SELECT id FROM sync_token ORDER BY id DESC LIMIT ?);",
+            )?
+            .execute((limit,))?;
         inner.sync_token = Some(token);
+        drop(inner);
+        self.with_metrics(SyncMetrics::record_token_write);
+        Ok(())
+    }
+
+    /// Sets how many sync tokens are kept in the history. Defaults to 4096.
+    ///
+    /// With the default long-polling timeout of 30 seconds, an idle bot receives a new token at least every 30 seconds.
+    pub fn set_sync_token_history_limit(&self, limit: usize) {
+        self.inner.lock_unpoisoned().sync_token_history_limit = limit;
+    }
+
+    /// Retrieves the history of sync tokens, newest first, along with the time each token was received.
+    pub fn get_sync_token_history(&self) -> Result<Vec<(String, SystemTime)>> {
+        let inner = self.inner.lock_unpoisoned();
+        let history = inner
+            .session_db
+            .prepare_cached("SELECT token, time FROM sync_token ORDER BY id DESC;")?
+            .query_map((), |row| Ok((row.get(0)?, from_unix_millis(row.get(1)?))))?
+            .collect::<Result<_, _>>()?;
+        Ok(history)
+    }
+
+    /// Rewinds the sync position by `n` sync tokens, so events received since then are delivered again on the next sync.
+    ///
+    /// Call this before starting to sync. If the history runs out, the next sync becomes a full initial sync.
+    ///
+    /// It returns the new current `sync_token`.
+    #[instrument(skip(self))]
+    pub fn rollback(&self, n: usize) -> Result<Option<String>> {
+        let mut inner = self.inner.lock_unpoisoned();
+        let deleted = inner
+            .session_db
+            .prepare_cached(
+                "DELETE FROM sync_token WHERE id IN (SELECT id FROM sync_token ORDER BY id DESC LIMIT ?);",
+            )?
+            .execute((n,))?;
+        if deleted != 0 {
+            self.mark_sync_token_reset(&inner)?;
+        }
+        Self::reload_sync_token(&mut inner)
+    }
+
+    /// Rewinds the sync position to the latest sync token received no later than `time`, so events received since then are delivered again on the next sync.
+    ///
+    /// Call this before starting to sync. If the history runs out, the next sync becomes a full initial sync.
+    ///
+    /// It returns the new current `sync_token`.
+    #[instrument(skip(self))]
+    pub fn rollback_to(&self, time: SystemTime) -> Result<Option<String>> {
+        let mut inner = self.inner.lock_unpoisoned();
+        let deleted = inner
+            .session_db
+            .prepare_cached("DELETE FROM sync_token WHERE time > ?;")?
+            .execute((unix_millis(time),))?;
+        if deleted != 0 {
+            self.mark_sync_token_reset(&inner)?;
+        }
+        Self::reload_sync_token(&mut inner)
+    }
+
+    /// Deletes the saved `sync_token` and its history, so the next sync becomes a full initial sync.
+    ///
+    /// This is useful if the bot's own database was wiped and needs to rebuild its state from scratch.
+    #[instrument(skip_all)]
+    pub fn clear_sync_token(&self) -> Result<()> {
+        let mut inner = self.inner.lock_unpoisoned();
+        inner
+            .session_db
+            .prepare_cached("DELETE FROM sync_token;")?
+            .execute(())?;
+        self.mark_sync_token_reset(&inner)?;
+        inner.sync_token = None;
+        info!("Cleared sync token.");
+        Ok(())
+    }
+
+    /// Remembers that the sync token went backwards on purpose, so [`SyncHelper::enable_sync_token_mirror`] doesn't restore the newer mirrored one.
+    fn mark_sync_token_reset(&self, inner: &SyncHelperInner) -> Result<()> {
+        inner
+            .session_db
+            .prepare_cached("INSERT OR REPLACE INTO sync_token_reset (id, time) VALUES (0, ?);")?
+            .execute((unix_millis(self.clock.now()),))?;
+        Ok(())
+    }
+
+    fn reload_sync_token(inner: &mut SyncHelperInner) -> Result<Option<String>> {
+        let sync_token: Option<String> = inner
+            .session_db
+            .query_row(
+                "SELECT token FROM sync_token ORDER BY id DESC LIMIT 1;",
+                (),
+                |row| row.get(0),
+            )
+            .optional()?;
+        info!(
+            "Rolled back sync token to: {}",
+            sync_token.as_deref().unwrap_or("None")
+        );
+        inner.sync_token = sync_token.clone();
+        Ok(sync_token)
+    }
+
+    /// Checkpoints the write-ahead log and optimizes the state database.
+    ///
+    /// The sync token is already written on every [`SyncHelper::set_sync_token`] call, but calling this before exiting makes sure nothing is left in the write-ahead log.
+    pub fn flush(&self) -> Result<()> {
+        debug!("Flushing the state database.");
+        let inner = self.inner.lock_unpoisoned();
+        inner.session_db.execute_batch(
+            "PRAGMA wal_checkpoint(TRUNCATE);
+PRAGMA optimize;",
+        )?;
         Ok(())
     }
 
@@ -163,13 +376,27 @@ impl SyncHelper {
 
     /// Convenience method that calls [`SyncHelper::set_sync_token`] using a [`SyncResponse`].
     ///
+    /// If [`SyncHelper::set_track_room_positions`] is enabled, it also records the last timeline event of each room.
+    ///
     /// On success, it returns [`Ok(LoopCtrl::Continue)`](LoopCtrl::Continue) for your convenience.
+    ///
+    /// With the `systemd` feature enabled, it also sends `READY=1` after the first sync response and `WATCHDOG=1` after every sync response to systemd.
     pub fn process_sync_response(
         &self,
         sync_response: &SyncResponse,
     ) -> Result<LoopCtrl, matrix_sdk::Error> {
+        self.update_room_positions(sync_response)
+            .map_err(|err| matrix_sdk::Error::UnknownError(err.into()))?;
         self.set_sync_token(sync_response.next_batch.clone())
             .map_err(|err| matrix_sdk::Error::UnknownError(err.into()))?;
+        let now = self.clock.now();
+        self.with_metrics(|metrics| metrics.record_response(sync_response, now));
+        self.finish_catch_up();
+        #[cfg(all(feature = "systemd", unix))]
+        {
+            crate::systemd::notify_ready();
+            crate::systemd::notify_watchdog();
+        }
         Ok(LoopCtrl::Continue)
     }
 
@@ -183,11 +410,9 @@ impl SyncHelper {
     pub async fn sync_once(
         &self,
         client: &Client,
-        sync_settings: SyncSettings,
+        sync_settings: impl Into<SyncSettings>,
     ) -> Result<SyncResponse, matrix_sdk::Error> {
-        let sync_stream = client
-            .sync_stream(self.process_sync_settings(sync_settings))
-            .await;
+        let sync_stream = self.pausable_sync_stream(client, sync_settings.into());
         tokio::pin!(sync_stream);
         let response = sync_stream
             .next()
@@ -196,20 +421,37 @@ impl SyncHelper {
             .unwrap()?;
         trace!("Sync response: {:?}", response);
         self.process_sync_response(&response)?;
+        _ = self.mirror_sync_token(client, false);
         Ok(response)
     }
 
+    /// Same as [`SyncHelper::sync_once`], but gives up after `timeout`, and classifies the error into a [`SyncError`].
+    ///
+    /// Useful during startup, to decide between retrying, re-running [`setup`](crate::setup), and alerting.
+    ///
+    /// Note that the long-polling timeout in `sync_settings` should be shorter than `timeout`.
+    #[instrument(skip(self, client, sync_settings))]
+    pub async fn sync_once_with_timeout(
+        &self,
+        client: &Client,
+        sync_settings: impl Into<SyncSettings>,
+        timeout: Duration,
+    ) -> Result<SyncResponse, SyncError> {
+        match tokio::time::timeout(timeout, self.sync_once(client, sync_settings)).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(SyncError::Timeout(timeout)),
+        }
+    }
+
     /// Convenience method that returns a [`Stream`], which calls [`SyncHelper::process_sync_settings`], [`matrix_sdk::Client::sync_once`], then [`SyncHelper::process_sync_response`] whenever being polled.
     ///
     /// Internally, it actually calls [`matrix_sdk::Client::sync_stream`] to let it manage retry logic.
     pub async fn sync_stream(
         &self,
         client: &Client,
-        sync_settings: SyncSettings,
+        sync_settings: impl Into<SyncSettings>,
     ) -> impl Stream<Item = Result<SyncResponse, matrix_sdk::Error>> {
-        let sync_stream = client
-            .sync_stream(self.process_sync_settings(sync_settings))
-            .await;
+        let sync_stream = self.pausable_sync_stream(client, sync_settings.into());
         try_stream! {
             tokio::pin!(sync_stream);
             loop {
@@ -231,11 +473,9 @@ impl SyncHelper {
     pub async fn sync(
         &self,
         client: &Client,
-        sync_settings: SyncSettings,
+        sync_settings: impl Into<SyncSettings>,
     ) -> Result<(), matrix_sdk::Error> {
-        let sync_stream = client
-            .sync_stream(self.process_sync_settings(sync_settings))
-            .await;
+        let sync_stream = self.pausable_sync_stream(client, sync_settings.into());
         tokio::pin!(sync_stream);
         loop {
             let response = sync_stream
@@ -248,3 +488,100 @@ impl SyncHelper {
         }
     }
 }
+
+/// A simpler alternative to [`SyncSettings`], accepted by the convenience methods of [`SyncHelper`].
+///
+/// The default value is tuned for bots: it uses [`BotFilter`], and the bot appears offline.
+#[derive(Clone, Debug)]
+pub struct SyncOptions {
+    /// How long the server may hold a sync request open if there are no new events (long-polling).
+    ///
+    /// [`None`] means the default of [`SyncSettings`], which is 30 seconds.
+    pub timeout: Option<Duration>,
+    /// The presence state to set while syncing. Defaults to [`PresenceState::Offline`].
+    ///
+    /// Most bots don't want to appear online merely because they are syncing.
+    ///
+    /// If you use [`presence::set`](crate::presence::set) or [`presence::heartbeat`](crate::presence::heartbeat), set this to the same state.
+    /// Otherwise, each sync request resets the presence to this one.
+    pub set_presence: PresenceState,
+    /// Maximum number of timeline events per room in each sync response.
+    ///
+    /// [`None`] means the server's default.
+    pub timeline_limit: Option<u32>,
+    /// Whether to receive presence events, as needed by [`presence::on_presence`](crate::presence::on_presence). Defaults to `false`.
+    pub receive_presence: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            set_presence: PresenceState::Offline,
+            timeline_limit: None,
+            receive_presence: false,
+        }
+    }
+}
+
+impl SyncOptions {
+    /// The default options, but with at most 10 timeline events per room in each sync response,
+    /// so a burst of activity doesn't arrive as one large response. Meant for [`LoginOptions::low_memory`](crate::LoginOptions::low_memory).
+    ///
+    /// # Warning
+    ///
+    /// **These options lose events.** If more than 10 events arrive in a room between two sync requests,
+    /// the server only sends the latest 10, marks the timeline as `limited`, and event handlers never see the older ones.
+    /// This also happens after the bot was offline, and in busy rooms during normal operation.
+    ///
+    /// Only use it if your bot can tolerate missed messages, for example, a bot that only reacts to the latest state.
+    /// Otherwise, keep [`SyncOptions::default`], which leaves the limit to the server, and rely on [`LoginOptions::low_memory`](crate::LoginOptions::low_memory) alone.
+    pub fn low_memory() -> Self {
+        Self {
+            timeline_limit: Some(10),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<SyncOptions> for SyncSettings {
+    fn from(options: SyncOptions) -> Self {
+        let filter = BotFilter::new()
+            .timeline_limit(options.timeline_limit)
+            .presence(options.receive_presence);
+        let mut sync_settings = SyncSettings::default()
+            .filter(filter.build().into())
+            .set_presence(options.set_presence);
+        if let Some(timeout) = options.timeout {
+            sync_settings = sync_settings.timeout(timeout);
+        }
+        sync_settings
+    }
+}
+
+/// The state database of a [`SyncHelper`], locked until dropped.
+pub(crate) struct SessionDb<'a>(MutexGuard<'a, SyncHelperInner>);
+
+impl Deref for SessionDb<'_> {
+    type Target = SQLiteHelper;
+
+    fn deref(&self) -> &SQLiteHelper {
+        &self.0.session_db
+    }
+}
+
+impl DerefMut for SessionDb<'_> {
+    fn deref_mut(&mut self) -> &mut SQLiteHelper {
+        &mut self.0.session_db
+    }
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_millis().try_into().unwrap_or(i64::MAX))
+        .unwrap_or(0)
+}
+
+pub(crate) fn from_unix_millis(millis: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
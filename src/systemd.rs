@@ -0,0 +1,54 @@
+use std::os::unix::net::UnixDatagram;
+use std::sync::Once;
+
+use tracing::{debug, warn};
+
+static NOTIFY_READY_ONCE: Once = Once::new();
+
+/// Sends `READY=1` to systemd, but only the first time it is called.
+pub(crate) fn notify_ready() {
+    NOTIFY_READY_ONCE.call_once(|| notify("READY=1"));
+}
+
+/// Sends `WATCHDOG=1` to systemd.
+///
+/// The interval between two sync responses is bounded by the long-polling timeout in [`SyncSettings`](matrix_sdk::config::SyncSettings), which defaults to 30 seconds.
+/// Make sure `WatchdogSec=` in the unit file is larger than that.
+pub(crate) fn notify_watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// Sends `STOPPING=1` to systemd.
+pub(crate) fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+fn notify(state: &str) {
+    // Not running under systemd, or the unit is not Type=notify
+    let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    debug!("Notifying systemd: {}", state);
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        if let Some(abstract_name) = socket_path.as_encoded_bytes().strip_prefix(b"@") {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                use std::os::linux::net::SocketAddrExt;
+                use std::os::unix::net::SocketAddr;
+
+                let addr = SocketAddr::from_abstract_name(abstract_name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            {
+                _ = abstract_name;
+                Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+            }
+        } else {
+            socket.send_to(state.as_bytes(), &socket_path)
+        }
+    });
+    if let Err(err) = result {
+        warn!("Failed to notify systemd: {}", err);
+    }
+}
@@ -0,0 +1,240 @@
+use std::future::Ready;
+use std::path::Path;
+
+use eyre::{Result, eyre};
+use matrix_sdk::Client;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::api::client::account::register;
+use matrix_sdk::ruma::api::client::uiaa::{AuthData, Dummy};
+use matrix_sdk::ruma::serde::Base64;
+use matrix_sdk::ruma::serde::base64::Standard;
+use rand::Rng;
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{ContainerAsync, GenericImage, ImageExt};
+use tracing::{info, instrument};
+
+use crate::SetupConfig;
+
+const SYNAPSE_IMAGE: &str = "matrixdotorg/synapse";
+// Pinned, so a new Synapse release can't change test results without a commit here
+const SYNAPSE_TAG: &str = "v1.139.0";
+const SERVER_NAME: &str = "localhost";
+
+/// A disposable Synapse homeserver in a Docker container, with open registration, for integration tests.
+///
+/// The container is removed when this value is dropped. Docker must be available to the test process.
+///
+/// # Example
+///
+/// An end-to-end encrypted round trip between two bots:
+///
+/// ```no_run
+/// use matrixbot_ezlogin::EzloginTestServer;
+///
+/// # async fn example() -> color_eyre::Result<()> {
+/// let server = EzloginTestServer::start().await?;
+/// let (alice, bob) = (server.create_account("alice").await?, server.create_account("bob").await?);
+/// let (alice_dir, bob_dir) = (std::env::temp_dir().join("alice"), std::env::temp_dir().join("bob"));
+/// alice.setup(&alice_dir).await?;
+/// bob.setup(&bob_dir).await?;
+/// let (alice_client, alice_sync) = matrixbot_ezlogin::login(&alice_dir).await?;
+/// let (bob_client, bob_sync) = matrixbot_ezlogin::login(&bob_dir).await?;
+/// // Create an encrypted room with alice_client, invite bob.user_id, and sync both clients…
+/// # Ok(())
+/// # }
+/// ```
+pub struct EzloginTestServer {
+    container: ContainerAsync<GenericImage>,
+    homeserver: String,
+}
+
+/// An account registered on an [`EzloginTestServer`].
+#[derive(Clone, Debug)]
+pub struct TestAccount {
+    /// The base URL of the homeserver.
+    pub homeserver: String,
+    /// The localpart of the user ID.
+    pub username: String,
+    /// The password.
+    pub password: String,
+    /// The full user ID.
+    pub user_id: OwnedUserId,
+}
+
+impl EzloginTestServer {
+    /// Starts a fresh Synapse container, and waits until it accepts requests.
+    #[instrument]
+    pub async fn start() -> Result<Self> {
+        let container = GenericImage::new(SYNAPSE_IMAGE, SYNAPSE_TAG)
+            .with_exposed_port(8008.tcp())
+            .with_wait_for(WaitFor::message_on_stderr(
+                "Synapse now listening on TCP port 8008",
+            ))
+            .with_env_var("SYNAPSE_CONFIG_PATH", "/data/homeserver.yaml")
+            // The copied files are owned by root, so Synapse must run as root to write next to them
+            .with_env_var("UID", "0")
+            .with_env_var("GID", "0")
+            .with_copy_to("/data/homeserver.yaml", homeserver_yaml().into_bytes())
+            .with_copy_to("/data/signing.key", signing_key().into_bytes())
+            .start()
+            .await?;
+        let homeserver = format!(
+            "http://{}:{}",
+            container.get_host().await?,
+            container.get_host_port_ipv4(8008).await?
+        );
+        info!("Synapse is ready at {}.", homeserver);
+        Ok(Self {
+            container,
+            homeserver,
+        })
+    }
+
+    /// The base URL of the homeserver, for example, `http://localhost:32768`.
+    pub fn homeserver(&self) -> &str {
+        &self.homeserver
+    }
+
+    /// The Docker container running Synapse, for example, to read its logs.
+    pub fn container(&self) -> &ContainerAsync<GenericImage> {
+        &self.container
+    }
+
+    /// Registers a new account with `username` and a random password.
+    #[instrument(skip(self))]
+    pub async fn create_account(&self, username: &str) -> Result<TestAccount> {
+        let password = rand::rng()
+            .sample_iter(rand::distr::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect::<String>();
+        let client = Client::builder()
+            .homeserver_url(&self.homeserver)
+            .build()
+            .await?;
+        let mut request = register::v3::Request::new();
+        request.username = Some(username.to_owned());
+        request.password = Some(password.clone());
+        request.inhibit_login = true;
+        request.auth = Some(AuthData::Dummy(Dummy::new()));
+        let response = client.matrix_auth().register(request).await?;
+        info!("Registered {}.", response.user_id);
+        Ok(TestAccount {
+            homeserver: self.homeserver.clone(),
+            username: username.to_owned(),
+            password,
+            user_id: response.user_id,
+        })
+    }
+}
+
+impl TestAccount {
+    /// Returns a [`SetupConfig`] that sets up this account in `data_dir` without asking anything.
+    ///
+    /// The account is new, so a new backup is created, and its recovery key is discarded.
+    pub fn setup_config<'a>(
+        &'a self,
+        data_dir: &'a Path,
+    ) -> SetupConfig<
+        'a,
+        Ready<Result<String>>,
+        Ready<Result<()>>,
+        impl FnOnce(String, bool) -> Ready<Result<()>>,
+    > {
+        SetupConfig {
+            data_dir,
+            homeserver: &self.homeserver,
+            username: &self.username,
+            password: &self.password,
+            device_name: "matrixbot-ezlogin test",
+            ask_recovery_key: std::future::ready(Err(eyre!(
+                "a test account is not expected to have a backup already"
+            ))),
+            before_create_backup: std::future::ready(Ok(())),
+            print_recovery_key: |_, _| std::future::ready(Ok(())),
+        }
+    }
+
+    /// Sets up this account in `data_dir` with [`TestAccount::setup_config`], so [`login`](crate::login) can use it.
+    pub async fn setup(&self, data_dir: &Path) -> Result<Client> {
+        crate::setup(self.setup_config(data_dir)).await
+    }
+}
+
+/// A minimal Synapse configuration for tests, with open registration and relaxed rate limits.
+fn homeserver_yaml() -> String {
+    let secret = || {
+        rand::rng()
+            .sample_iter(rand::distr::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect::<String>()
+    };
+    format!(
+        r#"server_name: "{server_name}"
+pid_file: /data/homeserver.pid
+listeners:
+  - port: 8008
+    type: http
+    tls: false
+    bind_addresses: ["0.0.0.0"]
+    resources:
+      - names: [client]
+database:
+  name: sqlite3
+  args:
+    database: /data/homeserver.db
+media_store_path: /data/media_store
+signing_key_path: /data/signing.key
+trusted_key_servers: []
+report_stats: false
+enable_registration: true
+enable_registration_without_verification: true
+registration_shared_secret: "{registration_secret}"
+macaroon_secret_key: "{macaroon_secret}"
+form_secret: "{form_secret}"
+suppress_key_server_warning: true
+rc_message:
+  per_second: 1000
+  burst_count: 1000
+rc_registration:
+  per_second: 1000
+  burst_count: 1000
+rc_login:
+  address:
+    per_second: 1000
+    burst_count: 1000
+  account:
+    per_second: 1000
+    burst_count: 1000
+  failed_attempts:
+    per_second: 1000
+    burst_count: 1000
+rc_joins:
+  local:
+    per_second: 1000
+    burst_count: 1000
+rc_invites:
+  per_room:
+    per_second: 1000
+    burst_count: 1000
+  per_user:
+    per_second: 1000
+    burst_count: 1000
+"#,
+        server_name = SERVER_NAME,
+        registration_secret = secret(),
+        macaroon_secret = secret(),
+        form_secret = secret(),
+    )
+}
+
+/// A fresh ed25519 signing key, in Synapse's key file format.
+fn signing_key() -> String {
+    let seed: [u8; 32] = rand::rng().random();
+    format!(
+        "ed25519 a_test {}\n",
+        Base64::<Standard>::new(seed.to_vec()).encode()
+    )
+}
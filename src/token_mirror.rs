@@ -0,0 +1,227 @@
+use std::time::{Duration, SystemTime};
+
+use eyre::{OptionExt, Result};
+use matrix_sdk::Client;
+use matrix_sdk::ruma::events::GlobalAccountDataEventType;
+use matrix_sdk::ruma::serde::Raw;
+use serde_json::json;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, info, instrument, warn};
+
+use crate::SyncHelper;
+use crate::mutex::MutexExt;
+use crate::sync::unix_millis;
+
+const MIRROR_EVENT_TYPE_PREFIX: &str = "io.github.m13253.matrixbot-ezlogin.sync_token.";
+
+#[derive(Debug)]
+pub(crate) struct TokenMirror {
+    // No `Client` here: the `Client` keeps this `SyncHelper` in its handler context, so holding one would leak both.
+    event_type: GlobalAccountDataEventType,
+    interval: Duration,
+    last_mirror: Option<SystemTime>,
+}
+
+impl SyncHelper {
+    /// Mirrors the sync token into the account data on the homeserver, at most once every `interval`.
+    ///
+    /// Before enabling the mirror, it compares the local sync token with the mirrored one, and picks the newer of the two.
+    /// This protects against the state database being restored from an older backup, which would otherwise replay events.
+    /// A token cleared by [`SyncHelper::clear_sync_token`] or rewound by [`SyncHelper::rollback`] stays that way, until the next sync stores a new one.
+    ///
+    /// Tokens are compared by their position in the local token history, never by wall-clock time, so clock differences between hosts don't matter.
+    ///
+    /// Each device mirrors into a separate account data event, because sync tokens are specific to devices.
+    ///
+    /// The mirror is written by the convenience methods, such as [`SyncHelper::sync`], and by [`run_until_shutdown`](crate::run_until_shutdown).
+    /// Calling [`SyncHelper::process_sync_response`] on your own doesn't write it, because it has no [`Client`] to write with.
+    #[instrument(skip_all)]
+    pub async fn enable_sync_token_mirror(
+        &self,
+        client: &Client,
+        interval: Duration,
+    ) -> Result<()> {
+        let device_id = client.device_id().ok_or_eyre("client is not logged in")?;
+        let event_type =
+            GlobalAccountDataEventType::from(format!("{}{}", MIRROR_EVENT_TYPE_PREFIX, device_id));
+
+        if let Some(raw) = client
+            .account()
+            .account_data_raw(event_type.clone())
+            .await?
+        {
+            let token = raw.get_field::<String>("token")?;
+            let seq = raw.get_field::<i64>("seq")?;
+            if let (Some(token), Some(seq)) = (token, seq) {
+                self.adopt_mirrored_sync_token(token, seq)?;
+            }
+        }
+
+        self.inner.lock_unpoisoned().token_mirror = Some(TokenMirror {
+            event_type,
+            interval,
+            last_mirror: None,
+        });
+        Ok(())
+    }
+
+    /// Uses the mirrored sync `token` at position `seq` if it is newer than the local one.
+    ///
+    /// After [`SyncHelper::clear_sync_token`] or [`SyncHelper::rollback`], the local token is older on purpose, so the mirrored one is ignored until the next [`SyncHelper::set_sync_token`].
+    fn adopt_mirrored_sync_token(&self, token: String, seq: i64) -> Result<()> {
+        let mut inner = self.inner.lock_unpoisoned();
+        let reset = inner
+            .session_db
+            .prepare_cached("SELECT 1 FROM sync_token_reset;")?
+            .exists(())?;
+        if reset {
+            info!(
+                "The sync token was cleared or rolled back, not using the mirrored one: {}",
+                token
+            );
+            return Ok(());
+        }
+        // A token still in the local history is at most as new as the local one
+        let known = inner
+            .session_db
+            .prepare_cached("SELECT 1 FROM sync_token WHERE token = ?;")?
+            .exists((&token,))?;
+        let local_seq: Option<i64> =
+            inner
+                .session_db
+                .query_row("SELECT MAX(id) FROM sync_token;", (), |row| row.get(0))?;
+        if !known && local_seq.is_none_or(|local_seq| local_seq < seq) {
+            info!(
+                "The mirrored sync token is newer than the local one, using it: {}",
+                token
+            );
+            // Keep its position, so the next mirror doesn't look older than this one
+            inner
+                .session_db
+                .prepare_cached("INSERT INTO sync_token (id, token, time) VALUES (?, ?, ?);")?
+                .execute((seq, &token, unix_millis(self.clock.now())))?;
+            inner.sync_token = Some(token);
+        }
+        Ok(())
+    }
+
+    /// Stops mirroring the sync token started by [`SyncHelper::enable_sync_token_mirror`].
+    pub fn disable_sync_token_mirror(&self) {
+        self.inner.lock_unpoisoned().token_mirror = None;
+    }
+
+    pub(crate) fn mirror_sync_token(&self, client: &Client, force: bool) -> Option<JoinHandle<()>> {
+        let mut inner = self.inner.lock_unpoisoned();
+        let token = inner.sync_token.clone()?;
+        let seq = match inner
+            .session_db
+            .query_row("SELECT MAX(id) FROM sync_token;", (), |row| {
+                row.get::<_, Option<i64>>(0)
+            }) {
+            Ok(seq) => seq?,
+            Err(err) => {
+                warn!("Failed to read the sync token position: {}", err);
+                return None;
+            }
+        };
+        let mirror = inner.token_mirror.as_mut()?;
+        let now = self.clock.now();
+        if !force
+            && mirror.last_mirror.is_some_and(|last_mirror| {
+                now.duration_since(last_mirror)
+                    .is_ok_and(|elapsed| elapsed < mirror.interval)
+            })
+        {
+            return None;
+        }
+        mirror.last_mirror = Some(now);
+        let client = client.clone();
+        let event_type = mirror.event_type.clone();
+        let content = json!({
+            "token": token,
+            "seq": seq,
+        });
+        Some(tokio::spawn(
+            async move {
+                let result = async {
+                    let content = Raw::from_json(serde_json::value::to_raw_value(&content)?);
+                    client
+                        .account()
+                        .set_account_data_raw(event_type, content)
+                        .await?;
+                    Ok::<_, eyre::Report>(())
+                }
+                .await;
+                if let Err(err) = result {
+                    warn!("Failed to mirror the sync token: {}", err);
+                }
+            }
+            .in_current_span(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::SystemClock;
+
+    fn sync_helper() -> SyncHelper {
+        SyncHelper::in_memory(Arc::new(SystemClock))
+    }
+
+    #[test]
+    fn adopts_newer_mirrored_token() {
+        let sync_helper = sync_helper();
+        sync_helper.set_sync_token("s1".to_owned()).unwrap();
+        sync_helper
+            .adopt_mirrored_sync_token("s9".to_owned(), 9)
+            .unwrap();
+        assert_eq!(sync_helper.get_sync_token().as_deref(), Some("s9"));
+    }
+
+    #[test]
+    fn ignores_older_mirrored_token() {
+        let sync_helper = sync_helper();
+        for i in 1..=3 {
+            sync_helper.set_sync_token(format!("s{}", i)).unwrap();
+        }
+        sync_helper
+            .adopt_mirrored_sync_token("s2".to_owned(), 2)
+            .unwrap();
+        assert_eq!(sync_helper.get_sync_token().as_deref(), Some("s3"));
+    }
+
+    #[test]
+    fn keeps_cleared_token_cleared() {
+        let sync_helper = sync_helper();
+        sync_helper.set_sync_token("s1".to_owned()).unwrap();
+        sync_helper.clear_sync_token().unwrap();
+        sync_helper
+            .adopt_mirrored_sync_token("s1".to_owned(), 1)
+            .unwrap();
+        assert_eq!(sync_helper.get_sync_token(), None);
+
+        // Once syncing again, newer mirrored tokens are used as usual
+        sync_helper.set_sync_token("s2".to_owned()).unwrap();
+        sync_helper
+            .adopt_mirrored_sync_token("s9".to_owned(), 9)
+            .unwrap();
+        assert_eq!(sync_helper.get_sync_token().as_deref(), Some("s9"));
+    }
+
+    #[test]
+    fn keeps_rolled_back_token() {
+        let sync_helper = sync_helper();
+        for i in 1..=3 {
+            sync_helper.set_sync_token(format!("s{}", i)).unwrap();
+        }
+        sync_helper.rollback(2).unwrap();
+        sync_helper
+            .adopt_mirrored_sync_token("s3".to_owned(), 3)
+            .unwrap();
+        assert_eq!(sync_helper.get_sync_token().as_deref(), Some("s1"));
+    }
+}
@@ -0,0 +1,138 @@
+//! Structured tracing events with stable names and fields, for log pipelines and alerting.
+//!
+//! Human-readable log messages may change wording between releases. The events listed here don't: their names, levels, and field names
+//! are a stable interface, and changing them is a breaking change. New fields may be added.
+//!
+//! All of them are emitted with the target `matrixbot_ezlogin::trace_events`, so a filter such as
+//! `warn,matrixbot_ezlogin::trace_events=debug` selects them. Most formatters, including `LogFormat::JsonLines` of `log_layer`,
+//! don't print tracing event names, so each event also carries its name in the `event` field.
+//!
+//! | Name | Level | Fields |
+//! |------|-------|--------|
+//! | `ezlogin.setup.finished` | `INFO` | `user_id`, `device_id` |
+//! | `ezlogin.login.finished` | `INFO` | `user_id`, `device_id` |
+//! | `ezlogin.login.failed` | `ERROR` | `error_kind` |
+//! | `ezlogin.sync.response` | `DEBUG` | `events`, `duration_ms` |
+//! | `ezlogin.sync.failed` | `WARN` | `error_kind`, `duration_ms` |
+//! | `ezlogin.send.succeeded` | `DEBUG` | `room`, `event_id` |
+//! | `ezlogin.send.failed` | `WARN` | `room`, `error_kind` |
+//!
+//! - `events` is the number of timeline events in the sync response.
+//! - `duration_ms` is the time the sync request took, including long-polling.
+//! - `room` is a room ID.
+//! - `ezlogin.send.failed` is emitted for every failed attempt, including those that [`SendQueue`](crate::SendQueue) retries later.
+//!
+//! `error_kind` is one of:
+//!
+//! | Value | Meaning |
+//! |-------|---------|
+//! | `rate_limited` | The homeserver returned `M_LIMIT_EXCEEDED`. |
+//! | `auth` | The access token was rejected, or the account was deactivated. |
+//! | `forbidden` | The homeserver returned `M_FORBIDDEN`. |
+//! | `not_found` | The homeserver returned `M_NOT_FOUND`. |
+//! | `client_error` | Any other 4xx response. |
+//! | `server_error` | A 5xx response. |
+//! | `network` | The homeserver could not be reached, or the response could not be read. |
+//! | `other` | Anything else, for example, a local database or encryption error. |
+
+use std::time::Duration;
+
+use matrix_sdk::Client;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::{EventId, RoomId};
+
+/// Emits an event with `$name` both as the tracing event name and as the `event` field.
+macro_rules! emit {
+    ($level:ident, $name:literal, $($fields:tt)*) => {
+        tracing::$level!(name: $name, event = $name, $($fields)*)
+    };
+}
+
+pub(crate) fn setup_finished(client: &Client) {
+    emit!(
+        info,
+        "ezlogin.setup.finished",
+        user_id = client.user_id().map(|user_id| user_id.as_str()),
+        device_id = client.device_id().map(|device_id| device_id.as_str()),
+    );
+}
+
+pub(crate) fn login_finished(client: &Client) {
+    emit!(
+        info,
+        "ezlogin.login.finished",
+        user_id = client.user_id().map(|user_id| user_id.as_str()),
+        device_id = client.device_id().map(|device_id| device_id.as_str()),
+    );
+}
+
+pub(crate) fn login_failed(err: &eyre::Report) {
+    emit!(
+        error,
+        "ezlogin.login.failed",
+        error_kind = err
+            .downcast_ref::<matrix_sdk::Error>()
+            .map_or("other", error_kind),
+    );
+}
+
+pub(crate) fn sync_response(events: usize, duration: Duration) {
+    emit!(
+        debug,
+        "ezlogin.sync.response",
+        events,
+        duration_ms = duration.as_millis() as u64,
+    );
+}
+
+pub(crate) fn sync_failed(err: &matrix_sdk::Error, duration: Duration) {
+    emit!(
+        warn,
+        "ezlogin.sync.failed",
+        error_kind = error_kind(err),
+        duration_ms = duration.as_millis() as u64,
+    );
+}
+
+pub(crate) fn send_succeeded(room_id: &RoomId, event_id: &EventId) {
+    emit!(
+        debug,
+        "ezlogin.send.succeeded",
+        room = room_id.as_str(),
+        event_id = event_id.as_str(),
+    );
+}
+
+pub(crate) fn send_failed(room_id: &RoomId, err: &matrix_sdk::Error) {
+    emit!(
+        warn,
+        "ezlogin.send.failed",
+        room = room_id.as_str(),
+        error_kind = error_kind(err),
+    );
+}
+
+/// Classifies `err` into one of the documented `error_kind` values.
+fn error_kind(err: &matrix_sdk::Error) -> &'static str {
+    match err.client_api_error_kind() {
+        Some(ErrorKind::LimitExceeded { .. }) => return "rate_limited",
+        Some(
+            ErrorKind::UnknownToken { .. }
+            | ErrorKind::MissingToken { .. }
+            | ErrorKind::UserDeactivated { .. },
+        ) => return "auth",
+        Some(ErrorKind::Forbidden { .. }) => return "forbidden",
+        Some(ErrorKind::NotFound) => return "not_found",
+        _ => (),
+    }
+    if let Some(api_error) = err.as_client_api_error() {
+        if api_error.status_code.is_server_error() {
+            return "server_error";
+        }
+        return "client_error";
+    }
+    match err {
+        matrix_sdk::Error::Http(_) => "network",
+        _ => "other",
+    }
+}
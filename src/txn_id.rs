@@ -0,0 +1,96 @@
+use std::time::{Duration, SystemTime};
+
+use eyre::Result;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::MessageLikeEventContent;
+use matrix_sdk::ruma::{OwnedEventId, OwnedTransactionId, RoomId, TransactionId};
+use tracing::debug;
+
+use crate::SyncHelper;
+use crate::mutex::MutexExt;
+use crate::sync::unix_millis;
+
+/// Homeservers only deduplicate transaction IDs for a limited time, so older entries are useless.
+const TXN_ID_TTL: Duration = Duration::from_secs(24 * 3600);
+const TXN_ID_PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+impl SyncHelper {
+    /// Returns the transaction ID for the outgoing message identified by `key` in `room_id`, generating and persisting one on first use.
+    ///
+    /// `key` must identify the message across process restarts, for example, `reply:$event_id` for the reply to a specific event.
+    /// Sending with the same transaction ID again makes the homeserver return the existing event instead of sending a duplicate.
+    ///
+    /// Entries older than a day are pruned automatically.
+    pub fn transaction_id(&self, room_id: &RoomId, key: &str) -> Result<OwnedTransactionId> {
+        let mut inner = self.inner.lock_unpoisoned();
+        let now = self.clock.now();
+        if inner.txn_id_last_prune.is_none_or(|last_prune| {
+            !now.duration_since(last_prune)
+                .is_ok_and(|elapsed| elapsed < TXN_ID_PRUNE_INTERVAL)
+        }) {
+            inner.txn_id_last_prune = Some(now);
+            let cutoff = unix_millis(
+                now.checked_sub(TXN_ID_TTL)
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+            );
+            let pruned = inner
+                .session_db
+                .prepare_cached("DELETE FROM txn_id WHERE time < ?;")?
+                .execute((cutoff,))?;
+            debug!("Pruned {} transaction IDs.", pruned);
+        }
+        inner
+            .session_db
+            .prepare_cached(
+                "INSERT OR IGNORE INTO txn_id (room_id, key, txn_id, time) VALUES (?, ?, ?, ?);",
+            )?
+            .execute((
+                room_id.as_str(),
+                key,
+                TransactionId::new().as_str(),
+                unix_millis(now),
+            ))?;
+        let txn_id: String = inner
+            .session_db
+            .prepare_cached("SELECT txn_id FROM txn_id WHERE room_id = ? AND key = ?;")?
+            .query_row((room_id.as_str(), key), |row| row.get(0))?;
+        Ok(txn_id.into())
+    }
+
+    /// Sends `content` to `room` with the persisted transaction ID of `key`, see [`SyncHelper::transaction_id`].
+    ///
+    /// If the bot crashes or restarts mid-send and the event handler runs again, the retried send is deduplicated by the homeserver,
+    /// so users don't receive the same reply twice. [`SendQueue`](crate::SendQueue) does the same for every queued message.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use matrix_sdk::ruma::events::room::message::{
+    ///     OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+    /// };
+    ///
+    /// # async fn example(sync_helper: &matrixbot_ezlogin::SyncHelper, room: &matrix_sdk::Room, event: &OriginalSyncRoomMessageEvent) -> color_eyre::Result<()> {
+    /// let content = RoomMessageEventContent::text_plain("Pong!");
+    /// sync_helper
+    ///     .send_idempotent(room, &format!("reply:{}", event.event_id), content)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_idempotent(
+        &self,
+        room: &Room,
+        key: &str,
+        content: impl MessageLikeEventContent,
+    ) -> Result<OwnedEventId> {
+        let txn_id = self.transaction_id(room.room_id(), key)?;
+        crate::send::send_raw(
+            room,
+            &content.event_type().to_string(),
+            serde_json::to_value(&content)?,
+            Some(txn_id),
+            self.rate_limiter().as_ref(),
+        )
+        .await
+    }
+}
@@ -0,0 +1,70 @@
+use std::pin::pin;
+use std::time::Duration;
+
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::api::client::typing::create_typing_event::v3::{Request, Typing, TypingInfo};
+use tokio::select;
+use tracing::warn;
+
+use crate::{Clock, SystemClock};
+
+/// How long the server shows the typing notification after each refresh.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the typing notification is refreshed, well before it times out.
+const TYPING_REFRESH_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Shows the bot as typing in `room` while `fut` runs, then returns its output.
+///
+/// The typing notification is refreshed every 20 seconds, so slow operations like calling an LLM keep the bot looking responsive.
+/// It is cleared once `fut` finishes. If the returned future is dropped early, the notification disappears by itself within 30 seconds.
+///
+/// Errors sending typing notifications are logged, but otherwise ignored.
+///
+/// # Example
+///
+/// ```no_run
+/// # async fn generate_answer() -> String { todo!() }
+/// # async fn example(room: matrix_sdk::Room) {
+/// let answer = matrixbot_ezlogin::with_typing(&room, generate_answer()).await;
+/// # }
+/// ```
+pub async fn with_typing<F: Future>(room: &Room, fut: F) -> F::Output {
+    with_typing_clock(room, &SystemClock, fut).await
+}
+
+/// Same as [`with_typing`], but waits between refreshes on `clock`.
+pub async fn with_typing_clock<F: Future>(room: &Room, clock: &dyn Clock, fut: F) -> F::Output {
+    let fut = pin!(fut);
+    let refresh = async {
+        loop {
+            send_typing(room, true).await;
+            clock.sleep(TYPING_REFRESH_INTERVAL).await;
+        }
+    };
+    let output = select! {
+        output = fut => output,
+        _ = refresh => unreachable!(),
+    };
+    send_typing(room, false).await;
+    output
+}
+
+async fn send_typing(room: &Room, typing: bool) {
+    let typing = if typing {
+        Typing::Yes(TypingInfo::new(TYPING_TIMEOUT))
+    } else {
+        Typing::No
+    };
+    let request = Request::new(
+        room.own_user_id().to_owned(),
+        room.room_id().to_owned(),
+        typing,
+    );
+    if let Err(err) = room.client().send(request).await {
+        warn!(
+            "Failed to send typing notification to room {}: {}",
+            room.room_id(),
+            err
+        );
+    }
+}
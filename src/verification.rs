@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use eyre::Result;
+use matrix_sdk::encryption::verification::{
+    SasState, SasVerification, VerificationRequest, VerificationRequestState,
+};
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent;
+use matrix_sdk::ruma::events::room::message::{MessageType, OriginalSyncRoomMessageEvent};
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId};
+use matrix_sdk::{Client, RoomState};
+use tokio::sync::oneshot;
+use tokio_stream::StreamExt;
+use tracing::{Instrument, error, info, instrument, warn};
+
+use crate::mutex::MutexExt;
+use crate::{MessageBuilder, SyncHelper};
+
+/// How long [`VerificationPolicy::AskAdminRoom`] waits for a human to compare the emoji.
+const ADMIN_CONFIRM_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How the bot responds to incoming `m.key.verification.request` to-device messages.
+///
+/// Only SAS (emoji) verification is supported. In-room verification requests are not handled.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum VerificationPolicy {
+    /// Leaves requests unanswered, so they time out on the other side.
+    #[default]
+    Ignore,
+    /// Cancels every request.
+    RejectAll,
+    /// Accepts and confirms SAS verification from the account's own devices that are already verified, and cancels every other request.
+    AcceptOwnVerifiedDevices,
+    /// Accepts requests from the account's own devices, and posts the emoji to an admin room for a human to compare.
+    ///
+    /// A member of the admin room replies `!verify confirm <flow ID>` if the emoji match, or `!verify reject <flow ID>` otherwise.
+    /// Requests from other users are cancelled.
+    ///
+    /// Make sure only trusted people can join the admin room.
+    AskAdminRoom(OwnedRoomId),
+}
+
+type PendingConfirmations = Arc<Mutex<HashMap<String, oneshot::Sender<bool>>>>;
+
+pub(crate) fn install(client: &Client, sync_helper: &SyncHelper, policy: &VerificationPolicy) {
+    if *policy == VerificationPolicy::Ignore {
+        return;
+    }
+    info!("Handling verification requests with policy {:?}.", policy);
+    let pending = PendingConfirmations::default();
+
+    if let VerificationPolicy::AskAdminRoom(admin_room_id) = policy {
+        let admin_room_id = admin_room_id.clone();
+        let pending = pending.clone();
+        client.add_event_handler(
+            move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+                let admin_room_id = admin_room_id.clone();
+                let pending = pending.clone();
+                async move {
+                    if room.room_id() != admin_room_id
+                        || room.state() != RoomState::Joined
+                        || Some(&*event.sender) == client.user_id()
+                    {
+                        return;
+                    }
+                    let MessageType::Text(text) = &event.content.msgtype else {
+                        return;
+                    };
+                    let mut words = text.body.split_whitespace();
+                    if words.next() != Some("!verify") {
+                        return;
+                    }
+                    let confirmed = match words.next() {
+                        Some("confirm") => true,
+                        Some("reject") => false,
+                        _ => return,
+                    };
+                    let Some(flow_id) = words.next() else {
+                        return;
+                    };
+                    let sender = pending.lock_unpoisoned().remove(flow_id);
+                    if let Some(sender) = sender {
+                        info!(
+                            "{} {} verification {}.",
+                            event.sender,
+                            if confirmed { "confirmed" } else { "rejected" },
+                            flow_id
+                        );
+                        _ = sender.send(confirmed);
+                    }
+                }
+            },
+        );
+    }
+
+    let policy = policy.clone();
+    let sync_helper = sync_helper.clone();
+    client.add_event_handler(
+        move |event: ToDeviceKeyVerificationRequestEvent, client: Client| {
+            let policy = policy.clone();
+            let pending = pending.clone();
+            let sync_helper = sync_helper.clone();
+            async move {
+                tokio::spawn(
+                    async move {
+                        if let Err(err) = handle_request(
+                            &client,
+                            event.sender,
+                            event.content.from_device,
+                            event.content.transaction_id.to_string(),
+                            &policy,
+                            &pending,
+                            &sync_helper,
+                        )
+                        .await
+                        {
+                            error!("Failed to handle a verification request: {:?}", err);
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+        },
+    );
+}
+
+#[instrument(skip_all)]
+async fn handle_request(
+    client: &Client,
+    sender: OwnedUserId,
+    device_id: OwnedDeviceId,
+    flow_id: String,
+    policy: &VerificationPolicy,
+    pending: &PendingConfirmations,
+    sync_helper: &SyncHelper,
+) -> Result<()> {
+    let encryption = client.encryption();
+    let Some(request) = encryption.get_verification_request(&sender, &flow_id).await else {
+        return Ok(());
+    };
+    let own_account = Some(&*sender) == client.user_id();
+    let verified_device = own_account
+        && encryption
+            .get_device(&sender, &device_id)
+            .await?
+            .is_some_and(|device| device.is_verified());
+    info!(
+        "Received verification request {} from {}, device {}.",
+        flow_id, sender, device_id
+    );
+
+    let accept = match policy {
+        VerificationPolicy::Ignore => return Ok(()),
+        VerificationPolicy::RejectAll => false,
+        VerificationPolicy::AcceptOwnVerifiedDevices => verified_device,
+        VerificationPolicy::AskAdminRoom(_) => own_account,
+    };
+    if !accept {
+        info!("Rejecting verification request {}.", flow_id);
+        request.cancel().await?;
+        return Ok(());
+    }
+    request.accept().await?;
+    wait_for_sas(
+        client,
+        request,
+        &flow_id,
+        verified_device,
+        policy,
+        pending,
+        sync_helper,
+    )
+    .await
+}
+
+async fn wait_for_sas(
+    client: &Client,
+    request: VerificationRequest,
+    flow_id: &str,
+    verified_device: bool,
+    policy: &VerificationPolicy,
+    pending: &PendingConfirmations,
+    sync_helper: &SyncHelper,
+) -> Result<()> {
+    let mut changes = request.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            VerificationRequestState::Transitioned { verification } => {
+                let Some(sas) = verification.sas() else {
+                    warn!("Verification {} is not SAS, cancelling.", flow_id);
+                    request.cancel().await?;
+                    return Ok(());
+                };
+                return handle_sas(
+                    client,
+                    sas,
+                    flow_id,
+                    verified_device,
+                    policy,
+                    pending,
+                    sync_helper,
+                )
+                .await;
+            }
+            VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => {
+                return Ok(());
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+async fn handle_sas(
+    client: &Client,
+    sas: SasVerification,
+    flow_id: &str,
+    verified_device: bool,
+    policy: &VerificationPolicy,
+    pending: &PendingConfirmations,
+    sync_helper: &SyncHelper,
+) -> Result<()> {
+    sas.accept().await?;
+    let mut changes = sas.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            SasState::KeysExchanged { emojis, decimals } => {
+                let confirmed = match policy {
+                    VerificationPolicy::AskAdminRoom(admin_room_id) => {
+                        let short_auth_string = match emojis {
+                            Some(emojis) => emojis
+                                .emojis
+                                .iter()
+                                .map(|emoji| format!("{} {}", emoji.symbol, emoji.description))
+                                .collect::<Vec<_>>()
+                                .join(", "),
+                            None => format!("{} {} {}", decimals.0, decimals.1, decimals.2),
+                        };
+                        ask_admin_room(
+                            client,
+                            admin_room_id,
+                            flow_id,
+                            &short_auth_string,
+                            pending,
+                            sync_helper,
+                        )
+                        .await?
+                    }
+                    _ => verified_device,
+                };
+                if confirmed {
+                    sas.confirm().await?;
+                } else {
+                    info!("Cancelling verification {}.", flow_id);
+                    sas.cancel().await?;
+                    return Ok(());
+                }
+            }
+            SasState::Done { .. } => {
+                info!("Verification {} finished.", flow_id);
+                return Ok(());
+            }
+            SasState::Cancelled(cancel_info) => {
+                warn!(
+                    "Verification {} was cancelled: {}",
+                    flow_id,
+                    cancel_info.reason()
+                );
+                return Ok(());
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+async fn ask_admin_room(
+    client: &Client,
+    admin_room_id: &OwnedRoomId,
+    flow_id: &str,
+    short_auth_string: &str,
+    pending: &PendingConfirmations,
+    sync_helper: &SyncHelper,
+) -> Result<bool> {
+    let Some(room) = client.get_room(admin_room_id) else {
+        warn!("Not a member of admin room {}.", admin_room_id);
+        return Ok(false);
+    };
+    let (tx, rx) = oneshot::channel();
+    pending.lock_unpoisoned().insert(flow_id.to_owned(), tx);
+    let message = MessageBuilder::notice()
+        .push_text(&format!(
+            "A device of this account wants to verify this bot: {}\nIf the other device shows the same, reply with `!verify confirm {}`. Otherwise, reply with `!verify reject {}`.",
+            short_auth_string, flow_id, flow_id
+        ))
+        .build();
+    crate::send::send_content(&room, message, sync_helper.rate_limiter().as_ref()).await?;
+
+    let confirmed = tokio::time::timeout(ADMIN_CONFIRM_TIMEOUT, rx)
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .unwrap_or(false);
+    pending.lock_unpoisoned().remove(flow_id);
+    Ok(confirmed)
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use matrix_sdk::Client;
+use matrix_sdk::config::SyncSettings;
+use tokio::select;
+use tokio_stream::StreamExt;
+use tracing::{info, instrument, trace, warn};
+
+use crate::SyncHelper;
+
+/// Configuration for [`SyncHelper::sync_with_watchdog`].
+///
+/// Long-lived connections occasionally get wedged silently, for example behind a NAT gateway that forgot the connection.
+/// The watchdog detects when no successful sync response arrives within [`stall_timeout`](SyncWatchdog::stall_timeout).
+#[derive(Clone)]
+pub struct SyncWatchdog {
+    /// How long to wait for a successful sync response before considering the sync stalled.
+    ///
+    /// This must be larger than the long-polling timeout in [`SyncSettings`], which defaults to 30 seconds.
+    pub stall_timeout: Duration,
+    /// Whether to tear down and rebuild the sync stream after a stall is detected.
+    ///
+    /// The rebuilt sync stream resumes from the last saved sync token.
+    pub rebuild_on_stall: bool,
+    /// A callback that is called with the time elapsed since the last successful sync response, every time a stall is detected.
+    pub on_stall: Option<Arc<dyn Fn(Duration) + Send + Sync>>,
+}
+
+impl SyncWatchdog {
+    /// Creates a [`SyncWatchdog`] that rebuilds the sync stream after `stall_timeout`, without any callback.
+    pub fn new(stall_timeout: Duration) -> Self {
+        Self {
+            stall_timeout,
+            rebuild_on_stall: true,
+            on_stall: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for SyncWatchdog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncWatchdog")
+            .field("stall_timeout", &self.stall_timeout)
+            .field("rebuild_on_stall", &self.rebuild_on_stall)
+            .field("on_stall", &self.on_stall.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl SyncHelper {
+    /// Same as [`SyncHelper::sync`], but observed by a [`SyncWatchdog`].
+    ///
+    /// Unlike [`SyncHelper::sync`], sync errors don't stop the loop. They are logged, and count as no progress for the watchdog.
+    /// Errors from saving the sync token are still returned.
+    #[instrument(skip_all)]
+    pub async fn sync_with_watchdog(
+        &self,
+        client: &Client,
+        sync_settings: impl Into<SyncSettings>,
+        watchdog: &SyncWatchdog,
+    ) -> Result<(), matrix_sdk::Error> {
+        let sync_settings = sync_settings.into();
+        loop {
+            let sync_stream = self.pausable_sync_stream(client, sync_settings.clone());
+            tokio::pin!(sync_stream);
+            let mut last_success = self.clock.now();
+            let mut deadline = last_success + watchdog.stall_timeout;
+            loop {
+                let timeout = self.clock.sleep(
+                    deadline
+                        .duration_since(self.clock.now())
+                        .unwrap_or_default(),
+                );
+                let response = select! {
+                    response = sync_stream.next() => Ok(response),
+                    () = timeout => Err(()),
+                };
+                match response {
+                    Ok(response) => match response
+                        // sync_stream is infinite
+                        .unwrap()
+                    {
+                        Ok(response) => {
+                            trace!("Sync response: {:?}", response);
+                            self.process_sync_response(&response)?;
+                            last_success = self.clock.now();
+                            deadline = last_success + watchdog.stall_timeout;
+                        }
+                        Err(err) => warn!("Sync failed: {}", err),
+                    },
+                    Err(_) if self.is_paused() => {
+                        // Not a stall
+                        last_success = self.clock.now();
+                        deadline = last_success + watchdog.stall_timeout;
+                    }
+                    Err(_) => {
+                        let elapsed = self
+                            .clock
+                            .now()
+                            .duration_since(last_success)
+                            .unwrap_or_default();
+                        warn!(
+                            "No successful sync response in {:.1}s.",
+                            elapsed.as_secs_f64()
+                        );
+                        if let Some(on_stall) = &watchdog.on_stall {
+                            on_stall(elapsed);
+                        }
+                        if watchdog.rebuild_on_stall {
+                            break;
+                        }
+                        deadline += watchdog.stall_timeout;
+                    }
+                }
+            }
+            info!("Rebuilding the sync stream.");
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use eyre::{Result, bail};
+use matrix_sdk::Client;
+use rand::Rng;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, instrument, warn};
+
+use crate::http::{self, constant_time_eq};
+use crate::mutex::MutexExt;
+use crate::reply::html_escape;
+use crate::{SetupConfig, setup};
+
+const MAX_REQUEST_SIZE: usize = 65536;
+
+/// Set up a Matrix bot account through a temporary web form, for machines without an interactive terminal.
+///
+/// It serves a form at `bind_addr`, completes the setup with the submitted credentials, shows the recovery key, then stops serving.
+///
+/// A random token is required to access the form, so other users on the same machine can't take over the setup.
+/// The complete URL is logged at the `INFO` level.
+///
+/// The form is served over plain HTTP. Keep `bind_addr` on localhost, and use SSH port forwarding for remote machines.
+///
+/// # Arguments
+///
+/// * `data_dir`: A directory to store the bot's state database.
+///
+///   Later [`login`](crate::login) calls need to use the same directory.
+///
+///   One directory can only store one session.
+///
+/// * `bind_addr`: The address to serve the form, for example, `127.0.0.1:8008`.
+#[instrument(skip(data_dir))]
+pub async fn setup_web(data_dir: &Path, bind_addr: SocketAddr) -> Result<Client> {
+    let listener = TcpListener::bind(bind_addr).await?;
+    let token = rand::rng()
+        .sample_iter(rand::distr::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect::<String>();
+    info!(
+        "Please open http://{}/?token={} to set up the Matrix account.",
+        listener.local_addr()?,
+        token
+    );
+
+    let mut client = None;
+    let mut recovery_key = None;
+    loop {
+        let (mut stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Failed to accept a connection: {}", err);
+                continue;
+            }
+        };
+        // Connections are served one by one, so the read timeout keeps a slow client from blocking the others
+        let request = match http::read_request(&mut stream, MAX_REQUEST_SIZE).await {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(err) => {
+                debug!("Failed to read from {}: {}", peer_addr, err);
+                continue;
+            }
+        };
+        let form = parse_form(&request.body);
+        let authorized = form_value(&parse_form(request.query.as_bytes()), "token")
+            .is_some_and(|value| constant_time_eq(value.as_bytes(), token.as_bytes()))
+            || form_value(&form, "token")
+                .is_some_and(|value| constant_time_eq(value.as_bytes(), token.as_bytes()));
+
+        let response = match (request.method.as_str(), request.path.as_str()) {
+            _ if !authorized => Response::new(
+                403,
+                "Forbidden",
+                page("Please use the URL shown in the log."),
+            ),
+            ("GET", "/") if client.is_none() => Response::new(200, "OK", setup_form(&token, None)),
+            ("GET", "/") => Response::new(
+                200,
+                "OK",
+                recovery_key_page(&token, recovery_key.as_ref(), None),
+            ),
+            ("POST", "/setup") if client.is_none() => match run_setup(data_dir, &form).await {
+                Ok((new_client, new_recovery_key)) => {
+                    client = Some(new_client);
+                    recovery_key = Some(new_recovery_key);
+                    Response::new(
+                        200,
+                        "OK",
+                        recovery_key_page(&token, recovery_key.as_ref(), None),
+                    )
+                }
+                Err(err) => {
+                    warn!("Setup failed: {:?}", err);
+                    Response::new(200, "OK", setup_form(&token, Some(&err.to_string())))
+                }
+            },
+            ("POST", "/done") if client.is_some() => {
+                let confirmed = match &recovery_key {
+                    Some((recovery_key, true)) => {
+                        let expected = recovery_key.split_whitespace().collect::<String>();
+                        let actual = form_value(&form, "recovery_key")
+                            .unwrap_or_default()
+                            .split_whitespace()
+                            .collect::<String>();
+                        actual == expected
+                    }
+                    // The admin just typed it in
+                    _ => true,
+                };
+                if confirmed {
+                    let response = Response::new(
+                        200,
+                        "OK",
+                        page("Setup finished. You can close this page now."),
+                    );
+                    _ = response.send(&mut stream).await;
+                    break;
+                }
+                Response::new(
+                    200,
+                    "OK",
+                    recovery_key_page(
+                        &token,
+                        recovery_key.as_ref(),
+                        Some("The recovery key doesn't match, please try again."),
+                    ),
+                )
+            }
+            ("GET" | "POST", _) => Response::new(404, "Not Found", page("Not found.")),
+            _ => Response::new(405, "Method Not Allowed", page("Method not allowed.")),
+        };
+        if let Err(err) = response.send(&mut stream).await {
+            debug!("Failed to respond to {}: {}", peer_addr, err);
+        }
+    }
+
+    info!("Web setup finished.");
+    // The loop only breaks after setup succeeded
+    Ok(client.unwrap())
+}
+
+async fn run_setup(data_dir: &Path, form: &[(String, String)]) -> Result<(Client, (String, bool))> {
+    let field = |name: &'static str| form_value(form, name).unwrap_or_default().trim();
+    let recovery_key = field("recovery_key");
+    let allow_reset = field("allow_reset") == "on";
+    let saved_recovery_key = Mutex::new(None);
+    let config = SetupConfig {
+        data_dir,
+        homeserver: field("homeserver"),
+        username: field("username"),
+        password: form_value(form, "password").unwrap_or_default(),
+        device_name: match field("device_name") {
+            "" => "matrixbot-ezlogin",
+            device_name => device_name,
+        },
+        ask_recovery_key: async {
+            if recovery_key.is_empty() {
+                bail!("a backup exists on the server, please enter its recovery key");
+            }
+            Ok(recovery_key.to_owned())
+        },
+        before_create_backup: async {
+            if !allow_reset {
+                bail!(
+                    "no backup exists on the server, please allow resetting the cryptographic identity"
+                );
+            }
+            Ok(())
+        },
+        print_recovery_key: async |recovery_key: String, new_backup: bool| {
+            *saved_recovery_key.lock_unpoisoned() = Some((recovery_key, new_backup));
+            Ok(())
+        },
+    };
+    let client = setup(config).await?;
+    let recovery_key = saved_recovery_key
+        .into_inner()
+        // lock() will only return an error after some other task panicked
+        .unwrap()
+        // setup always calls print_recovery_key before returning successfully
+        .unwrap_or_default();
+    Ok((client, recovery_key))
+}
+
+/// Parses `application/x-www-form-urlencoded` data.
+fn parse_form(data: &[u8]) -> Vec<(String, String)> {
+    data.split(|&b| b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut pair = pair.splitn(2, |&b| b == b'=');
+            let name = percent_decode(pair.next().unwrap_or_default());
+            let value = percent_decode(pair.next().unwrap_or_default());
+            (name, value)
+        })
+        .collect()
+}
+
+fn form_value<'a>(form: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    form.iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+fn percent_decode(data: &[u8]) -> String {
+    let mut result = Vec::with_capacity(data.len());
+    let mut iter = data.iter().copied();
+    while let Some(b) = iter.next() {
+        match b {
+            b'+' => result.push(b' '),
+            b'%' => {
+                let hex = [iter.next().unwrap_or(b'0'), iter.next().unwrap_or(b'0')];
+                let decoded = std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match decoded {
+                    Some(decoded) => result.push(decoded),
+                    None => result.extend_from_slice(&[b'%', hex[0], hex[1]]),
+                }
+            }
+            b => result.push(b),
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+fn page(body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width\"><title>matrixbot-ezlogin setup</title></head><body>\n{}\n</body></html>\n",
+        body
+    )
+}
+
+fn setup_form(token: &str, error: Option<&str>) -> String {
+    let mut body = String::from("<h1>Set up a Matrix bot account</h1>\n");
+    if let Some(error) = error {
+        _ = writeln!(
+            body,
+            "<p><strong>Error: {}</strong></p>",
+            html_escape(error)
+        );
+    }
+    _ = write!(
+        body,
+        concat!(
+            "<form method=\"post\" action=\"/setup\">\n",
+            "<input type=\"hidden\" name=\"token\" value=\"{}\">\n",
+            "<p><label>Matrix homeserver: <input name=\"homeserver\" required></label></p>\n",
+            "<p><label>User name: <input name=\"username\" required autocomplete=\"username\"></label></p>\n",
+            "<p><label>Password: <input name=\"password\" type=\"password\" required autocomplete=\"current-password\"></label></p>\n",
+            "<p><label>Device name: <input name=\"device_name\" value=\"matrixbot-ezlogin\"></label></p>\n",
+            "<p><label>Backup recovery key, if the account already has a server-side backup: <input name=\"recovery_key\" type=\"password\" autocomplete=\"off\"></label></p>\n",
+            "<p><label><input name=\"allow_reset\" type=\"checkbox\"> If the account has no server-side backup, reset the cryptographic identity to create one</label></p>\n",
+            "<p><button type=\"submit\">Set up</button></p>\n",
+            "</form>"
+        ),
+        html_escape(token)
+    );
+    page(&body)
+}
+
+fn recovery_key_page(
+    token: &str,
+    recovery_key: Option<&(String, bool)>,
+    error: Option<&str>,
+) -> String {
+    let mut body = String::from("<h1>Save the recovery key</h1>\n");
+    if let Some(error) = error {
+        _ = writeln!(
+            body,
+            "<p><strong>Error: {}</strong></p>",
+            html_escape(error)
+        );
+    }
+    let (recovery_key, new_backup) = recovery_key
+        .map(|(key, new)| (key.as_str(), *new))
+        .unwrap_or_default();
+    _ = writeln!(
+        body,
+        "<p>Please keep the recovery key in a safe place:</p>\n<p><code>{}</code></p>",
+        html_escape(recovery_key)
+    );
+    _ = write!(
+        body,
+        "<form method=\"post\" action=\"/done\">\n<input type=\"hidden\" name=\"token\" value=\"{}\">\n",
+        html_escape(token)
+    );
+    if new_backup {
+        body.push_str("<p><label>Type or paste the recovery key again to confirm: <input name=\"recovery_key\" autocomplete=\"off\" required></label></p>\n");
+    }
+    body.push_str("<p><button type=\"submit\">I have saved the recovery key</button></p>\n</form>");
+    page(&body)
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn new(status: u16, reason: &'static str, body: String) -> Self {
+        Self {
+            status,
+            reason,
+            body,
+        }
+    }
+
+    async fn send(&self, stream: &mut TcpStream) -> Result<()> {
+        http::write_response(
+            stream,
+            &format!("{} {}", self.status, self.reason),
+            &[
+                ("Content-Type", "text/html; charset=utf-8"),
+                ("Cache-Control", "no-store"),
+                ("Referrer-Policy", "no-referrer"),
+            ],
+            self.body.as_bytes(),
+        )
+        .await
+    }
+}
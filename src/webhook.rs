@@ -0,0 +1,449 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use eyre::{Result, bail};
+use hmac::{Hmac, Mac};
+use matrix_sdk::Client;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, instrument, warn};
+
+use crate::http::{self, Request, ServerHandle, constant_time_eq};
+use crate::reply::html_escape;
+use crate::{MessageBuilder, RateLimiter};
+
+const MAX_REQUEST_SIZE: usize = 1048576;
+
+/// How [`serve_webhooks`] turns the JSON payload of a webhook into a message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WebhookFormat {
+    /// Posts the `text`, `body`, or `message` field if the payload has one, or the whole payload as a code block otherwise.
+    #[default]
+    Generic,
+    /// Prometheus Alertmanager notifications, one line per alert.
+    Alertmanager,
+    /// Grafana alerting notifications.
+    Grafana,
+    /// GitHub webhooks, summarizing pushes, issues, pull requests, and releases.
+    ///
+    /// Set the same secret in GitHub's webhook settings. Requests are authenticated by their `X-Hub-Signature-256` header, an HMAC-SHA256 of the payload, instead of a bearer token.
+    GitHub,
+}
+
+/// A webhook endpoint served by [`serve_webhooks`].
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    /// The URL path, for example, `/alertmanager`.
+    pub path: String,
+    /// The room to post messages to. The bot must have joined it.
+    pub room_id: OwnedRoomId,
+    /// The secret a request must carry, either as `Authorization: Bearer <secret>`, or as `?token=<secret>` in the URL. It must not be empty.
+    ///
+    /// With [`WebhookFormat::GitHub`], the secret signs the payload instead, see there.
+    pub secret: String,
+    /// How to format the payload.
+    pub format: WebhookFormat,
+    /// A rate limiter that relayed messages wait for.
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl Webhook {
+    /// Creates a webhook at `path` that posts to `room_id` in [`WebhookFormat::Generic`].
+    ///
+    /// Fails if `secret` is empty or only whitespace, because anyone could send an empty secret.
+    pub fn new(path: &str, room_id: OwnedRoomId, secret: &str) -> Result<Self> {
+        let webhook = Self {
+            path: path.to_owned(),
+            room_id,
+            secret: secret.to_owned(),
+            format: WebhookFormat::Generic,
+            rate_limiter: None,
+        };
+        webhook.validate()?;
+        Ok(webhook)
+    }
+
+    /// Sets how to format the payload.
+    pub fn format(mut self, format: WebhookFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets a rate limiter that relayed messages wait for.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Returns an error if the secret would let anyone in.
+    fn validate(&self) -> Result<()> {
+        if self.secret.trim().is_empty() {
+            // TODO: If anyone needs programmable detection, transform these ad-hoc errors into named error types.
+            bail!("the secret of webhook {} must not be empty", self.path);
+        }
+        Ok(())
+    }
+}
+
+/// Starts accepting webhook `POST` requests on `listen_addr`, in a background task, and relays each of them as a message into the room of its [`Webhook`].
+///
+/// Messages are encrypted in encrypted rooms. If sending fails, the request fails with status 502, so the sender can retry.
+///
+/// The webhooks are served over plain HTTP. Put a TLS-terminating reverse proxy in front of it if the senders are on other machines.
+///
+/// Call [`ServerHandle::shutdown`] on the returned handle to stop serving, for example, before the bot logs out.
+///
+/// Fails if the secret of any webhook is empty.
+///
+/// # Example
+///
+/// ```no_run
+/// use matrixbot_ezlogin::{Webhook, WebhookFormat};
+///
+/// # async fn example(client: matrix_sdk::Client, room_id: matrix_sdk::ruma::OwnedRoomId) -> color_eyre::Result<()> {
+/// let secret = std::env::var("WEBHOOK_SECRET")?;
+/// let webhooks = matrixbot_ezlogin::serve_webhooks(
+///     "127.0.0.1:9095".parse()?,
+///     client,
+///     vec![Webhook::new("/alertmanager", room_id, &secret)?.format(WebhookFormat::Alertmanager)],
+/// )
+/// .await?;
+/// // ...
+/// webhooks.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+#[instrument(skip(client, webhooks))]
+pub async fn serve_webhooks(
+    listen_addr: SocketAddr,
+    client: Client,
+    webhooks: Vec<Webhook>,
+) -> Result<ServerHandle> {
+    // The fields are public, so Webhook::new may have been bypassed
+    for webhook in &webhooks {
+        webhook.validate()?;
+    }
+    let listener = TcpListener::bind(listen_addr).await?;
+    for webhook in &webhooks {
+        info!(
+            "Serving webhook at http://{}{} for room {}.",
+            listen_addr, webhook.path, webhook.room_id
+        );
+    }
+    let webhooks: Arc<[Webhook]> = webhooks.into();
+    Ok(http::serve(listener, move |stream| {
+        let client = client.clone();
+        let webhooks = webhooks.clone();
+        async move { handle_connection(stream, &client, &webhooks).await }
+    }))
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    client: &Client,
+    webhooks: &[Webhook],
+) -> Result<()> {
+    let Some(request) = http::read_request(&mut stream, MAX_REQUEST_SIZE).await? else {
+        return Ok(());
+    };
+    let status = match webhooks.iter().find(|webhook| webhook.path == request.path) {
+        None => "404 Not Found",
+        Some(_) if request.method != "POST" => "405 Method Not Allowed",
+        Some(webhook) if !is_authorized(&request, webhook) => "401 Unauthorized",
+        Some(webhook) => match serde_json::from_slice::<Value>(&request.body) {
+            Err(err) => {
+                debug!("Invalid payload for webhook {}: {}", webhook.path, err);
+                "400 Bad Request"
+            }
+            Ok(payload) => {
+                let event = request.header("x-github-event").unwrap_or_default();
+                match format_payload(webhook.format, event, &payload) {
+                    // For example, a GitHub event type we don't summarize
+                    None => "204 No Content",
+                    Some(content) => match relay(client, webhook, content).await {
+                        Ok(()) => "204 No Content",
+                        Err(err) => {
+                            warn!("Failed to relay webhook {}: {}", webhook.path, err);
+                            "502 Bad Gateway"
+                        }
+                    },
+                }
+            }
+        },
+    };
+    http::write_response(&mut stream, status, &[], b"").await
+}
+
+fn is_authorized(request: &Request, webhook: &Webhook) -> bool {
+    if webhook.format == WebhookFormat::GitHub {
+        return request
+            .header("x-hub-signature-256")
+            .is_some_and(|signature| verify_signature(&webhook.secret, &request.body, signature));
+    }
+    let bearer = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let token = request
+        .query
+        .split('&')
+        .find_map(|param| param.strip_prefix("token="));
+    [bearer, token]
+        .into_iter()
+        .flatten()
+        .any(|candidate| constant_time_eq(candidate.trim().as_bytes(), webhook.secret.as_bytes()))
+}
+
+/// Checks a `sha256=<hex>` signature as sent by GitHub.
+fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Some(signature) = signature
+        .strip_prefix("sha256=")
+        .filter(|hex| hex.len() == 64)
+        .and_then(|hex| {
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+        })
+    else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        // HMAC accepts keys of any length
+        .unwrap();
+    mac.update(body);
+    // verify_slice compares in constant time
+    mac.verify_slice(&signature).is_ok()
+}
+
+async fn relay(client: &Client, webhook: &Webhook, content: RoomMessageEventContent) -> Result<()> {
+    let Some(room) = client.get_room(&webhook.room_id) else {
+        bail!("not a member of room {}", webhook.room_id);
+    };
+    crate::send::send_content(&room, content, webhook.rate_limiter.as_ref()).await?;
+    Ok(())
+}
+
+fn format_payload(
+    format: WebhookFormat,
+    event: &str,
+    payload: &Value,
+) -> Option<RoomMessageEventContent> {
+    match format {
+        WebhookFormat::Generic => Some(format_generic(payload)),
+        WebhookFormat::Alertmanager => Some(format_alertmanager(payload)),
+        WebhookFormat::Grafana => Some(format_grafana(payload)),
+        WebhookFormat::GitHub => format_github(event, payload),
+    }
+}
+
+fn str_field<'a>(value: &'a Value, pointer: &str) -> Option<&'a str> {
+    value.pointer(pointer).and_then(Value::as_str)
+}
+
+fn format_generic(payload: &Value) -> RoomMessageEventContent {
+    if let Some(text) = ["/text", "/body", "/message"]
+        .into_iter()
+        .find_map(|pointer| str_field(payload, pointer))
+    {
+        return MessageBuilder::notice().push_text(text).build();
+    }
+    let json = serde_json::to_string_pretty(payload).unwrap_or_default();
+    MessageBuilder::notice()
+        .push_html(
+            &json,
+            &format!(
+                "<pre><code class=\"language-json\">{}</code></pre>",
+                html_escape(&json)
+            ),
+        )
+        .build()
+}
+
+fn format_alertmanager(payload: &Value) -> RoomMessageEventContent {
+    let status = str_field(payload, "/status").unwrap_or("unknown");
+    let alerts = payload
+        .get("alerts")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let name = str_field(payload, "/commonLabels/alertname").unwrap_or("alerts");
+    let mut plain = format!("[{}:{}] {}", status.to_uppercase(), alerts.len(), name);
+    let mut html = format!(
+        "<strong>[{}:{}] {}</strong>",
+        html_escape(&status.to_uppercase()),
+        alerts.len(),
+        html_escape(name)
+    );
+    for alert in alerts {
+        let summary = str_field(alert, "/annotations/summary")
+            .or_else(|| str_field(alert, "/annotations/description"))
+            .or_else(|| str_field(alert, "/labels/alertname"))
+            .unwrap_or_default();
+        let mut line = format!(
+            "{}: {}",
+            str_field(alert, "/status").unwrap_or(status),
+            summary
+        );
+        if let Some(instance) = str_field(alert, "/labels/instance") {
+            _ = write!(line, " ({})", instance);
+        }
+        _ = write!(plain, "\n• {}", line);
+        _ = write!(html, "<br>• {}", html_escape(&line));
+    }
+    MessageBuilder::notice().push_html(&plain, &html).build()
+}
+
+fn format_grafana(payload: &Value) -> RoomMessageEventContent {
+    let Some(title) = str_field(payload, "/title") else {
+        // Grafana's alert payload extends the Alertmanager payload
+        return format_alertmanager(payload);
+    };
+    let mut message = MessageBuilder::notice()
+        .push_html(title, &format!("<strong>{}</strong>", html_escape(title)));
+    if let Some(text) = str_field(payload, "/message").filter(|text| !text.is_empty()) {
+        message = message.push_text("\n").push_text(text);
+    }
+    message.build()
+}
+
+fn format_github(event: &str, payload: &Value) -> Option<RoomMessageEventContent> {
+    let repo = str_field(payload, "/repository/full_name").unwrap_or_default();
+    let sender = str_field(payload, "/sender/login").unwrap_or_default();
+    let (text, url) = match event {
+        "ping" => return None,
+        "push" => {
+            let commits = payload
+                .get("commits")
+                .and_then(Value::as_array)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            if commits.is_empty() {
+                return None;
+            }
+            let branch = str_field(payload, "/ref")
+                .unwrap_or_default()
+                .trim_start_matches("refs/heads/");
+            let mut text = format!(
+                "{} pushed {} commit(s) to {}:{}",
+                sender,
+                commits.len(),
+                repo,
+                branch
+            );
+            for commit in commits {
+                let id = str_field(commit, "/id").unwrap_or_default();
+                let title = str_field(commit, "/message")
+                    .unwrap_or_default()
+                    .lines()
+                    .next()
+                    .unwrap_or_default();
+                _ = write!(text, "\n• {} {}", id.get(..7).unwrap_or(id), title);
+            }
+            (text, str_field(payload, "/compare"))
+        }
+        "issues" | "pull_request" => {
+            let kind = if event == "issues" {
+                "issue"
+            } else {
+                "pull request"
+            };
+            let item = payload.get(event.trim_end_matches('s'))?;
+            let mut action = str_field(payload, "/action").unwrap_or_default();
+            if action == "closed" && item.get("merged").and_then(Value::as_bool) == Some(true) {
+                action = "merged";
+            }
+            if !matches!(action, "opened" | "closed" | "reopened" | "merged") {
+                return None;
+            }
+            (
+                format!(
+                    "{} {} {} {}#{}: {}",
+                    sender,
+                    action,
+                    kind,
+                    repo,
+                    item.get("number")
+                        .and_then(Value::as_u64)
+                        .unwrap_or_default(),
+                    str_field(item, "/title").unwrap_or_default()
+                ),
+                str_field(item, "/html_url"),
+            )
+        }
+        "release" => {
+            if str_field(payload, "/action") != Some("published") {
+                return None;
+            }
+            let release = payload.get("release")?;
+            (
+                format!(
+                    "{} published release {} of {}",
+                    sender,
+                    str_field(release, "/name")
+                        .filter(|name| !name.is_empty())
+                        .or_else(|| str_field(release, "/tag_name"))
+                        .unwrap_or_default(),
+                    repo
+                ),
+                str_field(release, "/html_url"),
+            )
+        }
+        _ => (format!("{} triggered {} on {}", sender, event, repo), None),
+    };
+    let mut message = MessageBuilder::notice().push_text(&text);
+    if let Some(url) = url {
+        message = message.push_html(
+            &format!("\n{}", url),
+            &format!("<br><a href=\"{0}\">{0}</a>", html_escape(url)),
+        );
+    }
+    Some(message.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::owned_room_id;
+
+    use super::*;
+
+    fn request(query: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "POST".to_owned(),
+            path: "/hook".to_owned(),
+            query: query.to_owned(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: b"{}".to_vec(),
+        }
+    }
+
+    #[test]
+    fn rejects_empty_secrets() {
+        for secret in ["", " ", "\t\n"] {
+            assert!(Webhook::new("/hook", owned_room_id!("!room:example.com"), secret).is_err());
+        }
+    }
+
+    #[test]
+    fn refuses_empty_tokens() {
+        let webhook = Webhook::new("/hook", owned_room_id!("!room:example.com"), "secret").unwrap();
+        assert!(is_authorized(&request("token=secret", &[]), &webhook));
+        assert!(is_authorized(
+            &request("", &[("Authorization", "Bearer secret")]),
+            &webhook
+        ));
+        assert!(!is_authorized(&request("", &[]), &webhook));
+        assert!(!is_authorized(&request("token=", &[]), &webhook));
+        assert!(!is_authorized(
+            &request("", &[("Authorization", "Bearer ")]),
+            &webhook
+        ));
+        assert!(!is_authorized(&request("token=wrong", &[]), &webhook));
+    }
+}
@@ -0,0 +1,74 @@
+// Needs Docker. Run with `cargo test --features test-server --test test-server`.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use matrixbot_ezlogin::{EzloginTestServer, SyncOptions};
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+
+#[tokio::test]
+async fn setup_login_and_sync() -> color_eyre::Result<()> {
+    let server = EzloginTestServer::start().await?;
+    let account = server.create_account("alice").await?;
+    let data_dir = std::env::temp_dir().join(format!("ezlogin-test-{}", std::process::id()));
+
+    account.setup(&data_dir).await?;
+    let (client, sync_helper) = matrixbot_ezlogin::login(&data_dir).await?;
+    assert_eq!(client.user_id(), Some(&*account.user_id));
+    sync_helper
+        .sync_once(&client, SyncOptions::default())
+        .await?;
+    assert!(sync_helper.get_sync_token().is_some());
+
+    drop(sync_helper);
+    drop(client);
+    std::fs::remove_dir_all(&data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn supervised_restart_after_sync_error() -> color_eyre::Result<()> {
+    let server = EzloginTestServer::start().await?;
+    let account = server.create_account("bob").await?;
+    let data_dir =
+        std::env::temp_dir().join(format!("ezlogin-test-restart-{}", std::process::id()));
+    account.setup(&data_dir).await?;
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let restarted = Arc::new(Notify::new());
+    let supervisor = matrixbot_ezlogin::run_supervised(&data_dir, {
+        let runs = runs.clone();
+        let restarted = restarted.clone();
+        move |client, sync_helper| {
+            let run = runs.fetch_add(1, Ordering::SeqCst);
+            let restarted = restarted.clone();
+            async move {
+                let mut tasks = JoinSet::new();
+                if run == 0 {
+                    // The homeserver rejects this token, so the sync loop fails
+                    sync_helper.set_sync_token("invalid".to_owned())?;
+                    // A task that never ends, holding on to the crashed session
+                    tasks.spawn(async move {
+                        let _session = (client, sync_helper);
+                        std::future::pending().await
+                    });
+                } else {
+                    sync_helper.rollback(1)?;
+                    restarted.notify_one();
+                }
+                Ok(tasks)
+            }
+        }
+    });
+    select! {
+        result = supervisor => panic!("run_supervised returned: {:?}", result),
+        result = tokio::time::timeout(Duration::from_secs(60), restarted.notified()) => result?,
+    }
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+    std::fs::remove_dir_all(&data_dir)?;
+    Ok(())
+}